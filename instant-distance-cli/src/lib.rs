@@ -0,0 +1,6 @@
+//! Support code for the `instant-distance-cli` binary
+//!
+//! The binary itself lives in `main.rs`; the file formats it reads are kept here so they can
+//! also be used (and tested) from other code.
+
+pub mod vecs;
@@ -0,0 +1,247 @@
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use instant_distance::eval::mean_recall_at_k;
+use instant_distance::points::Vector;
+use instant_distance::{Builder, HnswMap, Search};
+use instant_distance_cli::vecs;
+
+/// The `M` parameter the library is compiled with, which `build --m` has to match
+const M: usize = 32;
+
+/// Build and query instant-distance indexes from the command line
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Build an index from an `.fvecs` or `.bvecs` file
+    Build {
+        /// Vectors to index
+        #[arg(long)]
+        input: PathBuf,
+        /// Where to write the index
+        #[arg(long)]
+        out: PathBuf,
+        /// Candidate list size used while building the graph
+        #[arg(long, default_value_t = 100)]
+        ef_construction: usize,
+        /// Candidate list size used when searching the index
+        #[arg(long, default_value_t = 100)]
+        ef_search: usize,
+        /// Maximum number of links per node on the upper layers
+        ///
+        /// This is fixed when the library is compiled, so only 32 is accepted.
+        #[arg(long)]
+        m: Option<usize>,
+        /// Seed for the random number generator, for reproducible builds
+        #[arg(long)]
+        seed: Option<u64>,
+    },
+    /// Find the nearest neighbors for each vector in an `.fvecs` or `.bvecs` file
+    ///
+    /// Prints one line per query with the (zero-based) positions of the neighbors in the file the
+    /// index was built from, nearest first.
+    Query {
+        /// Index written by `build`
+        #[arg(long)]
+        index: PathBuf,
+        /// Query vectors
+        #[arg(long)]
+        input: PathBuf,
+        /// Number of neighbors to return per query
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+        /// Print `position:distance` pairs instead of bare positions
+        #[arg(long)]
+        distances: bool,
+    },
+    /// Measure recall and latency of an index against exact nearest neighbors
+    ///
+    /// The ground truth file holds the positions of the true nearest neighbors of each query in
+    /// the file the index was built from, nearest first, as distributed with the TEXMEX datasets.
+    Evaluate {
+        /// Index written by `build`
+        #[arg(long)]
+        index: PathBuf,
+        /// Query vectors
+        #[arg(long)]
+        queries: PathBuf,
+        /// Exact nearest neighbors for each query, as an `.ivecs` file
+        #[arg(long)]
+        ground_truth: PathBuf,
+        /// Number of neighbors to evaluate per query
+        #[arg(long, default_value_t = 10)]
+        k: usize,
+    },
+    /// Rewrite an index in the current serialization format
+    ///
+    /// Indexes written by older versions (including ones without a format header) are upgraded
+    /// in the process. Only the crate's native format is supported; there is no hnswlib or FAISS
+    /// interop to convert from or to yet.
+    Convert {
+        /// Index to read
+        #[arg(long)]
+        input: PathBuf,
+        /// Where to write the converted index
+        #[arg(long)]
+        out: PathBuf,
+    },
+}
+
+fn main() -> anyhow::Result<()> {
+    match Cli::parse().command {
+        Command::Build {
+            input,
+            out,
+            ef_construction,
+            ef_search,
+            m,
+            seed,
+        } => {
+            if let Some(m) = m.filter(|&m| m != M) {
+                bail!("--m {m} is not supported: the library is compiled with M = {M}");
+            }
+
+            let points = vecs::read_points(&input)?
+                .into_iter()
+                .map(Vector)
+                .collect::<Vec<_>>();
+            let values = (0..points.len() as u32).collect::<Vec<_>>();
+
+            let mut builder = Builder::default()
+                .ef_construction(ef_construction)
+                .ef_search(ef_search);
+            if let Some(seed) = seed {
+                builder = builder.seed(seed);
+            }
+
+            let start = Instant::now();
+            let len = points.len();
+            let map = builder.try_build(points, values)?;
+            eprintln!("built index for {len} points in {:?}", start.elapsed());
+
+            save(&map, &out)?;
+        }
+        Command::Query {
+            index,
+            input,
+            k,
+            distances,
+        } => {
+            let map = load(&index)?;
+            let queries = vecs::read_points(&input)?;
+            check_dims(&map, &queries)?;
+
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            let mut search = Search::default();
+            search.k(Some(k));
+            for query in queries {
+                let mut first = true;
+                for item in map.search(&Vector(query), &mut search) {
+                    if !first {
+                        write!(out, " ")?;
+                    }
+                    first = false;
+
+                    match distances {
+                        true => write!(out, "{}:{}", item.value, item.distance)?,
+                        false => write!(out, "{}", item.value)?,
+                    }
+                }
+                writeln!(out)?;
+            }
+            out.flush()?;
+        }
+        Command::Evaluate {
+            index,
+            queries,
+            ground_truth,
+            k,
+        } => {
+            let map = load(&index)?;
+            let queries = vecs::read_points(&queries)?;
+            check_dims(&map, &queries)?;
+            let ground_truth = vecs::read_ivecs(&ground_truth)?
+                .into_iter()
+                .map(|truth| truth.into_iter().map(|pos| pos as u32).collect())
+                .collect::<Vec<Vec<u32>>>();
+            if ground_truth.len() != queries.len() {
+                bail!(
+                    "got ground truth for {} queries, expected {}",
+                    ground_truth.len(),
+                    queries.len()
+                );
+            }
+
+            let mut search = Search::default();
+            search.k(Some(k));
+            let mut results = Vec::with_capacity(queries.len());
+            let mut latencies = Vec::with_capacity(queries.len());
+            for query in queries {
+                let query = Vector(query);
+                let start = Instant::now();
+                let found = map
+                    .search(&query, &mut search)
+                    .map(|item| *item.value)
+                    .collect::<Vec<_>>();
+                latencies.push(start.elapsed());
+                results.push(found);
+            }
+
+            let recall = mean_recall_at_k(&results, &ground_truth, k).unwrap_or(0.0);
+            latencies.sort_unstable();
+            let mean = latencies.iter().sum::<Duration>() / latencies.len().max(1) as u32;
+            println!("queries:   {}", results.len());
+            println!("recall@{k}: {recall:.4}");
+            println!("latency:   mean {mean:?}");
+            for pct in [50, 90, 99] {
+                println!("           p{pct} {:?}", percentile(&latencies, pct));
+            }
+        }
+        Command::Convert { input, out } => save(&load(&input)?, &out)?,
+    }
+
+    Ok(())
+}
+
+fn load(path: &Path) -> anyhow::Result<HnswMap<Vector, u32>> {
+    HnswMap::load_file(path)
+        .with_context(|| format!("failed to load index from {}", path.display()))
+}
+
+fn save(map: &HnswMap<Vector, u32>, path: &Path) -> anyhow::Result<()> {
+    map.save_file(path)
+        .with_context(|| format!("failed to write index to {}", path.display()))
+}
+
+fn check_dims(map: &HnswMap<Vector, u32>, queries: &[Vec<f32>]) -> anyhow::Result<()> {
+    let expected = match map.dims() {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+
+    match queries.iter().position(|query| query.len() != expected) {
+        Some(i) => bail!(
+            "query {i} has {} dimensions, index has {expected}",
+            queries[i].len()
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Nearest-rank percentile of a sorted slice
+fn percentile(sorted: &[Duration], pct: usize) -> Duration {
+    match sorted.len() {
+        0 => Duration::ZERO,
+        len => sorted[((len * pct + 99) / 100).saturating_sub(1)],
+    }
+}
@@ -0,0 +1,141 @@
+//! Readers and writers for the `.fvecs`, `.ivecs` and `.bvecs` formats used by the TEXMEX
+//! benchmark datasets
+//!
+//! Each vector is stored as a little-endian `i32` dimension count followed by that many
+//! components: 4-byte little-endian floats or integers, or single unsigned bytes for `.bvecs`.
+//! All vectors in a file must have the same dimensionality.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context};
+
+/// Read the vectors to index or query from an `.fvecs` or `.bvecs` file
+///
+/// Files with a `.bvecs` extension are read as bytes and converted to floats; anything else is
+/// read as `.fvecs`.
+pub fn read_points(path: &Path) -> anyhow::Result<Vec<Vec<f32>>> {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("bvecs") => Ok(read_bvecs(path)?
+            .into_iter()
+            .map(|vector| vector.into_iter().map(f32::from).collect())
+            .collect()),
+        _ => read_fvecs(path),
+    }
+}
+
+/// Read all vectors from an `.fvecs` file
+pub fn read_fvecs(path: &Path) -> anyhow::Result<Vec<Vec<f32>>> {
+    read_vecs(path, f32::from_le_bytes)
+}
+
+/// Read all vectors from an `.ivecs` file
+pub fn read_ivecs(path: &Path) -> anyhow::Result<Vec<Vec<i32>>> {
+    read_vecs(path, i32::from_le_bytes)
+}
+
+/// Read all vectors from a `.bvecs` file
+pub fn read_bvecs(path: &Path) -> anyhow::Result<Vec<Vec<u8>>> {
+    read_vecs(path, |[b]| b)
+}
+
+/// Write `vectors` to an `.fvecs` file
+pub fn write_fvecs(path: &Path, vectors: &[Vec<f32>]) -> anyhow::Result<()> {
+    write_vecs(path, vectors, |v| v.to_le_bytes())
+}
+
+/// Write `vectors` to an `.ivecs` file
+pub fn write_ivecs(path: &Path, vectors: &[Vec<i32>]) -> anyhow::Result<()> {
+    write_vecs(path, vectors, |v| v.to_le_bytes())
+}
+
+/// Write `vectors` to a `.bvecs` file
+pub fn write_bvecs(path: &Path, vectors: &[Vec<u8>]) -> anyhow::Result<()> {
+    write_vecs(path, vectors, |&b| [b])
+}
+
+fn read_vecs<T, const N: usize>(
+    path: &Path,
+    convert: fn([u8; N]) -> T,
+) -> anyhow::Result<Vec<Vec<T>>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut reader = BufReader::new(file);
+
+    let mut vectors = Vec::<Vec<T>>::new();
+    let truncated =
+        |vectors: &[Vec<T>]| format!("truncated vector {} in {}", vectors.len(), path.display());
+    let mut header = [0; 4];
+    loop {
+        let at_end = reader
+            .fill_buf()
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .is_empty();
+        if at_end {
+            break;
+        }
+
+        reader
+            .read_exact(&mut header)
+            .with_context(|| truncated(&vectors))?;
+
+        let dims = i32::from_le_bytes(header);
+        if dims <= 0 {
+            bail!(
+                "invalid dimension count {dims} for vector {} in {}",
+                vectors.len(),
+                path.display()
+            );
+        } else if let Some(first) = vectors.first() {
+            if dims as usize != first.len() {
+                bail!(
+                    "vector {} in {} has {dims} dimensions, expected {}",
+                    vectors.len(),
+                    path.display(),
+                    first.len()
+                );
+            }
+        }
+
+        let mut vector = Vec::with_capacity(dims as usize);
+        let mut buf = [0; N];
+        for _ in 0..dims {
+            reader
+                .read_exact(&mut buf)
+                .with_context(|| truncated(&vectors))?;
+            vector.push(convert(buf));
+        }
+        vectors.push(vector);
+    }
+
+    Ok(vectors)
+}
+
+fn write_vecs<T, const N: usize>(
+    path: &Path,
+    vectors: &[Vec<T>],
+    convert: fn(&T) -> [u8; N],
+) -> anyhow::Result<()> {
+    let context = || format!("failed to write {}", path.display());
+    let mut writer = BufWriter::new(File::create(path).with_context(context)?);
+    for (i, vector) in vectors.iter().enumerate() {
+        if vector.is_empty() {
+            bail!("vector {i} is empty");
+        } else if vector.len() != vectors[0].len() {
+            bail!(
+                "vector {i} has {} dimensions, expected {}",
+                vector.len(),
+                vectors[0].len()
+            );
+        }
+
+        writer
+            .write_all(&(vector.len() as i32).to_le_bytes())
+            .with_context(context)?;
+        for value in vector {
+            writer.write_all(&convert(value)).with_context(context)?;
+        }
+    }
+
+    writer.flush().with_context(context)
+}
@@ -0,0 +1,239 @@
+use std::fs;
+use std::path::PathBuf;
+
+use assert_cmd::Command;
+use instant_distance_cli::vecs::{
+    read_bvecs, read_fvecs, read_ivecs, read_points, write_bvecs, write_fvecs, write_ivecs,
+};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[test]
+fn build_query() {
+    let dir = TempDir::new("build-query");
+    let base = random_vectors(200, 8, 1);
+    write_fvecs(&dir.join("base.fvecs"), &base).unwrap();
+    build(&dir);
+
+    // Each base vector is its own nearest neighbor
+    let output = cli()
+        .args(["query", "--index"])
+        .arg(dir.join("index.idx"))
+        .arg("--input")
+        .arg(dir.join("base.fvecs"))
+        .args(["--k", "3"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let lines = output.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), base.len());
+    for (i, line) in lines.iter().enumerate() {
+        let positions = line.split(' ').collect::<Vec<_>>();
+        assert_eq!(positions.len(), 3);
+        assert_eq!(positions[0], i.to_string());
+    }
+
+    // Queries with the wrong dimensionality are rejected
+    write_fvecs(&dir.join("queries.fvecs"), &random_vectors(2, 4, 2)).unwrap();
+    cli()
+        .args(["query", "--index"])
+        .arg(dir.join("index.idx"))
+        .arg("--input")
+        .arg(dir.join("queries.fvecs"))
+        .assert()
+        .failure();
+
+    // M is fixed at compile time, so other values fail instead of being ignored
+    let output = cli()
+        .args(["build", "--input"])
+        .arg(dir.join("base.fvecs"))
+        .arg("--out")
+        .arg(dir.join("m.idx"))
+        .args(["--m", "16"])
+        .assert()
+        .failure()
+        .get_output()
+        .stderr
+        .clone();
+    let error = String::from_utf8(output).unwrap();
+    assert!(error.contains("--m 16 is not supported"), "{error}");
+    assert!(!dir.join("m.idx").exists());
+    cli()
+        .args(["build", "--input"])
+        .arg(dir.join("base.fvecs"))
+        .arg("--out")
+        .arg(dir.join("m.idx"))
+        .args(["--m", "32"])
+        .assert()
+        .success();
+}
+
+#[test]
+fn evaluate() {
+    let dir = TempDir::new("evaluate");
+    let base = random_vectors(200, 8, 3);
+    write_fvecs(&dir.join("base.fvecs"), &base).unwrap();
+    build(&dir);
+
+    // The exact two nearest neighbors of the first 20 base vectors, with the second one replaced
+    // by a position outside the index for every other query: recall@2 is (1 + 0.5) / 2
+    let queries = &base[..20];
+    let truth = queries
+        .iter()
+        .enumerate()
+        .map(|(i, query)| {
+            let mut order = (0..base.len()).collect::<Vec<_>>();
+            order.sort_by(|&a, &b| {
+                squared_distance(query, &base[a]).total_cmp(&squared_distance(query, &base[b]))
+            });
+            let second = match i % 2 {
+                0 => order[1] as i32,
+                _ => base.len() as i32,
+            };
+            vec![order[0] as i32, second]
+        })
+        .collect::<Vec<_>>();
+    write_fvecs(&dir.join("queries.fvecs"), queries).unwrap();
+    write_ivecs(&dir.join("truth.ivecs"), &truth).unwrap();
+
+    let output = cli()
+        .args(["evaluate", "--index"])
+        .arg(dir.join("index.idx"))
+        .arg("--queries")
+        .arg(dir.join("queries.fvecs"))
+        .arg("--ground-truth")
+        .arg(dir.join("truth.ivecs"))
+        .args(["--k", "2"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    assert!(output.contains("queries:   20\n"), "{output}");
+    assert!(output.contains("recall@2: 0.7500\n"), "{output}");
+
+    // The ground truth must cover every query
+    write_ivecs(&dir.join("truth.ivecs"), &truth[..10]).unwrap();
+    cli()
+        .args(["evaluate", "--index"])
+        .arg(dir.join("index.idx"))
+        .arg("--queries")
+        .arg(dir.join("queries.fvecs"))
+        .arg("--ground-truth")
+        .arg(dir.join("truth.ivecs"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn vecs_round_trip() {
+    let dir = TempDir::new("vecs-round-trip");
+
+    let floats = random_vectors(5, 3, 4);
+    write_fvecs(&dir.join("a.fvecs"), &floats).unwrap();
+    assert_eq!(read_fvecs(&dir.join("a.fvecs")).unwrap(), floats);
+    assert_eq!(read_points(&dir.join("a.fvecs")).unwrap(), floats);
+
+    let ints = vec![vec![1, -2, 3], vec![i32::MAX, 0, i32::MIN]];
+    write_ivecs(&dir.join("a.ivecs"), &ints).unwrap();
+    assert_eq!(read_ivecs(&dir.join("a.ivecs")).unwrap(), ints);
+
+    let bytes = vec![vec![0, 7, 255, 1], vec![2, 3, 4, 5]];
+    write_bvecs(&dir.join("a.bvecs"), &bytes).unwrap();
+    assert_eq!(
+        fs::metadata(dir.join("a.bvecs")).unwrap().len(),
+        2 * (4 + 4)
+    );
+    assert_eq!(read_bvecs(&dir.join("a.bvecs")).unwrap(), bytes);
+    assert_eq!(
+        read_points(&dir.join("a.bvecs")).unwrap(),
+        vec![vec![0.0, 7.0, 255.0, 1.0], vec![2.0, 3.0, 4.0, 5.0]]
+    );
+
+    write_fvecs(&dir.join("empty.fvecs"), &[]).unwrap();
+    assert!(read_fvecs(&dir.join("empty.fvecs")).unwrap().is_empty());
+}
+
+#[test]
+fn vecs_invalid() {
+    let dir = TempDir::new("vecs-invalid");
+    let path = dir.join("a.fvecs");
+    write_fvecs(&path, &random_vectors(2, 3, 5)).unwrap();
+    let valid = fs::read(&path).unwrap();
+
+    // A record cut short, in its components or in its header
+    fs::write(&path, &valid[..valid.len() - 2]).unwrap();
+    let error = read_fvecs(&path).unwrap_err().to_string();
+    assert!(error.starts_with("truncated vector 1"), "{error}");
+    fs::write(&path, &valid[..16 + 2]).unwrap();
+    let error = read_fvecs(&path).unwrap_err().to_string();
+    assert!(error.starts_with("truncated vector 1"), "{error}");
+
+    // A record with a different dimension count than the first
+    let mut mixed = valid[..16].to_vec();
+    mixed.extend_from_slice(&2i32.to_le_bytes());
+    mixed.extend_from_slice(&[0; 8]);
+    fs::write(&path, &mixed).unwrap();
+    let error = read_fvecs(&path).unwrap_err().to_string();
+    assert!(error.contains("has 2 dimensions, expected 3"), "{error}");
+
+    // A negative dimension count
+    fs::write(&path, (-1i32).to_le_bytes()).unwrap();
+    let error = read_fvecs(&path).unwrap_err().to_string();
+    assert!(error.starts_with("invalid dimension count -1"), "{error}");
+
+    // Mixed dimensions are rejected on writing too
+    assert!(write_ivecs(&dir.join("a.ivecs"), &[vec![1, 2], vec![3]]).is_err());
+    assert!(write_bvecs(&dir.join("a.bvecs"), &[vec![]]).is_err());
+}
+
+fn build(dir: &TempDir) {
+    cli()
+        .args(["build", "--input"])
+        .arg(dir.join("base.fvecs"))
+        .arg("--out")
+        .arg(dir.join("index.idx"))
+        .args(["--seed", "1"])
+        .assert()
+        .success();
+}
+
+fn cli() -> Command {
+    Command::cargo_bin("instant-distance-cli").unwrap()
+}
+
+fn random_vectors(len: usize, dims: usize, seed: u64) -> Vec<Vec<f32>> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|_| (0..dims).map(|_| rng.gen()).collect())
+        .collect()
+}
+
+fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// A directory for the files of one test, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("cli-{name}-{}", std::process::id()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
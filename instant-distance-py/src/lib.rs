@@ -1,17 +1,29 @@
-// borrow_deref_ref doesn't get macro detection right, allow for now
-#![allow(clippy::from_iter_instead_of_collect, clippy::borrow_deref_ref)]
-
+// borrow_deref_ref and unnecessary_fallible_conversions don't get macro detection right, and
+// pyo3 0.19's `#[pymethods]` expansion trips `non_local_definitions`; allow for now
+#![allow(
+    clippy::from_iter_instead_of_collect,
+    clippy::borrow_deref_ref,
+    clippy::unnecessary_fallible_conversions,
+    non_local_definitions
+)]
+
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::BufReader;
 use std::iter::FromIterator;
-
-use instant_distance::Point;
-use pyo3::conversion::IntoPy;
-use pyo3::exceptions::{PyTypeError, PyValueError};
-use pyo3::types::{PyList, PyModule, PyString};
-use pyo3::{pyclass, pymethods, pymodule};
-use pyo3::{Py, PyAny, PyErr, PyObject, PyRef, PyRefMut, PyResult, Python};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use instant_distance::{Point, PointId};
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::sync::GILOnceCell;
+use pyo3::types::{PyBytes, PyList, PyModule, PyString};
+use pyo3::{pyclass, pyfunction, pymethods, pymodule, wrap_pyfunction};
+use pyo3::{IntoPy, Py, PyAny, PyErr, PyObject, PyRef, PyRefMut, PyResult, Python};
 use serde::{Deserialize, Serialize};
 use serde_big_array::BigArray;
 
@@ -29,14 +41,24 @@ fn instant_distance_py(_: Python, m: &PyModule) -> PyResult<()> {
 
 #[pyclass]
 struct HnswMap {
-    inner: instant_distance::HnswMap<FloatArray, MapValue>,
+    inner: Arc<instant_distance::HnswMap<FloatArray, MapValue>>,
 }
 
 #[pymethods]
 impl HnswMap {
     /// Build the index
+    ///
+    /// String values are stored as-is. Other values are pickled so they can be stored in the
+    /// index file; pass `pickle_values=False` to only accept strings. Files with pickled values
+    /// can only be loaded with `load(fname, allow_pickle=True)`.
     #[staticmethod]
-    fn build(points: &PyList, values: &PyList, config: &Config) -> PyResult<Self> {
+    #[pyo3(signature = (points, values, config, pickle_values = true))]
+    fn build(
+        points: &PyList,
+        values: &PyList,
+        config: &Config,
+        pickle_values: bool,
+    ) -> PyResult<Self> {
         let points = points
             .into_iter()
             .map(FloatArray::try_from)
@@ -44,29 +66,58 @@ impl HnswMap {
 
         let values = values
             .into_iter()
-            .map(MapValue::try_from)
+            .map(|value| MapValue::new(value, pickle_values))
             .collect::<Result<Vec<_>, PyErr>>()?;
 
-        let hsnw_map = instant_distance::Builder::from(config).build(points, values);
-        Ok(Self { inner: hsnw_map })
+        let hsnw_map = instant_distance::Builder::from(config)
+            .try_build(points, values)
+            .map_err(build_error)?;
+        Ok(Self {
+            inner: Arc::new(hsnw_map),
+        })
     }
 
     /// Load an index from the given file name
+    ///
+    /// Files written by earlier versions of the bindings, which only supported 300 dimensions,
+    /// are converted while loading.
+    ///
+    /// Pickled values are unpickled when they are returned from a search, and unpickling data
+    /// from an untrusted file can execute arbitrary code. Loading a file with pickled values
+    /// raises `ValueError` unless `allow_pickle` is true; only enable it for trusted files.
     #[staticmethod]
-    fn load(fname: &str) -> PyResult<Self> {
-        let hnsw_map =
-            bincode::deserialize_from::<_, instant_distance::HnswMap<FloatArray, MapValue>>(
-                BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?),
-            )
-            .map_err(|e| PyValueError::new_err(format!("deserialization error: {e:?}")))?;
-        Ok(Self { inner: hnsw_map })
+    #[pyo3(signature = (fname, allow_pickle = false))]
+    fn load(fname: &str, allow_pickle: bool) -> PyResult<Self> {
+        let hnsw_map = match instant_distance::HnswMap::load(open(fname)?) {
+            Ok(hnsw_map) => hnsw_map,
+            Err(e) => instant_distance::HnswMap::<LegacyFloatArray, MapValue>::load(open(fname)?)
+                .and_then(|legacy| {
+                    let (hnsw, values) = legacy.into_parts();
+                    instant_distance::HnswMap::from_parts(LegacyFloatArray::upgrade(hnsw)?, values)
+                })
+                .map_err(|_| load_error(e))?,
+        };
+
+        let pickled = hnsw_map
+            .values
+            .iter()
+            .any(|value| matches!(value, MapValue::Pickled(_)));
+        if pickled && !allow_pickle {
+            return Err(PyValueError::new_err(
+                "index contains pickled values; load with `allow_pickle=True` if the file is trusted",
+            ));
+        }
+
+        Ok(Self {
+            inner: Arc::new(hnsw_map),
+        })
     }
 
     /// Dump the index to the given file name
     fn dump(&self, fname: &str) -> PyResult<()> {
-        let f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
-        bincode::serialize_into(f, &self.inner)
-            .map_err(|e| PyValueError::new_err(format!("serialization error: {e:?}")))?;
+        self.inner
+            .save_file(fname)
+            .map_err(|e| PyValueError::new_err(format!("serialization error: {e}")))?;
         Ok(())
     }
 
@@ -77,21 +128,90 @@ impl HnswMap {
     /// to the `ef_search` parameter set in the index's `config`.
     ///
     /// For best performance, reusing `Search` objects is recommended.
-    fn search(slf: Py<Self>, point: &PyAny, search: &mut Search, py: Python<'_>) -> PyResult<()> {
-        let point = FloatArray::try_from(point)?;
-        let _ = slf.try_borrow(py)?.inner.search(&point, &mut search.inner);
+    ///
+    /// If `filter` is given, only points that pass it are returned: `filter` may be a boolean
+    /// mask (a list or numpy array) indexed by point identifier, a collection of allowed point
+    /// identifiers, or a callable that takes the value of a point and returns whether it passes.
+    #[pyo3(signature = (point, search, filter = None))]
+    fn search(
+        slf: Py<Self>,
+        point: &PyAny,
+        search: &mut Search,
+        filter: Option<&PyAny>,
+        py: Python<'_>,
+    ) -> PyResult<()> {
+        let map = slf.try_borrow(py)?;
+        let point = FloatArray::query(point, map.inner.dims())?;
+        match filter {
+            Some(filter) => {
+                let filter = Filter::new(filter, map.inner.len())?;
+                let _ = map
+                    .inner
+                    .search_filtered(&point, &mut search.inner, |pid, value| {
+                        filter.accepts(pid, || value.to_object(py))
+                    });
+                filter.finish()?;
+            }
+            None => {
+                let _ = map.inner.search(&point, &mut search.inner);
+            }
+        }
+
         search.cur = Some((HnswType::Map(slf.clone_ref(py)), 0));
         Ok(())
     }
+
+    /// Search the index for the `k` points nearest to `point` without blocking the event loop
+    ///
+    /// Returns an awaitable that resolves to a list of `Neighbor` objects, nearest first. The
+    /// search runs on a pool of worker threads (one per CPU) that don't hold the GIL, so other
+    /// tasks keep running in the meantime; searches beyond the pool's capacity wait their turn.
+    /// Must be called from a coroutine running in an asyncio event loop.
+    fn search_async<'py>(&self, point: &PyAny, k: usize, py: Python<'py>) -> PyResult<&'py PyAny> {
+        let point = FloatArray::query(point, self.inner.dims())?;
+        let map = self.inner.clone();
+        spawn_search(py, move || {
+            let mut search = instant_distance::Search::default();
+            search.k(Some(k));
+            let results = map
+                .search(&point, &mut search)
+                .map(|item| (item.distance, item.pid))
+                .collect::<Vec<_>>();
+
+            move |py: Python<'_>| {
+                results
+                    .into_iter()
+                    .map(|(distance, pid)| {
+                        Ok(Neighbor {
+                            distance,
+                            pid: pid.into_inner(),
+                            value: map.values[pid.into_inner() as usize].to_object(py)?,
+                        })
+                    })
+                    .collect()
+            }
+        })
+    }
+
+    /// The number of points in the index
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// The parameters the index was built with
+    #[getter]
+    fn config(&self) -> Config {
+        Config::from(self.inner.config())
+    }
 }
 
 /// An instance of hierarchical navigable small worlds
 ///
-/// For now, this is specialized to only support 300-element (32-bit) float vectors
-/// with a squared Euclidean distance metric.
+/// For now, this is specialized to only support (32-bit) float vectors with a squared Euclidean
+/// distance metric. All points must have the same number of dimensions.
 #[pyclass]
 struct Hnsw {
-    inner: instant_distance::Hnsw<FloatArray>,
+    inner: Arc<instant_distance::Hnsw<FloatArray>>,
 }
 
 #[pymethods]
@@ -104,26 +224,40 @@ impl Hnsw {
             .map(FloatArray::try_from)
             .collect::<Result<Vec<_>, PyErr>>()?;
 
-        let (inner, ids) = instant_distance::Builder::from(config).build_hnsw(points);
+        let (inner, ids) = instant_distance::Builder::from(config)
+            .try_build_hnsw(points)
+            .map_err(build_error)?;
         let ids = Vec::from_iter(ids.into_iter().map(|pid| pid.into_inner()));
-        Ok((Self { inner }, ids))
+        Ok((
+            Self {
+                inner: Arc::new(inner),
+            },
+            ids,
+        ))
     }
 
     /// Load an index from the given file name
+    ///
+    /// Files written by earlier versions of the bindings, which only supported 300 dimensions,
+    /// are converted while loading.
     #[staticmethod]
     fn load(fname: &str) -> PyResult<Self> {
-        let hnsw = bincode::deserialize_from::<_, instant_distance::Hnsw<FloatArray>>(
-            BufReader::with_capacity(32 * 1024 * 1024, File::open(fname)?),
-        )
-        .map_err(|e| PyValueError::new_err(format!("deserialization error: {e:?}")))?;
-        Ok(Self { inner: hnsw })
+        let hnsw = match instant_distance::Hnsw::load(open(fname)?) {
+            Ok(hnsw) => hnsw,
+            Err(e) => instant_distance::Hnsw::<LegacyFloatArray>::load(open(fname)?)
+                .and_then(LegacyFloatArray::upgrade)
+                .map_err(|_| load_error(e))?,
+        };
+        Ok(Self {
+            inner: Arc::new(hnsw),
+        })
     }
 
     /// Dump the index to the given file name
     fn dump(&self, fname: &str) -> PyResult<()> {
-        let f = BufWriter::with_capacity(32 * 1024 * 1024, File::create(fname)?);
-        bincode::serialize_into(f, &self.inner)
-            .map_err(|e| PyValueError::new_err(format!("serialization error: {e:?}")))?;
+        self.inner
+            .save_file(fname)
+            .map_err(|e| PyValueError::new_err(format!("serialization error: {e}")))?;
         Ok(())
     }
 
@@ -134,12 +268,186 @@ impl Hnsw {
     /// to the `ef_search` parameter set in the index's `config`.
     ///
     /// For best performance, reusing `Search` objects is recommended.
-    fn search(slf: Py<Self>, point: &PyAny, search: &mut Search, py: Python<'_>) -> PyResult<()> {
-        let point = FloatArray::try_from(point)?;
-        let _ = slf.try_borrow(py)?.inner.search(&point, &mut search.inner);
+    ///
+    /// If `filter` is given, only points that pass it are returned: `filter` may be a boolean
+    /// mask (a list or numpy array) indexed by point identifier, a collection of allowed point
+    /// identifiers, or a callable that takes a point identifier and returns whether it passes.
+    #[pyo3(signature = (point, search, filter = None))]
+    fn search(
+        slf: Py<Self>,
+        point: &PyAny,
+        search: &mut Search,
+        filter: Option<&PyAny>,
+        py: Python<'_>,
+    ) -> PyResult<()> {
+        let hnsw = slf.try_borrow(py)?;
+        let point = FloatArray::query(point, hnsw.inner.dims())?;
+        match filter {
+            Some(filter) => {
+                let filter = Filter::new(filter, hnsw.inner.len())?;
+                let _ = hnsw
+                    .inner
+                    .search_filtered(&point, &mut search.inner, |pid| {
+                        filter.accepts(pid, || Ok(pid.into_inner().into_py(py)))
+                    });
+                filter.finish()?;
+            }
+            None => {
+                let _ = hnsw.inner.search(&point, &mut search.inner);
+            }
+        }
+
         search.cur = Some((HnswType::Hnsw(slf.clone_ref(py)), 0));
         Ok(())
     }
+
+    /// Search the index for the `k` points nearest to `point` without blocking the event loop
+    ///
+    /// See `HnswMap.search_async()` for details.
+    fn search_async<'py>(&self, point: &PyAny, k: usize, py: Python<'py>) -> PyResult<&'py PyAny> {
+        let point = FloatArray::query(point, self.inner.dims())?;
+        let hnsw = self.inner.clone();
+        spawn_search(py, move || {
+            let mut search = instant_distance::Search::default();
+            search.k(Some(k));
+            let results = hnsw
+                .search(&point, &mut search)
+                .map(|item| (item.distance, item.pid))
+                .collect::<Vec<_>>();
+
+            move |py: Python<'_>| {
+                let neighbors = results.into_iter().map(|(distance, pid)| Neighbor {
+                    distance,
+                    pid: pid.into_inner(),
+                    value: py.None(),
+                });
+                Ok(neighbors.collect())
+            }
+        })
+    }
+
+    /// The number of points in the index
+    fn __len__(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// The parameters the index was built with
+    #[getter]
+    fn config(&self) -> Config {
+        Config::from(self.inner.config())
+    }
+}
+
+/// Open the index file `fname` for loading
+fn open(fname: &str) -> PyResult<BufReader<File>> {
+    Ok(BufReader::with_capacity(
+        32 * 1024 * 1024,
+        File::open(fname)?,
+    ))
+}
+
+/// Convert an error from loading an index to a Python exception
+fn load_error(error: instant_distance::Error) -> PyErr {
+    PyValueError::new_err(format!("deserialization error: {error}"))
+}
+
+/// Convert an error from building an index to the matching Python exception
+fn build_error(error: instant_distance::Error) -> PyErr {
+    match error {
+        instant_distance::Error::EmptyPoint { .. }
+        | instant_distance::Error::DimensionMismatch { .. } => {
+            PyTypeError::new_err(error.to_string())
+        }
+        _ => PyValueError::new_err(error.to_string()),
+    }
+}
+
+/// Run `search` on the search pool, returning an asyncio future for its results
+///
+/// `search` runs without the GIL; it returns a function that converts its results to
+/// `Neighbor` objects once the GIL has been acquired again. The future is resolved from the
+/// event loop's thread, as asyncio futures aren't thread-safe.
+fn spawn_search<'py, S, R>(py: Python<'py>, search: S) -> PyResult<&'py PyAny>
+where
+    S: FnOnce() -> R + Send + 'static,
+    R: FnOnce(Python<'_>) -> PyResult<Vec<Neighbor>>,
+{
+    let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+    let future = event_loop.call_method0("create_future")?;
+    let (loop_ref, future_ref) = (PyObject::from(event_loop), PyObject::from(future));
+
+    let pool = SEARCH_POOL.get_or_try_init(py, || {
+        let atexit = py.import("atexit")?;
+        atexit.call_method1("register", (wrap_pyfunction!(finish_searches, py)?,))?;
+        rayon::ThreadPoolBuilder::new()
+            .thread_name(|i| format!("instant-distance-search-{i}"))
+            .build()
+            .map_err(|e| PyRuntimeError::new_err(format!("failed to start search threads: {e}")))
+    })?;
+
+    PENDING_SEARCHES.fetch_add(1, Ordering::SeqCst);
+    pool.spawn(move || {
+        let results = search();
+        Python::with_gil(|py| {
+            let (result, failed) = match results(py) {
+                Ok(neighbors) => (neighbors.into_py(py), false),
+                Err(e) => (e.into_value(py).into_py(py), true),
+            };
+
+            let resolved = wrap_pyfunction!(resolve, py).and_then(|resolve| {
+                loop_ref.call_method1(
+                    py,
+                    "call_soon_threadsafe",
+                    (resolve, future_ref, result, failed),
+                )
+            });
+
+            // The event loop was closed while searching, so nobody is waiting for the results
+            if let Err(e) = resolved {
+                e.print(py);
+            }
+        });
+        PENDING_SEARCHES.fetch_sub(1, Ordering::SeqCst);
+    });
+
+    Ok(future)
+}
+
+/// The threads that run `search_async()` searches, started by the first one
+///
+/// A dedicated pool keeps async searches from waiting for an index build on rayon's global pool,
+/// and bounds the number of threads no matter how many searches are pending.
+static SEARCH_POOL: GILOnceCell<rayon::ThreadPool> = GILOnceCell::new();
+
+/// The number of searches started on the search pool that may still need the GIL
+static PENDING_SEARCHES: AtomicUsize = AtomicUsize::new(0);
+
+/// Wait for the pending searches at interpreter exit
+///
+/// A search thread that takes the GIL to resolve its future after the interpreter started
+/// shutting down would crash the process, so `atexit` waits for them like Python does for
+/// non-daemon threads.
+#[pyfunction]
+fn finish_searches(py: Python<'_>) {
+    py.allow_threads(|| {
+        while PENDING_SEARCHES.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+}
+
+/// Set the result (or exception, if `failed`) of an asyncio future, unless it was cancelled
+#[pyfunction]
+fn resolve(future: &PyAny, result: &PyAny, failed: bool) -> PyResult<()> {
+    if future.call_method0("cancelled")?.is_true()? {
+        return Ok(());
+    }
+
+    match failed {
+        true => future.call_method1("set_exception", (result,))?,
+        false => future.call_method1("set_result", (result,))?,
+    };
+    Ok(())
 }
 
 /// Search buffer and result set
@@ -165,42 +473,170 @@ impl Search {
     }
 
     /// Return the next closest point
-    fn __next__(mut slf: PyRefMut<Self>) -> Option<Neighbor> {
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<Neighbor>> {
         let (index, idx) = match slf.cur.take() {
-            Some(x) => x,
-            None => return None,
+            Some(cur) => cur,
+            None => return Ok(None),
+        };
+
+        let neighbor = index.neighbor(slf.py(), &slf.inner, idx)?;
+        slf.cur = neighbor.as_ref().map(|_| (index, idx + 1));
+        Ok(neighbor)
+    }
+
+    /// Return all results from the last search as a list of `Neighbor` objects
+    ///
+    /// Unlike iterating over the `Search`, this always starts from the closest point.
+    fn results(&self, py: Python<'_>) -> PyResult<Vec<Neighbor>> {
+        let index = match &self.cur {
+            Some((index, _)) => index,
+            None => return Ok(Vec::new()),
         };
 
-        let py = slf.py();
-        let neighbor = match &index {
+        let mut results = Vec::new();
+        while let Some(neighbor) = index.neighbor(py, &self.inner, results.len())? {
+            results.push(neighbor);
+        }
+        Ok(results)
+    }
+
+    /// Return the results from the last search as a pair of numpy arrays
+    ///
+    /// The first array contains the point identifiers (`uint32`), the second contains the
+    /// distances (`float32`), both ordered from closest to furthest. Requires numpy.
+    fn arrays(&self, py: Python<'_>) -> PyResult<(PyObject, PyObject)> {
+        let (pids, distances) = self
+            .results(py)?
+            .into_iter()
+            .map(|neighbor| (neighbor.pid, neighbor.distance))
+            .unzip::<_, _, Vec<_>, Vec<_>>();
+
+        let numpy = py.import("numpy")?;
+        let pids = numpy.call_method1("array", (pids, "uint32"))?;
+        let distances = numpy.call_method1("array", (distances, "float32"))?;
+        Ok((pids.into(), distances.into()))
+    }
+}
+
+enum HnswType {
+    Hnsw(Py<Hnsw>),
+    Map(Py<HnswMap>),
+}
+
+impl HnswType {
+    fn neighbor(
+        &self,
+        py: Python<'_>,
+        search: &instant_distance::Search,
+        idx: usize,
+    ) -> PyResult<Option<Neighbor>> {
+        match self {
             HnswType::Hnsw(hnsw) => {
                 let hnsw = hnsw.as_ref(py).borrow();
-                let item = hnsw.inner.get(idx, &slf.inner);
-                item.map(|item| Neighbor {
+                let item = hnsw.inner.get(idx, search);
+                Ok(item.map(|item| Neighbor {
                     distance: item.distance,
                     pid: item.pid.into_inner(),
                     value: py.None(),
-                })
+                }))
             }
             HnswType::Map(map) => {
                 let map = map.as_ref(py).borrow();
-                let item = map.inner.get(idx, &slf.inner);
-                item.map(|item| Neighbor {
+                let item = match map.inner.get(idx, search) {
+                    Some(item) => item,
+                    None => return Ok(None),
+                };
+
+                Ok(Some(Neighbor {
                     distance: item.distance,
                     pid: item.pid.into_inner(),
-                    value: item.value.into_py(py),
-                })
+                    value: item.value.to_object(py)?,
+                }))
             }
+        }
+    }
+}
+
+/// A filter for the results of a search, as passed to `search()`
+enum Filter<'py> {
+    /// Whether each point passes, indexed by point identifier
+    Mask(Vec<bool>),
+    /// The identifiers of the points that pass
+    Ids(HashSet<u32>),
+    /// A Python callable, with the first error it raised
+    Callable(&'py PyAny, RefCell<Option<PyErr>>),
+}
+
+impl<'py> Filter<'py> {
+    fn new(filter: &'py PyAny, len: usize) -> PyResult<Self> {
+        if filter.is_callable() {
+            return Ok(Filter::Callable(filter, RefCell::new(None)));
+        }
+
+        // numpy arrays are converted to lists, which turns their elements into Python scalars
+        let filter = match filter.hasattr("dtype")? {
+            true => filter.call_method0("tolist")?,
+            false => filter,
         };
 
-        slf.cur = neighbor.as_ref().map(|_| (index, idx + 1));
-        neighbor
+        // An empty sequence would also extract as a mask, but means that no ids are allowed
+        if filter.len().map_or(false, |len| len == 0) {
+            return Ok(Filter::Ids(HashSet::new()));
+        }
+
+        if let Ok(mask) = filter.extract::<Vec<bool>>() {
+            if mask.len() != len {
+                return Err(PyValueError::new_err(format!(
+                    "filter mask has {} values, expected {len}",
+                    mask.len()
+                )));
+            }
+            return Ok(Filter::Mask(mask));
+        }
+
+        let ids = filter
+            .iter()
+            .map_err(|_| {
+                PyTypeError::new_err(
+                    "filter must be a boolean mask, a collection of ids or a callable",
+                )
+            })?
+            .map(|id| id?.extract::<u32>())
+            .collect::<PyResult<_>>()?;
+        Ok(Filter::Ids(ids))
     }
-}
 
-enum HnswType {
-    Hnsw(Py<Hnsw>),
-    Map(Py<HnswMap>),
+    /// Whether the point `pid` passes the filter
+    ///
+    /// `arg` produces the argument for a callable filter. Errors raised by the callable reject
+    /// the point; the first one is returned by `finish()`.
+    fn accepts(&self, pid: PointId, arg: impl FnOnce() -> PyResult<PyObject>) -> bool {
+        match self {
+            Filter::Mask(mask) => mask[pid.into_inner() as usize],
+            Filter::Ids(ids) => ids.contains(&pid.into_inner()),
+            Filter::Callable(callable, error) => {
+                if error.borrow().is_some() {
+                    return false;
+                }
+
+                match arg().and_then(|arg| callable.call1((arg,))?.is_true()) {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        *error.borrow_mut() = Some(e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the first error raised by a callable filter, if any
+    fn finish(self) -> PyResult<()> {
+        match self {
+            Filter::Callable(_, error) => error.into_inner().map_or(Ok(()), Err),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[pyclass]
@@ -245,6 +681,18 @@ impl Config {
     }
 }
 
+impl From<&instant_distance::Config> for Config {
+    fn from(config: &instant_distance::Config) -> Self {
+        Self {
+            ef_search: config.ef_search,
+            ef_construction: config.ef_construction,
+            ml: config.ml,
+            seed: config.seed,
+            heuristic: config.heuristic.map(Heuristic::from),
+        }
+    }
+}
+
 impl From<&Config> for instant_distance::Builder {
     fn from(py: &Config) -> Self {
         let Config {
@@ -274,21 +722,18 @@ struct Heuristic {
     /// Whether to keep pruned neighbors to make the neighbor set size constant
     #[pyo3(get, set)]
     keep_pruned: bool,
+    /// Relaxation factor for pruning neighbors
+    ///
+    /// Values above 1.0 keep more long-range links, trading graph density for recall.
+    #[pyo3(get, set)]
+    alpha: f32,
 }
 
 #[pymethods]
 impl Heuristic {
     #[new]
     fn new() -> Self {
-        let default = instant_distance::Heuristic::default();
-        let instant_distance::Heuristic {
-            extend_candidates,
-            keep_pruned,
-        } = default;
-        Self {
-            extend_candidates,
-            keep_pruned,
-        }
+        Self::from(instant_distance::Heuristic::default())
     }
 }
 
@@ -297,6 +742,22 @@ impl Default for Heuristic {
         Self {
             extend_candidates: false,
             keep_pruned: true,
+            alpha: 1.0,
+        }
+    }
+}
+
+impl From<instant_distance::Heuristic> for Heuristic {
+    fn from(heuristic: instant_distance::Heuristic) -> Self {
+        let instant_distance::Heuristic {
+            extend_candidates,
+            keep_pruned,
+            alpha,
+        } = heuristic;
+        Self {
+            extend_candidates,
+            keep_pruned,
+            alpha,
         }
     }
 }
@@ -306,10 +767,12 @@ impl From<Heuristic> for instant_distance::Heuristic {
         let Heuristic {
             extend_candidates,
             keep_pruned,
+            alpha,
         } = py;
         Self {
             extend_candidates,
             keep_pruned,
+            alpha,
         }
     }
 }
@@ -330,36 +793,63 @@ struct Neighbor {
 
 #[pymethods]
 impl Neighbor {
-    fn __repr__(&self) -> PyResult<String> {
-        match Python::with_gil(|py| self.value.is_none(py)) {
+    /// Identifier for the neighboring point (alias for `pid`)
+    #[getter]
+    fn id(&self) -> u32 {
+        self.pid
+    }
+
+    fn __repr__(&self, py: Python<'_>) -> PyResult<String> {
+        match self.value.is_none(py) {
             false => Ok(format!(
-                "instant_distance.Neighbor(distance={}, pid={}, value={})",
-                self.distance,
+                "instant_distance.Neighbor(id={}, distance={}, value={})",
                 self.pid,
-                Python::with_gil(|py| self.value.as_ref(py).repr().map(|s| s.to_string()))?,
+                self.distance,
+                self.value.as_ref(py).repr()?,
             )),
             true => Ok(format!(
-                "instant_distance.Item(distance={}, pid={})",
-                self.distance, self.pid,
+                "instant_distance.Neighbor(id={}, distance={})",
+                self.pid, self.distance,
             )),
         }
     }
 }
 
-#[repr(align(32))]
 #[derive(Clone, Deserialize, Serialize)]
-struct FloatArray(#[serde(with = "BigArray")] [f32; DIMENSIONS]);
+struct FloatArray(Vec<f32>);
+
+impl FloatArray {
+    /// Convert a query point, checking that it has the same dimensions as the indexed points
+    fn query(value: &PyAny, dims: Option<usize>) -> PyResult<Self> {
+        let point = Self::try_from(value)?;
+        match dims {
+            Some(expected) if point.0.len() != expected => Err(PyTypeError::new_err(format!(
+                "point has {} dimensions, expected {expected}",
+                point.0.len()
+            ))),
+            _ => Ok(point),
+        }
+    }
+}
 
 impl TryFrom<&PyAny> for FloatArray {
     type Error = PyErr;
 
     fn try_from(value: &PyAny) -> Result<Self, Self::Error> {
-        let mut new = FloatArray([0.0; DIMENSIONS]);
-        for (i, val) in value.iter()?.enumerate() {
-            match i >= DIMENSIONS {
-                true => return Err(PyTypeError::new_err("point array too long")),
-                false => new.0[i] = val?.extract::<f32>()?,
-            }
+        let new = value
+            .iter()?
+            .map(|val| val?.extract::<f32>())
+            .collect::<PyResult<Vec<_>>>()
+            .map(FloatArray)?;
+
+        if new.0.is_empty() {
+            return Err(PyTypeError::new_err("point array is empty"));
+        }
+
+        if !new.is_finite() {
+            return Err(PyValueError::new_err(
+                "point contains NaN or infinite values",
+            ));
         }
         Ok(new)
     }
@@ -367,68 +857,138 @@ impl TryFrom<&PyAny> for FloatArray {
 
 impl Point for FloatArray {
     fn distance(&self, rhs: &Self) -> f32 {
-        #[cfg(target_arch = "x86_64")]
-        {
-            use std::arch::x86_64::{
-                _mm256_castps256_ps128, _mm256_extractf128_ps, _mm256_fmadd_ps, _mm256_load_ps,
-                _mm256_setzero_ps, _mm256_sub_ps, _mm_add_ps, _mm_add_ss, _mm_cvtss_f32,
-                _mm_fmadd_ps, _mm_load_ps, _mm_movehl_ps, _mm_shuffle_ps, _mm_sub_ps,
-            };
-            debug_assert_eq!(self.0.len() % 8, 4);
-
-            unsafe {
-                let mut acc_8x = _mm256_setzero_ps();
-                for (lh_slice, rh_slice) in self.0.chunks_exact(8).zip(rhs.0.chunks_exact(8)) {
-                    let lh_8x = _mm256_load_ps(lh_slice.as_ptr());
-                    let rh_8x = _mm256_load_ps(rh_slice.as_ptr());
-                    let diff = _mm256_sub_ps(lh_8x, rh_8x);
-                    acc_8x = _mm256_fmadd_ps(diff, diff, acc_8x);
-                }
+        squared_euclidean(&self.0, &rhs.0)
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+
+    fn is_finite(&self) -> bool {
+        self.0.iter().all(|v| v.is_finite())
+    }
+}
+
+/// The point type of the 300-dimensional indexes written by earlier versions of the bindings
+///
+/// Those files store each point as a fixed-size array, without a length prefix. They're read
+/// with this type and then converted to `FloatArray`.
+#[derive(Clone, Deserialize)]
+struct LegacyFloatArray(#[serde(with = "BigArray")] [f32; LEGACY_DIMENSIONS]);
+
+impl LegacyFloatArray {
+    /// Convert a legacy index to one with `FloatArray` points, keeping the graph as it is
+    fn upgrade(
+        hnsw: instant_distance::Hnsw<Self>,
+    ) -> Result<instant_distance::Hnsw<FloatArray>, instant_distance::Error> {
+        let parts = hnsw.into_raw_parts();
+        instant_distance::Hnsw::from_raw_parts(instant_distance::RawParts {
+            config: parts.config,
+            points: parts
+                .points
+                .iter()
+                .map(|p| FloatArray(p.0.to_vec()))
+                .collect(),
+            order: parts.order,
+            neighbors: parts.neighbors,
+        })
+    }
+}
+
+impl Point for LegacyFloatArray {
+    fn distance(&self, rhs: &Self) -> f32 {
+        squared_euclidean(&self.0, &rhs.0)
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(LEGACY_DIMENSIONS)
+    }
 
-                let mut acc_4x = _mm256_extractf128_ps(acc_8x, 1); // upper half
-                let right = _mm256_castps256_ps128(acc_8x); // lower half
-                acc_4x = _mm_add_ps(acc_4x, right); // sum halves
+    fn is_finite(&self) -> bool {
+        self.0.iter().all(|v| v.is_finite())
+    }
+}
+
+const LEGACY_DIMENSIONS: usize = 300;
 
-                let lh_4x = _mm_load_ps(self.0[DIMENSIONS - 4..].as_ptr());
-                let rh_4x = _mm_load_ps(rhs.0[DIMENSIONS - 4..].as_ptr());
-                let diff = _mm_sub_ps(lh_4x, rh_4x);
-                acc_4x = _mm_fmadd_ps(diff, diff, acc_4x);
+fn squared_euclidean(lhs: &[f32], rhs: &[f32]) -> f32 {
+    #[cfg(target_arch = "x86_64")]
+    {
+        use std::arch::x86_64::{
+            _mm256_castps256_ps128, _mm256_extractf128_ps, _mm256_fmadd_ps, _mm256_loadu_ps,
+            _mm256_setzero_ps, _mm256_sub_ps, _mm_add_ps, _mm_add_ss, _mm_cvtss_f32, _mm_movehl_ps,
+            _mm_shuffle_ps,
+        };
 
-                let lower = _mm_movehl_ps(acc_4x, acc_4x);
-                acc_4x = _mm_add_ps(acc_4x, lower);
-                let upper = _mm_shuffle_ps(acc_4x, acc_4x, 0x1);
-                acc_4x = _mm_add_ss(acc_4x, upper);
-                _mm_cvtss_f32(acc_4x)
+        let split = lhs.len().min(rhs.len()) / 8 * 8;
+        let sum = unsafe {
+            let mut acc_8x = _mm256_setzero_ps();
+            for (lh_slice, rh_slice) in lhs[..split].chunks_exact(8).zip(rhs.chunks_exact(8)) {
+                let lh_8x = _mm256_loadu_ps(lh_slice.as_ptr());
+                let rh_8x = _mm256_loadu_ps(rh_slice.as_ptr());
+                let diff = _mm256_sub_ps(lh_8x, rh_8x);
+                acc_8x = _mm256_fmadd_ps(diff, diff, acc_8x);
             }
-        }
-        #[cfg(not(target_arch = "x86_64"))]
-        self.0
+
+            let mut acc_4x = _mm256_extractf128_ps(acc_8x, 1); // upper half
+            let right = _mm256_castps256_ps128(acc_8x); // lower half
+            acc_4x = _mm_add_ps(acc_4x, right); // sum halves
+
+            let lower = _mm_movehl_ps(acc_4x, acc_4x);
+            acc_4x = _mm_add_ps(acc_4x, lower);
+            let upper = _mm_shuffle_ps(acc_4x, acc_4x, 0x1);
+            acc_4x = _mm_add_ss(acc_4x, upper);
+            _mm_cvtss_f32(acc_4x)
+        };
+
+        // The components that don't fill a whole 8-lane chunk
+        let tail = lhs[split..]
             .iter()
-            .zip(rhs.0.iter())
+            .zip(&rhs[split..])
             .map(|(&a, &b)| (a - b).powi(2))
-            .sum::<f32>()
+            .sum::<f32>();
+        sum + tail
     }
+    #[cfg(not(target_arch = "x86_64"))]
+    lhs.iter()
+        .zip(rhs)
+        .map(|(&a, &b)| (a - b).powi(2))
+        .sum::<f32>()
 }
 
 #[derive(Clone, Deserialize, Serialize)]
 enum MapValue {
     String(String),
+    /// Any other Python object, serialized with `pickle`
+    Pickled(Vec<u8>),
 }
 
-impl TryFrom<&PyAny> for MapValue {
-    type Error = PyErr;
+impl MapValue {
+    fn new(value: &PyAny, pickle: bool) -> PyResult<Self> {
+        if let Ok(s) = value.extract::<String>() {
+            return Ok(MapValue::String(s));
+        }
 
-    fn try_from(value: &PyAny) -> Result<Self, Self::Error> {
-        Ok(MapValue::String(value.extract::<String>()?))
+        match pickle {
+            true => {
+                let pickle = value.py().import("pickle")?;
+                let bytes = pickle.call_method1("dumps", (value,))?;
+                Ok(MapValue::Pickled(bytes.extract()?))
+            }
+            false => Err(PyTypeError::new_err(
+                "values must be strings unless `pickle_values` is enabled",
+            )),
+        }
     }
-}
 
-impl IntoPy<Py<PyAny>> for &'_ MapValue {
-    fn into_py(self, py: Python<'_>) -> Py<PyAny> {
+    fn to_object(&self, py: Python<'_>) -> PyResult<PyObject> {
         match self {
-            MapValue::String(s) => PyString::new(py, s).into(),
+            MapValue::String(s) => Ok(PyString::new(py, s).into()),
+            MapValue::Pickled(bytes) => {
+                let pickle = py.import("pickle")?;
+                let bytes = PyBytes::new(py, bytes);
+                Ok(pickle.call_method1("loads", (bytes,))?.into())
+            }
         }
     }
 }
-
-const DIMENSIONS: usize = 300;
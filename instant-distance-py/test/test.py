@@ -1,4 +1,4 @@
-import instant_distance, random
+import asyncio, instant_distance, os, random, tempfile
 
 
 def test_hsnw():
@@ -35,6 +35,214 @@ def test_hsnw_map():
     assert approx_nearest == actual_word
 
 
+def test_non_finite():
+    points = [[random.random() for _ in range(300)] for _ in range(16)]
+    points[3][7] = float("nan")
+    config = instant_distance.Config()
+    try:
+        instant_distance.Hnsw.build(points, config)
+        assert False, "expected ValueError"
+    except ValueError:
+        pass
+
+    (hnsw, ids) = instant_distance.Hnsw.build(points[4:], config)
+    try:
+        hnsw.search([float("inf")] * 300, instant_distance.Search())
+        assert False, "expected ValueError"
+    except ValueError:
+        pass
+
+
+def test_dimensions():
+    points = [[random.random() for _ in range(300)] for _ in range(16)]
+    (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+    for point in ([0.5] * 299, [0.5] * 301):
+        try:
+            hnsw.search(point, instant_distance.Search())
+            assert False, "expected TypeError"
+        except TypeError:
+            pass
+
+
+def test_any_dimensions():
+    for dims in (3, 128, 768):
+        points = [[random.random() for _ in range(dims)] for _ in range(64)]
+        (hnsw, ids) = instant_distance.Hnsw.build(points, instant_distance.Config())
+        search = instant_distance.Search()
+        hnsw.search(points[5], search)
+        assert next(search).id == ids[5]
+
+    try:
+        instant_distance.Hnsw.build([[0.5] * 3, [0.5] * 4], instant_distance.Config())
+        assert False, "expected TypeError"
+    except TypeError:
+        pass
+
+
+def test_legacy_files():
+    # Written by bindings that stored points as fixed arrays of 300 floats
+    here = os.path.dirname(os.path.abspath(__file__))
+    points = [[((i * 31 + j * 17) % 101) / 101 for j in range(300)] for i in range(8)]
+    ids = [4, 1, 6, 7, 2, 0, 3, 5]
+
+    hnsw = instant_distance.Hnsw.load(os.path.join(here, "legacy-300.hnsw"))
+    hnsw_map = instant_distance.HnswMap.load(os.path.join(here, "legacy-300.hnswmap"))
+    for i, point in enumerate(points):
+        search = instant_distance.Search()
+        hnsw.search(point, search)
+        result = next(search)
+        assert result.id == ids[i] and result.distance == 0.0
+
+        search = instant_distance.Search()
+        hnsw_map.search(point, search)
+        assert next(search).value == f"point {i}"
+
+
+def test_results():
+    points = [[random.random() for _ in range(300)] for _ in range(256)]
+    config = instant_distance.Config()
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+    search = instant_distance.Search()
+    hnsw.search(points[7], search)
+
+    results = search.results()
+    assert len(results) == config.ef_search
+    assert results[0].id == ids[7]
+    assert results[0].distance == 0.0
+    assert [n.distance for n in results] == sorted(n.distance for n in results)
+
+    try:
+        import numpy
+    except ImportError:
+        return
+
+    (pids, distances) = search.arrays()
+    assert pids.dtype == numpy.uint32 and distances.dtype == numpy.float32
+    assert list(pids) == [n.id for n in results]
+
+
+def test_pickled_values():
+    points = [[random.random() for _ in range(300)] for _ in range(64)]
+    values = [{"index": i, "tags": ("a", i)} for i in range(64)]
+
+    config = instant_distance.Config()
+    hnsw_map = instant_distance.HnswMap.build(points, values, config)
+    with tempfile.TemporaryDirectory() as tmp:
+        fname = os.path.join(tmp, "map.idx")
+        hnsw_map.dump(fname)
+        try:
+            instant_distance.HnswMap.load(fname)
+        except ValueError:
+            pass
+        else:
+            raise AssertionError("expected ValueError for pickled values")
+        hnsw_map = instant_distance.HnswMap.load(fname, allow_pickle=True)
+
+    search = instant_distance.Search()
+    hnsw_map.search(points[5], search)
+    assert next(search).value == values[5]
+
+    try:
+        instant_distance.HnswMap.build(points, values, config, pickle_values=False)
+    except TypeError:
+        pass
+    else:
+        raise AssertionError("expected TypeError for non-string values")
+
+
+def test_filter():
+    points = [[random.random() for _ in range(300)] for _ in range(256)]
+    config = instant_distance.Config()
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+    allowed = set(ids[:64])
+
+    search = instant_distance.Search()
+    mask = [pid in allowed for pid in range(len(points))]
+    for filter in (mask, allowed, lambda pid: pid in allowed):
+        hnsw.search(points[100], search, filter=filter)
+        results = search.results()
+        assert results and all(n.id in allowed for n in results)
+
+    hnsw.search(points[10], search, filter=allowed)
+    assert next(search).id == ids[10]
+
+    # An empty collection allows no ids, rather than being a mask of the wrong length
+    for filter in ([], set(), ()):
+        hnsw.search(points[100], search, filter=filter)
+        assert search.results() == []
+
+    try:
+        import numpy
+    except ImportError:
+        pass
+    else:
+        hnsw.search(points[100], search, filter=numpy.array(mask))
+        assert all(n.id in allowed for n in search)
+        hnsw.search(points[100], search, filter=numpy.array([], dtype=numpy.uint32))
+        assert search.results() == []
+
+    values = [{"index": i} for i in range(256)]
+    hnsw_map = instant_distance.HnswMap.build(points, values, config)
+    hnsw_map.search(points[100], search, filter=lambda value: value["index"] % 2 == 1)
+    results = search.results()
+    assert results and all(n.value["index"] % 2 == 1 for n in results)
+
+    def fail(value):
+        raise KeyError("oops")
+
+    try:
+        hnsw_map.search(points[0], search, filter=fail)
+    except KeyError:
+        pass
+    else:
+        raise AssertionError("expected the filter's KeyError")
+
+    try:
+        hnsw.search(points[0], search, filter=[True, False])
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError for a mask of the wrong length")
+
+
+def test_search_async():
+    points = [[random.random() for _ in range(300)] for _ in range(256)]
+    values = [str(i) for i in range(256)]
+    config = instant_distance.Config()
+    hnsw_map = instant_distance.HnswMap.build(points, values, config)
+    (hnsw, ids) = instant_distance.Hnsw.build(points, config)
+
+    async def search():
+        queries = [hnsw_map.search_async(points[i], 5) for i in range(8)]
+        queries.append(hnsw.search_async(points[9], 3))
+        return await asyncio.gather(*queries)
+
+    results = asyncio.run(search())
+    for i, neighbors in enumerate(results[:8]):
+        assert len(neighbors) == 5
+        assert neighbors[0].value == values[i] and neighbors[0].distance == 0.0
+    assert [n.id for n in results[8]][:1] == [ids[9]] and len(results[8]) == 3
+
+    # Many more searches than worker threads queue up on the pool rather than each getting one
+    async def many():
+        return await asyncio.gather(*(hnsw_map.search_async(p, 1) for p in points))
+
+    results = asyncio.run(many())
+    assert [neighbors[0].value for neighbors in results] == values
+
+    try:
+        hnsw.search_async(points[0], 5)
+    except RuntimeError:
+        pass
+    else:
+        raise AssertionError("expected RuntimeError outside of an event loop")
+
+
 if __name__ == "__main__":
     test_hsnw()
     test_hsnw_map()
+    test_any_dimensions()
+    test_results()
+    test_pickled_values()
+    test_filter()
+    test_search_async()
@@ -1,57 +1,172 @@
-use bencher::{benchmark_group, benchmark_main, Bencher};
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use criterion::measurement::WallTime;
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkGroup, BenchmarkId, Criterion, Throughput,
+};
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 
-use instant_distance::Builder;
-
-benchmark_main!(benches);
-benchmark_group!(benches, build_heuristic);
+use instant_distance::packed::PackedHnsw;
+use instant_distance::points::FixedPoint;
+use instant_distance::{Builder, Point, Search};
 
-fn build_heuristic(bench: &mut Bencher) {
-    let mut rng = StdRng::seed_from_u64(SEED);
-    let points = (0..1024)
-        .map(|_| Point(rng.gen(), rng.gen()))
-        .collect::<Vec<_>>();
+criterion_main!(benches);
+criterion_group!(benches, build, search, distance, sift);
 
-    bench.iter(|| Builder::default().seed(SEED).build_hnsw(points.clone()))
+/// Build indexes of random vectors across a range of dimensions and sizes
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    group.sample_size(10);
+    for dims in DIMS {
+        for len in [1_000, 5_000] {
+            let points = random(dims, len);
+            group.throughput(Throughput::Elements(len as u64));
+            group.bench_with_input(
+                BenchmarkId::new(format!("{dims}d"), len),
+                &points,
+                |bench, points| {
+                    bench.iter(|| Builder::default().seed(SEED).build_hnsw(points.clone()))
+                },
+            );
+        }
+    }
+    group.finish();
 }
 
-const SEED: u64 = 123456789;
+/// Search indexes of random vectors across a range of dimensions and sizes
+fn search(c: &mut Criterion) {
+    let mut group = c.benchmark_group("search");
+    for dims in DIMS {
+        for len in [1_000, 10_000] {
+            let (hnsw, _) = Builder::default().seed(SEED).build_hnsw(random(dims, len));
+            let queries = random(dims, 64);
 
-/*
-fn randomized(builder: Builder) -> (u64, usize) {
-    let query = Point(rng.gen(), rng.gen());
-    let mut nearest = Vec::with_capacity(256);
-    for (i, p) in points.iter().enumerate() {
-        nearest.push((OrderedFloat::from(query.distance(p)), i));
-        if nearest.len() >= 200 {
-            nearest.sort_unstable();
-            nearest.truncate(100);
+            group.throughput(Throughput::Elements(queries.len() as u64));
+            group.bench_function(BenchmarkId::new(format!("{dims}d"), len), |bench| {
+                let mut search = Search::default();
+                bench.iter(|| {
+                    for query in &queries {
+                        black_box(hnsw.search(query, &mut search).len());
+                    }
+                })
+            });
+
+            let packed = PackedHnsw::new(hnsw);
+            group.bench_function(BenchmarkId::new(format!("{dims}d-packed"), len), |bench| {
+                let mut search = Search::default();
+                bench.iter(|| {
+                    for query in &queries {
+                        black_box(packed.search(query, &mut search).len());
+                    }
+                })
+            });
         }
     }
+    group.finish();
+}
+
+/// Compare the distance kernel for `Vec`-backed points against `FixedPoint`
+fn distance(c: &mut Criterion) {
+    let mut group = c.benchmark_group("distance");
+    distance_dims::<128>(&mut group);
+    distance_dims::<768>(&mut group);
+    group.finish();
+}
 
-    let mut search = Search::default();
-    let mut results = vec![PointId::default(); 100];
-    let found = hnsw.search(&query, &mut results, &mut search);
-    assert_eq!(found, 100);
+fn distance_dims<const D: usize>(group: &mut BenchmarkGroup<'_, WallTime>) {
+    let vectors = random(D, 2);
+    group.bench_function(BenchmarkId::new("vec", D), |bench| {
+        bench.iter(|| black_box(&vectors[0]).distance(black_box(&vectors[1])))
+    });
 
-    nearest.sort_unstable();
-    nearest.truncate(100);
-    let forced = nearest
-        .iter()
-        .map(|(_, i)| pids[*i])
-        .collect::<HashSet<_>>();
-    let found = results.into_iter().take(found).collect::<HashSet<_>>();
-    (seed, forced.intersection(&found).count())
+    let mut fixed = vectors.iter().map(|vector| {
+        let mut point = FixedPoint::<D>::default();
+        point.0.copy_from_slice(&vector.0);
+        point
+    });
+    let (a, b) = (fixed.next().unwrap(), fixed.next().unwrap());
+    group.bench_function(BenchmarkId::new("fixed", D), |bench| {
+        bench.iter(|| black_box(&a).distance(black_box(&b)))
+    });
 }
-*/
 
-#[derive(Clone, Copy, Debug)]
-struct Point(f32, f32);
+/// Search the SIFT1M dataset, if available
+///
+/// Set `SIFT_DIR` to a directory containing `sift_base.fvecs` and `sift_query.fvecs` (as
+/// downloaded by `make sift`) to enable this benchmark. Building the index for the full dataset
+/// takes a while; set `SIFT_LIMIT` to only index the first vectors.
+fn sift(c: &mut Criterion) {
+    let dir = match env::var("SIFT_DIR") {
+        Ok(dir) => dir,
+        Err(_) => return,
+    };
+
+    let limit = env::var("SIFT_LIMIT")
+        .ok()
+        .map(|limit| limit.parse::<usize>().expect("invalid SIFT_LIMIT"));
+    let mut base = read_fvecs(&Path::new(&dir).join("sift_base.fvecs"));
+    if let Some(limit) = limit {
+        base.truncate(limit);
+    }
+    let queries = read_fvecs(&Path::new(&dir).join("sift_query.fvecs"));
+
+    let len = base.len();
+    let (hnsw, _) = Builder::default().seed(SEED).build_hnsw(base);
+    let mut group = c.benchmark_group("sift");
+    group.throughput(Throughput::Elements(queries.len() as u64));
+    group.bench_function(BenchmarkId::new("search", len), |bench| {
+        let mut search = Search::default();
+        bench.iter(|| {
+            for query in &queries {
+                black_box(hnsw.search(query, &mut search).len());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn random(dims: usize, len: usize) -> Vec<Vector> {
+    let mut rng = StdRng::seed_from_u64(SEED);
+    (0..len)
+        .map(|_| Vector((0..dims).map(|_| rng.gen()).collect()))
+        .collect()
+}
+
+fn read_fvecs(path: &Path) -> Vec<Vector> {
+    let data = fs::read(path).unwrap_or_else(|e| panic!("failed to read {path:?}: {e}"));
+    let mut vectors = Vec::new();
+    let mut rest = &data[..];
+    while !rest.is_empty() {
+        let dims = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let (vector, next) = rest[4..].split_at(dims * 4);
+        vectors.push(Vector(
+            vector
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        ));
+        rest = next;
+    }
+    vectors
+}
+
+const DIMS: [usize; 4] = [2, 128, 300, 768];
+const SEED: u64 = 123456789;
+
+#[derive(Clone, Debug)]
+struct Vector(Vec<f32>);
 
-impl instant_distance::Point for Point {
+impl Point for Vector {
     fn distance(&self, other: &Self) -> f32 {
         // Euclidean distance metric
-        ((self.0 - other.0).powi(2) + (self.1 - other.1).powi(2)).sqrt()
+        self.0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>()
+            .sqrt()
     }
 }
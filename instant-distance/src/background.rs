@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use parking_lot::Mutex;
+
+/// Handle to an index being built on a background thread
+///
+/// Returned by [`Builder::build_background()`](crate::Builder::build_background). The build
+/// itself runs on the rayon thread pool, like a regular build; the handle can be used to poll
+/// its progress, to register a callback for when it completes, or to wait for the result.
+pub struct BuildHandle<T> {
+    thread: JoinHandle<Option<T>>,
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Send + 'static> BuildHandle<T> {
+    pub(crate) fn spawn(
+        total: usize,
+        build: impl FnOnce(Arc<AtomicUsize>) -> T + Send + 'static,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            total,
+            inserted: Arc::new(AtomicUsize::new(0)),
+            callback: Mutex::new(Callback::Pending),
+        });
+
+        let state = shared.clone();
+        let thread = thread::spawn(move || {
+            let built = build(state.inserted.clone());
+            state.inserted.store(state.total, Ordering::Relaxed);
+
+            let mut callback = state.callback.lock();
+            match std::mem::replace(&mut *callback, Callback::Finished) {
+                Callback::Registered(f) => {
+                    drop(callback);
+                    f(built);
+                    None
+                }
+                _ => Some(built),
+            }
+        });
+
+        Self { thread, shared }
+    }
+
+    /// How many of the points have been inserted so far
+    pub fn progress(&self) -> Progress {
+        Progress {
+            inserted: self.shared.inserted.load(Ordering::Relaxed),
+            total: self.shared.total,
+        }
+    }
+
+    /// Whether the build has finished
+    pub fn is_finished(&self) -> bool {
+        matches!(*self.shared.callback.lock(), Callback::Finished)
+    }
+
+    /// Call `f` with the finished index once the build completes
+    ///
+    /// If the build has already finished, `f` is called immediately on the current thread;
+    /// otherwise it is called on the background thread.
+    pub fn on_complete(self, f: impl FnOnce(T) + Send + 'static) {
+        let mut callback = self.shared.callback.lock();
+        if let Callback::Pending = *callback {
+            *callback = Callback::Registered(Box::new(f));
+            return;
+        }
+
+        drop(callback);
+        if let Some(built) = self.join_thread() {
+            f(built);
+        }
+    }
+
+    /// Wait for the build to finish and return the index
+    ///
+    /// If the build panicked, the panic is propagated to the caller.
+    pub fn join(self) -> T {
+        match self.join_thread() {
+            Some(built) => built,
+            None => unreachable!("result was passed to a callback"),
+        }
+    }
+
+    fn join_thread(self) -> Option<T> {
+        match self.thread.join() {
+            Ok(built) => built,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Progress of a background build
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of points inserted into the graph so far
+    pub inserted: usize,
+    /// The total number of points to insert
+    pub total: usize,
+}
+
+struct Shared<T> {
+    total: usize,
+    inserted: Arc<AtomicUsize>,
+    callback: Mutex<Callback<T>>,
+}
+
+enum Callback<T> {
+    Pending,
+    Registered(Box<dyn FnOnce(T) + Send>),
+    Finished,
+}
@@ -0,0 +1,254 @@
+//! Two-phase construction of a layer, set with [`Builder::bulk()`](crate::Builder::bulk)
+//!
+//! Instead of inserting points one at a time, all points on a layer are linked at once:
+//!
+//! 1. An approximate k-nearest neighbor graph is computed with NN-descent. Each round, every
+//!    node compares itself against the neighbors of its neighbors and keeps the nearest ones.
+//!    Each node only writes its own list, based on the lists from the previous round, so the
+//!    rounds parallelize without any shared mutation.
+//! 2. The neighbor lists are selected from the approximate neighbors with the same simple or
+//!    heuristic selection used for insertion, and linked back from the selected neighbors.
+//!
+//! Since no node is read while it is being updated, the graph doesn't depend on how the threads
+//! are scheduled, unlike a graph built by concurrent insertion.
+
+use std::sync::atomic::{self, AtomicUsize};
+
+use ordered_float::OrderedFloat;
+use rand::rngs::SmallRng;
+use rand::SeedableRng;
+
+use crate::par::*;
+use crate::types::{Candidate, LayerId, Visited, ZeroNode};
+use crate::{Construction, Point, PointId, M};
+
+impl<P: Point> Construction<'_, P> {
+    /// Link the first `len` points on `layer`, replacing their current neighbor lists
+    pub(crate) fn link(&self, layer: LayerId, len: usize, seed: u64) {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("link", layer = layer.0, points = len).entered();
+
+        let points = &self.points[..len];
+        let num = if layer.is_zero() { M * 2 } else { M };
+        let k = num.min(len.saturating_sub(1));
+        if k == 0 {
+            return;
+        }
+
+        let knn = descend(points, k, seed);
+        let retain = self.retain_on(layer);
+
+        // Select the neighbors of each node from its approximate nearest neighbors
+        let mut selected = Vec::new();
+        (0..len)
+            .into_par_iter()
+            .map(|i| {
+                let neighbors = knn[i].iter().copied().take_while(|pid| pid.is_valid());
+                self.select(PointId(i as u32), neighbors, &knn, num, retain)
+            })
+            .collect_into_vec(&mut selected);
+
+        // Link the selected neighbors back, and select again from the combined lists
+        let mut reverse = vec![Vec::new(); len];
+        for (i, neighbors) in selected.iter().enumerate() {
+            for candidate in neighbors {
+                reverse[candidate.pid.0 as usize].push(PointId(i as u32));
+            }
+        }
+
+        selected
+            .par_iter()
+            .zip(reverse)
+            .enumerate()
+            .for_each(|(i, (neighbors, reverse))| {
+                let pid = PointId(i as u32);
+                let candidates = neighbors.iter().map(|c| c.pid).chain(reverse);
+                let found = self.select(pid, candidates, &knn, num, retain);
+                self.zero[pid].update(|node| node.rewrite(found.iter().map(|c| c.pid)));
+            });
+    }
+
+    /// Select at most `num` (and `retain`) neighbors for `pid` from `candidates`, nearest first
+    fn select(
+        &self,
+        pid: PointId,
+        candidates: impl Iterator<Item = PointId>,
+        knn: &[ZeroNode],
+        num: usize,
+        retain: usize,
+    ) -> Vec<Candidate> {
+        let (mut search, insertion) = self.pool.pop();
+        search.reset();
+        search.ef = num.max(M * 2);
+        search.retain = retain;
+        search.extension = self.extension;
+        search.visited.insert(pid);
+
+        let point = &self.points[pid];
+        let points = &self.points[..knn.len()];
+        for candidate in candidates {
+            search.push(candidate, point, points);
+        }
+
+        let found = match self.heuristic {
+            None => {
+                let candidates = search.select_simple();
+                candidates[..Ord::min(candidates.len(), num.min(retain))].to_vec()
+            }
+            Some(heuristic) => search
+                .select_heuristic(point, knn, points, heuristic)
+                .to_vec(),
+        };
+
+        if search.pruned > 0 {
+            self.pruned
+                .fetch_add(search.pruned, atomic::Ordering::Relaxed);
+            search.pruned = 0;
+        }
+
+        self.pool.push((search, insertion));
+        found
+    }
+}
+
+/// Compute an approximate `k`-nearest neighbor graph of `points` with NN-descent
+///
+/// Returns the neighbors of each point, nearest first.
+fn descend<P: Point>(points: &[P], k: usize, seed: u64) -> Vec<ZeroNode> {
+    let len = points.len();
+    let distance = |a: usize, b: usize| OrderedFloat::from(points[a].distance(&points[b]));
+
+    // Start from random neighbors
+    let mut graph = Vec::new();
+    (0..len)
+        .into_par_iter()
+        .map(|i| {
+            let mut rng = SmallRng::seed_from_u64(seed ^ (i as u64).wrapping_mul(SPREAD));
+            let mut list = rand::seq::index::sample(&mut rng, len, k + 1)
+                .into_iter()
+                .filter(|&j| j != i)
+                .take(k)
+                .map(|j| Entry {
+                    candidate: Candidate {
+                        distance: distance(i, j),
+                        pid: PointId(j as u32),
+                    },
+                    new: true,
+                })
+                .collect::<Vec<_>>();
+            list.sort_unstable_by_key(|entry| entry.candidate);
+            list
+        })
+        .collect_into_vec(&mut graph);
+
+    // Only join a sample of the new neighbors each round, as in the NN-descent paper
+    let sample = (k / 2).max(1);
+    for _ in 0..ROUNDS {
+        // Take the nearest new neighbors of each node and mark them as old
+        let (new, old): (Vec<_>, Vec<_>) = graph
+            .par_iter_mut()
+            .map(|list| {
+                let (mut new, mut old) = (Vec::new(), Vec::new());
+                for entry in list.iter_mut() {
+                    match entry.new && new.len() < sample {
+                        true => {
+                            entry.new = false;
+                            new.push(entry.candidate.pid);
+                        }
+                        false if !entry.new => old.push(entry.candidate.pid),
+                        false => {}
+                    }
+                }
+                (new, old)
+            })
+            .unzip();
+
+        // Nodes are joined with their neighbors in both directions
+        let (mut new_all, mut old_all) = (new.clone(), old.clone());
+        for (lists, all) in [(&new, &mut new_all), (&old, &mut old_all)] {
+            for (i, list) in lists.iter().enumerate() {
+                for pid in list {
+                    let reverse = &mut all[pid.0 as usize];
+                    if reverse.len() < sample * 2 {
+                        reverse.push(PointId(i as u32));
+                    }
+                }
+            }
+        }
+
+        let updates = AtomicUsize::new(0);
+        graph.par_iter_mut().enumerate().for_each_init(
+            || Visited::with_capacity(len),
+            |visited, (i, list)| {
+                visited.clear();
+                visited.insert(PointId(i as u32));
+                visited.extend(list.iter().map(|entry| entry.candidate.pid));
+
+                // Neighbors of neighbors that are connected through at least one new edge
+                let new_hops = new_all[i].iter().flat_map(|u| {
+                    let u = u.0 as usize;
+                    new_all[u].iter().chain(&old_all[u])
+                });
+                let old_hops = old_all[i].iter().flat_map(|u| &new_all[u.0 as usize]);
+
+                let mut changed = 0;
+                for &pid in new_hops.chain(old_hops) {
+                    if !visited.insert(pid) {
+                        continue;
+                    }
+
+                    let candidate = Candidate {
+                        distance: distance(i, pid.0 as usize),
+                        pid,
+                    };
+                    if list.len() >= k && candidate >= list[k - 1].candidate {
+                        continue;
+                    }
+
+                    let idx = list.partition_point(|entry| entry.candidate < candidate);
+                    list.insert(
+                        idx,
+                        Entry {
+                            candidate,
+                            new: true,
+                        },
+                    );
+                    list.truncate(k);
+                    changed += 1;
+                }
+
+                updates.fetch_add(changed, atomic::Ordering::Relaxed);
+            },
+        );
+
+        // Stop once the graph has (nearly) converged
+        if updates.into_inner() * CONVERGED <= len * k {
+            break;
+        }
+    }
+
+    graph
+        .par_iter()
+        .map(|list| {
+            let mut node = ZeroNode::default();
+            node.rewrite(list.iter().map(|entry| entry.candidate.pid));
+            node
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    candidate: Candidate,
+    /// Whether this neighbor hasn't been joined with the node's other neighbors yet
+    new: bool,
+}
+
+/// Maximum number of NN-descent rounds
+const ROUNDS: usize = 12;
+
+/// NN-descent stops when fewer than `1 / CONVERGED` of the neighbors changed in a round
+const CONVERGED: usize = 1000;
+
+/// Spreads the per-node seeds over the seed space
+const SPREAD: u64 = 0x9e37_79b9_7f4a_7c15;
@@ -0,0 +1,181 @@
+//! Checkpointing for long-running builds
+//!
+//! A checkpoint holds everything needed to continue an interrupted build: the points in
+//! insertion order and their positions in the input, the values, the build parameters, the
+//! completed upper layers and the current state of the zero layer. Checkpoints are only meant
+//! to bridge an interruption, so checkpoints written by older versions of this crate can't be
+//! resumed.
+
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::files::write_atomic;
+use crate::persist::options;
+use crate::types::{UpperNode, ZeroNode};
+use crate::{Builder, Config, Error, Hnsw, HnswMap, Partial, Point, Snapshot};
+
+impl Builder {
+    /// Build an `HnswMap`, periodically saving the construction state to `path`
+    ///
+    /// A checkpoint is written after every `interval` inserted points and after each completed
+    /// upper layer. If the build is interrupted, it can be continued from the last checkpoint
+    /// with [`Builder::resume()`]. The checkpoint file is removed once the build completes.
+    ///
+    /// Performs the same checks as [`Builder::try_build()`].
+    pub fn build_checkpointed<P, V>(
+        self,
+        points: Vec<P>,
+        values: Vec<V>,
+        path: impl AsRef<Path>,
+        interval: usize,
+    ) -> Result<HnswMap<P, V>, Error>
+    where
+        P: Point + Serialize,
+        V: Serialize,
+    {
+        if points.len() != values.len() {
+            return Err(Error::LengthMismatch {
+                points: points.len(),
+                values: values.len(),
+            });
+        }
+
+        if interval == 0 {
+            return Err(Error::InvalidParameter {
+                name: "interval",
+                reason: "must be at least 1",
+            });
+        }
+
+        self.validate(&points)?;
+        let (partial, out) = Partial::new(points, &self)?;
+
+        // Put the values in insertion order, like `HnswMap::new()` does
+        let mut sorted = values.into_iter().zip(out).collect::<Vec<_>>();
+        sorted.sort_unstable_by_key(|(_, pid)| *pid);
+        let values = sorted.into_iter().map(|(value, _)| value).collect();
+
+        construct(partial, values, self, path.as_ref(), interval)
+    }
+
+    /// Continue a build from the checkpoint at `path`
+    ///
+    /// The build parameters are taken from the checkpoint; only the progress reporting settings
+    /// of this `Builder` are used. The build keeps writing checkpoints to `path` at the same
+    /// interval as before, and removes the file once it completes.
+    pub fn resume<P, V>(self, path: impl AsRef<Path>) -> Result<HnswMap<P, V>, Error>
+    where
+        P: Point + Serialize + DeserializeOwned,
+        V: Serialize + DeserializeOwned,
+    {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        if header[..8] != MAGIC {
+            return Err(Error::Serialization("not a checkpoint file".to_owned()));
+        }
+
+        let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let checkpoint = options().deserialize_from::<_, Checkpoint<P, V>>(reader)?;
+        let Checkpoint {
+            interval,
+            values,
+            config,
+            points,
+            zero,
+            layers,
+            ranges,
+            inputs,
+        } = checkpoint;
+
+        let partial = Partial {
+            config,
+            points,
+            zero,
+            layers,
+            ranges,
+            inputs,
+        };
+        construct(partial, values, self, path, interval)
+    }
+}
+
+fn construct<P: Point + Serialize, V: Serialize>(
+    partial: Partial<P>,
+    values: Vec<V>,
+    builder: Builder,
+    path: &Path,
+    interval: usize,
+) -> Result<HnswMap<P, V>, Error> {
+    // The input order doesn't change during construction, so it's not part of the snapshot
+    let inputs = partial.inputs.clone();
+    let mut write = |snapshot: Snapshot<'_, P>| {
+        let checkpoint = CheckpointRef {
+            interval,
+            values: &values,
+            config: snapshot.config,
+            points: snapshot.points,
+            zero: &snapshot.zero,
+            layers: snapshot.layers,
+            ranges: &snapshot.ranges,
+            inputs: &inputs,
+        };
+        save(&checkpoint, path)
+    };
+
+    let (hnsw, _) = Hnsw::construct(partial, builder, Some((interval, &mut write)))?;
+    fs::remove_file(path).ok();
+    Ok(HnswMap::new_unchecked(hnsw, values))
+}
+
+/// Write the checkpoint atomically, so an interruption doesn't destroy the previous checkpoint
+fn save<P: Serialize, V: Serialize>(
+    checkpoint: &CheckpointRef<'_, P, V>,
+    path: &Path,
+) -> Result<(), Error> {
+    write_atomic(path, |writer| {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(options().serialize_into(writer, checkpoint)?)
+    })
+}
+
+/// Checkpoint layout for writing; must match `Checkpoint`
+#[derive(Serialize)]
+struct CheckpointRef<'a, P, V> {
+    interval: usize,
+    values: &'a [V],
+    config: &'a Config,
+    points: &'a [P],
+    zero: &'a [ZeroNode],
+    layers: &'a [Vec<UpperNode>],
+    ranges: &'a [(usize, Range<usize>)],
+    inputs: &'a [u32],
+}
+
+/// Checkpoint layout for reading; must match `CheckpointRef`
+#[derive(Deserialize)]
+struct Checkpoint<P, V> {
+    interval: usize,
+    values: Vec<V>,
+    config: Config,
+    points: Vec<P>,
+    zero: Vec<ZeroNode>,
+    layers: Vec<Vec<UpperNode>>,
+    ranges: Vec<(usize, Range<usize>)>,
+    inputs: Vec<u32>,
+}
+
+const MAGIC: [u8; 8] = *b"idckpt\0\0";
+const VERSION: u32 = 3;
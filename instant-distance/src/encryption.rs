@@ -0,0 +1,225 @@
+//! Authenticated encryption of saved indexes, with [`Hnsw::save_encrypted()`]
+//!
+//! Encrypted indexes hold the same bytes as an index written with `save()`, encrypted with
+//! XChaCha20-Poly1305 in the STREAM construction: the file starts with an 8-byte magic value and
+//! a random 19-byte nonce prefix, followed by the encrypted index in chunks of 64 KiB, each with
+//! its own authentication tag. The last chunk is marked as such, so a truncated file fails to
+//! decrypt instead of loading a partial index. Indexes are encrypted and decrypted as they are
+//! written and read, without buffering the whole index in memory.
+
+use std::io::{self, Read, Write};
+
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use rand::RngCore;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::{Error, Hnsw, HnswMap, Point};
+
+impl<P: Point + Serialize> Hnsw<P> {
+    /// Write the index to `writer` like [`Hnsw::save()`], encrypted with `key`
+    ///
+    /// The key should be generated randomly (or derived with a proper key derivation function)
+    /// and kept secret; a fresh nonce is generated for every call.
+    pub fn save_encrypted(&self, writer: impl Write, key: &[u8; 32]) -> Result<(), Error> {
+        let mut writer = EncryptWriter::new(writer, key)?;
+        self.save(&mut writer)?;
+        Ok(writer.finish()?)
+    }
+}
+
+impl<P: Point + DeserializeOwned> Hnsw<P> {
+    /// Read an index written by [`Hnsw::save_encrypted()`] with the same `key`
+    ///
+    /// Returns [`Error::Io`] with [`io::ErrorKind::InvalidData`] if the key is wrong or the
+    /// file was modified or truncated.
+    pub fn load_encrypted(reader: impl Read, key: &[u8; 32]) -> Result<Self, Error> {
+        let mut reader = DecryptReader::new(reader, key)?;
+        let hnsw = Self::load(&mut reader)?;
+        reader.finish()?;
+        Ok(hnsw)
+    }
+}
+
+impl<P: Point + Serialize, V: Serialize> HnswMap<P, V> {
+    /// Write the map to `writer` like [`HnswMap::save()`], encrypted with `key`
+    ///
+    /// See [`Hnsw::save_encrypted()`] for details.
+    pub fn save_encrypted(&self, writer: impl Write, key: &[u8; 32]) -> Result<(), Error> {
+        let mut writer = EncryptWriter::new(writer, key)?;
+        self.save(&mut writer)?;
+        Ok(writer.finish()?)
+    }
+}
+
+impl<P: Point + DeserializeOwned, V: DeserializeOwned> HnswMap<P, V> {
+    /// Read a map written by [`HnswMap::save_encrypted()`] with the same `key`
+    ///
+    /// See [`Hnsw::load_encrypted()`] for details.
+    pub fn load_encrypted(reader: impl Read, key: &[u8; 32]) -> Result<Self, Error> {
+        let mut reader = DecryptReader::new(reader, key)?;
+        let map = Self::load(&mut reader)?;
+        reader.finish()?;
+        Ok(map)
+    }
+}
+
+/// Encrypts everything written to it, one chunk at a time
+struct EncryptWriter<W> {
+    writer: W,
+    encryptor: EncryptorBE32<XChaCha20Poly1305>,
+    /// The plaintext of the current chunk
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    fn new(mut writer: W, key: &[u8; 32]) -> Result<Self, Error> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&nonce)?;
+
+        let cipher = XChaCha20Poly1305::new(key.into());
+        Ok(Self {
+            writer,
+            encryptor: EncryptorBE32::from_aead(cipher, (&nonce).into()),
+            buf: Vec::with_capacity(CHUNK_LEN + TAG_LEN),
+        })
+    }
+
+    /// Encrypt and write the last chunk
+    fn finish(mut self) -> io::Result<()> {
+        self.encryptor
+            .encrypt_last_in_place(&[], &mut self.buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+        self.writer.write_all(&self.buf)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buf.len() == CHUNK_LEN {
+            self.encryptor
+                .encrypt_next_in_place(&[], &mut self.buf)
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+            self.writer.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+
+        let len = buf.len().min(CHUNK_LEN - self.buf.len());
+        self.buf.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+
+    /// Flush the underlying writer; the current chunk is only written once it's complete
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts and authenticates everything read from it, one chunk at a time
+struct DecryptReader<R> {
+    reader: R,
+    /// `None` once the last chunk has been decrypted
+    decryptor: Option<DecryptorBE32<XChaCha20Poly1305>>,
+    /// Ciphertext read ahead of the current chunk, to find out whether it's the last one
+    ahead: Vec<u8>,
+    /// The plaintext of the current chunk, and the position of the next byte to read from it
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn new(mut reader: R, key: &[u8; 32]) -> Result<Self, Error> {
+        let mut header = [0; 8 + NONCE_LEN];
+        reader.read_exact(&mut header).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => not_encrypted(),
+            _ => e.into(),
+        })?;
+        if header[..8] != MAGIC {
+            return Err(not_encrypted());
+        }
+
+        let nonce = <&[u8; NONCE_LEN]>::try_from(&header[8..]).unwrap();
+        let cipher = XChaCha20Poly1305::new(key.into());
+        Ok(Self {
+            reader,
+            decryptor: Some(DecryptorBE32::from_aead(cipher, nonce.into())),
+            ahead: Vec::new(),
+            buf: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    /// Read and decrypt the next chunk into `buf`
+    fn next_chunk(&mut self) -> io::Result<()> {
+        // A full chunk is only the last one if nothing follows it
+        let mut buf = std::mem::take(&mut self.ahead);
+        buf.reserve(CHUNK_LEN + TAG_LEN + 1);
+        (&mut self.reader)
+            .take((CHUNK_LEN + TAG_LEN + 1 - buf.len()) as u64)
+            .read_to_end(&mut buf)?;
+
+        let result = match buf.len() > CHUNK_LEN + TAG_LEN {
+            true => {
+                self.ahead = buf.split_off(CHUNK_LEN + TAG_LEN);
+                let decryptor = self.decryptor.as_mut().unwrap();
+                decryptor.decrypt_next_in_place(&[], &mut buf)
+            }
+            false => {
+                let decryptor = self.decryptor.take().unwrap();
+                decryptor.decrypt_last_in_place(&[], &mut buf)
+            }
+        };
+
+        result.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decryption failed: wrong key, or the index was modified or truncated",
+            )
+        })?;
+        self.buf = buf;
+        self.pos = 0;
+        Ok(())
+    }
+
+    /// Check that the index was followed by the end of the last chunk
+    fn finish(mut self) -> io::Result<()> {
+        match io::copy(&mut self, &mut io::sink())? {
+            0 => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected data after the index",
+            )),
+        }
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // The last chunk may be empty
+        while self.pos == self.buf.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+
+        let len = buf.len().min(self.buf.len() - self.pos);
+        buf[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+fn not_encrypted() -> Error {
+    Error::Serialization("not an encrypted index".to_owned())
+}
+
+const MAGIC: [u8; 8] = *b"idcrypt\0";
+/// The size of the nonce prefix: the XChaCha20 nonce without the 5 bytes STREAM uses itself
+const NONCE_LEN: usize = 19;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
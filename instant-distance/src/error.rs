@@ -0,0 +1,129 @@
+use std::{fmt, io};
+
+/// Errors returned by the fallible parts of the API
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A `Builder` parameter is out of range
+    InvalidParameter {
+        name: &'static str,
+        reason: &'static str,
+    },
+    /// The point at `index` has no dimensions
+    EmptyPoint { index: usize },
+    /// The point at `index` does not have the same dimensionality as the other points
+    DimensionMismatch {
+        index: usize,
+        expected: usize,
+        found: usize,
+    },
+    /// The point at `index` has NaN or infinite components
+    NonFinite { index: usize },
+    /// The query point has NaN or infinite components
+    NonFiniteQuery,
+    /// The query point does not have the same dimensionality as the indexed points
+    QueryDimensionMismatch { expected: usize, found: usize },
+    /// The number of values does not match the number of points
+    LengthMismatch { points: usize, values: usize },
+    /// The index can't hold more than `u32::MAX - 1` points
+    TooManyPoints(usize),
+    /// The layer assignment strategy did not return a layer for each point
+    LayerMismatch { points: usize, layers: usize },
+    /// The serialized index was written in a format version this version can't read
+    UnsupportedVersion(u32),
+    /// The serialized index does not match its checksum, so it was corrupted
+    ChecksumMismatch { expected: u32, found: u32 },
+    /// The deserialized or reassembled index is internally inconsistent
+    InvalidIndex(String),
+    /// Failed to serialize or deserialize an index
+    Serialization(String),
+    /// Line `line` (counting from 1) of an input file is malformed
+    Parse { line: usize, reason: String },
+    /// An I/O error occurred while reading or writing an index
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidParameter { name, reason } => {
+                write!(f, "invalid parameter `{name}`: {reason}")
+            }
+            Error::EmptyPoint { index } => write!(f, "point {index} has no dimensions"),
+            Error::DimensionMismatch {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "point {index} has {found} dimensions, expected {expected}"
+            ),
+            Error::NonFinite { index } => write!(f, "point {index} has non-finite components"),
+            Error::NonFiniteQuery => write!(f, "query point has non-finite components"),
+            Error::QueryDimensionMismatch { expected, found } => {
+                write!(f, "query point has {found} dimensions, expected {expected}")
+            }
+            Error::LengthMismatch { points, values } => {
+                write!(f, "got {values} values for {points} points")
+            }
+            Error::TooManyPoints(len) => write!(f, "too many points ({len})"),
+            Error::LayerMismatch { points, layers } => {
+                write!(f, "got {layers} layer assignments for {points} points")
+            }
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported serialization format version {version}")
+            }
+            Error::ChecksumMismatch { expected, found } => write!(
+                f,
+                "checksum mismatch (expected {expected:08x}, found {found:08x})"
+            ),
+            Error::InvalidIndex(reason) => write!(f, "invalid index: {reason}"),
+            Error::Serialization(error) => write!(f, "serialization error: {error}"),
+            Error::Parse { line, reason } => write!(f, "parse error on line {line}: {reason}"),
+            Error::Io(error) => write!(f, "I/O error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl From<bincode::Error> for Error {
+    fn from(error: bincode::Error) -> Self {
+        match *error {
+            bincode::ErrorKind::Io(error) => Error::Io(error),
+            error => Error::Serialization(error.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(error: parquet::errors::ParquetError) -> Self {
+        Error::Serialization(error.to_string())
+    }
+}
+
+#[cfg(feature = "object_store")]
+impl From<object_store::Error> for Error {
+    fn from(error: object_store::Error) -> Self {
+        let kind = match error {
+            object_store::Error::NotFound { .. } => io::ErrorKind::NotFound,
+            _ => io::ErrorKind::Other,
+        };
+        Error::Io(io::Error::new(kind, error))
+    }
+}
@@ -0,0 +1,205 @@
+//! Utilities for measuring search quality against exact nearest neighbors
+
+use std::time::{Duration, Instant};
+
+use ordered_float::OrderedFloat;
+
+use crate::par::*;
+use crate::{Builder, Error, Heuristic, Hnsw, Point, PointId, Search};
+
+/// Fraction of the `k` true nearest neighbors that appear among the first `k` results
+///
+/// `ground_truth` must be ordered from nearest to furthest, as in the ground truth files of the
+/// usual ANN benchmark datasets. If either slice holds fewer than `k` entries, only the available
+/// entries are considered, but the result is still relative to `k`.
+pub fn recall_at_k<T: PartialEq>(results: &[T], ground_truth: &[T], k: usize) -> f32 {
+    if k == 0 {
+        return 1.0;
+    }
+
+    let truth = &ground_truth[..k.min(ground_truth.len())];
+    let found = results
+        .iter()
+        .take(k)
+        .filter(|item| truth.contains(item))
+        .count();
+    found as f32 / k as f32
+}
+
+/// Mean `recall_at_k()` over a set of queries
+///
+/// Returns `None` if there are no queries.
+pub fn mean_recall_at_k<T: PartialEq>(
+    results: &[Vec<T>],
+    ground_truth: &[Vec<T>],
+    k: usize,
+) -> Option<f32> {
+    assert_eq!(
+        results.len(),
+        ground_truth.len(),
+        "expected ground truth for each query"
+    );
+
+    match results.len() {
+        0 => None,
+        len => {
+            let sum = results
+                .iter()
+                .zip(ground_truth)
+                .map(|(results, truth)| recall_at_k(results, truth, k))
+                .sum::<f32>();
+            Some(sum / len as f32)
+        }
+    }
+}
+
+/// The `k` exact nearest neighbors of each query among the points of `hnsw`, nearest first
+///
+/// Computed by comparing each query against every point, in parallel over the queries, so this
+/// is only practical for a sample of queries.
+pub fn exact_neighbors<P: Point>(hnsw: &Hnsw<P>, queries: &[P], k: usize) -> Vec<Vec<PointId>> {
+    let mut neighbors = Vec::with_capacity(queries.len());
+    queries
+        .par_iter()
+        .map(|query| {
+            let mut all = hnsw
+                .iter()
+                .map(|(pid, point)| (OrderedFloat(query.distance(point)), pid))
+                .collect::<Vec<_>>();
+            all.sort_unstable();
+            all.into_iter().take(k).map(|(_, pid)| pid).collect()
+        })
+        .collect_into_vec(&mut neighbors);
+    neighbors
+}
+
+/// Measure recall and search latency at several values of `ef_search`
+///
+/// For each value in `ef_values`, every query is searched for its `k` nearest neighbors (see
+/// [`Search::ef_search()`] and [`Search::k()`]). Returns the `ef`, the mean `recall_at_k()`
+/// against `ground_truth` and the mean duration of a search for each value, in the order of
+/// `ef_values`. The queries run one at a time on the current thread, so the latencies are those
+/// of single searches. Returns nothing if there are no queries.
+///
+/// Panics if there isn't ground truth for each query.
+pub fn sweep_ef<P: Point>(
+    hnsw: &Hnsw<P>,
+    queries: &[P],
+    ground_truth: &[Vec<PointId>],
+    ef_values: &[usize],
+    k: usize,
+) -> Vec<(usize, f32, Duration)> {
+    assert_eq!(
+        queries.len(),
+        ground_truth.len(),
+        "expected ground truth for each query"
+    );
+
+    if queries.is_empty() {
+        return Vec::new();
+    }
+
+    let mut search = Search::default();
+    search.k(Some(k));
+    ef_values
+        .iter()
+        .map(|&ef| {
+            search.ef_search(Some(ef));
+            let mut elapsed = Duration::ZERO;
+            let mut results = Vec::with_capacity(queries.len());
+            for query in queries {
+                let start = Instant::now();
+                let found = hnsw
+                    .search(query, &mut search)
+                    .map(|item| item.pid)
+                    .collect::<Vec<_>>();
+                elapsed += start.elapsed();
+                results.push(found);
+            }
+
+            let recall = mean_recall_at_k(&results, ground_truth, k).unwrap_or_default();
+            (ef, recall, elapsed / queries.len() as u32)
+        })
+        .collect()
+}
+
+/// Build time and recall of an index built with one setting of [`build_tradeoff()`]
+#[derive(Clone, Debug)]
+pub struct Tradeoff {
+    /// The `ef_construction` the index was built with
+    pub ef_construction: usize,
+    /// The neighbor selection heuristic the index was built with
+    pub heuristic: Option<Heuristic>,
+    /// Wall time spent building the graph
+    pub build_time: Duration,
+    /// Mean recall of the `k` nearest neighbors over the queries
+    pub recall: f32,
+}
+
+/// Build indexes over `sample` with several construction settings and compare them
+///
+/// Each `(ef_construction, heuristic)` pair in `settings` is applied to a clone of `builder`,
+/// and the resulting index is searched for the `k` nearest neighbors of each query, using the
+/// `ef_search` of `builder`. Recall is measured against the exact neighbors within the sample.
+///
+/// Build time grows a little faster than linearly with the number of points, so timing a
+/// sample of a few percent of the data gives a lower bound for the full build, and the recall
+/// differences between settings tend to carry over. Returns one entry per setting, in order.
+pub fn build_tradeoff<P: Point>(
+    builder: &Builder,
+    sample: &[P],
+    queries: &[P],
+    k: usize,
+    settings: &[(usize, Option<Heuristic>)],
+) -> Result<Vec<Tradeoff>, Error> {
+    // Ground truth as positions in the sample, which don't depend on how an index is built
+    let mut truth = Vec::with_capacity(queries.len());
+    queries
+        .par_iter()
+        .map(|query| {
+            let mut all = sample
+                .iter()
+                .enumerate()
+                .map(|(i, point)| (OrderedFloat(query.distance(point)), i))
+                .collect::<Vec<_>>();
+            all.sort_unstable();
+            all.into_iter().take(k).map(|(_, i)| i).collect::<Vec<_>>()
+        })
+        .collect_into_vec(&mut truth);
+
+    let mut tradeoffs = Vec::with_capacity(settings.len());
+    for &(ef_construction, heuristic) in settings {
+        let builder = builder
+            .clone()
+            .ef_construction(ef_construction)
+            .select_heuristic(heuristic);
+        builder.validate(sample)?;
+        let (hnsw, pids, report) = Hnsw::try_new_with_report(sample.to_vec(), builder)?;
+
+        // Map the results back to positions in the sample
+        let mut positions = vec![0; pids.len()];
+        for (i, pid) in pids.into_iter().enumerate() {
+            positions[pid.0 as usize] = i;
+        }
+
+        let mut search = Search::default();
+        search.k(Some(k));
+        let results = queries
+            .iter()
+            .map(|query| {
+                hnsw.search(query, &mut search)
+                    .map(|item| positions[item.pid.0 as usize])
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        tradeoffs.push(Tradeoff {
+            ef_construction,
+            heuristic,
+            build_time: report.duration,
+            recall: mean_recall_at_k(&results, &truth, k).unwrap_or_default(),
+        });
+    }
+
+    Ok(tradeoffs)
+}
@@ -0,0 +1,48 @@
+//! Crash-safe replacement of files
+//!
+//! Files are written to a temporary file next to their final path and renamed into place once
+//! they are complete, so a crash or a failed write never leaves a partial file behind, and an
+//! existing file stays intact until it is replaced.
+
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::Error;
+
+/// Write a file via a temporary file in the same directory, which is renamed into place
+pub(crate) fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut tmp = PathBuf::from(path).into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let result = (|| {
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    })();
+
+    if result.is_err() {
+        fs::remove_file(&tmp).ok();
+        return result;
+    }
+
+    // Make sure the rename itself is durable
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
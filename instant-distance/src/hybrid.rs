@@ -0,0 +1,821 @@
+//! Indexes that keep full-precision vectors on disk
+//!
+//! A [`HybridIndex`] keeps the graph and the quantized vectors in memory, while the original
+//! vectors live in a memory-mapped [`VectorFile`]. Searches traverse the graph using the
+//! quantized vectors only, then rerank the candidates using the exact vectors from the file, so
+//! only the pages holding the final candidates have to be read.
+//!
+//! Since the candidates are scattered over the file, the kernel's readahead would mostly read
+//! pages that are never used, so vector files are mapped with [`Access::Random`] by default.
+//! Call [`HybridIndex::warm_up()`] to load the whole file up front instead, if it fits in memory.
+//!
+//! With the `object_store` feature, a [`RemoteHybridIndex`] reads the vectors for reranking from
+//! a vector file in an object store (such as S3) with ranged reads, so the vector file doesn't
+//! have to be copied to local disk before the index can serve searches.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+#[cfg(feature = "object_store")]
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "object_store")]
+use std::sync::Arc;
+
+use memmap2::{Mmap, MmapOptions};
+#[cfg(feature = "object_store")]
+use object_store::{path::Path as ObjectPath, ObjectStore};
+use ordered_float::OrderedFloat;
+
+use crate::points::PointDataSource;
+use crate::quantize::{Sq8, Sq8Code};
+use crate::{Builder, Error, HnswMap, Search};
+
+/// An index searching quantized vectors, reranked with exact vectors from a [`VectorFile`]
+pub struct HybridIndex {
+    map: HnswMap<Sq8Code, u32>,
+    quantizer: Sq8,
+    vectors: VectorFile,
+}
+
+impl HybridIndex {
+    /// Build an index for the vectors in `vectors`
+    ///
+    /// The quantizer is trained on all vectors in the file.
+    pub fn build(builder: Builder, vectors: VectorFile) -> Result<Self, Error> {
+        // Training and encoding read the file from front to back
+        let mut vectors = vectors;
+        let access = vectors.access;
+        vectors.set_access(Access::Sequential)?;
+        let quantizer = Sq8::train(vectors.iter());
+        let codes = vectors
+            .iter()
+            .map(|vector| quantizer.encode(&vector))
+            .collect::<Vec<_>>();
+        vectors.set_access(access)?;
+
+        let positions = (0..vectors.len() as u32).collect();
+        let map = builder.try_build(codes, positions)?;
+        Ok(Self {
+            map,
+            quantizer,
+            vectors,
+        })
+    }
+
+    /// Create an index from a map previously built by `build()` and its vector file
+    ///
+    /// Together with `parts()`, this allows the in-memory part of the index to be persisted
+    /// separately from the vector file.
+    pub fn from_parts(
+        map: HnswMap<Sq8Code, u32>,
+        quantizer: Sq8,
+        vectors: VectorFile,
+    ) -> Result<Self, Error> {
+        check_dims(&map, vectors.dims())?;
+        Ok(Self {
+            map,
+            quantizer,
+            vectors,
+        })
+    }
+
+    /// The graph over the quantized vectors and the quantizer used to encode them
+    pub fn parts(&self) -> (&HnswMap<Sq8Code, u32>, &Sq8) {
+        (&self.map, &self.quantizer)
+    }
+
+    /// Find the `k` vectors nearest to `query`
+    ///
+    /// All candidates found by the graph search (up to `ef_search` of them) are reranked using
+    /// their exact vectors. Returns the positions of the nearest vectors in the vector file and
+    /// their Euclidean distances, nearest first.
+    pub fn search(&self, query: &[f32], k: usize, search: &mut Search) -> Vec<(u32, f32)> {
+        let code = self.quantizer.encode(query);
+        let reranked = self
+            .map
+            .search(&code, search)
+            .map(|item| {
+                let vector = self.vectors.get(*item.value as usize);
+                (*item.value, euclidean(query, vector.iter()))
+            })
+            .collect::<Vec<_>>();
+
+        nearest(reranked, k)
+    }
+
+    /// Find the `k` vectors nearest to `query`, reading the vectors for reranking with `reader`
+    ///
+    /// Like [`HybridIndex::search()`], but the exact vectors of all candidates are fetched in a
+    /// single batch of io_uring reads instead of through the memory map. `reader` must have been
+    /// created for the vector file of this index.
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    pub fn search_uring(
+        &self,
+        query: &[f32],
+        k: usize,
+        search: &mut Search,
+        reader: &mut UringReader,
+    ) -> Result<Vec<(u32, f32)>, Error> {
+        let code = self.quantizer.encode(query);
+        let positions = self
+            .map
+            .search(&code, search)
+            .map(|item| *item.value)
+            .collect::<Vec<_>>();
+
+        let vectors = reader.read(positions.iter().map(|&position| position as usize))?;
+        let reranked = positions
+            .into_iter()
+            .zip(vectors)
+            .map(|(position, vector)| (position, euclidean(query, vector.into_iter())))
+            .collect();
+        Ok(nearest(reranked, k))
+    }
+
+    /// The vector file of this index
+    pub fn vectors(&self) -> &VectorFile {
+        &self.vectors
+    }
+
+    /// Start reading the whole vector file into memory in the background
+    ///
+    /// See [`VectorFile::warm_up()`] for details.
+    pub fn warm_up(&self) -> Result<(), Error> {
+        self.vectors.warm_up()
+    }
+
+    /// The number of vectors in this index
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether this index contains no vectors
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// A memory-mapped file of `f32` vectors with the same dimensionality
+///
+/// The file starts with an 8-byte magic value, followed by the dimensionality and the number of
+/// vectors as little-endian `u64`s and then the components of all vectors as little-endian
+/// `f32`s. The file may be longer than that: space reserved for more vectors follows the last
+/// vector.
+pub struct VectorFile {
+    mmap: Mmap,
+    path: PathBuf,
+    dims: usize,
+    len: usize,
+    /// The number of vectors that fit in the file without growing it
+    capacity: usize,
+    access: Access,
+}
+
+impl VectorFile {
+    /// Write `vectors` to a new file at `path` and map it
+    pub fn create<'a>(
+        path: impl AsRef<Path>,
+        dims: usize,
+        vectors: impl IntoIterator<Item = &'a [f32]>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&[0; HEADER_LEN])?;
+
+        let mut len = 0u64;
+        for (index, vector) in vectors.into_iter().enumerate() {
+            if vector.len() != dims {
+                return Err(Error::DimensionMismatch {
+                    index,
+                    expected: dims,
+                    found: vector.len(),
+                });
+            }
+
+            for value in vector {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+            len += 1;
+        }
+
+        // Write the header last, so a partially written file is never valid
+        let mut file = writer.into_inner().map_err(|e| e.into_error())?;
+        io::Seek::rewind(&mut file)?;
+        file.write_all(&MAGIC)?;
+        file.write_all(&(dims as u64).to_le_bytes())?;
+        file.write_all(&len.to_le_bytes())?;
+        file.sync_all()?;
+        drop(file);
+
+        Self::open(path)
+    }
+
+    /// Map an existing vector file
+    ///
+    /// The file is mapped for [`Access::Random`]; see [`VectorFile::set_access()`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let mmap = map(&File::open(path)?, Access::Random)?;
+        let (dims, len) = parse_header(&mmap[..HEADER_LEN.min(mmap.len())], mmap.len() as u64)?;
+        let capacity = match dims {
+            0 => len,
+            _ => (mmap.len() - HEADER_LEN) / (dims * 4),
+        };
+
+        Ok(Self {
+            mmap,
+            path: path.to_owned(),
+            dims,
+            len,
+            capacity,
+            access: Access::Random,
+        })
+    }
+
+    /// Tell the kernel how the vectors will be accessed, so it can adjust its readahead
+    ///
+    /// The setting is kept when the file is remapped by `reserve()` or `append()`. Only
+    /// supported on Unix; on other platforms, this does nothing.
+    pub fn set_access(&mut self, access: Access) -> Result<(), Error> {
+        advise(&self.mmap, access)?;
+        self.access = access;
+        Ok(())
+    }
+
+    /// Start reading the whole file into memory in the background
+    ///
+    /// This avoids the latency of page faults during the first searches after the file was
+    /// opened, at the cost of reading vectors that may never be used. Only supported on Unix;
+    /// on other platforms, this does nothing.
+    pub fn warm_up(&self) -> Result<(), Error> {
+        warm_up(&self.mmap)
+    }
+
+    /// Reserve space in the file for at least `additional` more vectors
+    ///
+    /// The file is extended without copying the existing vectors; on most file systems, the new
+    /// space doesn't take up disk blocks until vectors are written to it. Like `Vec::reserve()`,
+    /// this may reserve more space than requested, so repeated calls don't grow the file on every
+    /// call.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), Error> {
+        let required = self
+            .len
+            .checked_add(additional)
+            .ok_or(Error::InvalidParameter {
+                name: "additional",
+                reason: "capacity overflow",
+            })?;
+
+        if required <= self.capacity || self.dims == 0 {
+            return Ok(());
+        }
+
+        let capacity = required.max(self.capacity.saturating_mul(2));
+        let size = (HEADER_LEN + capacity * self.dims * 4) as u64;
+        self.unmapped(|file| Ok(file.set_len(size)?))?;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    /// Append `vectors` to the end of the file
+    ///
+    /// The vectors are written into the reserved space if possible, growing the file otherwise.
+    /// The number of vectors in the header is only updated once all vectors have been written,
+    /// so an interrupted append leaves the file in its previous state.
+    pub fn append<'a>(
+        &mut self,
+        vectors: impl IntoIterator<Item = &'a [f32]>,
+    ) -> Result<(), Error> {
+        let (dims, start) = (self.dims, self.len);
+        let len = self.unmapped(|file| {
+            file.seek(SeekFrom::Start((HEADER_LEN + start * dims * 4) as u64))?;
+            let mut writer = BufWriter::new(file);
+
+            let mut len = start;
+            for vector in vectors {
+                if vector.len() != dims {
+                    return Err(Error::DimensionMismatch {
+                        index: len,
+                        expected: dims,
+                        found: vector.len(),
+                    });
+                }
+
+                for value in vector {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+                len += 1;
+            }
+
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            file.sync_data()?;
+            file.seek(SeekFrom::Start(16))?;
+            file.write_all(&(len as u64).to_le_bytes())?;
+            file.sync_data()?;
+            Ok(len)
+        })?;
+
+        self.len = len;
+        self.capacity = self.capacity.max(len);
+        Ok(())
+    }
+
+    /// Run `update` on the file while it isn't mapped, then map it again
+    ///
+    /// Windows refuses to resize a file while a view of it is mapped, which appending past the
+    /// reserved space also does. The file is mapped again even if `update` fails.
+    fn unmapped<T>(
+        &mut self,
+        update: impl FnOnce(&mut File) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.mmap = MmapOptions::new().len(0).map_anon()?.make_read_only()?;
+        let result = update(&mut file);
+        self.mmap = map(&file, self.access)?;
+        result
+    }
+
+    /// The components of the vector at position `index`, in their on-disk representation
+    pub fn get(&self, index: usize) -> VectorRef<'_> {
+        assert!(index < self.len, "vector index out of bounds");
+        let start = HEADER_LEN + index * self.dims * 4;
+        VectorRef(&self.mmap[start..start + self.dims * 4])
+    }
+
+    /// The vector at position `index`, as a point of type `T`
+    ///
+    /// Returns `None` if `T` can't be created from the components (see
+    /// [`PointDataSource::compose()`]).
+    pub fn reconstruct<T: PointDataSource>(&self, index: usize) -> Option<T> {
+        T::compose(&self.get(index).to_vec())
+    }
+
+    /// Iterate over the vectors in the file, decoded to `Vec<f32>`
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = Vec<f32>> + '_ {
+        (0..self.len).map(move |i| self.get(i).to_vec())
+    }
+
+    /// The dimensionality of the vectors, or `None` if the file is empty
+    pub fn dims(&self) -> Option<usize> {
+        match self.len {
+            0 => None,
+            _ => Some(self.dims),
+        }
+    }
+
+    /// The number of vectors in the file
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the file contains no vectors
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of vectors the file can hold without growing
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// A vector stored in a [`VectorFile`]
+#[derive(Clone, Copy)]
+pub struct VectorRef<'a>(&'a [u8]);
+
+impl<'a> VectorRef<'a> {
+    /// Iterate over the components of the vector
+    pub fn iter(&self) -> impl Iterator<Item = f32> + 'a {
+        self.0
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Copy the components of the vector into a `Vec`
+    pub fn to_vec(&self) -> Vec<f32> {
+        self.iter().collect()
+    }
+}
+
+/// Batched reads from a [`VectorFile`] through io_uring
+///
+/// Reading vectors through the memory map faults in one page at a time, so when the file is
+/// much larger than RAM, reranking waits for each read in turn. A `UringReader` submits the
+/// reads for a whole batch of vectors at once, so the SSD can serve them in parallel.
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub struct UringReader {
+    ring: io_uring::IoUring,
+    file: File,
+    dims: usize,
+    len: usize,
+    /// Set if waiting for a batch failed with reads still in flight
+    ///
+    /// Their buffers are leaked, so the kernel never writes to freed memory, but the ring may
+    /// still hold their entries, so the reader can't be used anymore.
+    broken: bool,
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+impl UringReader {
+    /// Open the file backing `vectors`, keeping up to `depth` reads in flight
+    pub fn new(vectors: &VectorFile, depth: u32) -> Result<Self, Error> {
+        if depth == 0 {
+            return Err(Error::InvalidParameter {
+                name: "depth",
+                reason: "must be at least 1",
+            });
+        }
+
+        Ok(Self {
+            ring: io_uring::IoUring::new(depth)?,
+            file: File::open(&vectors.path)?,
+            dims: vectors.dims,
+            len: vectors.len,
+            broken: false,
+        })
+    }
+
+    /// Read the vectors at the given positions, in the same order
+    ///
+    /// Panics if a position is out of bounds; no reads are submitted in that case. If a read
+    /// fails or returns fewer bytes than a vector, the rest of its batch is still waited for
+    /// before the first error is returned, so the reader can be used again afterwards.
+    pub fn read(
+        &mut self,
+        positions: impl IntoIterator<Item = usize>,
+    ) -> Result<Vec<Vec<f32>>, Error> {
+        use std::os::unix::io::AsRawFd;
+
+        use io_uring::{opcode, types};
+
+        if self.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "io_uring reader was abandoned with reads in flight",
+            )
+            .into());
+        }
+
+        let positions = positions.into_iter().collect::<Vec<_>>();
+        assert!(
+            positions.iter().all(|&position| position < self.len),
+            "vector index out of bounds"
+        );
+
+        let bytes = self.dims * 4;
+        let mut buffers = vec![vec![0u8; bytes]; positions.len()];
+        let depth = self.ring.params().sq_entries() as usize;
+        let fd = types::Fd(self.file.as_raw_fd());
+
+        let mut error = None;
+        let mut start = 0;
+        while start < positions.len() {
+            let end = positions.len().min(start + depth);
+            for (i, buffer) in buffers[start..end].iter_mut().enumerate() {
+                let offset = (HEADER_LEN + positions[start + i] * bytes) as u64;
+                let entry = opcode::Read::new(fd, buffer.as_mut_ptr(), bytes as u32)
+                    .offset(offset)
+                    .build()
+                    .user_data((start + i) as u64);
+                // Safety: the buffer outlives the read, since we wait for all completions below
+                // before touching the buffers again (or leak them if we can't); the batch never
+                // exceeds the queue size, and the queue is empty when the batch starts
+                unsafe { self.ring.submission().push(&entry) }.expect("submission queue is full");
+            }
+
+            // Wait for every read of the batch, even after an error, so no read is left in
+            // flight and no stale completion is left for the next batch
+            let mut pending = end - start;
+            while pending > 0 {
+                match self.ring.submit_and_wait(pending) {
+                    Ok(_) => {}
+                    Err(e) if retry(&e) => continue,
+                    Err(e) => {
+                        self.broken = true;
+                        std::mem::forget(buffers);
+                        return Err(e.into());
+                    }
+                }
+
+                for entry in self.ring.completion() {
+                    // Match the completion to its read; anything else can't be ours to count
+                    let index = entry.user_data() as usize;
+                    if !(start..end).contains(&index) {
+                        continue;
+                    }
+
+                    pending -= 1;
+                    let result = entry.result();
+                    let failed = if result < 0 {
+                        Some(io::Error::from_raw_os_error(-result))
+                    } else if result as usize != bytes {
+                        Some(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("short read of vector {}", positions[index]),
+                        ))
+                    } else {
+                        None
+                    };
+
+                    if let (Some(failed), None) = (failed, &error) {
+                        error = Some(failed);
+                    }
+                }
+            }
+
+            start = end;
+        }
+
+        if let Some(error) = error {
+            return Err(error.into());
+        }
+
+        Ok(buffers
+            .iter()
+            .map(|buffer| VectorRef(buffer).to_vec())
+            .collect())
+    }
+}
+
+/// Whether waiting for completions failed without losing track of the submitted reads
+#[cfg(all(feature = "uring", target_os = "linux"))]
+fn retry(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) || error.raw_os_error() == Some(libc::EBUSY)
+}
+
+/// Ranged reads of a [`VectorFile`] stored in an object store
+///
+/// Opening the file only reads its header, so a serving node can rerank against a vector file
+/// in blob storage (see [`RemoteHybridIndex`]) without copying it to local disk first. The
+/// vectors are fetched on demand, a batch at a time.
+#[cfg(feature = "object_store")]
+pub struct RemoteVectorFile {
+    store: Arc<dyn ObjectStore>,
+    location: ObjectPath,
+    dims: usize,
+    len: usize,
+}
+
+#[cfg(feature = "object_store")]
+impl RemoteVectorFile {
+    /// Read the header of the vector file at `location` in `store`
+    pub async fn open(store: Arc<dyn ObjectStore>, location: ObjectPath) -> Result<Self, Error> {
+        let size = store.head(&location).await?.size;
+        let header = store
+            .get_range(&location, 0..(HEADER_LEN as u64).min(size))
+            .await?;
+        let (dims, len) = parse_header(&header, size)?;
+        Ok(Self {
+            store,
+            location,
+            dims,
+            len,
+        })
+    }
+
+    /// Read the vectors at the given positions, in the same order
+    ///
+    /// All vectors are requested with a single `get_ranges()` call, which lets the store
+    /// coalesce nearby ranges. Panics if a position is out of bounds.
+    pub async fn read(
+        &self,
+        positions: impl IntoIterator<Item = usize>,
+    ) -> Result<Vec<Vec<f32>>, Error> {
+        let bytes = (self.dims * 4) as u64;
+        let ranges = positions
+            .into_iter()
+            .map(|position| {
+                assert!(position < self.len, "vector index out of bounds");
+                let start = HEADER_LEN as u64 + position as u64 * bytes;
+                start..start + bytes
+            })
+            .collect::<Vec<_>>();
+
+        let buffers = read_ranges(&*self.store, &self.location, ranges).await?;
+        Ok(buffers
+            .iter()
+            .map(|buffer| VectorRef(buffer).to_vec())
+            .collect())
+    }
+
+    /// The dimensionality of the vectors, or `None` if the file is empty
+    pub fn dims(&self) -> Option<usize> {
+        match self.len {
+            0 => None,
+            _ => Some(self.dims),
+        }
+    }
+
+    /// The number of vectors in the file
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the file contains no vectors
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A [`HybridIndex`] that reads the vectors for reranking from a [`RemoteVectorFile`]
+///
+/// The graph and the quantizer can be loaded from the same store with
+/// [`HnswMap::load_object()`], so a serving node needs no local copy of any part of the index.
+#[cfg(feature = "object_store")]
+pub struct RemoteHybridIndex {
+    map: HnswMap<Sq8Code, u32>,
+    quantizer: Sq8,
+    vectors: RemoteVectorFile,
+}
+
+#[cfg(feature = "object_store")]
+impl RemoteHybridIndex {
+    /// Create an index from a map built by [`HybridIndex::build()`] and its remote vector file
+    pub fn from_parts(
+        map: HnswMap<Sq8Code, u32>,
+        quantizer: Sq8,
+        vectors: RemoteVectorFile,
+    ) -> Result<Self, Error> {
+        check_dims(&map, vectors.dims())?;
+        Ok(Self {
+            map,
+            quantizer,
+            vectors,
+        })
+    }
+
+    /// Find the `k` vectors nearest to `query`
+    ///
+    /// Like [`HybridIndex::search()`], but the exact vectors of all candidates are fetched from
+    /// the object store in one batch of ranged reads.
+    pub async fn search(
+        &self,
+        query: &[f32],
+        k: usize,
+        search: &mut Search,
+    ) -> Result<Vec<(u32, f32)>, Error> {
+        let code = self.quantizer.encode(query);
+        let positions = self
+            .map
+            .search(&code, search)
+            .map(|item| *item.value)
+            .collect::<Vec<_>>();
+
+        let vectors = self
+            .vectors
+            .read(positions.iter().map(|&position| position as usize))
+            .await?;
+        let reranked = positions
+            .into_iter()
+            .zip(vectors)
+            .map(|(position, vector)| (position, euclidean(query, vector.into_iter())))
+            .collect();
+        Ok(nearest(reranked, k))
+    }
+
+    /// The remote vector file of this index
+    pub fn vectors(&self) -> &RemoteVectorFile {
+        &self.vectors
+    }
+
+    /// The number of vectors in this index
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether this index contains no vectors
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// Read the `ranges` of the object at `location` in `store`, in one request
+///
+/// Object stores reject empty ranges, so these are left out of the request.
+#[cfg(feature = "object_store")]
+pub(crate) async fn read_ranges(
+    store: &dyn ObjectStore,
+    location: &ObjectPath,
+    ranges: Vec<Range<u64>>,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let requested = ranges
+        .iter()
+        .filter(|range| !range.is_empty())
+        .cloned()
+        .collect::<Vec<_>>();
+    let mut buffers = store.get_ranges(location, &requested).await?.into_iter();
+    Ok(ranges
+        .iter()
+        .map(|range| match range.is_empty() {
+            true => Vec::new(),
+            false => buffers
+                .next()
+                .map_or_else(Vec::new, |buffer| buffer.to_vec()),
+        })
+        .collect())
+}
+
+/// Check that the vectors of a vector file with `dims` dimensions can be reranked for `map`
+fn check_dims(map: &HnswMap<Sq8Code, u32>, dims: Option<usize>) -> Result<(), Error> {
+    match (map.dims(), dims) {
+        (Some(expected), Some(found)) if expected != found => Err(Error::DimensionMismatch {
+            index: 0,
+            expected,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The dimensionality and the number of vectors from the `header` of a vector file
+///
+/// Checks that a file of `size` bytes holds all vectors.
+fn parse_header(header: &[u8], size: u64) -> Result<(usize, usize), Error> {
+    if header.len() < HEADER_LEN || header[..8] != MAGIC {
+        return Err(Error::Serialization("not a vector file".to_owned()));
+    }
+
+    let dims = read_u64(&header[8..16]);
+    let len = read_u64(&header[16..24]);
+    let used = dims
+        .checked_mul(len)
+        .and_then(|n| n.checked_mul(4))
+        .and_then(|n| n.checked_add(HEADER_LEN as u64));
+    if !matches!(used, Some(used) if used <= size) {
+        return Err(Error::Serialization("vector file is truncated".to_owned()));
+    }
+
+    Ok((dims as usize, len as usize))
+}
+
+/// The `k` nearest of the reranked candidates, nearest first
+fn nearest(mut reranked: Vec<(u32, f32)>, k: usize) -> Vec<(u32, f32)> {
+    reranked.sort_unstable_by_key(|&(_, distance)| OrderedFloat(distance));
+    reranked.truncate(k);
+    reranked
+}
+
+fn euclidean(query: &[f32], vector: impl Iterator<Item = f32>) -> f32 {
+    query
+        .iter()
+        .zip(vector)
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>()
+        .sqrt()
+}
+
+/// How a memory-mapped file is expected to be accessed, passed to the kernel with `madvise()`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// The kernel's default readahead
+    Normal,
+    /// Scattered reads, as during searches; disables readahead
+    Random,
+    /// Reads from front to back, as when iterating over the whole file; reads further ahead
+    Sequential,
+}
+
+pub(crate) fn map(file: &File, access: Access) -> Result<Mmap, Error> {
+    // Safety: the mapping is read-only; we assume the file isn't modified by other processes
+    // while it's mapped. `reserve()` and `append()` only modify the file while it's unmapped.
+    let mmap = unsafe { Mmap::map(file)? };
+    advise(&mmap, access)?;
+    Ok(mmap)
+}
+
+#[cfg(unix)]
+pub(crate) fn advise(mmap: &Mmap, access: Access) -> Result<(), Error> {
+    let advice = match access {
+        Access::Normal => memmap2::Advice::Normal,
+        Access::Random => memmap2::Advice::Random,
+        Access::Sequential => memmap2::Advice::Sequential,
+    };
+    Ok(mmap.advise(advice)?)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn advise(_: &Mmap, _: Access) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn warm_up(mmap: &Mmap) -> Result<(), Error> {
+    Ok(mmap.advise(memmap2::Advice::WillNeed)?)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn warm_up(_: &Mmap) -> Result<(), Error> {
+    Ok(())
+}
+
+pub(crate) fn read_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    buf.copy_from_slice(bytes);
+    u64::from_le_bytes(buf)
+}
+
+const MAGIC: [u8; 8] = *b"idvecs\0\0";
+const HEADER_LEN: usize = 24;
@@ -0,0 +1,121 @@
+//! Mapping between caller-defined keys and `PointId`s
+//!
+//! Building an index sorts the points by layer, so the `PointId`s of the results don't match
+//! the positions of the points that were passed to the `Builder`. An [`IdMap`] translates
+//! between the two, in both directions, so results can be reported using the caller's own keys
+//! (such as database row IDs or document names) and keys can be turned back into `PointId`s, for
+//! example to [exclude](crate::Search::exclude) them from a search.
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Builder, Error, Hnsw, Point, PointId};
+
+/// A bidirectional mapping between keys and the `PointId`s of an index
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(deserialize = "K: Deserialize<'de> + Eq + Hash"))
+)]
+pub struct IdMap<K> {
+    /// The key of each point, in `PointId` order
+    keys: Vec<K>,
+    pids: HashMap<K, PointId>,
+}
+
+impl<K: Clone + Eq + Hash> IdMap<K> {
+    /// Create the mapping for an index built by [`Builder::build_hnsw()`]
+    ///
+    /// `keys[i]` is the key of the `i`th point passed to the builder, and `pids` is the
+    /// `Vec<PointId>` returned along with the index. Returns [`Error::LengthMismatch`] if there
+    /// isn't a key for each point, and [`Error::InvalidParameter`] if the keys aren't unique.
+    pub fn new(keys: Vec<K>, pids: &[PointId]) -> Result<Self, Error> {
+        if keys.len() != pids.len() {
+            return Err(Error::LengthMismatch {
+                points: pids.len(),
+                values: keys.len(),
+            });
+        }
+
+        let mut map = HashMap::with_capacity(keys.len());
+        for (key, &pid) in keys.iter().zip(pids) {
+            if map.insert(key.clone(), pid).is_some() {
+                return Err(Error::InvalidParameter {
+                    name: "keys",
+                    reason: "must be unique",
+                });
+            }
+        }
+
+        let mut sorted = keys.into_iter().zip(pids).collect::<Vec<_>>();
+        sorted.sort_unstable_by_key(|(_, pid)| **pid);
+        Ok(Self {
+            keys: sorted.into_iter().map(|(key, _)| key).collect(),
+            pids: map,
+        })
+    }
+
+    /// The `PointId` of the point with the given key
+    pub fn pid<Q>(&self, key: &Q) -> Option<PointId>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        self.pids.get(key).copied()
+    }
+
+    /// The key of the point `pid`
+    ///
+    /// Panics if `pid` is not a point of the index this mapping was created for.
+    pub fn key(&self, pid: PointId) -> &K {
+        &self.keys[pid.0 as usize]
+    }
+
+    /// Iterate over the keys in `PointId` order
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (PointId, &K)> {
+        self.keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (PointId(i as u32), key))
+    }
+
+    /// The number of keys in the mapping
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// Whether the mapping contains no keys
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl Builder {
+    /// Build the `Hnsw`, along with the mapping between `keys` and the `PointId`s of the points
+    ///
+    /// `keys[i]` is the key of `points[i]`. Performs the same checks as
+    /// [`Builder::try_build()`]; additionally, the keys must be unique.
+    pub fn try_build_keyed<P, K>(
+        self,
+        points: Vec<P>,
+        keys: Vec<K>,
+    ) -> Result<(Hnsw<P>, IdMap<K>), Error>
+    where
+        P: Point,
+        K: Clone + Eq + Hash,
+    {
+        if points.len() != keys.len() {
+            return Err(Error::LengthMismatch {
+                points: points.len(),
+                values: keys.len(),
+            });
+        }
+
+        let (hnsw, pids) = self.try_build_hnsw(points)?;
+        Ok((hnsw, IdMap::new(keys, &pids)?))
+    }
+}
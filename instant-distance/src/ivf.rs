@@ -0,0 +1,142 @@
+//! Inverted file indexes
+//!
+//! An [`IvfIndex`] partitions the points with k-means and builds a separate index for each
+//! partition (an inverted list). Searches only visit the `nprobe` lists whose centroids are
+//! nearest to the query. Compared to a single graph over all points, each graph is smaller and
+//! cheaper to build, and a search can skip most of the data, at the cost of missing neighbors
+//! that were assigned to lists that weren't probed.
+
+use ordered_float::OrderedFloat;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::points::PointDataSource;
+use crate::train::{squared_distance, Centroids, KMeans};
+use crate::{Builder, Error, HnswMap, MapItem, Point, Search};
+
+/// A k-means partition of the points with an `HnswMap` for each partition
+///
+/// Points are assigned to the list with the nearest centroid by Euclidean distance between
+/// their components, regardless of the distance metric of `P`, which is used within the lists.
+/// Set [`Builder::flat_threshold()`] to scan small lists instead of building a graph for them.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct IvfIndex<P, V> {
+    centroids: Centroids,
+    lists: Vec<HnswMap<P, V>>,
+}
+
+impl<P: Point + PointDataSource, V: Clone> IvfIndex<P, V> {
+    /// Partition `points` into `lists` lists and build an index for each of them
+    ///
+    /// The centroids are trained with [`KMeans`] on all points, deterministically for a given
+    /// `seed`. Each list is built with a clone of `builder`. Performs the same checks as
+    /// [`Builder::try_build()`]; additionally, `lists` must be at least 1. If there are fewer
+    /// points than lists, only one list is created per point.
+    pub fn build(
+        builder: Builder,
+        points: Vec<P>,
+        values: Vec<V>,
+        lists: usize,
+        seed: u64,
+    ) -> Result<Self, Error> {
+        if points.len() != values.len() {
+            return Err(Error::LengthMismatch {
+                points: points.len(),
+                values: values.len(),
+            });
+        }
+
+        if lists == 0 {
+            return Err(Error::InvalidParameter {
+                name: "lists",
+                reason: "must be at least 1",
+            });
+        }
+
+        builder.validate(&points)?;
+        let centroids = match points.is_empty() {
+            true => Centroids::default(),
+            false => KMeans::new(lists).seed(seed).train(&points)?,
+        };
+
+        let assignments = centroids.assign(&points);
+        let mut partitions = (0..centroids.len())
+            .map(|_| (Vec::new(), Vec::new()))
+            .collect::<Vec<_>>();
+        for ((point, value), list) in points.into_iter().zip(values).zip(assignments) {
+            partitions[list].0.push(point);
+            partitions[list].1.push(value);
+        }
+
+        let lists = partitions
+            .into_iter()
+            .map(|(points, values)| builder.clone().try_build(points, values))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { centroids, lists })
+    }
+
+    /// Search the `nprobe` lists nearest to `point` for the points nearest to it
+    ///
+    /// Returns the index of the list and the item for each result, nearest first. Each list
+    /// is searched like [`HnswMap::search()`], and the results are merged and truncated to the
+    /// number of results a single search returns. `nprobe` is clamped to the number of lists.
+    pub fn search<'a>(
+        &'a self,
+        point: &P,
+        nprobe: usize,
+        search: &mut Search,
+    ) -> Vec<(usize, MapItem<'a, P, V>)> {
+        let mut results = Vec::new();
+        let mut limit = 0;
+        for list in self.nearest_lists(point, nprobe) {
+            let map = &self.lists[list];
+            limit = limit.max(search.limit(map.hnsw.config.ef_search));
+            let items = map.hnsw.search(point, search);
+            results.extend(items.map(|item| (list, MapItem::from(item, map))));
+        }
+
+        let score = search.score;
+        results.sort_by_key(|(_, item)| score.sort_key(item.distance));
+        results.truncate(limit);
+        results
+    }
+
+    /// The `n` lists with the centroids nearest to `point`, nearest first
+    pub fn nearest_lists(&self, point: &P, n: usize) -> Vec<usize> {
+        let mut lists = self
+            .centroids
+            .iter()
+            .map(|centroid| OrderedFloat(squared_distance(centroid, point.data())))
+            .enumerate()
+            .collect::<Vec<_>>();
+        lists.sort_unstable_by_key(|&(i, distance)| (distance, i));
+        lists.truncate(n.max(1));
+        lists.into_iter().map(|(i, _)| i).collect()
+    }
+
+    /// The index of the inverted list `list`
+    pub fn list(&self, list: usize) -> &HnswMap<P, V> {
+        &self.lists[list]
+    }
+
+    /// The centroids of the inverted lists
+    pub fn centroids(&self) -> &Centroids {
+        &self.centroids
+    }
+
+    /// The number of inverted lists
+    pub fn num_lists(&self) -> usize {
+        self.lists.len()
+    }
+
+    /// The number of points in this index, across all lists
+    pub fn len(&self) -> usize {
+        self.lists.iter().map(HnswMap::len).sum()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.lists.iter().all(HnswMap::is_empty)
+    }
+}
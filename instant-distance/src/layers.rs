@@ -0,0 +1,132 @@
+use rand::{Rng, RngCore};
+
+use crate::M;
+
+/// Strategy for assigning points to layers during construction
+///
+/// The `Builder` calls `assign()` once per build. It must return a layer for each of the `len`
+/// input points, in input order: `0` for points that only live on the zero layer, `1` for points
+/// that are also present on the first layer above it, and so on. Within a layer, points are
+/// inserted in random order. Layers above [`MAX_LAYER`] are rejected with
+/// `Error::InvalidParameter`.
+pub trait LayerAssignment: Send + Sync {
+    fn assign(&self, len: usize, ml: f32, rng: &mut dyn RngCore) -> Vec<usize>;
+}
+
+/// The highest layer a point can be assigned to
+///
+/// Every layer up to the highest one is allocated, so this keeps a stray layer number from
+/// exhausting memory. The random assignments stay far below it: with `ml` below 0.7 for
+/// [`Geometric`] or 1.7 for [`ContentHash`], neither produces layers above 64.
+pub const MAX_LAYER: usize = 64;
+
+/// The default layer assignment strategy
+///
+/// The number of points on each layer is derived from `ml`: each layer holds `ml` times as many
+/// points as the layer below it, stopping before a layer would have fewer than `M` points. Points
+/// are then distributed over the layers in random order.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Geometric;
+
+impl LayerAssignment for Geometric {
+    fn assign(&self, len: usize, ml: f32, rng: &mut dyn RngCore) -> Vec<usize> {
+        // Determine the number of points on each layer, from the top down.
+        let mut sizes = Vec::new();
+        let mut num = len;
+        loop {
+            let next = (num as f32 * ml) as usize;
+            if next < M {
+                break;
+            }
+            sizes.push(num - next);
+            num = next;
+        }
+        sizes.push(num);
+        sizes.reverse();
+
+        let mut shuffled = (0..len)
+            .map(|i| (rng.gen_range(0..len as u32), i))
+            .collect::<Vec<_>>();
+        shuffled.sort_unstable();
+
+        let mut layers = vec![0; len];
+        let mut shuffled = shuffled.into_iter();
+        for (i, size) in sizes.iter().enumerate() {
+            let layer = sizes.len() - i - 1;
+            for (_, idx) in shuffled.by_ref().take(*size) {
+                layers[idx] = layer;
+            }
+        }
+
+        layers
+    }
+}
+
+/// Externally supplied layer assignments
+///
+/// Contains the layer for each input point, in input order.
+#[derive(Clone, Debug)]
+pub struct Explicit(pub Vec<usize>);
+
+impl LayerAssignment for Explicit {
+    fn assign(&self, _: usize, _: f32, _: &mut dyn RngCore) -> Vec<usize> {
+        // The `Builder` returns `Error::LayerMismatch` if there isn't a layer for each point
+        self.0.clone()
+    }
+}
+
+/// Layer assignment derived from a hash of each point's content
+///
+/// Each point's layer is drawn from the same geometric distribution as in the paper (with
+/// parameter `ml`), but using a hash of the point's vector instead of the random number
+/// generator. Rebuilding the same dataset therefore yields an identical layer structure, even if
+/// the order of the input points changes.
+#[derive(Clone, Debug)]
+pub struct ContentHash(Vec<u64>);
+
+impl ContentHash {
+    /// Hash the given vectors, in input order
+    pub fn from_vectors<'a>(vectors: impl IntoIterator<Item = &'a [f32]>) -> Self {
+        Self(vectors.into_iter().map(hash_vector).collect())
+    }
+
+    /// Use precomputed content hashes, in input order
+    ///
+    /// This can be used for point types that aren't backed by `f32` vectors.
+    pub fn from_hashes(hashes: Vec<u64>) -> Self {
+        Self(hashes)
+    }
+}
+
+impl LayerAssignment for ContentHash {
+    fn assign(&self, _: usize, ml: f32, _: &mut dyn RngCore) -> Vec<usize> {
+        self.0
+            .iter()
+            .map(|&hash| {
+                // Map the hash to a uniform sample in (0, 1]
+                let uniform = ((hash >> 11) + 1) as f64 / (1u64 << 53) as f64;
+                (-uniform.ln() * ml as f64) as usize
+            })
+            .collect()
+    }
+}
+
+/// Hash the components of a vector in a way that is stable across platforms and builds
+fn hash_vector(data: &[f32]) -> u64 {
+    // FNV-1a over the bit patterns, followed by a SplitMix64 finalizer to spread the bits
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for &value in data {
+        // Make sure `0.0` and `-0.0` hash the same
+        let bits = if value == 0.0 { 0 } else { value.to_bits() };
+        for byte in bits.to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
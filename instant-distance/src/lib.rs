@@ -1,22 +1,74 @@
 use std::cmp::{max, Ordering, Reverse};
-use std::collections::BinaryHeap;
-use std::collections::HashSet;
-#[cfg(feature = "indicatif")]
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+use std::mem::MaybeUninit;
+use std::ops::Range;
 use std::sync::atomic::{self, AtomicUsize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "indicatif")]
 use indicatif::ProgressBar;
 use ordered_float::OrderedFloat;
-use parking_lot::{Mutex, RwLock};
+use parking_lot::Mutex;
 use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
-use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use rand::{Rng, RngCore, SeedableRng};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
+mod background;
+mod bulk;
+#[cfg(feature = "with-serde")]
+mod checkpoint;
+pub use background::{BuildHandle, Progress};
+#[cfg(feature = "encryption")]
+mod encryption;
+mod error;
+pub mod eval;
+#[cfg(any(feature = "with-serde", feature = "mmap"))]
+mod files;
+#[cfg(feature = "mmap")]
+pub mod hybrid;
+pub use error::Error;
+mod ids;
+pub use ids::IdMap;
+pub mod ivf;
+mod layers;
+pub mod loaders;
+pub mod namespace;
+mod optimize;
+pub mod packed;
+mod par;
+use par::*;
+#[cfg(feature = "huge-pages")]
+mod pages;
+#[cfg(feature = "with-serde")]
+mod persist;
+pub mod points;
+use points::PointDataSource;
+pub mod prefix;
+pub mod quantize;
+mod queue;
+mod raw;
+#[cfg(feature = "replay")]
+mod replay;
+mod shortcuts;
+pub use layers::{ContentHash, Explicit, Geometric, LayerAssignment, MAX_LAYER};
+use queue::CandidateQueue;
+pub use raw::RawParts;
+mod report;
+pub use report::{BuildReport, LayerReport};
+mod trace;
+pub mod train;
+pub use trace::{LayerTrace, Trace, Visit};
+pub mod transform;
 mod types;
-pub use types::PointId;
-use types::{Candidate, Layer, LayerId, UpperNode, Visited, ZeroNode, INVALID};
+pub use types::{LayerView, PointId};
+pub mod values;
+mod verify;
+use types::{AtomicZeroNode, Candidate, Layer, LayerId, UpperNode, Visited, ZeroNode, INVALID};
+pub use values::ValueStore;
+pub use verify::{Problem, VerifyReport};
 
 #[derive(Clone)]
 /// Parameters for building the `Hnsw`
@@ -26,8 +78,27 @@ pub struct Builder {
     heuristic: Option<Heuristic>,
     ml: f32,
     seed: u64,
+    rng: Option<Arc<Mutex<dyn RngCore + Send>>>,
+    layers: Arc<dyn LayerAssignment>,
+    flat_threshold: usize,
+    bulk: bool,
+    /// Maximum number of neighbors kept by each selection
+    retain: usize,
+    /// Maximum number of neighbors on each upper layer, starting with layer 1
+    upper_m: Vec<usize>,
+    extension: Extension,
+    /// Cores to pin the construction worker threads to
+    #[cfg(feature = "affinity")]
+    cores: Vec<usize>,
+    #[cfg(feature = "huge-pages")]
+    huge_pages: bool,
+    /// Recording or replaying the random decisions of the build
+    #[cfg(feature = "replay")]
+    replay: Option<Arc<replay::Session>>,
     #[cfg(feature = "indicatif")]
     progress: Option<ProgressBar>,
+    /// Counts inserted points, for `BuildHandle::progress()`
+    inserted: Option<Arc<AtomicUsize>>,
 }
 
 impl Builder {
@@ -61,12 +132,175 @@ impl Builder {
 
     /// Set the seed value for the random number generator used to generate a layer for each point
     ///
-    /// If this value is left unset, a seed is generated from entropy (via `getrandom()`).
+    /// If this value is left unset, a seed is generated from entropy (via `getrandom()`). The
+    /// seed is not used if a random number generator is supplied with [`Builder::rng()`].
     pub fn seed(mut self, seed: u64) -> Self {
         self.seed = seed;
         self
     }
 
+    /// Use `rng` to assign points to layers and to shuffle the insertion order
+    ///
+    /// By default, a `SmallRng` seeded with the [`Builder::seed()`] value is used. Supplying a
+    /// generator gives full control over the source of randomness, for example to use a
+    /// cryptographically secure generator. The generator is shared by clones of this `Builder`
+    /// and advances with each build, so building twice yields different graphs unless it is
+    /// reseeded in between.
+    pub fn rng(mut self, rng: impl RngCore + Send + 'static) -> Self {
+        self.rng = Some(Arc::new(Mutex::new(rng)));
+        self
+    }
+
+    /// Set the strategy used to assign points to layers
+    ///
+    /// Defaults to [`Geometric`], which distributes points over layers at random.
+    pub fn layer_assignment(mut self, layers: impl LayerAssignment + 'static) -> Self {
+        self.layers = Arc::new(layers);
+        self
+    }
+
+    /// Set `ef_construction`, `ef_search` and the neighbor selection heuristic from a preset
+    ///
+    /// Presets are a starting point that can be adjusted with the individual setters; setters
+    /// called after `preset()` override the preset's values. `M` is fixed at compile time and
+    /// is not affected.
+    pub fn preset(mut self, preset: Preset) -> Self {
+        let (ef_construction, ef_search, heuristic) = match preset {
+            Preset::Fast => (40, 32, None),
+            Preset::Balanced => (100, 100, Some(Heuristic::default())),
+            Preset::Accurate => (
+                400,
+                250,
+                Some(Heuristic {
+                    extend_candidates: false,
+                    keep_pruned: true,
+                    alpha: 1.2,
+                }),
+            ),
+        };
+
+        self.ef_construction = ef_construction;
+        self.ef_search = ef_search;
+        self.heuristic = heuristic;
+        self
+    }
+
+    /// Skip building a graph for fewer than `threshold` points
+    ///
+    /// For small sets of points, comparing the query against every point is about as fast as
+    /// traversing a graph, gives exact results and saves the construction time. Indexes with
+    /// fewer than `threshold` points are built without a graph; their searches scan all points,
+    /// but otherwise behave like graph searches (see [`Hnsw::is_flat()`]). Defaults to 0, so a
+    /// graph is always built.
+    pub fn flat_threshold(mut self, threshold: usize) -> Self {
+        self.flat_threshold = threshold;
+        self
+    }
+
+    /// Keep at most `retain` neighbors each time the neighbors of a node are selected
+    ///
+    /// Neighbor selection keeps the nearest candidates (with the simple selection) or the
+    /// candidates that pass the heuristic's occlusion check, topped up with pruned candidates if
+    /// [`Heuristic::keep_pruned`] is set, until `retain` neighbors are selected. Lowering it
+    /// makes the zero layer sparser and limits how many pruned candidates are added back, which
+    /// reduces memory traffic during searches at some cost in recall. Upper layers keep at most
+    /// `M` neighbors regardless. Must be between 1 and `2 * M` (64); defaults to `2 * M`.
+    pub fn retain(mut self, retain: usize) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// Keep at most `upper_m[i]` neighbors for each node on layer `i + 1`
+    ///
+    /// Upper layers have room for `M` neighbors per node, so each entry must be between 1 and
+    /// `M` (32). Layers beyond the end of `upper_m` keep up to `M` neighbors, so a larger degree
+    /// near the top is set by lowering the layers below it, for example `vec![8, 16]` for 8
+    /// neighbors on layer 1, 16 on layer 2 and 32 above. The limit applies wherever neighbors are
+    /// selected while building the layer, like [`Builder::retain()`], and is recorded in
+    /// [`Config::upper_m`]. Defaults to no limits below `M`.
+    pub fn upper_m(mut self, upper_m: Vec<usize>) -> Self {
+        self.upper_m = upper_m;
+        self
+    }
+
+    /// Limit the candidates added by [`Heuristic::extend_candidates`]
+    ///
+    /// Extending the candidates adds the neighbors of every candidate, so selecting the
+    /// neighbors of a node near a hub (a node linked from many others) can compare it against
+    /// thousands of points. See [`Extension`] for the available limits; by default, all
+    /// neighbors of all candidates are added. Has no effect unless `extend_candidates` is set.
+    pub fn extension(mut self, extension: Extension) -> Self {
+        self.extension = extension;
+        self
+    }
+
+    /// Link all points on a layer at once, instead of inserting them one by one
+    ///
+    /// Bulk construction first computes an approximate nearest neighbor graph for each layer,
+    /// with every worker thread only updating its own nodes, and then selects and links the
+    /// neighbor lists from it (see the [`Builder::select_heuristic()`] setting). This avoids the
+    /// contention between concurrent insertions, so it is usually faster on many cores, and the
+    /// resulting graph doesn't depend on thread scheduling. `ef_construction` is not used.
+    /// Defaults to `false`.
+    pub fn bulk(mut self, bulk: bool) -> Self {
+        self.bulk = bulk;
+        self
+    }
+
+    /// Pin the construction worker threads to the given cores
+    ///
+    /// Construction runs on the current rayon thread pool (the global pool, unless the build is
+    /// started from within `ThreadPool::install()`). Before the build starts, worker `i` of that
+    /// pool is pinned to core `cores[i % cores.len()]`; on hosts shared with other workloads,
+    /// this makes build times more consistent. The workers stay pinned after the build.
+    ///
+    /// Core IDs are numbered from zero, like in `/proc/cpuinfo` on Linux. On platforms that
+    /// don't support pinning threads, this has no effect. Pass an empty list to leave the
+    /// workers unpinned (the default).
+    #[cfg(feature = "affinity")]
+    pub fn pin_threads(mut self, cores: impl IntoIterator<Item = usize>) -> Self {
+        self.cores = cores.into_iter().collect();
+        self
+    }
+
+    /// Back the points and the zero layer with transparent huge pages
+    ///
+    /// The buffers are advised before they are filled, so the kernel can allocate huge pages for
+    /// them from the start; for loaded indexes, use [`Hnsw::advise_huge_pages()`] instead. This
+    /// reduces TLB misses during searches of indexes that are much larger than the TLB covers
+    /// (a few megabytes with regular pages). Only supported on Linux, and only effective if
+    /// transparent huge pages are set to `madvise` or `always`. Defaults to `false`.
+    #[cfg(feature = "huge-pages")]
+    pub fn huge_pages(mut self, huge_pages: bool) -> Self {
+        self.huge_pages = huge_pages;
+        self
+    }
+
+    /// Record the random decisions of each build to the file at `path`
+    ///
+    /// The recording holds the numbers drawn from the random number generator, the resulting
+    /// layers and insertion order, and the order in which the worker threads started inserting
+    /// the points. Pass it to [`Builder::replay()`] to repeat the build. The file is written
+    /// once the build completes, replacing the recording of any previous build.
+    #[cfg(feature = "replay")]
+    pub fn record(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.replay = Some(Arc::new(replay::Session::new(path.into(), false)));
+        self
+    }
+
+    /// Repeat the build recorded in the file at `path` by [`Builder::record()`]
+    ///
+    /// The recorded numbers are used instead of the random number generator, and the points are
+    /// inserted one at a time, in the order their insertions started in the recorded build. The
+    /// other parameters and the points must be the same as for the recorded build; otherwise,
+    /// the build fails with [`Error::InvalidParameter`]. Since the insertions don't overlap, a
+    /// replay doesn't necessarily reproduce problems caused by insertions racing each other.
+    #[cfg(feature = "replay")]
+    pub fn replay(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.replay = Some(Arc::new(replay::Session::new(path.into(), true)));
+        self
+    }
+
     /// A `ProgressBar` to track `Hnsw` construction progress
     #[cfg(feature = "indicatif")]
     pub fn progress(mut self, bar: ProgressBar) -> Self {
@@ -84,6 +318,155 @@ impl Builder {
         Hnsw::new(points, self)
     }
 
+    /// Build an `HnswMap`, also returning statistics about the build
+    pub fn build_with_report<P: Point, V: Clone>(
+        self,
+        points: Vec<P>,
+        values: Vec<V>,
+    ) -> (HnswMap<P, V>, BuildReport) {
+        HnswMap::new_with_report(points, values, self)
+    }
+
+    /// Build the `Hnsw`, also returning statistics about the build
+    pub fn build_hnsw_with_report<P: Point>(
+        self,
+        points: Vec<P>,
+    ) -> (Hnsw<P>, Vec<PointId>, BuildReport) {
+        Hnsw::new_with_report(points, self)
+    }
+
+    /// Build an `HnswMap` on a background thread
+    ///
+    /// Returns immediately with a [`BuildHandle`], which can be used to poll the progress of
+    /// the build, to register a callback for the finished map or to wait for it.
+    pub fn build_background<P, V>(
+        self,
+        points: Vec<P>,
+        values: Vec<V>,
+    ) -> BuildHandle<HnswMap<P, V>>
+    where
+        P: Point + Send + 'static,
+        V: Clone + Send + 'static,
+    {
+        BuildHandle::spawn(points.len(), move |inserted| {
+            let builder = Self {
+                inserted: Some(inserted),
+                ..self
+            };
+            HnswMap::new(points, values, builder)
+        })
+    }
+
+    /// Build an `HnswMap`, validating the configuration and input first
+    ///
+    /// Unlike [`Builder::build()`], this returns an error instead of panicking if the builder
+    /// parameters are out of range, if the points don't all have the same (non-zero)
+    /// dimensionality or if the number of values doesn't match the number of points.
+    pub fn try_build<P: Point, V: Clone>(
+        self,
+        points: Vec<P>,
+        values: Vec<V>,
+    ) -> Result<HnswMap<P, V>, Error> {
+        if points.len() != values.len() {
+            return Err(Error::LengthMismatch {
+                points: points.len(),
+                values: values.len(),
+            });
+        }
+
+        self.validate(&points)?;
+        Ok(HnswMap::try_new_with_report(points, values, self)?.0)
+    }
+
+    /// Build the `Hnsw`, validating the configuration and input first
+    ///
+    /// See [`Builder::try_build()`] for the checks performed.
+    pub fn try_build_hnsw<P: Point>(
+        self,
+        points: Vec<P>,
+    ) -> Result<(Hnsw<P>, Vec<PointId>), Error> {
+        self.validate(&points)?;
+        let (hnsw, ids, _) = Hnsw::try_new_with_report(points, self)?;
+        Ok((hnsw, ids))
+    }
+
+    fn validate<P: Point>(&self, points: &[P]) -> Result<(), Error> {
+        if self.ef_search == 0 {
+            return Err(Error::InvalidParameter {
+                name: "ef_search",
+                reason: "must be at least 1",
+            });
+        }
+
+        if self.ef_construction == 0 {
+            return Err(Error::InvalidParameter {
+                name: "ef_construction",
+                reason: "must be at least 1",
+            });
+        }
+
+        if !(self.ml > 0.0 && self.ml.is_finite()) {
+            return Err(Error::InvalidParameter {
+                name: "ml",
+                reason: "must be a positive, finite number",
+            });
+        }
+
+        if !(1..=M * 2).contains(&self.retain) {
+            return Err(Error::InvalidParameter {
+                name: "retain",
+                reason: "must be between 1 and 64",
+            });
+        }
+
+        if self.upper_m.iter().any(|m| !(1..=M).contains(m)) {
+            return Err(Error::InvalidParameter {
+                name: "upper_m",
+                reason: "must be between 1 and 32",
+            });
+        }
+
+        if let Some(heuristic) = &self.heuristic {
+            if !(heuristic.alpha > 0.0 && heuristic.alpha.is_finite()) {
+                return Err(Error::InvalidParameter {
+                    name: "alpha",
+                    reason: "must be a positive, finite number",
+                });
+            }
+        }
+
+        if points.len() >= u32::MAX as usize {
+            return Err(Error::TooManyPoints(points.len()));
+        }
+
+        let mut expected = None;
+        for (index, point) in points.iter().enumerate() {
+            if !point.is_finite() {
+                return Err(Error::NonFinite { index });
+            }
+
+            let found = match point.dims() {
+                Some(0) => return Err(Error::EmptyPoint { index }),
+                Some(dims) => dims,
+                None => continue,
+            };
+
+            match expected {
+                None => expected = Some(found),
+                Some(expected) if expected != found => {
+                    return Err(Error::DimensionMismatch {
+                        index,
+                        expected,
+                        found,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
     #[doc(hidden)]
     pub fn into_parts(self) -> (usize, usize, f32, u64) {
         let Self {
@@ -106,16 +489,38 @@ impl Default for Builder {
             heuristic: Some(Heuristic::default()),
             ml: 1.0 / (M as f32).ln(),
             seed: rand::random(),
+            rng: None,
+            layers: Arc::new(Geometric),
+            flat_threshold: 0,
+            bulk: false,
+            retain: M * 2,
+            upper_m: Vec::new(),
+            extension: Extension::default(),
+            #[cfg(feature = "affinity")]
+            cores: Vec::new(),
+            #[cfg(feature = "huge-pages")]
+            huge_pages: false,
+            #[cfg(feature = "replay")]
+            replay: None,
             #[cfg(feature = "indicatif")]
             progress: None,
+            inserted: None,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Heuristic {
     pub extend_candidates: bool,
     pub keep_pruned: bool,
+    /// Relaxation factor for the occlusion check (`alpha` in the DiskANN/Vamana paper)
+    ///
+    /// A candidate is pruned if it is more than `alpha` times closer to an already selected
+    /// neighbor than it is to the point being inserted. The default of `1.0` matches the HNSW
+    /// paper; larger values keep more long-range edges (denser graph, better recall on clustered
+    /// data), smaller values prune more aggressively.
+    pub alpha: f32,
 }
 
 impl Default for Heuristic {
@@ -123,14 +528,102 @@ impl Default for Heuristic {
         Heuristic {
             extend_candidates: false,
             keep_pruned: true,
+            alpha: 1.0,
+        }
+    }
+}
+
+/// Limits on the candidates added by [`Heuristic::extend_candidates`], set with
+/// [`Builder::extension()`]
+///
+/// Candidates are extended in order, starting with the neighbors of the nearest candidate, so
+/// the limits drop the neighbors of the furthest candidates first.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Extension {
+    /// Maximum number of neighbors of candidates to add per selection, if any
+    pub max_candidates: Option<usize>,
+    /// Only add neighbors of candidates that are nearer than the furthest candidate
+    ///
+    /// Neighbors further away than all original candidates are only selected if enough of the
+    /// original candidates are pruned, so skipping them rarely changes the selection.
+    pub within_furthest: bool,
+}
+
+/// Parameter combinations for common trade-offs, set with [`Builder::preset()`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Quick construction and searches at the cost of recall
+    ///
+    /// Uses a narrow beam (`ef_construction` 40, `ef_search` 32) and the simple neighbor
+    /// selection, which is cheaper than the heuristic but builds a less navigable graph.
+    Fast,
+    /// The default parameters: `ef_construction` and `ef_search` of 100 with the heuristic
+    Balanced,
+    /// High recall at the cost of slower construction and searches
+    ///
+    /// Uses a wide beam (`ef_construction` 400, `ef_search` 250) and a relaxed heuristic
+    /// (`alpha` 1.2) that keeps more long-range links.
+    Accurate,
+}
+
+/// How the `distance` of search results is reported, set with [`Search::score()`]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Score {
+    /// The distance as computed by `Point::distance()`
+    Distance,
+    /// `1 / (1 + distance)`, a similarity from `1.0` (equal) to `0.0` for any metric
+    Inverse,
+    /// `1 - distance`, the cosine similarity for [`points::Cosine`] and other points using the
+    /// cosine distance
+    Cosine,
+}
+
+impl Score {
+    fn apply(self, distance: f32) -> f32 {
+        match self {
+            Score::Distance => distance,
+            Score::Inverse => 1.0 / (1.0 + distance),
+            Score::Cosine => 1.0 - distance,
         }
     }
+
+    /// A key that sorts reported scores nearest first, as the distances they were derived from
+    fn sort_key(self, score: f32) -> OrderedFloat<f32> {
+        match self {
+            Score::Distance => OrderedFloat(score),
+            // Both similarities decrease with the distance
+            Score::Inverse | Score::Cosine => OrderedFloat(-score),
+        }
+    }
+}
+
+/// How `Hnsw::search_multi()` combines the results for several query points
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Fusion {
+    /// Rank by the distance to the nearest query point (the highest similarity)
+    Min,
+    /// Rank by the mean distance to all query points
+    Mean,
+    /// Reciprocal rank fusion: rank by the sum of `1 / (k + rank)` over the query points
+    ///
+    /// `rank` is the 1-based position of a result among the `ef_search` candidates found for a
+    /// query point (within [`Search::max_distance()`] of it, if set); query points that didn't
+    /// find a result don't contribute. Because a higher sum is better, the negated sum is
+    /// reported as the distance, without applying [`Search::score()`], and the distance
+    /// threshold doesn't apply to it. `k` is commonly set to 60.
+    ReciprocalRank { k: f32 },
 }
 
+/// An `Hnsw` with a value for each point
+///
+/// The values are kept in a `Vec` unless another [`ValueStore`] is attached with
+/// [`HnswMap::from_parts()`].
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
-pub struct HnswMap<P, V> {
+pub struct HnswMap<P, V: ?Sized, S = Vec<V>> {
     hnsw: Hnsw<P>,
-    pub values: Vec<V>,
+    pub values: S,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    marker: PhantomData<fn() -> Box<V>>,
 }
 
 impl<P, V> HnswMap<P, V>
@@ -139,63 +632,303 @@ where
     V: Clone,
 {
     fn new(points: Vec<P>, values: Vec<V>, builder: Builder) -> Self {
-        let (hnsw, ids) = Hnsw::new(points, builder);
+        Self::new_with_report(points, values, builder).0
+    }
+
+    fn new_with_report(points: Vec<P>, values: Vec<V>, builder: Builder) -> (Self, BuildReport) {
+        Self::try_new_with_report(points, values, builder).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_new_with_report(
+        points: Vec<P>,
+        values: Vec<V>,
+        builder: Builder,
+    ) -> Result<(Self, BuildReport), Error> {
+        let (hnsw, ids, report) = Hnsw::try_new_with_report(points, builder)?;
 
         let mut sorted = ids.into_iter().enumerate().collect::<Vec<_>>();
-        sorted.sort_unstable_by(|a, b| a.1.cmp(&b.1));
+        sorted.sort_unstable_by_key(|a| a.1);
         let new = sorted
             .into_iter()
             .map(|(src, _)| values[src].clone())
             .collect();
 
-        Self { hnsw, values: new }
+        Ok((Self::new_unchecked(hnsw, new), report))
+    }
+}
+
+impl<P, V: ?Sized, S> HnswMap<P, V, S> {
+    /// Combine `hnsw` and `values` without checking that there is a value for each point
+    pub(crate) fn new_unchecked(hnsw: Hnsw<P>, values: S) -> Self {
+        Self {
+            hnsw,
+            values,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<P, V, S> HnswMap<P, V, S>
+where
+    P: Point,
+    V: ?Sized,
+    S: ValueStore<V>,
+{
+    /// Combine an index with the values for its points
+    ///
+    /// `values` must hold a value for each point, in `PointId` order (see [`ValueStore`]).
+    /// Returns [`Error::LengthMismatch`] if the number of values doesn't match the number of
+    /// points.
+    pub fn from_parts(hnsw: Hnsw<P>, values: S) -> Result<Self, Error> {
+        if hnsw.len() != values.len() {
+            return Err(Error::LengthMismatch {
+                points: hnsw.len(),
+                values: values.len(),
+            });
+        }
+
+        Ok(Self::new_unchecked(hnsw, values))
+    }
+
+    /// Split the map into the index and the values
+    pub fn into_parts(self) -> (Hnsw<P>, S) {
+        (self.hnsw, self.values)
     }
 
     pub fn search<'a>(
         &'a self,
         point: &P,
         search: &'a mut Search,
-    ) -> impl Iterator<Item = MapItem<'a, P, V>> + ExactSizeIterator + 'a {
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a {
         self.hnsw
             .search(point, search)
             .map(move |item| MapItem::from(item, self))
     }
 
+    /// Search the index like [`HnswMap::search()`], after checking the query point
+    ///
+    /// See [`Hnsw::try_search()`] for details.
+    pub fn try_search<'a>(
+        &'a self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> Result<impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a, Error> {
+        Ok(self
+            .hnsw
+            .try_search(point, search)?
+            .map(move |item| MapItem::from(item, self)))
+    }
+
+    /// Search the index for the points nearest to `point` for which `filter` returns `true`
+    ///
+    /// `filter` is called with the ID and value of each point. See [`Hnsw::search_filtered()`]
+    /// for details.
+    pub fn search_filtered<'a>(
+        &'a self,
+        point: &P,
+        search: &'a mut Search,
+        filter: impl Fn(PointId, &V) -> bool + 'a,
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a {
+        self.hnsw.search_nearest(point, search, |pid| {
+            filter(pid, self.values.value(pid.0 as usize))
+        });
+        search
+            .iter()
+            .map(move |candidate| MapItem::from(Item::new(candidate, &self.hnsw), self))
+    }
+
+    /// Search for points near any of `points`, merging the results into a single ranking
+    ///
+    /// See [`Hnsw::search_multi()`] for details.
+    pub fn search_multi<'a>(
+        &'a self,
+        points: &[P],
+        fusion: Fusion,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a {
+        self.hnsw
+            .search_multi(points, fusion, search)
+            .map(move |item| MapItem::from(item, self))
+    }
+
+    /// Search the index for the points nearest to each of `queries`
+    ///
+    /// See [`Hnsw::search_batch()`] for details.
+    pub fn search_batch<'a>(
+        &'a self,
+        queries: &[P],
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a> + 'a
+    {
+        self.hnsw
+            .search_batch(queries, search)
+            .map(move |items| items.map(move |item| MapItem::from(item, self)))
+    }
+
+    /// Search for points near `point` that are dissimilar to the points in `avoid`
+    ///
+    /// See [`Hnsw::search_contrastive()`] for details.
+    pub fn search_contrastive<'a>(
+        &'a self,
+        point: &P,
+        avoid: &[P],
+        weight: f32,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a {
+        self.hnsw
+            .search_contrastive(point, avoid, weight, search)
+            .map(move |item| MapItem::from(item, self))
+    }
+
+    /// Continue a search to find `additional` more results
+    ///
+    /// See [`Hnsw::search_more()`] for details.
+    pub fn search_more<'a>(
+        &'a self,
+        point: &P,
+        search: &'a mut Search,
+        additional: usize,
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a {
+        self.hnsw
+            .search_more(point, search, additional)
+            .map(move |item| MapItem::from(item, self))
+    }
+
     /// Iterate over the keys and values in this index
     pub fn iter(&self) -> impl Iterator<Item = (PointId, &P)> {
         self.hnsw.iter()
     }
 
+    /// The layers of the graph, from the bottom layer up
+    ///
+    /// See [`Hnsw::layers()`] for details.
+    pub fn layers(&self) -> impl ExactSizeIterator<Item = LayerView<'_>> {
+        self.hnsw.layers()
+    }
+
+    /// Iterate over the points on `layer`, where `0` is the bottom layer
+    ///
+    /// See [`Hnsw::iter_layer()`] for details.
+    pub fn iter_layer(&self, layer: usize) -> impl ExactSizeIterator<Item = (PointId, &P)> {
+        self.hnsw.iter_layer(layer)
+    }
+
+    /// The parameters this index was built with
+    pub fn config(&self) -> &Config {
+        self.hnsw.config()
+    }
+
+    /// The index of `pid` in the slice of points this map was built from
+    ///
+    /// See [`Hnsw::input_index()`] for details.
+    pub fn input_index(&self, pid: PointId) -> Option<usize> {
+        self.hnsw.input_index(pid)
+    }
+
+    /// The number of points in this index
+    pub fn len(&self) -> usize {
+        self.hnsw.len()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.hnsw.is_empty()
+    }
+
+    /// Whether this index was built without a graph
+    ///
+    /// See [`Hnsw::is_flat()`] for details.
+    pub fn is_flat(&self) -> bool {
+        self.hnsw.is_flat()
+    }
+
+    /// The dimensionality of the points in this index
+    ///
+    /// See [`Hnsw::dims()`] for details.
+    pub fn dims(&self) -> Option<usize> {
+        self.hnsw.dims()
+    }
+
     #[doc(hidden)]
     pub fn get(&self, i: usize, search: &Search) -> Option<MapItem<'_, P, V>> {
         Some(MapItem::from(self.hnsw.get(i, search)?, self))
     }
 }
 
-pub struct MapItem<'a, P, V> {
+impl<P: PointDataSource, V: ?Sized, S> HnswMap<P, V, S> {
+    /// Recreate the point `pid` as a `T`, from the components of the indexed point
+    ///
+    /// See [`Hnsw::reconstruct()`] for details.
+    pub fn reconstruct<T: PointDataSource>(&self, pid: PointId) -> Option<T> {
+        self.hnsw.reconstruct(pid)
+    }
+}
+
+pub struct MapItem<'a, P, V: ?Sized> {
     pub distance: f32,
     pub pid: PointId,
+    /// The index of the point in the slice the map was built from (see [`Item::index`])
+    pub index: Option<usize>,
     pub point: &'a P,
     pub value: &'a V,
 }
 
-impl<'a, P, V> MapItem<'a, P, V> {
-    fn from(item: Item<'a, P>, map: &'a HnswMap<P, V>) -> Self {
+impl<'a, P, V: ?Sized> MapItem<'a, P, V> {
+    fn from<S: ValueStore<V>>(item: Item<'a, P>, map: &'a HnswMap<P, V, S>) -> Self {
         MapItem {
             distance: item.distance,
             pid: item.pid,
+            index: item.index,
             point: item.point,
-            value: &map.values[item.pid.0 as usize],
+            value: map.values.value(item.pid.0 as usize),
         }
     }
 }
 
+/// The parameters an `Hnsw` was built with
+///
+/// These are stored with the index, so they survive serialization. Indexes migrated from
+/// serialization formats that didn't record the build parameters have `ef_construction`, `ml`
+/// and `seed` set to zero.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    /// The `M` parameter from the paper (maximum number of links on upper layers)
+    pub m: usize,
+    /// Lower limits on the number of links on upper layers, starting with layer 1
+    ///
+    /// Layers beyond the end keep up to `m` links. See [`Builder::upper_m()`].
+    pub upper_m: Vec<usize>,
+    /// The `ef` parameter used for searches
+    pub ef_search: usize,
+    /// The `efConstruction` parameter used for construction
+    pub ef_construction: usize,
+    /// The `mL` parameter used to determine layer sizes
+    pub ml: f32,
+    /// The seed for the random number generator used during construction
+    ///
+    /// Not meaningful if the index was built with a generator supplied via [`Builder::rng()`].
+    pub seed: u64,
+    /// The neighbor selection heuristic, if any
+    pub heuristic: Option<Heuristic>,
+    /// The name of the point type, which determines the distance metric
+    ///
+    /// This is taken from `std::any::type_name()`, so it's meant for human consumption only.
+    pub metric: String,
+    /// The dimensionality of the indexed points, if known (see [`Point::dims()`])
+    pub dims: Option<usize>,
+}
+
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Hnsw<P> {
-    ef_search: usize,
+    config: Config,
     points: Vec<P>,
     zero: Vec<ZeroNode>,
     layers: Vec<Vec<UpperNode>>,
+    /// The index of each point in the slice the index was built from, by `PointId`
+    ///
+    /// `None` for indexes loaded from format versions that didn't record it.
+    inputs: Option<Vec<u32>>,
 }
 
 impl<P> Hnsw<P>
@@ -207,225 +940,986 @@ where
     }
 
     fn new(points: Vec<P>, builder: Builder) -> (Self, Vec<PointId>) {
-        let ef_search = builder.ef_search;
-        let ef_construction = builder.ef_construction;
-        let ml = builder.ml;
-        let heuristic = builder.heuristic;
-        let mut rng = SmallRng::seed_from_u64(builder.seed);
+        let (hnsw, out, _) = Self::new_with_report(points, builder);
+        (hnsw, out)
+    }
+
+    fn new_with_report(points: Vec<P>, builder: Builder) -> (Self, Vec<PointId>, BuildReport) {
+        Self::try_new_with_report(points, builder).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    fn try_new_with_report(
+        points: Vec<P>,
+        builder: Builder,
+    ) -> Result<(Self, Vec<PointId>, BuildReport), Error> {
+        let (partial, out) = Partial::new(points, &builder)?;
+        let (hnsw, report) = Self::construct(partial, builder, None)?;
+        Ok((hnsw, out, report))
+    }
+
+    /// Insert the remaining points of a `Partial` index
+    ///
+    /// If `checkpoint` is given, it is called with a snapshot of the construction state after
+    /// every `interval` inserted points and after each completed upper layer.
+    pub(crate) fn construct(
+        partial: Partial<P>,
+        builder: Builder,
+        mut checkpoint: Option<(usize, Checkpoint<'_, P>)>,
+    ) -> Result<(Self, BuildReport), Error> {
+        let Partial {
+            config,
+            points,
+            zero,
+            mut layers,
+            ranges,
+            inputs,
+        } = partial;
+
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("build", points = points.len()).entered();
+
+        #[cfg(feature = "affinity")]
+        if !builder.cores.is_empty() {
+            let cores = &builder.cores;
+            rayon::broadcast(|ctx| {
+                let id = cores[ctx.index() % cores.len()];
+                // Pinning is only a performance hint, so don't fail the build if it's unsupported
+                core_affinity::set_for_current(core_affinity::CoreId { id })
+            });
+        }
 
         #[cfg(feature = "indicatif")]
         let progress = builder.progress;
         #[cfg(feature = "indicatif")]
         if let Some(bar) = &progress {
             bar.set_length(points.len() as u64);
-            bar.set_message("Build index (preparation)");
-        }
-
-        if points.is_empty() {
-            return (
-                Self {
-                    ef_search,
-                    zero: Vec::new(),
-                    points: Vec::new(),
-                    layers: Vec::new(),
-                },
-                Vec::new(),
-            );
         }
 
-        // Determine the number and size of layers.
-
-        let mut sizes = Vec::new();
-        let mut num = points.len();
-        loop {
-            let next = (num as f32 * ml) as usize;
-            if next < M {
-                break;
-            }
-            sizes.push((num - next, num));
-            num = next;
-        }
-        sizes.push((num, num));
-        sizes.reverse();
-        let top = LayerId(sizes.len() - 1);
-
-        // Give all points a random layer and sort the list of nodes by descending order for
-        // construction. This allows us to copy higher layers to lower layers as construction
-        // progresses, while preserving randomness in each point's layer and insertion order.
-
-        assert!(points.len() < u32::MAX as usize);
-        let mut shuffled = (0..points.len())
-            .map(|i| (PointId(rng.gen_range(0..points.len() as u32)), i))
-            .collect::<Vec<_>>();
-        shuffled.sort_unstable();
-
-        let mut out = vec![INVALID; points.len()];
-        let points = shuffled
-            .into_iter()
-            .enumerate()
-            .map(|(i, (_, idx))| {
-                out[idx] = PointId(i as u32);
-                points[idx].clone()
-            })
-            .collect::<Vec<_>>();
-
-        // Figure out how many nodes will go on each layer. This helps us allocate memory capacity
-        // for each layer in advance, and also helps enable batch insertion of points.
-
-        let num_layers = sizes.len();
-        let mut ranges = Vec::with_capacity(top.0);
-        for (i, (size, cumulative)) in sizes.into_iter().enumerate() {
-            let start = cumulative - size;
-            // Skip the first point, since we insert the enter point separately
-            ranges.push((LayerId(num_layers - i - 1), max(start, 1)..cumulative));
+        // Moving the nodes into and out of locks rewrites the whole layer, so do it from the
+        // worker threads for the reason explained in `Partial::new()`
+        let mut locked = Vec::with_capacity(zero.len());
+        #[cfg(feature = "huge-pages")]
+        if builder.huge_pages {
+            pages::advise(locked.as_ptr(), locked.capacity());
         }
-
-        // Initialize data for layers
-
-        let mut layers = vec![vec![]; top.0];
-        let zero = points
-            .iter()
-            .map(|_| RwLock::new(ZeroNode::default()))
-            .collect::<Vec<_>>();
-
+        zero.into_par_iter()
+            .map(AtomicZeroNode::new)
+            .collect_into_vec(&mut locked);
+        let zero = locked;
+        let top = LayerId(layers.len());
+        let bulk = builder.bulk;
         let state = Construction {
             zero: zero.as_slice(),
             pool: SearchPool::new(points.len()),
             top,
             points: &points,
-            heuristic,
-            ef_construction,
+            heuristic: config.heuristic,
+            ef_construction: config.ef_construction,
+            retain: builder.retain,
+            upper_m: &config.upper_m,
+            extension: builder.extension,
             #[cfg(feature = "indicatif")]
             progress,
             #[cfg(feature = "indicatif")]
             done: AtomicUsize::new(0),
+            inserted: builder.inserted,
+            pruned: AtomicUsize::new(0),
         };
 
-        for (layer, range) in ranges {
+        let started = Instant::now();
+        let mut report = BuildReport::default();
+
+        let interval = checkpoint
+            .as_ref()
+            .map_or(usize::MAX, |(interval, _)| *interval);
+        for (i, (layer, range)) in ranges.iter().enumerate() {
+            let layer = LayerId(*layer);
+
+            #[cfg(feature = "tracing")]
+            let _span =
+                tracing::debug_span!("layer", layer = layer.0, points = range.len()).entered();
+
             #[cfg(feature = "indicatif")]
             if let Some(bar) = &state.progress {
                 bar.set_message(format!("Building index (layer {})", layer.0));
             }
 
-            let inserter = |pid| state.insert(pid, layer, &layers);
+            let layer_started = Instant::now();
+            let pruned = state.pruned.load(atomic::Ordering::Relaxed);
+            let inserter = |pid| {
+                #[cfg(feature = "replay")]
+                if let Some(session) = &builder.replay {
+                    session.inserting(pid);
+                }
+                state.insert(pid, layer, &layers)
+            };
 
-            let end = range.end;
-            if layer == top {
-                range.into_iter().for_each(|i| inserter(PointId(i as u32)))
-            } else {
-                range
-                    .into_par_iter()
-                    .for_each(|i| inserter(PointId(i as u32)));
+            if bulk {
+                state.link(layer, range.end, config.seed);
+                if let Some(inserted) = &state.inserted {
+                    inserted.fetch_add(range.len(), atomic::Ordering::Relaxed);
+                }
+            }
+
+            // Insert the points in batches of `interval` so we can checkpoint in between
+            let mut start = if bulk { range.end } else { range.start };
+            while start < range.end {
+                let end = range.end.min(start.saturating_add(interval));
+                #[cfg(feature = "replay")]
+                let replayed = match &builder.replay {
+                    Some(session) => session.batch(start..end)?,
+                    None => None,
+                };
+                #[cfg(not(feature = "replay"))]
+                let replayed: Option<Vec<PointId>> = None;
+
+                if let Some(order) = replayed {
+                    order.into_iter().for_each(inserter);
+                } else if layer == top {
+                    (start..end).for_each(|i| inserter(PointId(i as u32)))
+                } else {
+                    (start..end)
+                        .into_par_iter()
+                        .for_each(|i| inserter(PointId(i as u32)));
+                }
+
+                start = end;
+                if let (Some((_, checkpoint)), true) = (&mut checkpoint, end < range.end) {
+                    let mut remaining = vec![(layer.0, end..range.end)];
+                    remaining.extend_from_slice(&ranges[i + 1..]);
+                    checkpoint(Snapshot::new(&config, &points, &zero, &layers, remaining))?;
+                }
             }
 
             // For layers above the zero layer, make a copy of the current state of the zero layer
-            // with `nearest` truncated to `M` elements.
+            // with `nearest` truncated to `M` elements, or the lower limit for this layer.
+            let duration = layer_started.elapsed();
             if !layer.is_zero() {
-                (&state.zero[..end])
+                let len = state.retain_on(layer).min(M);
+                (&state.zero[..range.end])
                     .into_par_iter()
-                    .map(|zero| UpperNode::from_zero(&zero.read()))
+                    .map(|zero| UpperNode::from_zero(&zero.load(), len))
                     .collect_into_vec(&mut layers[layer.0 - 1]);
+
+                if let Some((_, checkpoint)) = &mut checkpoint {
+                    let remaining = ranges[i + 1..].to_vec();
+                    checkpoint(Snapshot::new(&config, &points, &zero, &layers, remaining))?;
+                }
             }
+
+            let (nodes, slots) = match layer.0 {
+                0 => (zero.len(), zero.iter().map(|node| node.load().fill()).sum()),
+                l => {
+                    let nodes = &layers[l - 1];
+                    (
+                        nodes.len(),
+                        nodes.iter().map(UpperNode::fill).sum::<usize>(),
+                    )
+                }
+            };
+            let capacity = nodes
+                * match layer.is_zero() {
+                    true => M * 2,
+                    false => state.upper_m(layer).unwrap_or(M),
+                };
+            report.layers.push(LayerReport {
+                layer: layer.0,
+                points: nodes,
+                duration,
+                neighbor_fill: match capacity {
+                    0 => 0.0,
+                    _ => slots as f32 / capacity as f32,
+                },
+                pruned: state.pruned.load(atomic::Ordering::Relaxed) - pruned,
+            });
         }
+        report.duration = started.elapsed();
 
         #[cfg(feature = "indicatif")]
         if let Some(bar) = &state.progress {
             bar.finish();
         }
 
-        (
-            Self {
-                ef_search,
-                zero: zero.into_iter().map(|node| node.into_inner()).collect(),
-                points,
-                layers,
-            },
-            out,
-        )
-    }
+        let mut unlocked = Vec::with_capacity(zero.len());
+        #[cfg(feature = "huge-pages")]
+        if builder.huge_pages {
+            pages::advise(unlocked.as_ptr(), unlocked.capacity());
+        }
+        zero.into_par_iter()
+            .map(|node| node.into_inner())
+            .collect_into_vec(&mut unlocked);
+        let hnsw = Self {
+            config,
+            zero: unlocked,
+            points,
+            layers,
+            inputs: Some(inputs),
+        };
+        #[cfg(feature = "replay")]
+        if let Some(session) = &builder.replay {
+            session.finish()?;
+        }
+
+        Ok((hnsw, report))
+    }
+
+    /// Search the index for the points nearest to the reference point `point`
+    ///
+    /// The results are returned in the `out` parameter; the number of neighbors to search for
+    /// is limited by the size of the `out` parameter, and the number of results found is returned
+    /// in the return value.
+    pub fn search<'a, 'b: 'a>(
+        &'b self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Item<'b, P>> + 'a {
+        self.search_nearest(point, search, |_| true);
+        search
+            .iter()
+            .map(move |candidate| Item::new(candidate, self))
+    }
+
+    /// Search the index like [`Hnsw::search()`], after checking the query point
+    ///
+    /// Returns an error instead of searching if `point` has NaN or infinite components, or if
+    /// its dimensionality differs from that of the indexed points.
+    pub fn try_search<'a, 'b: 'a>(
+        &'b self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> Result<impl ExactSizeIterator<Item = Item<'b, P>> + 'a, Error> {
+        if !point.is_finite() {
+            return Err(Error::NonFiniteQuery);
+        }
+
+        if let (Some(expected), Some(found)) = (self.dims(), point.dims()) {
+            if expected != found {
+                return Err(Error::QueryDimensionMismatch { expected, found });
+            }
+        }
+
+        Ok(self.search(point, search))
+    }
+
+    /// Search the index for the points nearest to `point` for which `filter` returns `true`
+    ///
+    /// The upper layers are searched as usual, and the filter only decides which points of the
+    /// zero layer are added to the results. Other points are still traversed to reach more
+    /// points that pass it, but a very selective filter makes it harder to find them: if only a
+    /// small fraction of the points pass the filter, the search may return fewer than
+    /// `ef_search` results.
+    pub fn search_filtered<'a, 'b: 'a>(
+        &'b self,
+        point: &P,
+        search: &'a mut Search,
+        filter: impl Fn(PointId) -> bool + 'a,
+    ) -> impl ExactSizeIterator<Item = Item<'b, P>> + 'a {
+        self.search_nearest(point, search, filter);
+        search
+            .iter()
+            .map(move |candidate| Item::new(candidate, self))
+    }
+
+    /// Search for points near `point` that are dissimilar to the points in `avoid`
+    ///
+    /// The candidates of a regular search are reranked by `distance + weight * similarity`,
+    /// where `similarity` is the highest `1 / (1 + d)` for the distance `d` between the candidate
+    /// and any of the points in `avoid`. The adjusted scores are reported as the distances of the
+    /// results. The whole beam of candidates found for `point` is reranked before exclusions,
+    /// [`Search::max_distance()`] (which then bounds the adjusted scores), deduplication and
+    /// [`Search::k()`] are applied, so a higher `ef_search` gives the penalty more alternatives
+    /// to choose from.
+    pub fn search_contrastive<'a, 'b: 'a>(
+        &'b self,
+        point: &P,
+        avoid: &[P],
+        weight: f32,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Item<'b, P>> + 'a {
+        self.search_beam(point, search, |_| true);
+        if !avoid.is_empty() && weight != 0.0 {
+            for candidate in &mut search.nearest {
+                let other = &self.points[candidate.pid.0 as usize];
+                let similarity = avoid
+                    .iter()
+                    .map(|avoid| 1.0 / (1.0 + avoid.distance(other)))
+                    .fold(0.0, f32::max);
+                candidate.distance = OrderedFloat(candidate.distance.0 + weight * similarity);
+            }
+            search.nearest.sort_unstable();
+        }
+
+        search.finish(&self.points);
+        search
+            .iter()
+            .map(move |candidate| Item::new(candidate, self))
+    }
+
+    /// Search for points near any of `points`, merging the results into a single ranking
+    ///
+    /// This is useful for queries represented by several embeddings. Each query point is
+    /// searched separately; the union of the candidates found for all of them is then ranked as
+    /// specified by `fusion`. For `Fusion::Min` and `Fusion::Mean`, the distances from each
+    /// candidate to all query points are computed, including query points that didn't find it.
+    /// Exclusions, the distance threshold, deduplication and `k` are applied to the fused
+    /// ranking, which is cut to the beam width first, so at most `ef_search` results are
+    /// returned unless `k` is larger.
+    pub fn search_multi<'a, 'b: 'a>(
+        &'b self,
+        points: &[P],
+        fusion: Fusion,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Item<'b, P>> + 'a {
+        let mut fused = HashMap::<PointId, f32>::new();
+        for point in points {
+            self.search_beam(point, search, |_| true);
+            let (excluded, max_distance) = (&search.excluded, search.max_distance);
+            let found = search.nearest.iter().filter(|candidate| {
+                !excluded.contains(&candidate.pid)
+                    && max_distance.map_or(true, |max| candidate.distance.0 <= max)
+            });
+            for (rank, candidate) in found.enumerate() {
+                let score = fused.entry(candidate.pid).or_insert(0.0);
+                if let Fusion::ReciprocalRank { k } = fusion {
+                    *score -= 1.0 / (k + rank as f32 + 1.0);
+                }
+            }
+        }
+
+        search.nearest.clear();
+        search.returned.clear();
+        for (pid, score) in fused {
+            let other = &self.points[pid.0 as usize];
+            let distances = points.iter().map(|point| point.distance(other));
+            let distance = match fusion {
+                Fusion::Min => distances.fold(f32::INFINITY, f32::min),
+                Fusion::Mean => distances.sum::<f32>() / points.len() as f32,
+                Fusion::ReciprocalRank { .. } => score,
+            };
+            search.nearest.push(Candidate {
+                distance: OrderedFloat(distance),
+                pid,
+            });
+        }
+
+        search.nearest.sort_unstable();
+        search.nearest.truncate(search.beam(self.config.ef_search));
+        let score = match fusion {
+            Fusion::ReciprocalRank { .. } => {
+                // The candidates were already limited to the threshold per query point
+                let max_distance = search.max_distance.take();
+                search.finish(&self.points);
+                search.max_distance = max_distance;
+                Score::Distance
+            }
+            _ => {
+                search.finish(&self.points);
+                search.score
+            }
+        };
+
+        search
+            .iter_scored(score)
+            .map(move |candidate| Item::new(candidate, self))
+    }
+
+    /// Search the index for the points nearest to each of `queries`
+    ///
+    /// This returns the same kind of results as calling [`Hnsw::search()`] for each query, but
+    /// avoids duplicate work for correlated query streams, where consecutive queries are often
+    /// identical or close together:
+    ///
+    /// * A query identical to the previous one (at distance zero) reuses its results.
+    /// * A query closer to the previous query than the furthest result of that query skips the
+    ///   upper layers, and searches the zero layer starting from the previous query's results.
+    ///
+    /// Queries in the batch are searched in order on the current thread. The results of all
+    /// queries are stored back to back in `search`, whose buffers are reused across batches, so
+    /// searching a batch doesn't allocate once `search` has been used for batches of similar size.
+    pub fn search_batch<'a, 'b: 'a>(
+        &'b self,
+        queries: &[P],
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = impl ExactSizeIterator<Item = Item<'b, P>> + 'a> + 'a {
+        let mut batch = std::mem::take(&mut search.batch);
+        batch.clear();
+        search.offsets.clear();
+        search.offsets.push(0);
+        for (i, query) in queries.iter().enumerate() {
+            let previous = (i > 0).then(|| {
+                let found = search.offsets[i - 1]..search.offsets[i];
+                (queries[i - 1].distance(query), found)
+            });
+
+            // A float literal pattern would warn on older compilers
+            #[allow(clippy::redundant_guards)]
+            match previous {
+                Some((distance, found)) if distance == 0.0 => batch.extend_from_within(found),
+                Some((distance, found))
+                    if !self.is_flat()
+                        && batch[found.clone()]
+                            .last()
+                            .map_or(false, |c| distance < c.distance.0) =>
+                {
+                    self.search_near(query, search, &batch[found]);
+                    batch.extend_from_slice(&search.nearest);
+                }
+                _ => {
+                    self.search_nearest(query, search, |_| true);
+                    batch.extend_from_slice(&search.nearest);
+                }
+            }
+            search.offsets.push(batch.len());
+        }
+        search.batch = batch;
+
+        let (score, batch) = (search.score, &search.batch);
+        search.offsets.windows(2).map(move |range| {
+            batch[range[0]..range[1]].iter().map(move |candidate| {
+                let candidate = Candidate {
+                    distance: OrderedFloat(score.apply(candidate.distance.0)),
+                    pid: candidate.pid,
+                };
+                Item::new(candidate, self)
+            })
+        })
+    }
+
+    /// Fill `search.nearest` with the results of a zero layer search starting from `entries`
+    fn search_near(&self, point: &P, search: &mut Search, entries: &[Candidate]) {
+        search.reset();
+        search.visited.reserve_capacity(self.points.len());
+        if let Some(trace) = &mut search.trace {
+            trace.start_layer(0);
+        }
+
+        for entry in entries {
+            search.push(entry.pid, point, &self.points);
+        }
+
+        search.ef = search.beam(self.config.ef_search);
+        search.bound = search.max_distance.map(OrderedFloat);
+        search.search(point, self.zero.as_slice(), &self.points, M * 2);
+        search.finish(&self.points);
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("instant_distance_queries_total").increment(1);
+            metrics::counter!("instant_distance_distance_evaluations_total")
+                .increment(search.visited_count as u64);
+        }
+    }
+
+    /// Fill `search.nearest` with the results of a search for `point` that pass `filter`
+    fn search_nearest(&self, point: &P, search: &mut Search, filter: impl Fn(PointId) -> bool) {
+        self.search_beam(point, search, filter);
+        search.finish(&self.points);
+    }
+
+    /// Fill `search.nearest` with the beam of a search for `point`, before `Search::finish()`
+    ///
+    /// Only points that pass `filter` are kept, but exclusions, the distance threshold,
+    /// deduplication and `k` are left to the caller.
+    fn search_beam(&self, point: &P, search: &mut Search, filter: impl Fn(PointId) -> bool) {
+        search.reset();
+        if self.points.is_empty() {
+            return;
+        }
+
+        #[cfg(feature = "metrics")]
+        let start = std::time::Instant::now();
+
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!(
+            "search",
+            ef = self.config.ef_search,
+            visited = tracing::field::Empty
+        )
+        .entered();
+
+        search.visited.reserve_capacity(self.points.len());
+        self.search_from(point, search, PointId(0), filter);
+
+        #[cfg(feature = "tracing")]
+        span.record("visited", search.visited_count);
+
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("instant_distance_queries_total").increment(1);
+            metrics::counter!("instant_distance_distance_evaluations_total")
+                .increment(search.visited_count as u64);
+            metrics::histogram!("instant_distance_search_duration_seconds")
+                .record(start.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Search the layers from the top layer of `entry` down, starting from `entry`
+    ///
+    /// Only points for which `filter` returns `true` are added to the results; see
+    /// [`Search::traverse()`] for how it is applied.
+    pub(crate) fn search_from(
+        &self,
+        point: &P,
+        search: &mut Search,
+        entry: PointId,
+        filter: impl Fn(PointId) -> bool,
+    ) {
+        if self.is_flat() {
+            let ef = search.beam(self.config.ef_search);
+            search.scan(point, &self.points, ef, filter);
+            return;
+        }
+
+        search.traverse(
+            point,
+            &self.points,
+            self.config.ef_search,
+            (entry, self.top_layer(entry)),
+            self.zero.as_slice(),
+            |l| self.layers[l - 1].as_slice(),
+            filter,
+        );
+    }
+
+    /// The highest layer that contains `pid`
+    ///
+    /// Points are sorted by layer, so each upper layer contains a prefix of the points.
+    pub(crate) fn top_layer(&self, pid: PointId) -> usize {
+        self.layers
+            .iter()
+            .take_while(|layer| (pid.0 as usize) < layer.len())
+            .count()
+    }
+
+    /// Continue a search to find `additional` more results
+    ///
+    /// `search` must hold the state of a previous call to `search()` (or `search_more()`) for the
+    /// same `point`. The zero layer is searched again with a beam that is `additional` entries
+    /// wider, starting from the results found so far. Only results that weren't returned before
+    /// are returned, nearest first; there may be fewer than `additional` of them.
+    pub fn search_more<'a, 'b: 'a>(
+        &'b self,
+        point: &P,
+        search: &'a mut Search,
+        additional: usize,
+    ) -> impl ExactSizeIterator<Item = Item<'b, P>> + 'a {
+        let map = move |candidate| Item::new(candidate, self);
+        if self.points.is_empty() {
+            search.reset();
+            return search.iter().map(map);
+        }
+
+        let returned = std::mem::take(&mut search.returned);
+        search.deadline = search.timeout.map(|timeout| Instant::now() + timeout);
+        search.truncated = false;
+        search.visited.clear();
+        search.candidates.clear();
+        search.nearest.clear();
+        search.pending.clear();
+        if let Some(trace) = &mut search.trace {
+            trace.clear();
+            trace.start_layer(0);
+        }
+
+        search.ef = search.ef.max(1) + additional;
+        search.bound = search.max_distance.map(OrderedFloat);
+        if self.is_flat() {
+            search.scan(point, &self.points, search.ef, |_| true);
+        } else {
+            match returned.is_empty() {
+                true => search.push(PointId(0), point, &self.points),
+                false => {
+                    for &pid in &returned {
+                        search.push(pid, point, &self.points);
+                    }
+                }
+            }
+
+            search.search(point, self.zero.as_slice(), &self.points, M * 2);
+        }
+        search.nearest.retain(|c| !returned.contains(&c.pid));
+        search.returned = returned;
+        search.finish(&self.points);
+        search.iter().map(map)
+    }
+
+    /// Iterate over the keys and values in this index
+    pub fn iter(&self) -> impl Iterator<Item = (PointId, &P)> {
+        self.points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (PointId(i as u32), p))
+    }
+
+    /// Iterate over the points on `layer`, where `0` is the bottom layer
+    ///
+    /// Every point is on the bottom layer. Points are sorted by layer, so the points on each
+    /// upper layer are a prefix of the points on the layer below it, and the first point is the
+    /// entry point for searches. Yields nothing for layers above the top layer.
+    pub fn iter_layer(&self, layer: usize) -> impl ExactSizeIterator<Item = (PointId, &P)> {
+        let len = match layer {
+            0 => self.points.len(),
+            l => self.layers.get(l - 1).map_or(0, Vec::len),
+        };
+
+        self.points[..len]
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (PointId(i as u32), p))
+    }
+
+    /// The layers of the graph, from the bottom layer up
+    ///
+    /// Yields nothing for flat indexes (see [`Hnsw::is_flat()`]), which have no graph.
+    pub fn layers(&self) -> impl ExactSizeIterator<Item = LayerView<'_>> {
+        let start = usize::from(self.zero.is_empty());
+        (start..self.layers.len() + 1).map(move |layer| match layer {
+            0 => LayerView::zero(&self.zero),
+            l => LayerView::upper(l, &self.layers[l - 1]),
+        })
+    }
+
+    /// The parameters this index was built with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The index of `pid` in the slice of points this index was built from
+    ///
+    /// Returns `None` if `pid` is out of range, or if the index doesn't know the input order
+    /// (see [`Item::index`]).
+    pub fn input_index(&self, pid: PointId) -> Option<usize> {
+        let inputs = self.inputs.as_ref()?;
+        inputs.get(pid.0 as usize).map(|&idx| idx as usize)
+    }
+
+    /// The number of points in this index
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Whether this index was built without a graph
+    ///
+    /// See [`Builder::flat_threshold()`]. Searches of flat indexes compare the query against
+    /// every point, so they always find the exact nearest neighbors.
+    pub fn is_flat(&self) -> bool {
+        self.zero.is_empty() && !self.points.is_empty()
+    }
+
+    /// The dimensionality of the points in this index
+    ///
+    /// This is derived from [`Point::dims()`] for the indexed points; it returns `None` if the
+    /// index is empty or if the point type does not report a dimensionality.
+    pub fn dims(&self) -> Option<usize> {
+        self.points.first().and_then(Point::dims)
+    }
+
+    #[doc(hidden)]
+    pub fn get(&self, i: usize, search: &Search) -> Option<Item<'_, P>> {
+        Some(Item::new(search.iter().nth(i)?, self))
+    }
+}
+
+impl<P: PointDataSource> Hnsw<P> {
+    /// Recreate the point `pid` as a `T`, from the components of the indexed point
+    ///
+    /// This converts between point types that store the same components, for example to get a
+    /// `Vec<f32>` or a caller-defined point type out of an index of [`Vector`](points::Vector)s.
+    /// Returns `None` if `T` can't be created from the components (see
+    /// [`PointDataSource::compose()`]).
+    pub fn reconstruct<T: PointDataSource>(&self, pid: PointId) -> Option<T> {
+        T::compose(self[pid].data())
+    }
+}
+
+pub struct Item<'a, P> {
+    pub distance: f32,
+    pub pid: PointId,
+    /// The index of the point in the slice the index was built from
+    ///
+    /// This is the inverse of the `Vec<PointId>` returned by [`Builder::build_hnsw()`]. It is
+    /// `None` for indexes loaded from files that predate format version 3, or converted from
+    /// the upstream crate, which didn't record the input order.
+    pub index: Option<usize>,
+    pub point: &'a P,
+}
+
+impl<'a, P> Item<'a, P> {
+    fn new(candidate: Candidate, hnsw: &'a Hnsw<P>) -> Self {
+        Self {
+            distance: candidate.distance.into_inner(),
+            pid: candidate.pid,
+            index: (hnsw.inputs.as_ref()).map(|inputs| inputs[candidate.pid.0 as usize] as usize),
+            point: &hnsw[candidate.pid],
+        }
+    }
+}
+
+/// An `Hnsw` that is still under construction
+///
+/// The points have already been assigned to layers and sorted in insertion order; `ranges`
+/// holds the points that remain to be inserted on each layer, from the top down.
+pub(crate) struct Partial<P> {
+    pub(crate) config: Config,
+    pub(crate) points: Vec<P>,
+    pub(crate) zero: Vec<ZeroNode>,
+    pub(crate) layers: Vec<Vec<UpperNode>>,
+    pub(crate) ranges: Vec<(usize, Range<usize>)>,
+    /// The index of each point in the input slice, by `PointId`
+    pub(crate) inputs: Vec<u32>,
+}
+
+impl<P: Point> Partial<P> {
+    /// Assign layers to the `points` and determine their insertion order
+    ///
+    /// Also returns the `PointId` assigned to each of the input points.
+    pub(crate) fn new(points: Vec<P>, builder: &Builder) -> Result<(Self, Vec<PointId>), Error> {
+        let config = Config {
+            m: M,
+            upper_m: builder.upper_m.clone(),
+            ef_search: builder.ef_search,
+            ef_construction: builder.ef_construction,
+            ml: builder.ml,
+            seed: builder.seed,
+            heuristic: builder.heuristic,
+            metric: std::any::type_name::<P>().to_owned(),
+            dims: points.first().and_then(Point::dims),
+        };
+
+        #[cfg(feature = "indicatif")]
+        if let Some(bar) = &builder.progress {
+            bar.set_message("Build index (preparation)");
+        }
+
+        if points.is_empty() {
+            let partial = Self {
+                config,
+                points: Vec::new(),
+                zero: Vec::new(),
+                layers: Vec::new(),
+                ranges: Vec::new(),
+                inputs: Vec::new(),
+            };
+            return Ok((partial, Vec::new()));
+        }
 
-    /// Search the index for the points nearest to the reference point `point`
-    ///
-    /// The results are returned in the `out` parameter; the number of neighbors to search for
-    /// is limited by the size of the `out` parameter, and the number of results found is returned
-    /// in the return value.
-    pub fn search<'a, 'b: 'a>(
-        &'b self,
-        point: &P,
-        search: &'a mut Search,
-    ) -> impl Iterator<Item = Item<'b, P>> + ExactSizeIterator + 'a {
-        search.reset();
-        let map = move |candidate| Item::new(candidate, self);
-        if self.points.is_empty() {
-            return search.iter().map(map);
+        // Give all points a layer and sort the list of nodes by descending layer for
+        // construction. This allows us to copy higher layers to lower layers as construction
+        // progresses, while preserving randomness in each point's insertion order.
+
+        if points.len() >= u32::MAX as usize {
+            return Err(Error::TooManyPoints(points.len()));
         }
 
-        search.visited.reserve_capacity(self.points.len());
-        search.push(PointId(0), point, &self.points);
-        for cur in LayerId(self.layers.len()).descend() {
-            let (ef, num) = match cur.is_zero() {
-                true => (self.ef_search, M * 2),
-                false => (1, M),
+        if points.len() < builder.flat_threshold {
+            let out = (0..points.len() as u32).map(PointId).collect();
+            let partial = Self {
+                config,
+                inputs: (0..points.len() as u32).collect(),
+                points,
+                zero: Vec::new(),
+                layers: Vec::new(),
+                ranges: Vec::new(),
             };
+            return Ok((partial, out));
+        }
 
-            search.ef = ef;
-            match cur.0 {
-                0 => search.search(point, self.zero.as_slice(), &self.points, num),
-                l => search.search(point, self.layers[l - 1].as_slice(), &self.points, num),
+        let (mut seeded, mut custom);
+        let rng: &mut dyn RngCore = match &builder.rng {
+            Some(rng) => {
+                custom = rng.lock();
+                &mut *custom
             }
-
-            if !cur.is_zero() {
-                search.cull();
+            None => {
+                seeded = SmallRng::seed_from_u64(builder.seed);
+                &mut seeded
             }
+        };
+
+        #[cfg(feature = "replay")]
+        let mut session = None;
+        #[cfg(feature = "replay")]
+        let rng: &mut dyn RngCore = match &builder.replay {
+            Some(replay) => session.insert(replay.rng(rng)?),
+            None => rng,
+        };
+
+        let assigned = builder.layers.assign(points.len(), builder.ml, rng);
+        if assigned.len() != points.len() {
+            return Err(Error::LayerMismatch {
+                points: points.len(),
+                layers: assigned.len(),
+            });
         }
 
-        search.iter().map(map)
-    }
+        if assigned.iter().any(|&layer| layer > MAX_LAYER) {
+            return Err(Error::InvalidParameter {
+                name: "layer_assignment",
+                reason: "layers must be at most 64",
+            });
+        }
 
-    /// Iterate over the keys and values in this index
-    pub fn iter(&self) -> impl Iterator<Item = (PointId, &P)> {
-        self.points
+        let mut shuffled = assigned
             .iter()
             .enumerate()
-            .map(|(i, p)| (PointId(i as u32), p))
-    }
+            .map(|(i, &layer)| (Reverse(layer), rng.gen_range(0..points.len() as u32), i))
+            .collect::<Vec<_>>();
+        shuffled.sort_unstable();
 
-    #[doc(hidden)]
-    pub fn get(&self, i: usize, search: &Search) -> Option<Item<'_, P>> {
-        Some(Item::new(search.nearest.get(i).copied()?, self))
+        let mut out = vec![INVALID; points.len()];
+        let mut inputs = Vec::with_capacity(points.len());
+        let mut sorted = Vec::with_capacity(points.len());
+        let mut zero = Vec::with_capacity(points.len());
+        #[cfg(feature = "huge-pages")]
+        if builder.huge_pages {
+            pages::advise(sorted.as_ptr(), sorted.capacity());
+            pages::advise(zero.as_ptr(), zero.capacity());
+        }
+
+        // `Point` doesn't require `Send`, so the points can't be cloned on the worker threads.
+        // Touch the pages of their buffer from the workers instead, for the reason explained
+        // below; points that keep their components on the heap (like `Vector`) still allocate
+        // them on this thread.
+        first_touch(&mut sorted);
+
+        sorted.extend(shuffled.iter().enumerate().map(|(i, &(_, _, idx))| {
+            out[idx] = PointId(i as u32);
+            inputs.push(idx as u32);
+            points[idx].clone()
+        }));
+        let points = sorted;
+
+        #[cfg(feature = "replay")]
+        if let (Some(replay), Some(session)) = (&builder.replay, session) {
+            replay.planned(session, &assigned, &inputs)?;
+        }
+
+        // Initialize the zero layer from the worker threads, rather than on this thread.
+        // Operating systems generally place memory on the NUMA node of the thread that first
+        // writes to it, so this spreads the neighbor lists over the nodes the workers run on,
+        // instead of concentrating them on one node and making most of the accesses during
+        // construction cross-socket.
+        (0..points.len())
+            .into_par_iter()
+            .map(|_| ZeroNode::default())
+            .collect_into_vec(&mut zero);
+
+        // Figure out how many nodes will go on each layer. This helps us allocate memory capacity
+        // for each layer in advance, and also helps enable batch insertion of points.
+
+        let top = LayerId(shuffled[0].0 .0);
+        let mut ranges = Vec::with_capacity(top.0 + 1);
+        let mut cumulative = 0;
+        for layer in top.descend() {
+            let start = cumulative;
+            cumulative += shuffled[cumulative..]
+                .iter()
+                .take_while(|(Reverse(l), _, _)| *l == layer.0)
+                .count();
+            // Skip the first point, since we insert the enter point separately
+            ranges.push((layer.0, max(start, 1)..cumulative));
+        }
+
+        let partial = Self {
+            config,
+            zero,
+            points,
+            layers: vec![vec![]; top.0],
+            ranges,
+            inputs,
+        };
+        Ok((partial, out))
     }
 }
 
-pub struct Item<'a, P> {
-    pub distance: f32,
-    pub pid: PointId,
-    pub point: &'a P,
+/// Write to every page of the spare capacity of `buf` from the worker threads
+fn first_touch<T>(buf: &mut Vec<T>) {
+    let spare = buf.spare_capacity_mut();
+    let len = std::mem::size_of_val(spare);
+    // Safety: the bytes lie within the allocation of `buf`, and any value is valid for
+    // `MaybeUninit<u8>`; the capacity stays uninitialized as far as `T` is concerned
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(spare.as_mut_ptr().cast::<MaybeUninit<u8>>(), len)
+    };
+    bytes
+        .par_chunks_mut(PAGE_SIZE)
+        .for_each(|page| page[0] = MaybeUninit::new(0));
 }
 
-impl<'a, P> Item<'a, P> {
-    fn new(candidate: Candidate, hnsw: &'a Hnsw<P>) -> Self {
+/// The smallest page size of the platforms the crate runs on
+const PAGE_SIZE: usize = 4096;
+
+/// Callback that persists a construction `Snapshot`
+pub(crate) type Checkpoint<'a, P> = &'a mut dyn FnMut(Snapshot<'_, P>) -> Result<(), Error>;
+
+/// A consistent view of the construction state, taken in between batches of insertions
+#[cfg_attr(not(feature = "with-serde"), allow(dead_code))]
+pub(crate) struct Snapshot<'a, P> {
+    pub(crate) config: &'a Config,
+    pub(crate) points: &'a [P],
+    pub(crate) zero: Vec<ZeroNode>,
+    pub(crate) layers: &'a [Vec<UpperNode>],
+    pub(crate) ranges: Vec<(usize, Range<usize>)>,
+}
+
+impl<'a, P> Snapshot<'a, P> {
+    fn new(
+        config: &'a Config,
+        points: &'a [P],
+        zero: &[AtomicZeroNode],
+        layers: &'a [Vec<UpperNode>],
+        ranges: Vec<(usize, Range<usize>)>,
+    ) -> Self {
         Self {
-            distance: candidate.distance.into_inner(),
-            pid: candidate.pid,
-            point: &hnsw[candidate.pid],
+            config,
+            points,
+            zero: zero.iter().map(AtomicZeroNode::load).collect(),
+            layers,
+            ranges,
         }
     }
 }
 
 struct Construction<'a, P: Point> {
-    zero: &'a [RwLock<ZeroNode>],
+    zero: &'a [AtomicZeroNode],
     pool: SearchPool,
     top: LayerId,
     points: &'a [P],
     heuristic: Option<Heuristic>,
     ef_construction: usize,
+    /// Maximum number of neighbors kept by each selection
+    retain: usize,
+    /// Lower limits for the upper layers, from `Builder::upper_m()`
+    upper_m: &'a [usize],
+    extension: Extension,
     #[cfg(feature = "indicatif")]
     progress: Option<ProgressBar>,
     #[cfg(feature = "indicatif")]
     done: AtomicUsize,
+    inserted: Option<Arc<AtomicUsize>>,
+    /// Number of candidates rejected by the selection heuristic
+    pruned: AtomicUsize,
 }
 
 impl<'a, P: Point> Construction<'a, P> {
+    /// The limit set with `Builder::upper_m()` for `layer`, if any
+    fn upper_m(&self, layer: LayerId) -> Option<usize> {
+        self.upper_m.get(layer.0.checked_sub(1)?).copied()
+    }
+
+    /// Maximum number of neighbors kept by each selection on `layer`
+    pub(crate) fn retain_on(&self, layer: LayerId) -> usize {
+        match self.upper_m(layer) {
+            Some(m) => self.retain.min(m),
+            None => self.retain,
+        }
+    }
+
     /// Insert new node in the zero layer
     ///
     /// * `new` is the `PointId` for the new node
@@ -435,9 +1929,16 @@ impl<'a, P: Point> Construction<'a, P> {
     /// Creates the new node, initializing its `nearest` array and updates the nearest neighbors
     /// for the new node's neighbors if necessary before appending the new node to the layer.
     fn insert(&self, new: PointId, layer: LayerId, layers: &[Vec<UpperNode>]) {
-        let mut node = self.zero[new].write();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::trace_span!("insert", pid = new.0, layer = layer.0).entered();
+
         let (mut search, mut insertion) = self.pool.pop();
+        let retain = self.retain_on(layer);
         insertion.ef = self.ef_construction;
+        search.retain = retain;
+        insertion.retain = retain;
+        search.extension = self.extension;
+        insertion.extension = self.extension;
 
         let point = &self.points[new];
         search.reset();
@@ -465,9 +1966,11 @@ impl<'a, P: Point> Construction<'a, P> {
         let found = match self.heuristic {
             None => {
                 let candidates = search.select_simple();
-                &candidates[..Ord::min(candidates.len(), M * 2)]
+                &candidates[..Ord::min(candidates.len(), retain)]
             }
             Some(heuristic) => {
+                // The new node may be found when extending the candidates
+                search.visited.insert(new);
                 search.select_heuristic(&self.points[new], self.zero, self.points, heuristic)
             }
         };
@@ -478,42 +1981,67 @@ impl<'a, P: Point> Construction<'a, P> {
             found.iter().map(|c| c.pid).collect::<HashSet<_>>().len()
         );
 
-        for (i, candidate) in found.iter().enumerate() {
+        // Link the new node to its neighbors before linking them back to it, so that searches
+        // reaching the new node through one of them can continue from there
+        self.zero[new].update(|node| {
+            for (i, candidate) in found.iter().enumerate() {
+                node.set(i, candidate.pid);
+            }
+        });
+
+        for candidate in found {
             // `candidate` here is the new node's neighbor
             let &Candidate { distance, pid } = candidate;
             if let Some(heuristic) = self.heuristic {
-                let found = insertion.add_neighbor_heuristic(
-                    new,
-                    self.zero.nearest_iter(pid),
-                    self.zero,
-                    &self.points[pid],
-                    self.points,
-                    heuristic,
-                );
-
-                self.zero[pid]
-                    .write()
-                    .rewrite(found.iter().map(|candidate| candidate.pid));
+                // The selection looks at other nodes, so it can't run while holding this one;
+                // retry it if another thread updated the node in the meantime
+                let (node, pruned) = (&self.zero[pid], insertion.pruned);
+                loop {
+                    // Only count the candidates pruned by the selection that was stored
+                    insertion.pruned = pruned;
+                    let (current, version) = node.load_versioned();
+                    let found = insertion.add_neighbor_heuristic(
+                        new,
+                        pid,
+                        current.iter().copied().take_while(|pid| pid.is_valid()),
+                        self.zero,
+                        self.points,
+                        heuristic,
+                    );
+
+                    let mut updated = ZeroNode::default();
+                    updated.rewrite(found.iter().map(|candidate| candidate.pid));
+                    if node.replace(version, &updated) {
+                        break;
+                    }
+                }
             } else {
                 // Find the correct index to insert at to keep the neighbor's neighbors sorted
                 let old = &self.points[pid];
-                let idx = self.zero[pid]
-                    .read()
-                    .binary_search_by(|third| {
-                        // `third` here is one of the neighbors of the new node's neighbor.
-                        let third = match third {
-                            pid if pid.is_valid() => *pid,
-                            // if `third` is `None`, our new `node` is always "closer"
-                            _ => return Ordering::Greater,
-                        };
-
-                        distance.cmp(&old.distance(&self.points[third]).into())
-                    })
-                    .unwrap_or_else(|e| e);
-
-                self.zero[pid].write().insert(idx, new);
+                self.zero[pid].update(|node| {
+                    let idx = node
+                        .binary_search_by(|third| {
+                            // `third` here is one of the neighbors of the new node's neighbor.
+                            let third = match third {
+                                pid if pid.is_valid() => *pid,
+                                // if `third` is `None`, our new `node` is always "closer"
+                                _ => return Ordering::Greater,
+                            };
+
+                            OrderedFloat::from(old.distance(&self.points[third])).cmp(&distance)
+                        })
+                        .unwrap_or_else(|e| e);
+
+                    node.insert(idx, new, retain);
+                });
             }
-            node.set(i, pid);
+        }
+
+        #[cfg(feature = "metrics")]
+        metrics::counter!("instant_distance_inserts_total").increment(1);
+
+        if let Some(inserted) = &self.inserted {
+            inserted.fetch_add(1, atomic::Ordering::Relaxed);
         }
 
         #[cfg(feature = "indicatif")]
@@ -524,6 +2052,13 @@ impl<'a, P: Point> Construction<'a, P> {
             }
         }
 
+        let pruned = search.pruned + insertion.pruned;
+        if pruned > 0 {
+            self.pruned.fetch_add(pruned, atomic::Ordering::Relaxed);
+            search.pruned = 0;
+            insertion.pruned = 0;
+        }
+
         self.pool.push((search, insertion));
     }
 }
@@ -561,16 +2096,56 @@ pub struct Search {
     /// Nodes visited so far (`v` in the paper)
     visited: Visited,
     /// Candidates for further inspection (`C` in the paper)
-    candidates: BinaryHeap<Reverse<Candidate>>,
+    candidates: CandidateQueue,
     /// Nearest neighbors found so far (`W` in the paper)
     ///
     /// This must always be in sorted (nearest first) order.
     nearest: Vec<Candidate>,
+    /// Results found since the last `merge()`, in no particular order
+    pending: Vec<Candidate>,
     /// Working set for heuristic selection
     working: Vec<Candidate>,
     discarded: Vec<Candidate>,
     /// Maximum number of nearest neighbors to retain (`ef` in the paper)
     ef: usize,
+    /// Number of nodes visited (and distances computed) since the last reset
+    visited_count: usize,
+    /// Number of candidates rejected by `select_heuristic()`, for `BuildReport`
+    pruned: usize,
+    /// Maximum number of neighbors kept by `select_heuristic()`
+    retain: usize,
+    /// Limits on the candidates added by `select_heuristic()`
+    extension: Extension,
+    /// Record of visited nodes, if tracing is enabled
+    trace: Option<Trace>,
+    /// Points that are traversed but never returned as results
+    excluded: Vec<PointId>,
+    /// Results further away than this are discarded
+    max_distance: Option<f32>,
+    /// Results closer than this to a nearer result are discarded
+    dedup: Option<f32>,
+    /// Points returned by the last search and subsequent calls to `search_more()`
+    returned: HashSet<PointId>,
+    /// Candidates further away than this are not expanded (set only while searching layer zero)
+    bound: Option<OrderedFloat<f32>>,
+    /// Number of entry points for the zero layer
+    probes: usize,
+    /// Maximum number of results to return, independent of `ef`
+    k: Option<usize>,
+    /// Beam width for the zero layer, overriding the `ef_search` of the index
+    ef_search: Option<usize>,
+    /// How distances are reported in the results
+    score: Score,
+    /// Maximum duration of a search
+    timeout: Option<Duration>,
+    /// When the current search has to stop, derived from `timeout`
+    deadline: Option<Instant>,
+    /// Whether the last search was stopped at the deadline
+    truncated: bool,
+    /// Results of all queries in the last `search_batch()`, back to back
+    batch: Vec<Candidate>,
+    /// Start of the results of each query in `batch`, followed by the end of the last one
+    offsets: Vec<usize>,
 }
 
 impl Search {
@@ -581,6 +2156,189 @@ impl Search {
         }
     }
 
+    /// Enable or disable recording a [`Trace`] of the nodes visited by each search
+    ///
+    /// Tracing slows down searches, so it should only be enabled for debugging.
+    pub fn record_trace(&mut self, enabled: bool) {
+        self.trace = match enabled {
+            true => Some(self.trace.take().unwrap_or_default()),
+            false => None,
+        };
+    }
+
+    /// The trace of the last search, if tracing is enabled
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    /// Leave the point `pid` out of the results of subsequent searches
+    ///
+    /// This is useful to find the neighbors of a point that is itself part of the index, which
+    /// would otherwise always come first. Excluded points are still used to traverse the graph,
+    /// so excluding points does not affect the quality of the other results, but searches return
+    /// fewer than `ef_search` results if excluded points would have been among them.
+    ///
+    /// Exclusions apply until they are removed with [`Search::clear_excluded()`].
+    pub fn exclude(&mut self, pid: PointId) {
+        if !self.excluded.contains(&pid) {
+            self.excluded.push(pid);
+        }
+    }
+
+    /// Remove all exclusions added with [`Search::exclude()`]
+    pub fn clear_excluded(&mut self) {
+        self.excluded.clear();
+    }
+
+    /// Only return results within `distance` of the query point (or all results for `None`)
+    ///
+    /// Besides limiting the results, this makes searches stop expanding the graph beyond the
+    /// threshold once a point within it has been found on the zero layer, so searches with a
+    /// tight threshold finish sooner. Points within the threshold that are only reachable
+    /// through points outside of it may be missed.
+    pub fn max_distance(&mut self, distance: Option<f32>) {
+        self.max_distance = distance;
+    }
+
+    /// Report the `distance` of results as `score` instead of as the raw distance
+    ///
+    /// Results are always ordered nearest first, so with a similarity score, the most similar
+    /// results come first. This lets code that ranks results work with similarities without
+    /// knowing which metric the index uses.
+    pub fn score(&mut self, score: Score) {
+        self.score = score;
+    }
+
+    /// Collapse results that are within `epsilon` of a nearer result (or keep all for `None`)
+    ///
+    /// Of each group of near-duplicate points, only the one nearest to the query is returned,
+    /// so duplicates in the indexed data don't crowd out other results. This compares each
+    /// result to the results kept before it, which takes `O(n²)` distance calculations for
+    /// `n` results.
+    pub fn dedup(&mut self, epsilon: Option<f32>) {
+        self.dedup = epsilon;
+    }
+
+    /// Stop searches that take longer than `timeout` (or never stop them for `None`)
+    ///
+    /// Once the deadline has passed, the search stops expanding the graph and returns the
+    /// results found so far, which may be far from the nearest neighbors if it stops on an upper
+    /// layer. Use [`Search::truncated()`] to check whether a search was stopped early. The
+    /// deadline is checked before each node is expanded, so a search can overrun it by the time
+    /// it takes to compare the query against the neighbors of one node.
+    pub fn timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Whether the last search was stopped because it exceeded the timeout
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    /// Start the search of the zero layer from up to `probes` entry points
+    ///
+    /// By default, the upper layers are searched with a beam width of 1, so the search of the
+    /// zero layer starts from a single point. With more probes, the last upper layer is searched
+    /// more widely and the search of the zero layer starts from several entry points, preferring
+    /// points that are far apart from each other. On clustered data, this improves recall when
+    /// the query is near several clusters, at a lower cost than increasing `ef_search`.
+    pub fn probes(&mut self, probes: usize) {
+        self.probes = probes.max(1);
+    }
+
+    /// Return at most `k` results from each search
+    ///
+    /// The number of results is independent of the beam width: the zero layer is still explored
+    /// with `ef_search` candidates, so asking for a single result is as accurate as taking the
+    /// first result of a full search. If `k` is larger than `ef_search`, the beam is widened to
+    /// `k`. With `None` (the default), searches return up to `ef_search` results.
+    pub fn k(&mut self, k: Option<usize>) {
+        self.k = k;
+    }
+
+    /// Search the zero layer with a beam of width `ef`, instead of the `ef_search` of the index
+    ///
+    /// This allows trading recall for speed per query, without rebuilding or reloading the
+    /// index; see [`eval::sweep_ef()`] to measure the effect. Searches return up to `ef` results,
+    /// unless `k` is set. With `None` (the default), the `ef_search` the index was built with is
+    /// used.
+    pub fn ef_search(&mut self, ef: Option<usize>) {
+        self.ef_search = ef.map(|ef| ef.max(1));
+    }
+
+    /// The beam width for the zero layer, widened to fit `k` results
+    fn beam(&self, ef_search: usize) -> usize {
+        self.ef_search.unwrap_or(ef_search).max(self.k.unwrap_or(0))
+    }
+
+    /// The maximum number of results to return, given the `ef_search` of the index
+    pub(crate) fn limit(&self, ef_search: usize) -> usize {
+        self.k
+            .unwrap_or_else(|| self.ef_search.unwrap_or(ef_search))
+    }
+
+    /// Search the layers from `top` down, starting from `entry` on layer `top`
+    ///
+    /// `upper` returns the upper layer with the given (1-based) number. The upper layers are
+    /// searched without `filter`, to find the entry points of the zero layer. On the zero layer,
+    /// only points for which `filter` returns `true` are added to the results; other points are
+    /// still traversed to reach them.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn traverse<P: Point, Z: Layer + Copy, U: Layer>(
+        &mut self,
+        point: &P,
+        points: &[P],
+        ef_search: usize,
+        (entry, top): (PointId, usize),
+        zero: Z,
+        upper: impl Fn(usize) -> U,
+        filter: impl Fn(PointId) -> bool,
+    ) {
+        if let Some(trace) = &mut self.trace {
+            trace.start_layer(top);
+        }
+        self.push_filtered(entry, point, points, top > 0 || filter(entry));
+        for cur in LayerId(top).descend() {
+            if let (Some(trace), false) = (&mut self.trace, cur.0 == top) {
+                trace.start_layer(cur.0);
+            }
+
+            let (ef, num) = match cur.0 {
+                0 => (self.beam(ef_search), M * 2),
+                // Gather extra candidates to choose diverse entry points from
+                1 if self.probes > 1 => (self.probes * 2, M),
+                _ => (1, M),
+            };
+
+            #[cfg(feature = "tracing")]
+            let _span = tracing::trace_span!("search_layer", layer = cur.0, ef).entered();
+
+            self.ef = ef;
+            self.bound = match cur.is_zero() {
+                true => self.max_distance.map(OrderedFloat),
+                false => None,
+            };
+            match cur.0 {
+                0 => self.search_filtered(point, zero, points, num, &filter),
+                l => self.search(point, upper(l), points, num),
+            }
+
+            if cur.0 == 1 && self.probes > 1 {
+                self.diversify(points);
+            }
+
+            if !cur.is_zero() {
+                self.cull();
+            }
+
+            // The entry points of the zero layer are still expanded, but only become results if
+            // they pass the filter
+            if cur.0 == 1 {
+                self.nearest.retain(|candidate| filter(candidate.pid));
+            }
+        }
+    }
+
     /// Search the given layer for nodes near the given `point`
     ///
     /// This contains the loops from the paper's algorithm 2. `point` represents `q`, the query
@@ -596,33 +2354,65 @@ impl Search {
     /// Invariants: `self.nearest` should be in sorted (nearest first) order, and should be
     /// truncated to `self.ef`.
     fn search<L: Layer, P: Point>(&mut self, point: &P, layer: L, points: &[P], links: usize) {
-        while let Some(Reverse(candidate)) = self.candidates.pop() {
+        self.search_filtered(point, layer, points, links, &|_| true)
+    }
+
+    /// Search the given layer, only adding nodes for which `filter` returns `true` to the results
+    ///
+    /// Nodes rejected by the filter are still expanded if they are nearer than the furthest
+    /// result, so that accepted nodes behind them can be found.
+    fn search_filtered<L: Layer, P: Point>(
+        &mut self,
+        point: &P,
+        layer: L,
+        points: &[P],
+        links: usize,
+        filter: &impl Fn(PointId) -> bool,
+    ) {
+        self.merge();
+        while let Some(candidate) = self.candidates.pop() {
+            if self.expired() {
+                break;
+            }
+
             if let Some(furthest) = self.nearest.last() {
                 if candidate.distance > furthest.distance {
                     break;
                 }
             }
 
+            // Once we've found a point within the bound, stop at the first candidate outside of
+            // it: candidates are popped in order of distance, so all remaining ones are outside.
+            if let (Some(bound), Some(nearest)) = (self.bound, self.nearest.first()) {
+                if nearest.distance <= bound && candidate.distance > bound {
+                    break;
+                }
+            }
+
             for pid in layer.nearest_iter(candidate.pid).take(links) {
-                self.push(pid, point, points);
+                self.push_filtered(pid, point, points, filter(pid));
             }
 
-            // If we don't truncate here, `furthest` will be further out than necessary, making
-            // us continue looping while we could have broken out.
-            self.nearest.truncate(self.ef);
+            // If we don't merge here, `furthest` will be further out than necessary, making us
+            // continue looping while we could have broken out.
+            self.merge();
         }
     }
 
+    /// Select the neighbors of `pid` from its `current` neighbors and the `new` node
     fn add_neighbor_heuristic<L: Layer, P: Point>(
         &mut self,
         new: PointId,
+        pid: PointId,
         current: impl Iterator<Item = PointId>,
         layer: L,
-        point: &P,
         points: &[P],
         params: Heuristic,
     ) -> &[Candidate] {
         self.reset();
+        // The node itself may be found when extending the candidates
+        self.visited.insert(pid);
+        let point = &points[pid];
         self.push(new, point, points);
         for pid in current {
             self.push(pid, point, points);
@@ -640,21 +2430,33 @@ impl Search {
         points: &[P],
         params: Heuristic,
     ) -> &[Candidate] {
+        self.merge();
         self.working.clear();
         // Get input candidates from `self.nearest` and store them in `self.working`.
         // `self.candidates` will represent `W` from the paper's algorithm 4 for now.
+        let furthest = match (self.extension.within_furthest, self.nearest.last()) {
+            (true, Some(furthest)) => furthest.distance,
+            _ => OrderedFloat(f32::INFINITY),
+        };
+        let mut remaining = self.extension.max_candidates.unwrap_or(usize::MAX);
         for &candidate in &self.nearest {
             self.working.push(candidate);
             if params.extend_candidates {
                 for hop in layer.nearest_iter(candidate.pid) {
+                    if remaining == 0 {
+                        break;
+                    }
+
                     if !self.visited.insert(hop) {
                         continue;
                     }
 
                     let other = &points[hop];
                     let distance = OrderedFloat::from(point.distance(other));
-                    let new = Candidate { distance, pid: hop };
-                    self.working.push(new);
+                    if distance < furthest {
+                        self.working.push(Candidate { distance, pid: hop });
+                        remaining -= 1;
+                    }
                 }
             }
         }
@@ -666,7 +2468,7 @@ impl Search {
         self.nearest.clear();
         self.discarded.clear();
         for candidate in self.working.drain(..) {
-            if self.nearest.len() >= M * 2 {
+            if self.nearest.len() >= self.retain {
                 break;
             }
 
@@ -674,8 +2476,8 @@ impl Search {
             // are to the query point, to facilitate bridging between clustered points.
             let candidate_point = &points[candidate.pid];
             let nearest = !self.nearest.iter().any(|result| {
-                let distance = OrderedFloat::from(candidate_point.distance(&points[result.pid]));
-                distance < candidate.distance
+                let distance = params.alpha * candidate_point.distance(&points[result.pid]);
+                OrderedFloat::from(distance) < candidate.distance
             });
 
             match nearest {
@@ -684,10 +2486,11 @@ impl Search {
             }
         }
 
+        self.pruned += self.discarded.len();
         if params.keep_pruned {
             // Add discarded connections from `working` (`Wd`) to `self.nearest` (`R`)
             for candidate in self.discarded.drain(..) {
-                if self.nearest.len() >= M * 2 {
+                if self.nearest.len() >= self.retain {
                     break;
                 }
                 self.nearest.push(candidate);
@@ -702,21 +2505,94 @@ impl Search {
     /// Will immediately return if the node has been considered before. This implements
     /// the inner loop from the paper's algorithm 2.
     fn push<P: Point>(&mut self, pid: PointId, point: &P, points: &[P]) {
+        self.push_filtered(pid, point, points, true)
+    }
+
+    /// Track node `pid`, only adding it to the results if `accepted` is `true`
+    ///
+    /// Rejected nodes are still added to the candidates if they are nearer than the furthest
+    /// result (or if there are fewer than `ef` results), so the search continues through them.
+    fn push_filtered<P: Point>(&mut self, pid: PointId, point: &P, points: &[P], accepted: bool) {
         if !self.visited.insert(pid) {
             return;
         }
 
+        self.visited_count += 1;
         let other = &points[pid];
-        let distance = OrderedFloat::from(point.distance(other));
-        let new = Candidate { distance, pid };
-        let idx = match self.nearest.binary_search(&new) {
-            Err(idx) if idx < self.ef => idx,
-            Err(_) => return,
-            Ok(_) => unreachable!(),
+        // Only the furthest result's distance matters once the results are full, but keep the
+        // distances exact while tracing
+        let bound = match (self.nearest.last(), &self.trace) {
+            (Some(furthest), None) if self.nearest.len() >= self.ef => furthest.distance.0,
+            _ => f32::INFINITY,
         };
+        let distance = OrderedFloat::from(point.distance_bounded(other, bound));
+        let new = Candidate { distance, pid };
+        if !accepted {
+            if let Some(trace) = &mut self.trace {
+                trace.visit(pid, distance.into_inner(), false);
+            }
+
+            let full = self.nearest.len() >= self.ef;
+            if !full || self.nearest.last().map_or(true, |furthest| new < *furthest) {
+                self.candidates.push(new);
+            }
+            return;
+        }
+
+        // Results found since the last merge aren't taken into account here, which only lets
+        // through candidates that `merge()` will drop again
+        let full = self.nearest.len() >= self.ef;
+        if full
+            && self
+                .nearest
+                .last()
+                .map_or(true, |furthest| new >= *furthest)
+        {
+            if let Some(trace) = &mut self.trace {
+                trace.visit(pid, distance.into_inner(), false);
+            }
+            return;
+        }
+
+        if let Some(trace) = &mut self.trace {
+            trace.visit(pid, distance.into_inner(), true);
+        }
+
+        self.pending.push(new);
+        self.candidates.push(new);
+    }
+
+    /// Merge the `pending` results into `nearest`, keeping the nearest `ef` results
+    ///
+    /// Collecting new results and merging them in once per expanded node, rather than inserting
+    /// each one into the sorted `nearest` list, avoids shifting the list for every result, which
+    /// gets expensive for large `ef`.
+    fn merge(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        self.pending.sort_unstable();
+        self.working.clear();
+        let (mut old, mut new) = (
+            self.nearest.iter().peekable(),
+            self.pending.iter().peekable(),
+        );
+        while self.working.len() < self.ef {
+            let next = match (old.peek(), new.peek()) {
+                (Some(a), Some(b)) if a <= b => old.next(),
+                (Some(_), Some(_)) | (None, _) => new.next(),
+                (Some(_), None) => old.next(),
+            };
+
+            match next {
+                Some(&candidate) => self.working.push(candidate),
+                None => break,
+            }
+        }
 
-        self.nearest.insert(idx, new);
-        self.candidates.push(Reverse(new));
+        std::mem::swap(&mut self.nearest, &mut self.working);
+        self.pending.clear();
     }
 
     /// Lower the search to the next lower level
@@ -729,38 +2605,181 @@ impl Search {
     fn cull(&mut self) {
         self.candidates.clear();
         for &candidate in self.nearest.iter() {
-            self.candidates.push(Reverse(candidate));
+            self.candidates.push(candidate);
         }
 
         self.visited.clear();
         self.visited.extend(self.nearest.iter().map(|c| c.pid));
     }
 
+    /// Whether the deadline has passed, which marks the search as truncated
+    fn expired(&mut self) -> bool {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                self.truncated = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Compare `point` against all `points`, keeping the `ef` nearest that pass `filter`
+    ///
+    /// This replaces the graph traversal for flat indexes. `ef` is used as is, so callers apply
+    /// `beam()` where the `ef_search` of the search should override it.
+    fn scan<P: Point>(
+        &mut self,
+        point: &P,
+        points: &[P],
+        ef: usize,
+        filter: impl Fn(PointId) -> bool,
+    ) {
+        if let Some(trace) = &mut self.trace {
+            trace.start_layer(0);
+        }
+
+        self.ef = ef;
+        for pid in (0..points.len() as u32).map(PointId) {
+            if self.expired() {
+                break;
+            }
+
+            if filter(pid) {
+                self.push(pid, point, points);
+                if self.pending.len() >= ef {
+                    self.merge();
+                }
+            }
+        }
+        self.merge();
+        self.candidates.clear();
+    }
+
+    /// Reduce `nearest` to `probes` entry points, preferring diverse ones
+    ///
+    /// Candidates that are closer to an already selected entry point than to the query are
+    /// skipped, like in the neighbor selection heuristic; remaining slots are filled with the
+    /// nearest of the skipped candidates.
+    fn diversify<P: Point>(&mut self, points: &[P]) {
+        self.working.clear();
+        self.discarded.clear();
+        for candidate in self.nearest.drain(..) {
+            let point = &points[candidate.pid];
+            let occluded = self.working.iter().any(|selected| {
+                OrderedFloat::from(point.distance(&points[selected.pid])) < candidate.distance
+            });
+            match occluded || self.working.len() >= self.probes {
+                true => self.discarded.push(candidate),
+                false => self.working.push(candidate),
+            }
+        }
+
+        let missing = self.probes.saturating_sub(self.working.len());
+        self.working.extend(self.discarded.drain(..).take(missing));
+        self.working.sort_unstable();
+        std::mem::swap(&mut self.nearest, &mut self.working);
+    }
+
+    /// Apply exclusions, the distance threshold, deduplication and `k` to the results of a search
+    fn finish<P: Point>(&mut self, points: &[P]) {
+        if !self.excluded.is_empty() {
+            let excluded = &self.excluded;
+            self.nearest.retain(|c| !excluded.contains(&c.pid));
+        }
+
+        if let Some(max) = self.max_distance {
+            let end = self
+                .nearest
+                .partition_point(|c| c.distance.into_inner() <= max);
+            self.nearest.truncate(end);
+        }
+
+        if let Some(epsilon) = self.dedup {
+            self.working.clear();
+            for candidate in self.nearest.drain(..) {
+                let point = &points[candidate.pid];
+                let duplicate = self
+                    .working
+                    .iter()
+                    .any(|kept| point.distance(&points[kept.pid]) < epsilon);
+                if !duplicate {
+                    self.working.push(candidate);
+                }
+            }
+            std::mem::swap(&mut self.nearest, &mut self.working);
+        }
+
+        if let Some(k) = self.k {
+            self.nearest.truncate(k);
+        }
+
+        self.returned.extend(self.nearest.iter().map(|c| c.pid));
+    }
+
     /// Resets the state to be ready for a new search
     fn reset(&mut self) {
         let Search {
             visited,
             candidates,
             nearest,
+            pending,
             working,
             discarded,
             ef: _,
+            visited_count,
+            pruned: _,
+            retain: _,
+            extension: _,
+            trace,
+            excluded: _,
+            max_distance: _,
+            dedup: _,
+            returned,
+            bound,
+            probes: _,
+            k: _,
+            ef_search: _,
+            score: _,
+            timeout,
+            deadline,
+            truncated,
+            batch: _,
+            offsets: _,
         } = self;
 
+        *bound = None;
+        *deadline = timeout.map(|timeout| Instant::now() + timeout);
+        *truncated = false;
+        returned.clear();
+
         visited.clear();
+        *visited_count = 0;
+        if let Some(trace) = trace {
+            trace.clear();
+        }
         candidates.clear();
         nearest.clear();
+        pending.clear();
         working.clear();
         discarded.clear();
     }
 
     /// Selection of neighbors for insertion (algorithm 3 from the paper)
     fn select_simple(&mut self) -> &[Candidate] {
+        self.merge();
         &self.nearest
     }
 
-    fn iter(&self) -> impl Iterator<Item = Candidate> + ExactSizeIterator + '_ {
-        self.nearest.iter().copied()
+    fn iter(&self) -> impl ExactSizeIterator<Item = Candidate> + '_ {
+        self.iter_scored(self.score)
+    }
+
+    /// The results, with their distances reported as `score`
+    fn iter_scored(&self, score: Score) -> impl ExactSizeIterator<Item = Candidate> + '_ {
+        self.nearest.iter().map(move |candidate| Candidate {
+            distance: OrderedFloat(score.apply(candidate.distance.0)),
+            pid: candidate.pid,
+        })
     }
 }
 
@@ -768,17 +2787,67 @@ impl Default for Search {
     fn default() -> Self {
         Self {
             visited: Visited::with_capacity(0),
-            candidates: BinaryHeap::new(),
+            candidates: CandidateQueue::default(),
             nearest: Vec::new(),
+            pending: Vec::new(),
             working: Vec::new(),
             discarded: Vec::new(),
             ef: 1,
+            visited_count: 0,
+            pruned: 0,
+            retain: M * 2,
+            extension: Extension::default(),
+            trace: None,
+            excluded: Vec::new(),
+            max_distance: None,
+            dedup: None,
+            returned: HashSet::new(),
+            bound: None,
+            probes: 1,
+            k: None,
+            ef_search: None,
+            score: Score::Distance,
+            timeout: None,
+            deadline: None,
+            truncated: false,
+            batch: Vec::new(),
+            offsets: Vec::new(),
         }
     }
 }
 
 pub trait Point: Clone + Sync {
     fn distance(&self, other: &Self) -> f32;
+
+    /// The distance to `other`, which may be cut short once it is known to exceed `bound`
+    ///
+    /// Searches pass the distance of the furthest result they would keep, so implementations can
+    /// stop accumulating as soon as the partial distance exceeds it, and return any value greater
+    /// than `bound` instead (typically `f32::INFINITY`). If the distance is at most `bound`, this
+    /// must return the same value as `distance()`. The default implementation always computes
+    /// the full distance.
+    fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+        let _ = bound;
+        self.distance(other)
+    }
+
+    /// The number of dimensions for this point, if it has a fixed-size vector representation
+    ///
+    /// Defaults to `None` for point types that aren't backed by a vector.
+    fn dims(&self) -> Option<usize> {
+        None
+    }
+
+    /// Whether this point is free of NaN and infinite components
+    ///
+    /// A single NaN distance breaks the ordering of neighbors, so [`Builder::try_build()`] and
+    /// [`Hnsw::try_search()`] reject points for which this returns `false`. The default
+    /// implementation checks that the distance from the point to itself is finite, which holds
+    /// for common metrics when all components are finite; vector-backed point types can
+    /// override it to check their components directly.
+    fn is_finite(&self) -> bool {
+        self.distance(self).is_finite()
+    }
 }
 
 /// The parameter `M` from the paper
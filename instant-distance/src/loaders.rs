@@ -0,0 +1,8 @@
+//! Readers for common embedding and dataset file formats
+//!
+//! These read files into points that can be passed to a [`Builder`](crate::Builder) directly,
+//! so the parsing doesn't have to be rewritten for every program that indexes them.
+
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod word_vectors;
@@ -0,0 +1,166 @@
+//! Vectors stored in a list column of a Parquet file
+//!
+//! The column can be a `list`, `large_list` or `fixed_size_list` of 32- or 64-bit floats;
+//! 64-bit components are narrowed to `f32`. Only that column is decoded, one record batch at a
+//! time, so the other columns of wide tables don't have to fit in memory. [`batches()`] hands
+//! out the points of each batch as it is read, for pipelines that process them in chunks.
+
+use std::fs::File;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, Float64Type};
+use arrow_array::Array;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ProjectionMask;
+use parquet::errors::ParquetError;
+use parquet::file::reader::ChunkReader;
+
+use crate::Error;
+
+/// Read the points in `column` of the Parquet file at `path`
+///
+/// See [`read()`] for details.
+pub fn open<P>(path: impl AsRef<Path>, column: &str, limit: Option<usize>) -> Result<Vec<P>, Error>
+where
+    P: From<Vec<f32>>,
+{
+    read(File::open(path)?, column, limit)
+}
+
+/// Read the points in `column` of the Parquet data in `reader`, in row order
+///
+/// Only the first `limit` rows are read, if given. All vectors must have the same number of
+/// components as the first one. Returns [`Error::EmptyPoint`] for null or empty lists and
+/// [`Error::NonFinite`] for lists with null components.
+pub fn read<P>(
+    reader: impl ChunkReader + 'static,
+    column: &str,
+    limit: Option<usize>,
+) -> Result<Vec<P>, Error>
+where
+    P: From<Vec<f32>>,
+{
+    let mut points = Vec::new();
+    for batch in batches(reader, column, limit)? {
+        points.extend(batch?);
+    }
+
+    Ok(points)
+}
+
+/// Iterate over the points in `column` of the Parquet data in `reader`, one record batch at a
+/// time
+///
+/// Returns [`Error::InvalidParameter`] if the file has no column named `column`. The points are
+/// checked as in [`read()`], continuing the row numbering across batches.
+pub fn batches<P>(
+    reader: impl ChunkReader + 'static,
+    column: &str,
+    limit: Option<usize>,
+) -> Result<Batches<P>, Error>
+where
+    P: From<Vec<f32>>,
+{
+    let builder = ParquetRecordBatchReaderBuilder::try_new(reader)?;
+    let index = builder
+        .schema()
+        .index_of(column)
+        .map_err(|_| Error::InvalidParameter {
+            name: "column",
+            reason: "no column with this name",
+        })?;
+
+    let mask = ProjectionMask::roots(builder.parquet_schema(), [index]);
+    let mut builder = builder.with_projection(mask).with_batch_size(BATCH_ROWS);
+    if let Some(limit) = limit {
+        builder = builder.with_limit(limit);
+    }
+
+    Ok(Batches {
+        reader: builder.build()?,
+        rows: 0,
+        dims: None,
+        point: PhantomData,
+    })
+}
+
+/// The points of a Parquet column, one record batch at a time, from [`batches()`]
+pub struct Batches<P> {
+    reader: ParquetRecordBatchReader,
+    /// The number of rows read so far
+    rows: usize,
+    dims: Option<usize>,
+    point: PhantomData<fn() -> P>,
+}
+
+impl<P: From<Vec<f32>>> Iterator for Batches<P> {
+    type Item = Result<Vec<P>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let batch = match self.reader.next()? {
+            Ok(batch) => batch,
+            Err(e) => return Some(Err(ParquetError::from(e).into())),
+        };
+
+        let column = batch.column(0);
+        let mut points = Vec::with_capacity(column.len());
+        for i in 0..column.len() {
+            let index = self.rows + i;
+            let vector = match vector(column, i, index) {
+                Ok(vector) => vector,
+                Err(e) => return Some(Err(e)),
+            };
+
+            let expected = *self.dims.get_or_insert(vector.len());
+            if vector.len() != expected {
+                return Some(Err(Error::DimensionMismatch {
+                    index,
+                    expected,
+                    found: vector.len(),
+                }));
+            }
+
+            points.push(P::from(vector));
+        }
+
+        self.rows += column.len();
+        Some(Ok(points))
+    }
+}
+
+/// The components of the list in row `i` of `column`, which is row `index` of the file
+fn vector(column: &dyn Array, i: usize, index: usize) -> Result<Vec<f32>, Error> {
+    let values = if let Some(list) = column.as_list_opt::<i32>() {
+        list.value(i)
+    } else if let Some(list) = column.as_list_opt::<i64>() {
+        list.value(i)
+    } else if let Some(list) = column.as_fixed_size_list_opt() {
+        list.value(i)
+    } else {
+        return Err(NOT_FLOAT_LIST);
+    };
+
+    if column.is_null(i) || values.is_empty() {
+        return Err(Error::EmptyPoint { index });
+    } else if values.null_count() > 0 {
+        return Err(Error::NonFinite { index });
+    }
+
+    if let Some(values) = values.as_primitive_opt::<Float32Type>() {
+        Ok(values.values().to_vec())
+    } else if let Some(values) = values.as_primitive_opt::<Float64Type>() {
+        Ok(values.values().iter().map(|&v| v as f32).collect())
+    } else {
+        Err(NOT_FLOAT_LIST)
+    }
+}
+
+const NOT_FLOAT_LIST: Error = Error::InvalidParameter {
+    name: "column",
+    reason: "not a list of floats",
+};
+
+/// The number of rows decoded at a time
+const BATCH_ROWS: usize = 8 * 1024;
@@ -0,0 +1,138 @@
+//! Word vectors in the text format used by fastText and GloVe
+//!
+//! Each line holds a word followed by the components of its vector, separated by spaces. fastText
+//! `.vec` files start with a header line holding the number of words and the dimensionality;
+//! GloVe files have no header. Both are accepted: a first line with exactly two integers is
+//! taken to be a header.
+//!
+//! The lines are read in chunks, and the lines in each chunk are parsed in parallel.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::par::*;
+use crate::Error;
+
+/// The words and vectors read from a word vector file, in file order
+pub struct WordVectors<P> {
+    pub words: Vec<String>,
+    pub points: Vec<P>,
+}
+
+/// Read the word vectors from the file at `path`
+///
+/// See [`read()`] for details.
+pub fn open<P>(path: impl AsRef<Path>, limit: Option<usize>) -> Result<WordVectors<P>, Error>
+where
+    P: From<Vec<f32>> + Send,
+{
+    let file = File::open(path)?;
+    read(BufReader::with_capacity(BUFFER_SIZE, file), limit)
+}
+
+/// Read word vectors from `reader`, converting each vector to a point with `P::from()`
+///
+/// Only the first `limit` words are read, if given; word vector files are usually sorted by
+/// word frequency, so this keeps the most common words. All vectors must have the same number
+/// of components as the first one (or the number given in the header). Returns
+/// [`Error::Parse`] for lines that can't be parsed.
+pub fn read<P>(mut reader: impl BufRead, limit: Option<usize>) -> Result<WordVectors<P>, Error>
+where
+    P: From<Vec<f32>> + Send,
+{
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut words = WordVectors {
+        words: Vec::new(),
+        points: Vec::new(),
+    };
+
+    let mut lines = vec![String::new(); CHUNK_LINES];
+    let mut numbers = vec![0; CHUNK_LINES];
+    let (mut line_no, mut dims) = (0, None);
+    let mut first = true;
+    while words.words.len() < limit {
+        // Read the next chunk, reusing the line buffers of the previous one
+        let wanted = CHUNK_LINES.min(limit - words.words.len());
+        let mut len = 0;
+        while len < wanted {
+            let line = &mut lines[len];
+            line.clear();
+            if reader.read_line(line)? == 0 {
+                break;
+            }
+
+            line_no += 1;
+            if first {
+                first = false;
+                if let Some((count, header_dims)) = header(line) {
+                    let capacity = count.min(limit);
+                    words.words.reserve(capacity);
+                    words.points.reserve(capacity);
+                    dims = Some(header_dims);
+                    continue;
+                }
+            }
+
+            if !line.trim().is_empty() {
+                numbers[len] = line_no;
+                len += 1;
+            }
+        }
+
+        if len == 0 {
+            break;
+        }
+
+        let mut parsed = Vec::with_capacity(len);
+        lines[..len]
+            .par_iter()
+            .map(|line| parse(line))
+            .collect_into_vec(&mut parsed);
+
+        for (result, &line) in parsed.into_iter().zip(&numbers) {
+            let (word, vector) = result.map_err(|reason| Error::Parse { line, reason })?;
+
+            let expected = *dims.get_or_insert(vector.len());
+            if vector.len() != expected {
+                return Err(Error::DimensionMismatch {
+                    index: words.words.len(),
+                    expected,
+                    found: vector.len(),
+                });
+            }
+
+            words.words.push(word);
+            words.points.push(P::from(vector));
+        }
+    }
+
+    Ok(words)
+}
+
+/// The word count and dimensionality from a fastText header line
+fn header(line: &str) -> Option<(usize, usize)> {
+    let mut fields = line.split_whitespace();
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(count), Some(dims), None) => Some((count.parse().ok()?, dims.parse().ok()?)),
+        _ => None,
+    }
+}
+
+fn parse(line: &str) -> Result<(String, Vec<f32>), String> {
+    let mut fields = line.split_whitespace();
+    let word = fields.next().ok_or("empty line")?;
+    let vector = fields
+        .map(|field| field.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("invalid component for {word:?}: {e}"))?;
+
+    match vector.is_empty() {
+        true => Err(format!("no components for {word:?}")),
+        false => Ok((word.to_owned(), vector)),
+    }
+}
+
+/// The number of lines parsed in parallel at a time
+const CHUNK_LINES: usize = 16 * 1024;
+const BUFFER_SIZE: usize = 4 * 1024 * 1024;
@@ -0,0 +1,111 @@
+//! Indexes shared by several tenants
+//!
+//! A [`NamespacedMap`] tags each point with a namespace and restricts each search to a single
+//! namespace, so many small tenants can share one index instead of each needing their own.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Builder, Error, HnswMap, Item, MapItem, Point, PointId, Search};
+
+/// An `HnswMap` whose points each belong to a namespace
+///
+/// Searches only return points from the requested namespace. They start from an entry point in
+/// that namespace and traverse points from other namespaces only to reach more points in it, so
+/// searching a small namespace doesn't require wading through the rest of the index first.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct NamespacedMap<P, V> {
+    map: HnswMap<P, V>,
+    /// The namespace of each point, in `PointId` order
+    namespaces: Vec<u32>,
+    /// The point of each namespace on the highest layer, used as the entry point
+    entries: HashMap<u32, PointId>,
+}
+
+impl<P: Point, V: Clone> NamespacedMap<P, V> {
+    /// Build an index for `points`, where point `i` belongs to namespace `namespaces[i]`
+    ///
+    /// Performs the same checks as [`Builder::try_build()`]; additionally, there must be a
+    /// namespace for each point.
+    pub fn build(
+        builder: Builder,
+        points: Vec<P>,
+        namespaces: Vec<u32>,
+        values: Vec<V>,
+    ) -> Result<Self, Error> {
+        for len in [values.len(), namespaces.len()] {
+            if points.len() != len {
+                return Err(Error::LengthMismatch {
+                    points: points.len(),
+                    values: len,
+                });
+            }
+        }
+
+        let (hnsw, pids) = builder.try_build_hnsw(points)?;
+        let mut sorted = pids
+            .into_iter()
+            .zip(namespaces.into_iter().zip(values))
+            .collect::<Vec<_>>();
+        sorted.sort_unstable_by_key(|(pid, _)| *pid);
+        let (namespaces, values): (Vec<_>, _) = sorted.into_iter().map(|(_, pair)| pair).unzip();
+
+        // Points are sorted by layer, so the first point of each namespace is on its highest layer
+        let mut entries = HashMap::new();
+        for (i, &namespace) in namespaces.iter().enumerate() {
+            entries.entry(namespace).or_insert(PointId(i as u32));
+        }
+
+        Ok(Self {
+            map: HnswMap::new_unchecked(hnsw, values),
+            namespaces,
+            entries,
+        })
+    }
+
+    /// Search `namespace` for the points nearest to `point`
+    ///
+    /// Returns no results if the namespace has no points.
+    pub fn search<'a>(
+        &'a self,
+        point: &P,
+        namespace: u32,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, P, V>> + 'a {
+        search.reset();
+        if let Some(&entry) = self.entries.get(&namespace) {
+            let hnsw = &self.map.hnsw;
+            search.visited.reserve_capacity(hnsw.points.len());
+            hnsw.search_from(point, search, entry, |pid| {
+                self.namespaces[pid.0 as usize] == namespace
+            });
+            search.finish(&hnsw.points);
+        }
+
+        search
+            .iter()
+            .map(move |candidate| MapItem::from(Item::new(candidate, &self.map.hnsw), &self.map))
+    }
+
+    /// The namespace of the point `pid`
+    pub fn namespace(&self, pid: PointId) -> u32 {
+        self.namespaces[pid.0 as usize]
+    }
+
+    /// The underlying index, containing the points of all namespaces
+    pub fn map(&self) -> &HnswMap<P, V> {
+        &self.map
+    }
+
+    /// The number of points in this index, across all namespaces
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
@@ -0,0 +1,128 @@
+//! Pruning of the neighbor lists of a built graph, with [`Hnsw::optimize()`]
+//!
+//! During construction, a node's neighbor list is selected when the node is inserted, and then
+//! extended as later nodes link back to it. Depending on the insertion order, the final list
+//! can hold neighbors that are occluded by other neighbors (and are therefore redundant), in
+//! particular if it was built with the simple selection. Running the occlusion check of the
+//! selection heuristic over each final list removes these edges, which makes searches visit
+//! fewer nodes.
+//!
+//! Unlike during construction, the pruned edges aren't compensated for by links added later,
+//! so an edge `a -> c` is only removed if the neighbor `b` occluding it links to `c` itself and
+//! is nearer to `c` than `a` is. Every pruned edge is then replaced by a path through nearer and
+//! nearer nodes, so no node becomes unreachable.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ordered_float::OrderedFloat;
+
+use crate::par::*;
+use crate::types::{Candidate, Layer, UpperNode, INVALID};
+use crate::{Hnsw, HnswMap, Point, PointId, ValueStore, M};
+
+impl<P: Point> Hnsw<P> {
+    /// Remove the neighbors occluded by nearer neighbors from all neighbor lists
+    ///
+    /// This applies the occlusion check of the selection heuristic to each node's final list,
+    /// with the relaxation factor `alpha` (see [`Heuristic::alpha`](crate::Heuristic::alpha)):
+    /// a neighbor `c` of `a` is removed if a nearer neighbor `b` is more than `alpha` times
+    /// closer to `c` than `a` is, and `b` links to `c`. No neighbors are added. Returns the
+    /// number of links removed.
+    ///
+    /// Every node is pruned based on the graph as it was before this call, so the result
+    /// doesn't depend on the order in which the nodes are processed. This is most effective on
+    /// indexes built with the simple selection (see [`Builder::select_heuristic()`]).
+    ///
+    /// [`Builder::select_heuristic()`]: crate::Builder::select_heuristic
+    pub fn optimize(&mut self, alpha: f32) -> usize {
+        let removed = AtomicUsize::new(0);
+
+        let mut lists = Vec::new();
+        let zero = self.zero.as_slice();
+        prune(zero, zero.len(), &self.points, alpha, &removed, &mut lists);
+        for (node, list) in self.zero.iter_mut().zip(&lists) {
+            node.rewrite(list.iter().copied());
+        }
+
+        for i in 0..self.layers.len() {
+            let layer = self.layers[i].as_slice();
+            prune(
+                layer,
+                layer.len(),
+                &self.points,
+                alpha,
+                &removed,
+                &mut lists,
+            );
+            for (node, list) in self.layers[i].iter_mut().zip(&lists) {
+                *node = UpperNode([INVALID; M]);
+                node.0[..list.len()].copy_from_slice(list);
+            }
+        }
+
+        removed.into_inner()
+    }
+}
+
+impl<P, V, S> HnswMap<P, V, S>
+where
+    P: Point,
+    V: ?Sized,
+    S: ValueStore<V>,
+{
+    /// Remove the neighbors occluded by nearer neighbors from all neighbor lists
+    ///
+    /// See [`Hnsw::optimize()`] for details.
+    pub fn optimize(&mut self, alpha: f32) -> usize {
+        self.hnsw.optimize(alpha)
+    }
+}
+
+/// Select the new neighbor list of each of the `len` nodes on `layer` into `lists`
+fn prune<L: Layer + Copy + Sync, P: Point>(
+    layer: L,
+    len: usize,
+    points: &[P],
+    alpha: f32,
+    removed: &AtomicUsize,
+    lists: &mut Vec<Vec<PointId>>,
+) {
+    (0..len)
+        .into_par_iter()
+        .map(|i| {
+            let pid = PointId(i as u32);
+            let point = &points[pid];
+            let mut candidates = layer
+                .nearest_iter(pid)
+                .map(|neighbor| Candidate {
+                    distance: OrderedFloat::from(point.distance(&points[neighbor])),
+                    pid: neighbor,
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_unstable();
+
+            let mut selected = Vec::<Candidate>::with_capacity(candidates.len());
+            for candidate in &candidates {
+                let candidate_point = &points[candidate.pid];
+                let occluded = selected.iter().any(|nearer| {
+                    let distance = candidate_point.distance(&points[nearer.pid]);
+                    OrderedFloat::from(alpha * distance) < candidate.distance
+                        && OrderedFloat::from(distance) < candidate.distance
+                        && layer
+                            .nearest_iter(nearer.pid)
+                            .any(|pid| pid == candidate.pid)
+                });
+
+                if !occluded {
+                    selected.push(*candidate);
+                }
+            }
+
+            removed.fetch_add(candidates.len() - selected.len(), Ordering::Relaxed);
+            selected
+                .into_iter()
+                .map(|candidate| candidate.pid)
+                .collect()
+        })
+        .collect_into_vec(lists);
+}
@@ -0,0 +1,310 @@
+//! Compact, read-only representation of a built graph
+//!
+//! During construction, every node has room for the maximum number of neighbors (`M * 2` on the
+//! zero layer and `M` on the upper layers), with unused slots holding an invalid `PointId`. That
+//! makes concurrent insertion simple, but wastes memory once the graph is complete. A
+//! [`PackedHnsw`] stores the neighbor lists of each layer back to back instead, so each node
+//! only takes up space for the neighbors it has, and iterating over a node's neighbors doesn't
+//! have to scan for the end of the list.
+//!
+//! The lists can additionally be compressed (see [`Encoding`]): each list is sorted and
+//! delta-encoded as variable-length integers, so neighbors with nearby IDs take one or two bytes
+//! instead of four. The lists are then decoded on the fly during searches.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::types::{Layer, UpperNode, ZeroNode};
+use crate::{Config, Hnsw, Item, Point, PointId, Search};
+
+/// A read-only `Hnsw` with compressed neighbor lists
+///
+/// Searches return the same results as the `Hnsw` it was created from, up to the order in
+/// which equidistant neighbors are visited.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct PackedHnsw<P> {
+    config: Config,
+    points: Vec<P>,
+    zero: Neighbors,
+    layers: Vec<Neighbors>,
+    inputs: Option<Vec<u32>>,
+}
+
+impl<P: Point> PackedHnsw<P> {
+    /// Pack the neighbor lists of `hnsw` using the [`Encoding::Delta`] encoding
+    pub fn new(hnsw: Hnsw<P>) -> Self {
+        Self::with_encoding(hnsw, Encoding::Delta)
+    }
+
+    /// Pack the neighbor lists of `hnsw` using the given encoding
+    pub fn with_encoding(hnsw: Hnsw<P>, encoding: Encoding) -> Self {
+        let Hnsw {
+            config,
+            points,
+            zero,
+            layers,
+            inputs,
+        } = hnsw;
+
+        let zero = zero.iter().map(|node: &ZeroNode| &node.0[..]);
+        Self {
+            config,
+            points,
+            zero: Neighbors::new(zero, encoding),
+            layers: layers
+                .iter()
+                .map(|layer| {
+                    let nodes = layer.iter().map(|node: &UpperNode| &node.0[..]);
+                    Neighbors::new(nodes, encoding)
+                })
+                .collect(),
+            inputs,
+        }
+    }
+
+    /// Search the index for the points nearest to `point`
+    ///
+    /// See [`Hnsw::search()`] for details.
+    pub fn search<'a, 'b: 'a>(
+        &'b self,
+        point: &P,
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = Item<'b, P>> + 'a {
+        search.reset();
+        if !self.points.is_empty() {
+            search.visited.reserve_capacity(self.points.len());
+            match self.zero.len() {
+                // The index was built without a graph
+                0 => {
+                    let ef = search.beam(self.config.ef_search);
+                    search.scan(point, &self.points, ef, |_| true)
+                }
+                _ => search.traverse(
+                    point,
+                    &self.points,
+                    self.config.ef_search,
+                    (PointId(0), self.layers.len()),
+                    &self.zero,
+                    |l| &self.layers[l - 1],
+                    |_| true,
+                ),
+            }
+            search.finish(&self.points);
+        }
+
+        search.iter().map(move |candidate| Item {
+            distance: candidate.distance.into_inner(),
+            pid: candidate.pid,
+            index: self.input_index(candidate.pid),
+            point: &self.points[candidate.pid.0 as usize],
+        })
+    }
+
+    /// Iterate over the keys and values in this index
+    pub fn iter(&self) -> impl Iterator<Item = (PointId, &P)> {
+        self.points
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (PointId(i as u32), p))
+    }
+
+    /// The parameters the index was built with
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The index of `pid` in the slice of points the index was built from
+    ///
+    /// See [`Hnsw::input_index()`] for details.
+    pub fn input_index(&self, pid: PointId) -> Option<usize> {
+        let inputs = self.inputs.as_ref()?;
+        inputs.get(pid.0 as usize).map(|&idx| idx as usize)
+    }
+
+    /// The number of points in this index
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// The encoding of the neighbor lists
+    pub fn encoding(&self) -> Encoding {
+        match self.zero {
+            Neighbors::Plain { .. } => Encoding::Plain,
+            Neighbors::Delta { .. } => Encoding::Delta,
+        }
+    }
+
+    /// The number of bytes used to store the neighbor lists of all layers
+    pub fn graph_bytes(&self) -> usize {
+        self.layers
+            .iter()
+            .chain(Some(&self.zero))
+            .map(Neighbors::bytes)
+            .sum()
+    }
+}
+
+impl<P: Point> From<Hnsw<P>> for PackedHnsw<P> {
+    fn from(hnsw: Hnsw<P>) -> Self {
+        Self::new(hnsw)
+    }
+}
+
+/// How a [`PackedHnsw`] stores its neighbor lists
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Four bytes per neighbor, in the order of the original lists (nearest first)
+    ///
+    /// Searches visit the same points in the same order as they would in the `Hnsw`, without
+    /// skipping over unused slots.
+    Plain,
+    /// Sorted neighbor lists, delta-encoded as variable-length integers
+    ///
+    /// This typically takes less than half the memory of `Plain`, at the cost of decoding each
+    /// list while it is traversed.
+    Delta,
+}
+
+/// The neighbor lists of all nodes on a layer, back to back
+///
+/// The list of node `i` spans from `offsets[i]` to `offsets[i + 1]` in the data.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+enum Neighbors {
+    Plain {
+        offsets: Vec<usize>,
+        ids: Vec<PointId>,
+    },
+    Delta {
+        offsets: Vec<usize>,
+        data: Vec<u8>,
+    },
+}
+
+impl Neighbors {
+    fn new<'a>(nodes: impl ExactSizeIterator<Item = &'a [PointId]>, encoding: Encoding) -> Self {
+        let mut offsets = Vec::with_capacity(nodes.len() + 1);
+        offsets.push(0);
+        let valid = |node: &'a [PointId]| node.iter().take_while(|pid| pid.is_valid());
+
+        match encoding {
+            Encoding::Plain => {
+                let mut ids = Vec::new();
+                for node in nodes {
+                    ids.extend(valid(node));
+                    offsets.push(ids.len());
+                }
+
+                ids.shrink_to_fit();
+                Self::Plain { offsets, ids }
+            }
+            Encoding::Delta => {
+                let mut data = Vec::new();
+                let mut sorted = Vec::new();
+                for node in nodes {
+                    sorted.clear();
+                    sorted.extend(valid(node).map(|pid| pid.0));
+                    sorted.sort_unstable();
+
+                    let mut prev = 0;
+                    for &pid in &sorted {
+                        write_varint(&mut data, pid - prev);
+                        prev = pid;
+                    }
+                    offsets.push(data.len());
+                }
+
+                data.shrink_to_fit();
+                Self::Delta { offsets, data }
+            }
+        }
+    }
+
+    /// The number of nodes on the layer
+    fn len(&self) -> usize {
+        match self {
+            Self::Plain { offsets, .. } | Self::Delta { offsets, .. } => offsets.len() - 1,
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        let (offsets, data) = match self {
+            Self::Plain { offsets, ids } => (offsets, ids.len() * std::mem::size_of::<PointId>()),
+            Self::Delta { offsets, data } => (offsets, data.len()),
+        };
+        offsets.len() * std::mem::size_of::<usize>() + data
+    }
+}
+
+impl<'a> Layer for &'a Neighbors {
+    type Iter = NeighborIter<'a>;
+
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
+        let i = pid.0 as usize;
+        match self {
+            Neighbors::Plain { offsets, ids } => {
+                NeighborIter::Plain(ids[offsets[i]..offsets[i + 1]].iter())
+            }
+            Neighbors::Delta { offsets, data } => NeighborIter::Delta(DeltaIter {
+                data: &data[offsets[i]..offsets[i + 1]],
+                prev: 0,
+            }),
+        }
+    }
+}
+
+pub(crate) enum NeighborIter<'a> {
+    Plain(std::slice::Iter<'a, PointId>),
+    Delta(DeltaIter<'a>),
+}
+
+impl Iterator for NeighborIter<'_> {
+    type Item = PointId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Plain(iter) => iter.next().copied(),
+            Self::Delta(iter) => iter.next(),
+        }
+    }
+}
+
+/// Decodes a delta-encoded neighbor list
+pub(crate) struct DeltaIter<'a> {
+    data: &'a [u8],
+    prev: u32,
+}
+
+impl Iterator for DeltaIter<'_> {
+    type Item = PointId;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut delta = 0;
+        let mut shift = 0;
+        loop {
+            let (&byte, rest) = self.data.split_first()?;
+            self.data = rest;
+            delta |= u32::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+
+        self.prev += delta;
+        Some(PointId(self.prev))
+    }
+}
+
+/// Write `value` as a LEB128 variable-length integer
+fn write_varint(out: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
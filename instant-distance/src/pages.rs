@@ -0,0 +1,53 @@
+//! Transparent huge pages for the large buffers of an index
+//!
+//! Searches jump between points and neighbor lists all over the index, so with 4 KiB pages
+//! nearly every step of a traversal through a multi-gigabyte index misses the TLB. Backing the
+//! points and the zero layer with 2 MiB pages cuts the number of TLB entries needed by a factor
+//! of 512. This is only a hint: the kernel falls back to regular pages if it has no huge pages
+//! available, or if transparent huge pages are disabled (`/sys/kernel/mm/transparent_hugepage`).
+
+use crate::{Hnsw, Point};
+
+impl<P: Point> Hnsw<P> {
+    /// Ask the kernel to back the points and the zero layer with transparent huge pages
+    ///
+    /// Use this for indexes that were loaded rather than built with
+    /// [`Builder::huge_pages()`](crate::Builder::huge_pages). Memory that is already in use
+    /// is converted to huge pages in the background, so the effect isn't immediate. Only
+    /// supported on Linux; on other platforms, this does nothing.
+    pub fn advise_huge_pages(&self) {
+        advise(self.points.as_ptr(), self.points.len());
+        advise(self.zero.as_ptr(), self.zero.len());
+    }
+}
+
+/// Ask for huge pages for the `len` elements at `ptr`, which may not be initialized yet
+///
+/// Only the huge pages that fall entirely within the buffer are advised, so other allocations
+/// that share the pages at either end are not affected. Failures are ignored, since this is
+/// only a hint.
+#[cfg(target_os = "linux")]
+pub(crate) fn advise<T>(ptr: *const T, len: usize) {
+    let start = ptr as usize;
+    let end = start + len * std::mem::size_of::<T>();
+    let (start, end) = (
+        (start + HUGE_PAGE - 1) & !(HUGE_PAGE - 1),
+        end & !(HUGE_PAGE - 1),
+    );
+    if start >= end {
+        return;
+    }
+
+    // Safety: the range lies within a single live allocation, and `MADV_HUGEPAGE` doesn't
+    // change its contents
+    unsafe {
+        libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_HUGEPAGE);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn advise<T>(_: *const T, _: usize) {}
+
+/// The size of a transparent huge page on x86_64 and (with 4 KiB base pages) aarch64
+#[cfg(target_os = "linux")]
+const HUGE_PAGE: usize = 2 << 20;
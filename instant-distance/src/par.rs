@@ -0,0 +1,91 @@
+//! Parallel iteration with rayon, or sequential iteration without the `rayon` feature
+//!
+//! Without the `rayon` feature, the traits in this module give ordinary iterators the methods
+//! of rayon's parallel iterators that this crate uses, so the same code runs on a single thread
+//! on targets that don't support threads (such as `wasm32-unknown-unknown`). Import them with
+//! `use crate::par::*`.
+
+#[cfg(feature = "rayon")]
+pub(crate) use rayon::iter::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+    IntoParallelRefMutIterator, ParallelIterator,
+};
+#[cfg(feature = "rayon")]
+pub(crate) use rayon::slice::ParallelSliceMut;
+
+#[cfg(not(feature = "rayon"))]
+pub(crate) use sequential::*;
+
+#[cfg(not(feature = "rayon"))]
+mod sequential {
+    use std::slice;
+
+    pub(crate) trait IntoParallelIterator: IntoIterator + Sized {
+        fn into_par_iter(self) -> Self::IntoIter {
+            self.into_iter()
+        }
+    }
+
+    impl<I: IntoIterator> IntoParallelIterator for I {}
+
+    pub(crate) trait IntoParallelRefIterator<'a> {
+        type Iter: Iterator;
+
+        fn par_iter(&'a self) -> Self::Iter;
+    }
+
+    impl<'a, T: 'a> IntoParallelRefIterator<'a> for [T] {
+        type Iter = slice::Iter<'a, T>;
+
+        fn par_iter(&'a self) -> Self::Iter {
+            self.iter()
+        }
+    }
+
+    pub(crate) trait IntoParallelRefMutIterator<'a> {
+        type Iter: Iterator;
+
+        fn par_iter_mut(&'a mut self) -> Self::Iter;
+    }
+
+    impl<'a, T: 'a> IntoParallelRefMutIterator<'a> for [T] {
+        type Iter = slice::IterMut<'a, T>;
+
+        fn par_iter_mut(&'a mut self) -> Self::Iter {
+            self.iter_mut()
+        }
+    }
+
+    pub(crate) trait ParallelSliceMut<T> {
+        fn par_chunks_mut(&mut self, chunk_size: usize) -> slice::ChunksMut<'_, T>;
+    }
+
+    impl<T> ParallelSliceMut<T> for [T] {
+        fn par_chunks_mut(&mut self, chunk_size: usize) -> slice::ChunksMut<'_, T> {
+            self.chunks_mut(chunk_size)
+        }
+    }
+
+    pub(crate) trait ParallelIterator: Iterator + Sized {
+        /// Like rayon's `for_each_init()`, with a single state for all items
+        fn for_each_init<T, I, F>(self, init: I, mut op: F)
+        where
+            I: Fn() -> T,
+            F: FnMut(&mut T, Self::Item),
+        {
+            let mut state = init();
+            self.for_each(|item| op(&mut state, item));
+        }
+    }
+
+    impl<I: Iterator> ParallelIterator for I {}
+
+    pub(crate) trait IndexedParallelIterator: Iterator + Sized {
+        fn collect_into_vec(self, target: &mut Vec<Self::Item>) {
+            target.clear();
+            target.extend(self);
+        }
+    }
+
+    impl<I: Iterator> IndexedParallelIterator for I {}
+}
@@ -0,0 +1,608 @@
+//! Versioned on-disk format for `Hnsw` and `HnswMap`
+//!
+//! Serialized indexes start with an 8-byte magic value and a 32-bit format version, followed by
+//! the bincode-encoded index and (since format version 2) a CRC-32 checksum of the encoded index.
+//! Indexes written without this header (by versions before the header was introduced, which is
+//! format version 0) are detected and converted in memory on load. Format version 3 added the
+//! position of each point in the input slice (see [`Item::index`](crate::Item::index)); indexes
+//! written in older versions load without it. Format version 4 added the per-layer limits of
+//! [`Config::upper_m`], which are empty for indexes written in older versions.
+//!
+//! Format version 0 is the layout of the upstream `instant-distance` crate (up to 0.6), so
+//! `load()` also reads indexes that upstream users wrote with `bincode::serialize()`. Indexes
+//! that were written with other serde formats can be converted with
+//! [`Hnsw::deserialize_upstream()`] and [`HnswMap::deserialize_upstream()`].
+//!
+//! The format is the same on all platforms: all integers and floats are little-endian with fixed
+//! widths (`usize` values and sequence lengths are always written as `u64`), so an index built on
+//! x86_64 can be loaded on big-endian or 32-bit targets. Point and value types are encoded using
+//! their own `Serialize` implementations, which should avoid platform-dependent representations
+//! for the same guarantee to hold.
+//!
+//! With the `object_store` feature, `save_object()` and `load_object()` write and read indexes
+//! in the same format to and from any `ObjectStore`, such as S3, GCS or Azure Blob Storage.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+
+use bincode::Options;
+#[cfg(feature = "object_store")]
+use object_store::{path::Path as ObjectPath, ObjectStore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::files::write_atomic;
+use crate::types::{PointId, UpperNode, ZeroNode, INVALID};
+use crate::{Config, Error, Heuristic, Hnsw, HnswMap, Point, VerifyReport, M};
+
+impl<P: Point + Serialize> Hnsw<P> {
+    /// Write the index to `writer` in the current format version
+    pub fn save(&self, writer: impl Write) -> Result<(), Error> {
+        write(writer, self)
+    }
+
+    /// Write the index to the file at `path`, replacing it atomically
+    ///
+    /// See [`HnswMap::save_file()`] for details.
+    pub fn save_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        write_atomic(path.as_ref(), |writer| self.save(writer))
+    }
+}
+
+impl<P: Point + DeserializeOwned> Hnsw<P> {
+    /// Read an index from `reader`, converting it from older format versions if necessary
+    ///
+    /// Returns [`Error::ChecksumMismatch`] if the index is corrupted, or
+    /// [`Error::InvalidIndex`] if the graph structure is inconsistent.
+    pub fn load(reader: impl Read) -> Result<Self, Error> {
+        let hnsw: Self = match read_header(reader)? {
+            Versioned::Current(reader) => read_checked(reader)?,
+            Versioned::V3(reader) => read_checked::<V3Hnsw<P>>(reader)?.into(),
+            Versioned::V2(reader) => read_checked::<V2Hnsw<P>>(reader)?.into(),
+            Versioned::Unchecked(reader) => {
+                options().deserialize_from::<_, V2Hnsw<P>>(reader)?.into()
+            }
+            Versioned::Legacy(reader) => options()
+                .deserialize_from::<_, LegacyHnsw<P>>(reader)?
+                .into(),
+        };
+
+        check(&hnsw)?;
+        Ok(hnsw)
+    }
+
+    /// Read an index from the file at `path`
+    pub fn load_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::load(BufReader::new(File::open(path)?))
+    }
+
+    /// Read an index like [`Hnsw::load()`], then check it with [`Hnsw::verify()`]
+    ///
+    /// Returns [`Error::InvalidIndex`] listing all problems found, so an index from an untrusted
+    /// source fails to load instead of returning poor results or panicking during a search.
+    pub fn load_verified(reader: impl Read) -> Result<Self, Error> {
+        let hnsw = Self::load(reader)?;
+        verified(hnsw.verify())?;
+        Ok(hnsw)
+    }
+}
+
+impl<'de, P: Point + Deserialize<'de>> Hnsw<P> {
+    /// Deserialize an index serialized by the upstream `instant-distance` crate
+    ///
+    /// Use the deserializer of the serde format the index was written with, for example
+    /// `serde_json::Deserializer`. Only `ef_search` is taken over from the upstream index; the
+    /// other build parameters in [`Hnsw::config()`] are unknown.
+    pub fn deserialize_upstream<D: Deserializer<'de>>(deserializer: D) -> Result<Self, Error> {
+        let legacy = LegacyHnsw::<P>::deserialize(deserializer)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let hnsw = Self::from(legacy);
+        check(&hnsw)?;
+        Ok(hnsw)
+    }
+}
+
+impl<P: Point + Serialize, V: Serialize> HnswMap<P, V> {
+    /// Write the map to `writer` in the current format version
+    pub fn save(&self, writer: impl Write) -> Result<(), Error> {
+        write(writer, self)
+    }
+
+    /// Write the map to the file at `path`, replacing it atomically
+    ///
+    /// The map is written to a temporary file in the same directory, which is flushed to disk
+    /// and then renamed to `path`. If the process crashes while saving, `path` still holds the
+    /// previous version of the file (if any) rather than a partially written one.
+    pub fn save_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        write_atomic(path.as_ref(), |writer| self.save(writer))
+    }
+}
+
+impl<P: Point + DeserializeOwned, V: DeserializeOwned> HnswMap<P, V> {
+    /// Read a map from `reader`, converting it from older format versions if necessary
+    ///
+    /// Returns [`Error::ChecksumMismatch`] if the map is corrupted, or [`Error::InvalidIndex`] if
+    /// its structure is inconsistent.
+    pub fn load(reader: impl Read) -> Result<Self, Error> {
+        let map: Self = match read_header(reader)? {
+            Versioned::Current(reader) => read_checked(reader)?,
+            Versioned::V3(reader) => read_checked::<V3HnswMap<P, V>>(reader)?.into(),
+            Versioned::V2(reader) => read_checked::<V2HnswMap<P, V>>(reader)?.into(),
+            Versioned::Unchecked(reader) => options()
+                .deserialize_from::<_, V2HnswMap<P, V>>(reader)?
+                .into(),
+            Versioned::Legacy(reader) => {
+                let legacy = options().deserialize_from::<_, LegacyHnswMap<P, V>>(reader)?;
+                let LegacyHnswMap { hnsw, values } = legacy;
+                HnswMap::new_unchecked(hnsw.into(), values)
+            }
+        };
+
+        check_map(&map)?;
+        Ok(map)
+    }
+
+    /// Read a map from the file at `path`
+    pub fn load_file(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::load(BufReader::new(File::open(path)?))
+    }
+
+    /// Read a map like [`HnswMap::load()`], then check it with [`HnswMap::verify()`]
+    ///
+    /// See [`Hnsw::load_verified()`] for details.
+    pub fn load_verified(reader: impl Read) -> Result<Self, Error> {
+        let map = Self::load(reader)?;
+        verified(map.verify())?;
+        Ok(map)
+    }
+}
+
+#[cfg(feature = "object_store")]
+impl<P: Point + Serialize> Hnsw<P> {
+    /// Write the index to `location` in `store`, in the current format version
+    ///
+    /// The index is encoded in memory and then uploaded with a single `put()`, which replaces
+    /// the object atomically.
+    pub async fn save_object(
+        &self,
+        store: &dyn ObjectStore,
+        location: &ObjectPath,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        self.save(&mut buf)?;
+        store.put(location, buf.into()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "object_store")]
+impl<P: Point + DeserializeOwned> Hnsw<P> {
+    /// Read an index from `location` in `store`, like [`Hnsw::load()`]
+    ///
+    /// The whole object is downloaded before it is decoded.
+    pub async fn load_object(
+        store: &dyn ObjectStore,
+        location: &ObjectPath,
+    ) -> Result<Self, Error> {
+        let bytes = store.get(location).await?.bytes().await?;
+        Self::load(&bytes[..])
+    }
+}
+
+#[cfg(feature = "object_store")]
+impl<P: Point + Serialize, V: Serialize> HnswMap<P, V> {
+    /// Write the map to `location` in `store`, in the current format version
+    ///
+    /// See [`Hnsw::save_object()`] for details.
+    pub async fn save_object(
+        &self,
+        store: &dyn ObjectStore,
+        location: &ObjectPath,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        self.save(&mut buf)?;
+        store.put(location, buf.into()).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "object_store")]
+impl<P: Point + DeserializeOwned, V: DeserializeOwned> HnswMap<P, V> {
+    /// Read a map from `location` in `store`, like [`HnswMap::load()`]
+    ///
+    /// The whole object is downloaded before it is decoded.
+    pub async fn load_object(
+        store: &dyn ObjectStore,
+        location: &ObjectPath,
+    ) -> Result<Self, Error> {
+        let bytes = store.get(location).await?.bytes().await?;
+        Self::load(&bytes[..])
+    }
+}
+
+impl<'de, P: Point + Deserialize<'de>, V: Deserialize<'de>> HnswMap<P, V> {
+    /// Deserialize a map serialized by the upstream `instant-distance` crate
+    ///
+    /// See [`Hnsw::deserialize_upstream()`] for details.
+    pub fn deserialize_upstream<D: Deserializer<'de>>(deserializer: D) -> Result<Self, Error> {
+        let LegacyHnswMap { hnsw, values } = LegacyHnswMap::<P, V>::deserialize(deserializer)
+            .map_err(|e| Error::Serialization(e.to_string()))?;
+        let map = HnswMap::new_unchecked(hnsw.into(), values);
+        check_map(&map)?;
+        Ok(map)
+    }
+}
+
+fn write<T: Serialize>(mut writer: impl Write, value: &T) -> Result<(), Error> {
+    write_header(&mut writer)?;
+    let mut writer = Checksummed::new(writer);
+    options().serialize_into(&mut writer, value)?;
+    let checksum = writer.hasher.finalize();
+    writer.inner.write_all(&checksum.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_checked<T: DeserializeOwned>(reader: impl Read) -> Result<T, Error> {
+    let mut reader = Checksummed::new(reader);
+    let value = options().deserialize_from(&mut reader)?;
+    let found = reader.hasher.finalize();
+
+    let mut expected = [0; 4];
+    reader.inner.read_exact(&mut expected)?;
+    let expected = u32::from_le_bytes(expected);
+    match expected == found {
+        true => Ok(value),
+        false => Err(Error::ChecksumMismatch { expected, found }),
+    }
+}
+
+/// Check the invariants the search code relies on, so a corrupted index can't cause a panic
+///
+/// The checksum only covers files written in the current format version, and doesn't protect
+/// against indexes that were serialized by buggy or malicious code.
+fn check<P>(hnsw: &Hnsw<P>) -> Result<(), Error> {
+    let len = hnsw.points.len();
+    if len >= u32::MAX as usize {
+        return Err(Error::TooManyPoints(len));
+    }
+
+    // Flat indexes have no graph at all
+    let flat = hnsw.zero.is_empty() && hnsw.layers.is_empty();
+    if hnsw.zero.len() != len && !flat {
+        return Err(Error::InvalidIndex(format!(
+            "{} zero layer nodes for {len} points",
+            hnsw.zero.len()
+        )));
+    }
+
+    let links = |nodes: &[PointId], bound: usize, layer: usize| match nodes
+        .iter()
+        .find(|&&pid| pid != INVALID && pid.0 as usize >= bound)
+    {
+        Some(pid) => Err(Error::InvalidIndex(format!(
+            "layer {layer} links to point {} outside the layer",
+            pid.0
+        ))),
+        None => Ok(()),
+    };
+
+    for node in &hnsw.zero {
+        links(&node.0, len, 0)?;
+    }
+
+    let mut below = len;
+    for (i, layer) in hnsw.layers.iter().enumerate() {
+        if layer.len() > below {
+            return Err(Error::InvalidIndex(format!(
+                "layer {} has more nodes than the layer below it",
+                i + 1
+            )));
+        }
+
+        for node in layer {
+            links(&node.0, layer.len(), i + 1)?;
+        }
+        below = layer.len();
+    }
+
+    if let Some(inputs) = &hnsw.inputs {
+        if inputs.len() != len {
+            return Err(Error::InvalidIndex(format!(
+                "{} input positions for {len} points",
+                inputs.len()
+            )));
+        }
+
+        let mut seen = vec![false; len];
+        for &idx in inputs {
+            match seen.get_mut(idx as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => {
+                    return Err(Error::InvalidIndex(format!(
+                        "input position {idx} is out of range or occurs more than once"
+                    )))
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_map<P, V>(map: &HnswMap<P, V>) -> Result<(), Error> {
+    check(&map.hnsw)?;
+    match map.values.len() == map.hnsw.points.len() {
+        true => Ok(()),
+        false => Err(Error::InvalidIndex(format!(
+            "{} values for {} points",
+            map.values.len(),
+            map.hnsw.points.len()
+        ))),
+    }
+}
+
+fn verified(report: VerifyReport) -> Result<(), Error> {
+    match report.is_ok() {
+        true => Ok(()),
+        false => Err(Error::InvalidIndex(report.to_string())),
+    }
+}
+
+/// Computes a CRC-32 checksum over the bytes passing through a reader or writer
+struct Checksummed<T> {
+    inner: T,
+    hasher: crc32fast::Hasher,
+}
+
+impl<T> Checksummed<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+fn write_header(writer: &mut impl Write) -> Result<(), Error> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_header<R: Read>(mut reader: R) -> Result<Versioned<R>, Error> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        // No header, so this was written before versioning was introduced. Put back the bytes
+        // we consumed so the legacy layout can be read from the start.
+        return Ok(Versioned::Legacy(io::Cursor::new(magic).chain(reader)));
+    }
+
+    let mut version = [0; 4];
+    reader.read_exact(&mut version)?;
+    match u32::from_le_bytes(version) {
+        VERSION => Ok(Versioned::Current(reader)),
+        3 => Ok(Versioned::V3(reader)),
+        2 => Ok(Versioned::V2(reader)),
+        1 => Ok(Versioned::Unchecked(reader)),
+        version => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// The bincode configuration for the body of the format
+///
+/// This spells out the settings of bincode's legacy default configuration (which format version
+/// 0 used), so they can't change from under us.
+pub(crate) fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_little_endian()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+}
+
+enum Versioned<R> {
+    Current(R),
+    /// Format version 3, which didn't record the per-layer limits in the `Config`
+    V3(R),
+    /// Format version 2, which didn't record the input positions of the points
+    V2(R),
+    /// Format version 1, which is the same as version 2 but without a checksum
+    Unchecked(R),
+    Legacy(io::Chain<io::Cursor<[u8; 8]>, R>),
+}
+
+/// Layout of `Config` in format versions 1 to 3
+#[derive(Deserialize)]
+struct V3Config {
+    m: usize,
+    ef_search: usize,
+    ef_construction: usize,
+    ml: f32,
+    seed: u64,
+    heuristic: Option<Heuristic>,
+    metric: String,
+    dims: Option<usize>,
+}
+
+impl From<V3Config> for Config {
+    fn from(v3: V3Config) -> Self {
+        let V3Config {
+            m,
+            ef_search,
+            ef_construction,
+            ml,
+            seed,
+            heuristic,
+            metric,
+            dims,
+        } = v3;
+
+        Self {
+            m,
+            upper_m: Vec::new(),
+            ef_search,
+            ef_construction,
+            ml,
+            seed,
+            heuristic,
+            metric,
+            dims,
+        }
+    }
+}
+
+/// Layout of `Hnsw` in format version 3
+#[derive(Deserialize)]
+struct V3Hnsw<P> {
+    config: V3Config,
+    points: Vec<P>,
+    zero: Vec<ZeroNode>,
+    layers: Vec<Vec<UpperNode>>,
+    inputs: Option<Vec<u32>>,
+}
+
+impl<P> From<V3Hnsw<P>> for Hnsw<P> {
+    fn from(v3: V3Hnsw<P>) -> Self {
+        let V3Hnsw {
+            config,
+            points,
+            zero,
+            layers,
+            inputs,
+        } = v3;
+
+        Self {
+            config: config.into(),
+            points,
+            zero,
+            layers,
+            inputs,
+        }
+    }
+}
+
+/// Layout of `HnswMap` in format version 3
+#[derive(Deserialize)]
+struct V3HnswMap<P, V> {
+    hnsw: V3Hnsw<P>,
+    values: Vec<V>,
+}
+
+impl<P, V> From<V3HnswMap<P, V>> for HnswMap<P, V> {
+    fn from(v3: V3HnswMap<P, V>) -> Self {
+        HnswMap::new_unchecked(v3.hnsw.into(), v3.values)
+    }
+}
+
+/// Layout of `Hnsw` in format versions 1 and 2
+#[derive(Deserialize)]
+struct V2Hnsw<P> {
+    config: V3Config,
+    points: Vec<P>,
+    zero: Vec<ZeroNode>,
+    layers: Vec<Vec<UpperNode>>,
+}
+
+impl<P> From<V2Hnsw<P>> for Hnsw<P> {
+    fn from(v2: V2Hnsw<P>) -> Self {
+        let V2Hnsw {
+            config,
+            points,
+            zero,
+            layers,
+        } = v2;
+
+        Self {
+            config: config.into(),
+            points,
+            zero,
+            layers,
+            inputs: None,
+        }
+    }
+}
+
+/// Layout of `HnswMap` in format versions 1 and 2
+#[derive(Deserialize)]
+struct V2HnswMap<P, V> {
+    hnsw: V2Hnsw<P>,
+    values: Vec<V>,
+}
+
+impl<P, V> From<V2HnswMap<P, V>> for HnswMap<P, V> {
+    fn from(v2: V2HnswMap<P, V>) -> Self {
+        HnswMap::new_unchecked(v2.hnsw.into(), v2.values)
+    }
+}
+
+/// Layout of `Hnsw` in format version 0 and in the upstream crate
+#[derive(Deserialize)]
+struct LegacyHnsw<P> {
+    ef_search: usize,
+    points: Vec<P>,
+    zero: Vec<ZeroNode>,
+    layers: Vec<Vec<UpperNode>>,
+}
+
+impl<P: Point> From<LegacyHnsw<P>> for Hnsw<P> {
+    fn from(legacy: LegacyHnsw<P>) -> Self {
+        let LegacyHnsw {
+            ef_search,
+            points,
+            zero,
+            layers,
+        } = legacy;
+
+        // Format version 0 only recorded `ef_search`; the other build parameters are unknown
+        let config = Config {
+            m: M,
+            upper_m: Vec::new(),
+            ef_search,
+            ef_construction: 0,
+            ml: 0.0,
+            seed: 0,
+            heuristic: None,
+            metric: std::any::type_name::<P>().to_owned(),
+            dims: points.first().and_then(Point::dims),
+        };
+
+        Self {
+            config,
+            points,
+            zero,
+            layers,
+            inputs: None,
+        }
+    }
+}
+
+/// Layout of `HnswMap` in format version 0 and in the upstream crate
+#[derive(Deserialize)]
+struct LegacyHnswMap<P, V> {
+    hnsw: LegacyHnsw<P>,
+    values: Vec<V>,
+}
+
+const MAGIC: [u8; 8] = *b"idhnsw\0\0";
+const VERSION: u32 = 4;
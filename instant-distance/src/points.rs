@@ -0,0 +1,524 @@
+//! Ready-made point types
+//!
+//! The [`Point`] trait can be implemented for any type; the types in this module cover common
+//! cases where points are dense `f32` vectors.
+//!
+//! # Divergences
+//!
+//! [`JensenShannon`] and [`KullbackLeibler`] compare probability distributions, such as topic
+//! model outputs or normalized histograms. The graph construction assumes that distances are
+//! symmetric: the neighbor selection compares distances measured from both ends of an edge. The
+//! Kullback-Leibler divergence is not symmetric, so `KullbackLeibler` uses the symmetrized form;
+//! if the direction matters, rerank the results using [`kl_divergence()`].
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use serde_big_array::BigArray;
+
+use crate::Point;
+
+/// A point backed by a dense vector of `f32` components
+///
+/// This gives metrics and transforms access to the raw components of a point, independent of
+/// how the point type stores them.
+pub trait PointDataSource {
+    /// The components of this point
+    fn data(&self) -> &[f32];
+
+    /// Create a point from its components, the inverse of `data()`
+    ///
+    /// Returns `None` if `data` doesn't describe a valid point of this type, such as when the
+    /// number of components is fixed and doesn't match. The default implementation always
+    /// returns `None`, for point types that can't be recreated from their components.
+    fn compose(data: &[f32]) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        let _ = data;
+        None
+    }
+}
+
+impl PointDataSource for Vec<f32> {
+    fn data(&self) -> &[f32] {
+        self
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        Some(data.to_vec())
+    }
+}
+
+impl<const D: usize> PointDataSource for [f32; D] {
+    fn data(&self) -> &[f32] {
+        self
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        data.try_into().ok()
+    }
+}
+
+/// A point with any number of `f32` components, compared by Euclidean distance
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Vector(pub Vec<f32>);
+
+impl Point for Vector {
+    fn distance(&self, other: &Self) -> f32 {
+        euclidean_bounded(&self.0, &other.0, f32::INFINITY)
+    }
+
+    fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+        euclidean_bounded(&self.0, &other.0, bound)
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+
+    fn is_finite(&self) -> bool {
+        self.0.iter().all(|v| v.is_finite())
+    }
+}
+
+impl PointDataSource for Vector {
+    fn data(&self) -> &[f32] {
+        &self.0
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        Some(Self(data.to_vec()))
+    }
+}
+
+impl From<Vec<f32>> for Vector {
+    fn from(components: Vec<f32>) -> Self {
+        Self(components)
+    }
+}
+
+/// A point with any number of `f32` components, compared by cosine distance
+///
+/// The distance is `1 - cos(a, b)`, from `0.0` for vectors pointing in the same direction to
+/// `2.0` for opposite vectors. The L2 norm of the vector is computed once, when the point is
+/// created, so each distance computation only takes a dot product. Zero vectors are at distance
+/// `1.0` from every other point.
+///
+/// The norms of the components that follow each block of 64 are cached as well. By the
+/// Cauchy-Schwarz inequality, the dot product of the remaining components is at most the product
+/// of their norms, so bounded distance computations skip the rest of a candidate once even that
+/// can't bring it within the bound.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(from = "CosineParts"))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cosine {
+    components: Vec<f32>,
+    norm: f32,
+    /// The norm of the components after each block of `BLOCK`, except the last
+    #[cfg_attr(feature = "serde", serde(skip))]
+    tails: Vec<f32>,
+}
+
+impl Cosine {
+    pub fn new(components: Vec<f32>) -> Self {
+        let norm = components.iter().map(|v| v * v).sum::<f32>().sqrt();
+        Self {
+            tails: tails(&components),
+            components,
+            norm,
+        }
+    }
+
+    /// The L2 norm of the vector
+    pub fn norm(&self) -> f32 {
+        self.norm
+    }
+}
+
+impl Point for Cosine {
+    fn distance(&self, other: &Self) -> f32 {
+        cosine_bounded(self, other, f32::INFINITY)
+    }
+
+    fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+        cosine_bounded(self, other, bound)
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.components.len())
+    }
+
+    fn is_finite(&self) -> bool {
+        self.norm.is_finite()
+    }
+}
+
+/// The serialized fields of a `Cosine`, from which the tail norms are recomputed
+#[cfg(feature = "serde")]
+#[derive(Deserialize)]
+struct CosineParts {
+    components: Vec<f32>,
+    norm: f32,
+}
+
+#[cfg(feature = "serde")]
+impl From<CosineParts> for Cosine {
+    fn from(parts: CosineParts) -> Self {
+        Self {
+            tails: tails(&parts.components),
+            components: parts.components,
+            norm: parts.norm,
+        }
+    }
+}
+
+impl PointDataSource for Cosine {
+    fn data(&self) -> &[f32] {
+        &self.components
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        Some(Self::new(data.to_vec()))
+    }
+}
+
+impl From<Vec<f32>> for Cosine {
+    fn from(components: Vec<f32>) -> Self {
+        Self::new(components)
+    }
+}
+
+/// A point with a dimensionality known at compile time, compared by Euclidean distance
+///
+/// Because the number of components is part of the type, the distance calculation is
+/// monomorphized for each dimensionality, which allows the compiler to fully unroll and
+/// vectorize it. This is typically faster than a `Vec<f32>`-backed point, and avoids storing a
+/// length and a pointer per point.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FixedPoint<const D: usize>(
+    #[cfg_attr(feature = "serde", serde(with = "BigArray"))] pub [f32; D],
+);
+
+impl<const D: usize> Point for FixedPoint<D> {
+    fn distance(&self, other: &Self) -> f32 {
+        // Accumulate into independent lanes, so the compiler doesn't have to preserve the order
+        // of the floating point additions and is free to use SIMD registers
+        let mut lanes = [0.0f32; LANES];
+        let (chunks, rest) = (D / LANES, D % LANES);
+        for chunk in 0..chunks {
+            for (lane, sum) in lanes.iter_mut().enumerate() {
+                let i = chunk * LANES + lane;
+                let diff = self.0[i] - other.0[i];
+                *sum += diff * diff;
+            }
+        }
+
+        for (lane, sum) in lanes.iter_mut().enumerate().take(rest) {
+            let i = chunks * LANES + lane;
+            let diff = self.0[i] - other.0[i];
+            *sum += diff * diff;
+        }
+
+        lanes.iter().sum::<f32>().sqrt()
+    }
+
+    fn distance_bounded(&self, other: &Self, bound: f32) -> f32 {
+        euclidean_bounded(&self.0, &other.0, bound)
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(D)
+    }
+
+    fn is_finite(&self) -> bool {
+        self.0.iter().all(|v| v.is_finite())
+    }
+}
+
+impl<const D: usize> PointDataSource for FixedPoint<D> {
+    fn data(&self) -> &[f32] {
+        &self.0
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        data.try_into().ok().map(Self)
+    }
+}
+
+impl<const D: usize> From<[f32; D]> for FixedPoint<D> {
+    fn from(components: [f32; D]) -> Self {
+        Self(components)
+    }
+}
+
+impl<const D: usize> Default for FixedPoint<D> {
+    fn default() -> Self {
+        Self([0.0; D])
+    }
+}
+
+/// A probability distribution compared by the square root of the Jensen-Shannon divergence
+///
+/// The components should be non-negative and sum to one. The Jensen-Shannon divergence is
+/// symmetric and always finite, and its square root is a metric, ranging from `0.0` for equal
+/// distributions to `1.0` for distributions with disjoint support.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct JensenShannon(pub Vec<f32>);
+
+impl Point for JensenShannon {
+    fn distance(&self, other: &Self) -> f32 {
+        js_divergence(&self.0, &other.0).sqrt()
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+
+    fn is_finite(&self) -> bool {
+        self.0.iter().all(|v| v.is_finite())
+    }
+}
+
+impl PointDataSource for JensenShannon {
+    fn data(&self) -> &[f32] {
+        &self.0
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        Some(Self(data.to_vec()))
+    }
+}
+
+/// A probability distribution compared by the symmetrized Kullback-Leibler divergence
+///
+/// The distance is `(KL(p || q) + KL(q || p)) / 2`, in bits. The components should be
+/// non-negative and sum to one. If a component is zero in one distribution and not in the
+/// other, the divergence is infinite; smooth the distributions (for example by adding a small
+/// constant to every component before normalizing) if that is possible in your data, or use
+/// [`JensenShannon`] instead.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KullbackLeibler(pub Vec<f32>);
+
+impl Point for KullbackLeibler {
+    fn distance(&self, other: &Self) -> f32 {
+        (kl_divergence(&self.0, &other.0) + kl_divergence(&other.0, &self.0)) / 2.0
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+
+    fn is_finite(&self) -> bool {
+        self.0.iter().all(|v| v.is_finite())
+    }
+}
+
+impl PointDataSource for KullbackLeibler {
+    fn data(&self) -> &[f32] {
+        &self.0
+    }
+
+    fn compose(data: &[f32]) -> Option<Self> {
+        Some(Self(data.to_vec()))
+    }
+}
+
+/// A set of small integers, packed into 64-bit words
+///
+/// This is the storage for binary point types, such as [`Jaccard`]. Bit `i` is stored in bit
+/// `i % 64` of word `i / 64`.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BitSet(pub Vec<u64>);
+
+impl BitSet {
+    /// Create an empty set with room for the values `0..bits`
+    pub fn with_capacity(bits: usize) -> Self {
+        Self(vec![0; (bits + 63) / 64])
+    }
+
+    /// Add `value` to the set, growing the storage if necessary
+    pub fn insert(&mut self, value: usize) {
+        let word = value / 64;
+        if word >= self.0.len() {
+            self.0.resize(word + 1, 0);
+        }
+        self.0[word] |= 1 << (value % 64);
+    }
+
+    /// Whether the set contains `value`
+    pub fn contains(&self, value: usize) -> bool {
+        match self.0.get(value / 64) {
+            Some(word) => word & (1 << (value % 64)) != 0,
+            None => false,
+        }
+    }
+
+    /// The number of values in the set
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    /// Whether the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&word| word == 0)
+    }
+
+    /// The number of values in both sets and the number of values in either set
+    pub fn intersection_union(&self, other: &Self) -> (usize, usize) {
+        let (short, long) = match self.0.len() <= other.0.len() {
+            true => (&self.0, &other.0),
+            false => (&other.0, &self.0),
+        };
+
+        let (mut intersection, mut union) = (0, 0);
+        for (a, b) in short.iter().zip(long) {
+            intersection += (a & b).count_ones() as usize;
+            union += (a | b).count_ones() as usize;
+        }
+
+        let rest = long[short.len()..]
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum::<usize>();
+        (intersection, union + rest)
+    }
+}
+
+impl FromIterator<usize> for BitSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> Self {
+        let mut set = Self::default();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
+
+/// A set compared by Jaccard distance, `1 - |A ∩ B| / |A ∪ B|`
+///
+/// Useful for tag sets and MinHash signatures encoded as bits. The distance between two empty
+/// sets is `0.0`. The sets don't need to have the same storage length.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Jaccard(pub BitSet);
+
+impl Point for Jaccard {
+    fn distance(&self, other: &Self) -> f32 {
+        match self.0.intersection_union(&other.0) {
+            (_, 0) => 0.0,
+            (intersection, union) => 1.0 - intersection as f32 / union as f32,
+        }
+    }
+}
+
+/// The Kullback-Leibler divergence `KL(p || q)` in bits
+///
+/// Components where `p` is zero don't contribute; components where only `q` is zero make the
+/// divergence infinite.
+pub fn kl_divergence(p: &[f32], q: &[f32]) -> f32 {
+    p.iter()
+        .zip(q)
+        .filter(|(&p, _)| p > 0.0)
+        .map(|(&p, &q)| p * (p / q).log2())
+        .sum()
+}
+
+/// The Jensen-Shannon divergence between `p` and `q` in bits, from `0.0` to `1.0`
+pub fn js_divergence(p: &[f32], q: &[f32]) -> f32 {
+    let sum = p
+        .iter()
+        .zip(q)
+        .map(|(&p, &q)| {
+            let m = (p + q) / 2.0;
+            let term = |x: f32| match x > 0.0 {
+                true => x * (x / m).log2(),
+                false => 0.0,
+            };
+            term(p) + term(q)
+        })
+        .sum::<f32>();
+
+    // Rounding errors can make the sum slightly negative for (nearly) equal distributions
+    (sum / 2.0).max(0.0)
+}
+
+/// The Euclidean distance between `a` and `b`, or `f32::INFINITY` if it exceeds `bound`
+///
+/// The squared distance is accumulated in blocks of `BLOCK` components, checking against the
+/// bound in between, so far-away points are rejected after computing only part of the distance.
+/// The components are summed in the same order as in `FixedPoint::distance()`, so both give the
+/// same result when the bound isn't exceeded.
+fn euclidean_bounded(a: &[f32], b: &[f32], bound: f32) -> f32 {
+    let mut lanes = [0.0f32; LANES];
+    for (a, b) in a.chunks(BLOCK).zip(b.chunks(BLOCK)) {
+        for (i, (a, b)) in a.iter().zip(b).enumerate() {
+            let diff = a - b;
+            lanes[i % LANES] += diff * diff;
+        }
+
+        // Compare the distance rather than squaring the bound, which could round it down
+        if lanes.iter().sum::<f32>().sqrt() > bound {
+            return f32::INFINITY;
+        }
+    }
+
+    lanes.iter().sum::<f32>().sqrt()
+}
+
+/// The cosine distance between `a` and `b`, or `f32::INFINITY` once it must exceed `bound`
+fn cosine_bounded(a: &Cosine, b: &Cosine, bound: f32) -> f32 {
+    let norms = a.norm * b.norm;
+    if norms == 0.0 {
+        return 1.0;
+    }
+
+    let mut dot = 0.0f32;
+    let blocks = a.components.chunks(BLOCK).zip(b.components.chunks(BLOCK));
+    for (i, (x, y)) in blocks.enumerate() {
+        for (x, y) in x.iter().zip(y) {
+            dot += x * y;
+        }
+
+        // The remaining components add at most the product of their norms to the dot product
+        if let (Some(rest_a), Some(rest_b)) = (a.tails.get(i), b.tails.get(i)) {
+            let nearest = 1.0 - (dot + rest_a * rest_b) / norms;
+            if nearest - COSINE_SLACK > bound {
+                return f32::INFINITY;
+            }
+        }
+    }
+
+    // Rounding errors can push the cosine slightly outside of `-1.0..=1.0`
+    (1.0 - dot / norms).clamp(0.0, 2.0)
+}
+
+/// The norms of the components after each block of `BLOCK`, except the last
+fn tails(components: &[f32]) -> Vec<f32> {
+    let mut tails = components
+        .chunks(BLOCK)
+        .skip(1)
+        .rev()
+        .scan(0.0f32, |sum, block| {
+            *sum += block.iter().map(|v| v * v).sum::<f32>();
+            Some(sum.sqrt())
+        })
+        .collect::<Vec<_>>();
+    tails.reverse();
+    tails
+}
+
+/// The number of independent accumulators used by distance kernels
+const LANES: usize = 8;
+
+/// The number of components between checks in `euclidean_bounded()` and `cosine_bounded()`
+const BLOCK: usize = 64;
+
+/// Room for rounding errors in the Cauchy-Schwarz bound of `cosine_bounded()`, so that points
+/// within the bound are never cut short
+const COSINE_SLACK: f32 = 1e-4;
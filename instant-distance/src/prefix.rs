@@ -0,0 +1,146 @@
+//! Indexes that traverse the graph using a prefix of each vector
+//!
+//! Embeddings trained with Matryoshka representation learning keep most of their information in
+//! the leading dimensions, so a prefix (for example, the first 64 of 768 dimensions) is enough
+//! to find good candidates. A [`PrefixIndex`] builds its graph over these prefixes, which cuts
+//! the memory traffic of the graph traversal, then reranks the candidates using the full
+//! vectors.
+
+use ordered_float::OrderedFloat;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::points::{PointDataSource, Vector};
+use crate::{Builder, Error, HnswMap, PointId, Search};
+
+/// An index searching vector prefixes, reranked with the full vectors
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct PrefixIndex<V> {
+    map: HnswMap<Vector, V>,
+    prefix: usize,
+    dims: usize,
+    /// The full vectors in `PointId` order, concatenated
+    full: Vec<f32>,
+}
+
+impl<V: Clone> PrefixIndex<V> {
+    /// Build an index for `points`, traversing the graph using their first `prefix` dimensions
+    ///
+    /// Performs the same checks as [`Builder::try_build()`]; `prefix` must be between 1 and the
+    /// dimensionality of the points.
+    pub fn build<S: PointDataSource>(
+        builder: Builder,
+        prefix: usize,
+        points: &[S],
+        values: Vec<V>,
+    ) -> Result<Self, Error> {
+        let dims = points.first().map_or(prefix, |point| point.data().len());
+        if prefix == 0 || prefix > dims {
+            return Err(Error::InvalidParameter {
+                name: "prefix",
+                reason: "must be between 1 and the number of dimensions",
+            });
+        }
+
+        for (index, point) in points.iter().enumerate() {
+            if point.data().len() != dims {
+                return Err(Error::DimensionMismatch {
+                    index,
+                    expected: dims,
+                    found: point.data().len(),
+                });
+            }
+        }
+
+        if points.len() != values.len() {
+            return Err(Error::LengthMismatch {
+                points: points.len(),
+                values: values.len(),
+            });
+        }
+
+        let prefixes = points
+            .iter()
+            .map(|point| Vector(point.data()[..prefix].to_vec()))
+            .collect();
+        let (hnsw, pids) = builder.try_build_hnsw(prefixes)?;
+
+        // Store the values and the full vectors in the order of the points in the graph
+        let mut full = vec![0.0; dims * points.len()];
+        let mut sorted = Vec::with_capacity(points.len());
+        for ((point, value), pid) in points.iter().zip(values).zip(pids) {
+            let start = pid.into_inner() as usize * dims;
+            full[start..start + dims].copy_from_slice(point.data());
+            sorted.push((pid, value));
+        }
+
+        sorted.sort_unstable_by_key(|(pid, _)| *pid);
+        let values = sorted.into_iter().map(|(_, value)| value).collect();
+        let map = HnswMap::new_unchecked(hnsw, values);
+        Ok(Self {
+            map,
+            prefix,
+            dims,
+            full,
+        })
+    }
+
+    /// Find the `k` points nearest to `query`
+    ///
+    /// All candidates found by the graph search (up to `ef_search` of them) are reranked using
+    /// their full vectors. Returns the values of the nearest points and their Euclidean
+    /// distances over all dimensions, nearest first.
+    ///
+    /// Panics if `query` doesn't have the same dimensionality as the indexed points.
+    pub fn search<'a>(&'a self, query: &[f32], k: usize, search: &mut Search) -> Vec<(&'a V, f32)> {
+        assert_eq!(
+            query.len(),
+            self.dims,
+            "query has the wrong number of dimensions"
+        );
+
+        let prefix = Vector(query[..self.prefix].to_vec());
+        let mut reranked = self
+            .map
+            .search(&prefix, search)
+            .map(|item| (item.pid, self.distance(query, item.pid)))
+            .collect::<Vec<_>>();
+
+        reranked.sort_unstable_by_key(|&(_, distance)| OrderedFloat(distance));
+        reranked.truncate(k);
+        reranked
+            .into_iter()
+            .map(|(pid, distance)| (&self.map.values[pid.into_inner() as usize], distance))
+            .collect()
+    }
+
+    /// The number of dimensions used to traverse the graph
+    pub fn prefix(&self) -> usize {
+        self.prefix
+    }
+
+    /// The full number of dimensions of the indexed points
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    /// The number of points in this index
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    fn distance(&self, query: &[f32], pid: PointId) -> f32 {
+        let start = pid.into_inner() as usize * self.dims;
+        query
+            .iter()
+            .zip(&self.full[start..start + self.dims])
+            .map(|(a, b)| (a - b) * (a - b))
+            .sum::<f32>()
+            .sqrt()
+    }
+}
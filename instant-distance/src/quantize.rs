@@ -0,0 +1,395 @@
+//! Compact encodings for `f32` vectors
+//!
+//! Quantized vectors take a fraction of the memory of the original vectors, at the cost of some
+//! precision in distance calculations. They are typically indexed in place of the original
+//! vectors, with the exact vectors used only to rerank the final candidates.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::points::PointDataSource;
+use crate::train::{squared_distance, AnisotropicLoss, Centroids, KMeans};
+use crate::{Error, Point};
+
+/// Scalar quantizer mapping each component to 8 bits
+///
+/// All components share a single range, derived from the smallest and largest component of the
+/// training vectors. Because of that, Euclidean distances between codes are proportional to the
+/// distances between the decoded vectors, so codes can be indexed directly.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sq8 {
+    min: f32,
+    scale: f32,
+}
+
+impl Sq8 {
+    /// Derive the quantization range from a set of training vectors
+    pub fn train<V: AsRef<[f32]>>(vectors: impl IntoIterator<Item = V>) -> Self {
+        let (min, scale) = train_range(vectors, 255.0);
+        Self { min, scale }
+    }
+
+    /// Encode a vector, clamping components that fall outside the trained range
+    pub fn encode(&self, vector: &[f32]) -> Sq8Code {
+        Sq8Code(
+            vector
+                .iter()
+                .map(|&value| ((value - self.min) / self.scale).round().clamp(0.0, 255.0) as u8)
+                .collect(),
+        )
+    }
+
+    /// Approximately reconstruct the vector for `code`
+    pub fn decode(&self, code: &Sq8Code) -> Vec<f32> {
+        code.0
+            .iter()
+            .map(|&byte| self.min + byte as f32 * self.scale)
+            .collect()
+    }
+
+    /// The size of one quantization step, which converts code distances to vector distances
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+}
+
+/// A vector encoded by [`Sq8`]
+///
+/// Distances between codes are measured in quantization steps; multiply by [`Sq8::scale()`] to
+/// get (approximate) distances between the original vectors.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sq8Code(pub Vec<u8>);
+
+impl Point for Sq8Code {
+    fn distance(&self, other: &Self) -> f32 {
+        let sum = self
+            .0
+            .iter()
+            .zip(&other.0)
+            .map(|(&a, &b)| {
+                let diff = a as i32 - b as i32;
+                (diff * diff) as u32
+            })
+            .sum::<u32>();
+        (sum as f32).sqrt()
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+/// Scalar quantizer mapping each component to 4 bits
+///
+/// Like [`Sq8`], but with 16 levels per component, packed two components per byte. Codes take
+/// half the memory of `Sq8` codes, at the cost of a coarser approximation of the distances.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sq4 {
+    min: f32,
+    scale: f32,
+}
+
+impl Sq4 {
+    /// Derive the quantization range from a set of training vectors
+    pub fn train<V: AsRef<[f32]>>(vectors: impl IntoIterator<Item = V>) -> Self {
+        let (min, scale) = train_range(vectors, 15.0);
+        Self { min, scale }
+    }
+
+    /// Encode a vector, clamping components that fall outside the trained range
+    pub fn encode(&self, vector: &[f32]) -> Sq4Code {
+        let level = |value: f32| ((value - self.min) / self.scale).round().clamp(0.0, 15.0) as u8;
+        Sq4Code {
+            data: vector
+                .chunks(2)
+                .map(|pair| level(pair[0]) | pair.get(1).map_or(0, |&value| level(value) << 4))
+                .collect(),
+            len: vector.len(),
+        }
+    }
+
+    /// Approximately reconstruct the vector for `code`
+    pub fn decode(&self, code: &Sq4Code) -> Vec<f32> {
+        (0..code.len)
+            .map(|i| self.min + code.get(i) as f32 * self.scale)
+            .collect()
+    }
+
+    /// The size of one quantization step, which converts code distances to vector distances
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+}
+
+/// A vector encoded by [`Sq4`]
+///
+/// Component `2 * i` is stored in the low nibble of byte `i`, and component `2 * i + 1` in the
+/// high nibble. For vectors with an odd number of components, the last high nibble is zero.
+///
+/// Distances between codes are measured in quantization steps; multiply by [`Sq4::scale()`] to
+/// get (approximate) distances between the original vectors.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sq4Code {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl Sq4Code {
+    /// The quantization level of component `index`, from 0 to 15
+    ///
+    /// Panics if `index` is out of bounds.
+    pub fn get(&self, index: usize) -> u8 {
+        assert!(index < self.len, "component index out of bounds");
+        (self.data[index / 2] >> (index % 2 * 4)) & 0x0f
+    }
+
+    /// The packed components
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The number of components
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the code has no components
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Point for Sq4Code {
+    /// Looks up the squared differences of both nibbles of each byte pair in a table
+    ///
+    /// The loop is split into independent lanes, so the lookups and additions of neighboring
+    /// bytes don't wait on each other.
+    fn distance(&self, other: &Self) -> f32 {
+        let diff = |a: u8, b: u8| {
+            let (lo, hi) = ((a & 0x0f) << 4 | (b & 0x0f), (a & 0xf0) | (b >> 4));
+            SQUARED_DIFFS[lo as usize] as u32 + SQUARED_DIFFS[hi as usize] as u32
+        };
+
+        let (a, b) = (&self.data, &other.data);
+        let mut lanes = [0u32; LANES];
+        let (a_chunks, b_chunks) = (a.chunks_exact(LANES), b.chunks_exact(LANES));
+        let (a_rest, b_rest) = (a_chunks.remainder(), b_chunks.remainder());
+        for (a, b) in a_chunks.zip(b_chunks) {
+            for i in 0..LANES {
+                lanes[i] += diff(a[i], b[i]);
+            }
+        }
+
+        let rest = a_rest.iter().zip(b_rest).map(|(&a, &b)| diff(a, b));
+        let sum = lanes.iter().sum::<u32>() + rest.sum::<u32>();
+        (sum as f32).sqrt()
+    }
+
+    fn dims(&self) -> Option<usize> {
+        Some(self.len)
+    }
+}
+
+/// Residual vector quantizer with a codebook of 256 vectors per stage
+///
+/// Each stage quantizes what is left of the vector after the previous stages: the first stage
+/// picks the nearest centroid of its codebook, the second stage the centroid nearest to the
+/// difference between the vector and the first centroid, and so on. A code takes one byte per
+/// stage and decodes to the sum of the selected centroids. Compared to product quantization
+/// with the same code size, each stage covers all dimensions, which usually gives a more
+/// accurate approximation.
+///
+/// Distances between codes depend on the codebooks, so codes can't be indexed directly; use
+/// [`Residual::distance()`] or [`Residual::inner_product()`] to score codes against a query, for
+/// example to rerank candidates.
+///
+/// For maximum inner product search, [`Residual::train_anisotropic()`] trains and encodes with
+/// an [`AnisotropicLoss`], which keeps the inner products of each vector with the queries it
+/// scores highest for more accurate than minimizing the Euclidean error does.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Residual {
+    dims: usize,
+    codebooks: Vec<Centroids>,
+    loss: Option<AnisotropicLoss>,
+}
+
+impl Residual {
+    /// Train a quantizer with `stages` stages on `sample`
+    ///
+    /// The codebooks are trained with k-means, one stage at a time, on the residuals left by
+    /// the previous stages. Training is deterministic for a given `seed`.
+    pub fn train<S: PointDataSource>(
+        sample: &[S],
+        stages: usize,
+        seed: u64,
+    ) -> Result<Self, Error> {
+        Self::train_with(sample, stages, None, seed)
+    }
+
+    /// Train a quantizer with `stages` stages on `sample`, minimizing the anisotropic `loss`
+    ///
+    /// At every stage, the loss is measured along the original vector, so the parallel part of
+    /// the error left after all stages is what's kept small. Codes are selected with the same
+    /// loss by [`Residual::encode()`].
+    pub fn train_anisotropic<S: PointDataSource>(
+        sample: &[S],
+        stages: usize,
+        loss: AnisotropicLoss,
+        seed: u64,
+    ) -> Result<Self, Error> {
+        Self::train_with(sample, stages, Some(loss), seed)
+    }
+
+    fn train_with<S: PointDataSource>(
+        sample: &[S],
+        stages: usize,
+        loss: Option<AnisotropicLoss>,
+        seed: u64,
+    ) -> Result<Self, Error> {
+        if stages == 0 {
+            return Err(Error::InvalidParameter {
+                name: "stages",
+                reason: "must be at least 1",
+            });
+        }
+
+        let vectors = sample.iter().map(|point| point.data()).collect::<Vec<_>>();
+        let mut residuals = vectors.iter().map(|v| v.to_vec()).collect::<Vec<_>>();
+        let mut quantizer = Self {
+            dims: 0,
+            codebooks: Vec::with_capacity(stages),
+            loss,
+        };
+        for stage in 0..stages {
+            let codebook = KMeans::new(CODEBOOK_SIZE)
+                .seed(seed ^ stage as u64)
+                .anisotropic(loss)
+                .train_residuals(&residuals, &vectors)?;
+            for (residual, vector) in residuals.iter_mut().zip(&vectors) {
+                let centroid = codebook.get(quantizer.select(&codebook, residual, vector));
+                residual.iter_mut().zip(centroid).for_each(|(r, c)| *r -= c);
+            }
+            quantizer.codebooks.push(codebook);
+        }
+
+        quantizer.dims = residuals[0].len();
+        Ok(quantizer)
+    }
+
+    /// The centroid of `codebook` to quantize the `residual` of `vector` with
+    fn select(&self, codebook: &Centroids, residual: &[f32], vector: &[f32]) -> usize {
+        match &self.loss {
+            Some(loss) => codebook.nearest_anisotropic(residual, vector, loss).0,
+            None => codebook.nearest(residual).0,
+        }
+    }
+
+    /// Encode a vector, selecting the nearest centroid at each stage
+    ///
+    /// Quantizers trained with an anisotropic loss select the centroid with the smallest loss
+    /// instead. Panics if `vector` doesn't have the dimensionality the quantizer was trained on.
+    pub fn encode(&self, vector: &[f32]) -> ResidualCode {
+        assert_eq!(vector.len(), self.dims, "vector dimensionality mismatch");
+        let mut residual = vector.to_vec();
+        ResidualCode(
+            self.codebooks
+                .iter()
+                .map(|codebook| {
+                    let i = self.select(codebook, &residual, vector);
+                    let centroid = codebook.get(i);
+                    residual.iter_mut().zip(centroid).for_each(|(r, c)| *r -= c);
+                    i as u8
+                })
+                .collect(),
+        )
+    }
+
+    /// Approximately reconstruct the vector for `code`
+    pub fn decode(&self, code: &ResidualCode) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dims];
+        for (codebook, &i) in self.codebooks.iter().zip(&code.0) {
+            let centroid = codebook.get(i as usize);
+            vector.iter_mut().zip(centroid).for_each(|(v, c)| *v += c);
+        }
+        vector
+    }
+
+    /// The Euclidean distance between `query` and the vector `code` decodes to
+    pub fn distance(&self, query: &[f32], code: &ResidualCode) -> f32 {
+        squared_distance(query, &self.decode(code)).sqrt()
+    }
+
+    /// The inner product of `query` and the vector `code` decodes to
+    pub fn inner_product(&self, query: &[f32], code: &ResidualCode) -> f32 {
+        query
+            .iter()
+            .zip(self.decode(code))
+            .map(|(q, v)| q * v)
+            .sum()
+    }
+
+    /// The anisotropic loss the quantizer was trained with, if any
+    pub fn loss(&self) -> Option<&AnisotropicLoss> {
+        self.loss.as_ref()
+    }
+
+    /// The number of stages, which is the size of a code in bytes
+    pub fn stages(&self) -> usize {
+        self.codebooks.len()
+    }
+
+    /// The dimensionality of the vectors this quantizer encodes
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+}
+
+/// A vector encoded by [`Residual`], with the selected centroid of each stage
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResidualCode(pub Vec<u8>);
+
+/// The smallest component and the size of a quantization step with `levels` steps
+fn train_range<V: AsRef<[f32]>>(vectors: impl IntoIterator<Item = V>, levels: f32) -> (f32, f32) {
+    let (mut min, mut max) = (f32::INFINITY, f32::NEG_INFINITY);
+    for vector in vectors {
+        for &value in vector.as_ref() {
+            min = min.min(value);
+            max = max.max(value);
+        }
+    }
+
+    if min > max {
+        // No training data
+        return (0.0, 1.0);
+    }
+
+    let scale = match max - min {
+        range if range > 0.0 => range / levels,
+        _ => 1.0,
+    };
+    (min, scale)
+}
+
+/// Squared difference between the nibbles `i >> 4` and `i & 0x0f`
+const SQUARED_DIFFS: [u8; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let diff = (i >> 4) as i32 - (i & 0x0f) as i32;
+        table[i] = (diff * diff) as u8;
+        i += 1;
+    }
+    table
+};
+
+/// Number of independent accumulators in `Sq4Code::distance()`
+const LANES: usize = 8;
+
+/// Number of centroids per stage of a `Residual` quantizer, so each stage fits in a byte
+const CODEBOOK_SIZE: usize = 256;
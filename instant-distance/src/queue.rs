@@ -0,0 +1,121 @@
+//! The queue of candidates to expand during a search (`C` in the paper)
+//!
+//! By default this is a binary heap. With the `pairing-heap` feature, a pairing heap is used
+//! instead: pushes are constant-time, which suits searches that push many more candidates than
+//! they pop, as searches with a small `ef` do. Compare the two with `cargo bench -- search`, with
+//! and without the feature.
+
+#[cfg(not(feature = "pairing-heap"))]
+use std::cmp::Reverse;
+#[cfg(not(feature = "pairing-heap"))]
+use std::collections::BinaryHeap;
+
+use crate::types::Candidate;
+
+/// A min-queue of candidates, popping the nearest candidate first
+#[cfg(not(feature = "pairing-heap"))]
+#[derive(Clone, Default)]
+pub(crate) struct CandidateQueue(BinaryHeap<Reverse<Candidate>>);
+
+#[cfg(not(feature = "pairing-heap"))]
+impl CandidateQueue {
+    pub(crate) fn push(&mut self, candidate: Candidate) {
+        self.0.push(Reverse(candidate));
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Candidate> {
+        self.0.pop().map(|Reverse(candidate)| candidate)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// A min-queue of candidates, popping the nearest candidate first
+///
+/// The nodes are kept in an arena that is only emptied by `clear()`; a search pushes each point
+/// at most once, so the arena doesn't grow beyond the number of visited points.
+#[cfg(feature = "pairing-heap")]
+#[derive(Clone, Default)]
+pub(crate) struct CandidateQueue {
+    nodes: Vec<Node>,
+    root: Option<u32>,
+    /// Scratch space for the subtrees of the popped root
+    pairs: Vec<u32>,
+}
+
+#[cfg(feature = "pairing-heap")]
+impl CandidateQueue {
+    pub(crate) fn push(&mut self, candidate: Candidate) {
+        let idx = self.nodes.len() as u32;
+        self.nodes.push(Node {
+            candidate,
+            child: None,
+            sibling: None,
+        });
+        self.root = Some(match self.root {
+            Some(root) => self.meld(root, idx),
+            None => idx,
+        });
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<Candidate> {
+        let root = self.root?;
+        let node = self.nodes[root as usize];
+
+        // Meld the subtrees in pairs from left to right, then the pairs from right to left
+        self.pairs.clear();
+        let mut next = node.child;
+        while let Some(first) = next {
+            match self.nodes[first as usize].sibling {
+                Some(second) => {
+                    next = self.nodes[second as usize].sibling;
+                    self.nodes[first as usize].sibling = None;
+                    self.nodes[second as usize].sibling = None;
+                    let melded = self.meld(first, second);
+                    self.pairs.push(melded);
+                }
+                None => {
+                    next = None;
+                    self.pairs.push(first);
+                }
+            }
+        }
+
+        self.root = self.pairs.pop();
+        while let Some(pair) = self.pairs.pop() {
+            self.root = self.root.map(|root| self.meld(pair, root));
+        }
+
+        Some(node.candidate)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.root = None;
+    }
+
+    /// Make the root with the larger candidate the first child of the other root
+    fn meld(&mut self, a: u32, b: u32) -> u32 {
+        let (parent, child) =
+            match self.nodes[a as usize].candidate <= self.nodes[b as usize].candidate {
+                true => (a, b),
+                false => (b, a),
+            };
+
+        self.nodes[child as usize].sibling = self.nodes[parent as usize].child;
+        self.nodes[parent as usize].child = Some(child);
+        parent
+    }
+}
+
+#[cfg(feature = "pairing-heap")]
+#[derive(Clone, Copy)]
+struct Node {
+    candidate: Candidate,
+    /// The first child of this node
+    child: Option<u32>,
+    /// The next child of this node's parent
+    sibling: Option<u32>,
+}
@@ -0,0 +1,144 @@
+//! Conversion of an `Hnsw` to and from plain buffers, for custom storage
+
+use crate::types::{PointId, UpperNode, ZeroNode, INVALID};
+use crate::{Config, Error, Hnsw, Point, M};
+
+/// The contents of an [`Hnsw`] as owned buffers
+///
+/// Created with [`Hnsw::into_raw_parts()`] and turned back into an index with
+/// [`Hnsw::from_raw_parts()`], so indexes can be stored or transported without serde. The
+/// buffers can be written out in any format, as long as they are restored unchanged.
+#[derive(Clone, Debug)]
+pub struct RawParts<P> {
+    /// The parameters the index was built with
+    pub config: Config,
+    /// The points, in `PointId` order
+    pub points: Vec<P>,
+    /// The position of each point in the slice the index was built from, in `PointId` order
+    ///
+    /// `None` if the index doesn't know the input order (see [`Item::index`](crate::Item::index)).
+    pub order: Option<Vec<u32>>,
+    /// The neighbor lists of each layer, from the bottom layer up
+    ///
+    /// Each node on a layer takes up a fixed number of slots: [`RawParts::ZERO_SLOTS`] on the
+    /// bottom layer and [`RawParts::UPPER_SLOTS`] on the upper layers. A node's neighbors come
+    /// first, nearest first, followed by `u32::MAX` in the unused slots. Empty for flat indexes.
+    pub neighbors: Vec<Vec<u32>>,
+}
+
+impl<P> RawParts<P> {
+    /// The number of neighbor slots per node on the bottom layer
+    pub const ZERO_SLOTS: usize = M * 2;
+    /// The number of neighbor slots per node on the upper layers
+    pub const UPPER_SLOTS: usize = M;
+}
+
+impl<P: Point> Hnsw<P> {
+    /// Split the index into plain buffers
+    pub fn into_raw_parts(self) -> RawParts<P> {
+        let Self {
+            config,
+            points,
+            zero,
+            layers,
+            inputs,
+        } = self;
+
+        let mut neighbors = Vec::with_capacity(layers.len() + 1);
+        if !zero.is_empty() || !layers.is_empty() {
+            neighbors.push(
+                zero.iter()
+                    .flat_map(|node| node.0)
+                    .map(|pid| pid.0)
+                    .collect(),
+            );
+        }
+
+        for layer in &layers {
+            neighbors.push(
+                layer
+                    .iter()
+                    .flat_map(|node| node.0)
+                    .map(|pid| pid.0)
+                    .collect(),
+            );
+        }
+
+        RawParts {
+            config,
+            points,
+            order: inputs,
+            neighbors,
+        }
+    }
+
+    /// Reassemble an index from the buffers returned by [`Hnsw::into_raw_parts()`]
+    ///
+    /// The parts are checked with [`Hnsw::verify()`], so this returns [`Error::InvalidIndex`]
+    /// rather than an index that could return poor results or panic during a search.
+    pub fn from_raw_parts(parts: RawParts<P>) -> Result<Self, Error> {
+        let RawParts {
+            config,
+            points,
+            order,
+            neighbors,
+        } = parts;
+
+        let mut layers = neighbors.iter().enumerate().map(|(layer, slots)| {
+            let width = match layer {
+                0 => RawParts::<P>::ZERO_SLOTS,
+                _ => RawParts::<P>::UPPER_SLOTS,
+            };
+
+            match slots.len() % width {
+                0 => Ok(slots.chunks_exact(width)),
+                _ => Err(Error::InvalidIndex(format!(
+                    "layer {layer} has {} slots, which is not a multiple of {width}",
+                    slots.len()
+                ))),
+            }
+        });
+
+        let zero = match layers.next() {
+            Some(nodes) => nodes?
+                .map(|slots| {
+                    let mut node = ZeroNode([INVALID; M * 2]);
+                    node.0
+                        .iter_mut()
+                        .zip(slots)
+                        .for_each(|(n, &s)| *n = PointId(s));
+                    node
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let layers = layers
+            .map(|nodes| {
+                let nodes = nodes?.map(|slots| {
+                    let mut node = UpperNode([INVALID; M]);
+                    node.0
+                        .iter_mut()
+                        .zip(slots)
+                        .for_each(|(n, &s)| *n = PointId(s));
+                    node
+                });
+                Ok(nodes.collect())
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let hnsw = Self {
+            config,
+            points,
+            zero,
+            layers,
+            inputs: order,
+        };
+
+        let report = hnsw.verify();
+        match report.is_ok() {
+            true => Ok(hnsw),
+            false => Err(Error::InvalidIndex(report.to_string())),
+        }
+    }
+}
@@ -0,0 +1,257 @@
+//! Recording and replaying the random decisions of a build, set with
+//! [`Builder::record()`](crate::Builder::record) and [`Builder::replay()`](crate::Builder::replay)
+//!
+//! A recording holds the numbers drawn from the random number generator, the layer assigned to
+//! each point, the resulting insertion order and the order in which the worker threads started
+//! inserting the points. A replay feeds the recorded numbers to the layer assignment and the
+//! shuffle, checks that they lead to the recorded layers and insertion order, and then inserts
+//! the points one at a time in the order they were started in the recorded build.
+//!
+//! Concurrent insertions start in a different order on every build, so a replay turns one
+//! particular build into a deterministic, single-threaded one that can be rerun under a
+//! debugger. The overlap between concurrent insertions isn't recorded, so bugs that depend on
+//! two insertions running at the same moment don't necessarily reproduce.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use parking_lot::Mutex;
+use rand::RngCore;
+
+use crate::{Error, PointId};
+
+/// A recording or replay, shared by the clones of a `Builder`
+pub(crate) struct Session {
+    path: PathBuf,
+    replay: bool,
+    state: Mutex<State>,
+}
+
+impl Session {
+    pub(crate) fn new(path: PathBuf, replay: bool) -> Self {
+        Self {
+            path,
+            replay,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Start a build, wrapping the generator that would otherwise be used
+    ///
+    /// A replay reads the recording here, and draws from it instead of from `rng`.
+    pub(crate) fn rng<'a>(&self, rng: &'a mut dyn RngCore) -> Result<SessionRng<'a>, Error> {
+        let mut state = self.state.lock();
+        *state = State::default();
+        match self.replay {
+            true => {
+                state.recording = Recording::read(&self.path)?;
+                let draws = state.recording.draws.clone();
+                Ok(SessionRng::Replay { draws, next: 0 })
+            }
+            false => Ok(SessionRng::Record {
+                inner: rng,
+                draws: Vec::new(),
+            }),
+        }
+    }
+
+    /// Record or check the outcome of the layer assignment and the shuffle
+    pub(crate) fn planned(
+        &self,
+        rng: SessionRng<'_>,
+        layers: &[usize],
+        order: &[u32],
+    ) -> Result<(), Error> {
+        let layers = layers.iter().map(|&l| l as u64).collect::<Vec<_>>();
+        let mut state = self.state.lock();
+        let recording = &mut state.recording;
+        match rng {
+            SessionRng::Record { draws, .. } => {
+                recording.draws = draws;
+                recording.layers = layers;
+                recording.order = order.to_vec();
+                Ok(())
+            }
+            SessionRng::Replay { draws, next } => {
+                match next <= draws.len() && recording.layers == layers && recording.order == order
+                {
+                    true => Ok(()),
+                    false => Err(MISMATCH),
+                }
+            }
+        }
+    }
+
+    /// Record the start of the insertion of `pid`
+    pub(crate) fn inserting(&self, pid: PointId) {
+        if !self.replay {
+            self.state.lock().recording.inserts.push(pid.0);
+        }
+    }
+
+    /// The recorded order of the insertions of the points in `batch`, if this is a replay
+    pub(crate) fn batch(&self, batch: Range<usize>) -> Result<Option<Vec<PointId>>, Error> {
+        if !self.replay {
+            return Ok(None);
+        }
+
+        let mut state = self.state.lock();
+        let start = state.inserted;
+        let end = start + batch.len();
+        let order = match state.recording.inserts.get(start..end) {
+            Some(order) => order,
+            None => return Err(MISMATCH),
+        };
+
+        match order.iter().all(|&pid| batch.contains(&(pid as usize))) {
+            true => {
+                let order = order.iter().map(|&pid| PointId(pid)).collect();
+                state.inserted = end;
+                Ok(Some(order))
+            }
+            false => Err(MISMATCH),
+        }
+    }
+
+    /// Finish the build, writing the recording or checking that the replay is complete
+    pub(crate) fn finish(&self) -> Result<(), Error> {
+        let state = self.state.lock();
+        match self.replay {
+            true if state.inserted != state.recording.inserts.len() => Err(MISMATCH),
+            true => Ok(()),
+            false => state.recording.write(&self.path),
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    recording: Recording,
+    /// The number of recorded insertions replayed so far
+    inserted: usize,
+}
+
+/// The random number generator used during a recording or replay
+pub(crate) enum SessionRng<'a> {
+    /// Draws from `inner`, keeping a copy of the numbers drawn
+    Record {
+        inner: &'a mut dyn RngCore,
+        draws: Vec<u64>,
+    },
+    /// Draws the recorded numbers; yields zeros once they run out, which `planned()` detects
+    Replay { draws: Vec<u64>, next: usize },
+}
+
+impl RngCore for SessionRng<'_> {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            SessionRng::Record { inner, draws } => {
+                let value = inner.next_u64();
+                draws.push(value);
+                value
+            }
+            SessionRng::Replay { draws, next } => {
+                let value = draws.get(*next).copied().unwrap_or(0);
+                *next += 1;
+                value
+            }
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// The contents of a recording file
+///
+/// The file starts with an 8-byte magic value, followed by the numbers drawn from the
+/// generator, the layers of the points in input order, the input index of each point in
+/// insertion order and the points in the order their insertions started. Each list is stored
+/// as its length followed by its elements, all little-endian; the first two lists hold `u64`s
+/// and the others `u32`s.
+#[derive(Default)]
+struct Recording {
+    draws: Vec<u64>,
+    layers: Vec<u64>,
+    order: Vec<u32>,
+    inserts: Vec<u32>,
+}
+
+impl Recording {
+    fn read(path: &Path) -> Result<Self, Error> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::Serialization("not a build recording".to_owned()));
+        }
+
+        Ok(Self {
+            draws: read_list(&mut reader, u64::from_le_bytes)?,
+            layers: read_list(&mut reader, u64::from_le_bytes)?,
+            order: read_list(&mut reader, u32::from_le_bytes)?,
+            inserts: read_list(&mut reader, u32::from_le_bytes)?,
+        })
+    }
+
+    fn write(&self, path: &Path) -> Result<(), Error> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&MAGIC)?;
+        for list in [&self.draws, &self.layers] {
+            writer.write_all(&(list.len() as u64).to_le_bytes())?;
+            for value in list {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+
+        for list in [&self.order, &self.inserts] {
+            writer.write_all(&(list.len() as u64).to_le_bytes())?;
+            for value in list {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn read_list<T, const N: usize>(
+    reader: &mut impl Read,
+    decode: fn([u8; N]) -> T,
+) -> Result<Vec<T>, Error> {
+    let mut len = [0; 8];
+    reader.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+
+    // Don't trust the length for the allocation, in case the file is corrupted
+    let mut list = Vec::with_capacity(len.min(1 << 20));
+    for _ in 0..len {
+        let mut bytes = [0; N];
+        reader.read_exact(&mut bytes)?;
+        list.push(decode(bytes));
+    }
+    Ok(list)
+}
+
+const MISMATCH: Error = Error::InvalidParameter {
+    name: "replay",
+    reason: "the recording doesn't match this build",
+};
+
+const MAGIC: [u8; 8] = *b"idrply\0\0";
@@ -0,0 +1,35 @@
+use std::time::Duration;
+
+/// Statistics collected while building an index
+///
+/// Returned by [`Builder::build_with_report()`](crate::Builder::build_with_report) and
+/// [`Builder::build_hnsw_with_report()`](crate::Builder::build_hnsw_with_report), to compare
+/// the effect of different build parameters.
+#[derive(Clone, Debug, Default)]
+pub struct BuildReport {
+    /// Total wall time spent building the graph
+    pub duration: Duration,
+    /// Statistics for each layer, from the top down
+    pub layers: Vec<LayerReport>,
+}
+
+/// Statistics for a single layer of the graph
+#[derive(Clone, Debug)]
+pub struct LayerReport {
+    /// The layer number, where `0` is the bottom layer
+    pub layer: usize,
+    /// The number of nodes on this layer (including the nodes inserted on higher layers)
+    pub points: usize,
+    /// Wall time spent inserting the points that were added on this layer
+    pub duration: Duration,
+    /// The average fraction of the neighbor slots in use, from `0.0` to `1.0`
+    ///
+    /// Nodes on the zero layer have `2 * M` slots; nodes on the upper layers have `M`. For
+    /// upper layers, this is measured when the layer is completed.
+    pub neighbor_fill: f32,
+    /// The number of candidate neighbors rejected by the selection heuristic
+    ///
+    /// This counts candidates discarded by the occlusion check, including those that were added
+    /// back because of [`Heuristic::keep_pruned`](crate::Heuristic::keep_pruned).
+    pub pruned: usize,
+}
@@ -0,0 +1,168 @@
+//! Two-hop shortcut edges for poorly connected nodes, with [`Hnsw::add_shortcuts()`]
+//!
+//! The selection heuristic keeps a node's neighbors spread out, but a node can still end up
+//! with a neighbor list that is clustered on one side of it, for example at the edge of a dense
+//! region. A search that reaches such a node can only leave it towards the cluster, which takes
+//! a larger `ef` to recover from. Linking the node to some of its two-hop neighbors gives the
+//! search a direct way around the cluster.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ordered_float::OrderedFloat;
+
+use crate::par::*;
+use crate::types::{Candidate, Layer, UpperNode, INVALID};
+use crate::{Hnsw, HnswMap, Point, PointId, ValueStore, M};
+
+impl<P: Point> Hnsw<P> {
+    /// Link nodes with clustered neighbors to up to `max_per_node` of their two-hop neighbors
+    ///
+    /// A node's neighbors are clustered if one of them is closer to all the others than the
+    /// node itself is. Such a node gets links to the nearest of its neighbors' neighbors,
+    /// preferring the ones that aren't occluded by its current neighbors, as far as its neighbor
+    /// list has unused slots. Returns the number of links added.
+    ///
+    /// Lists built with [`Heuristic::keep_pruned`](crate::Heuristic::keep_pruned) are usually
+    /// full, so this is mostly useful for indexes built without it.
+    ///
+    /// Every node is extended based on the graph as it was before this call, so the result
+    /// doesn't depend on the order in which the nodes are processed. The extra links make
+    /// searches with a low `ef` more reliable, at the cost of some more distance computations.
+    pub fn add_shortcuts(&mut self, max_per_node: usize) -> usize {
+        let added = AtomicUsize::new(0);
+
+        let mut lists = Vec::new();
+        let zero = self.zero.as_slice();
+        let len = zero.len();
+        extend(
+            zero,
+            len,
+            M * 2,
+            &self.points,
+            max_per_node,
+            &added,
+            &mut lists,
+        );
+        for (node, list) in self.zero.iter_mut().zip(&lists) {
+            if let Some(list) = list {
+                node.rewrite(list.iter().copied());
+            }
+        }
+
+        for i in 0..self.layers.len() {
+            let layer = self.layers[i].as_slice();
+            let len = layer.len();
+            extend(
+                layer,
+                len,
+                M,
+                &self.points,
+                max_per_node,
+                &added,
+                &mut lists,
+            );
+            for (node, list) in self.layers[i].iter_mut().zip(&lists) {
+                if let Some(list) = list {
+                    *node = UpperNode([INVALID; M]);
+                    node.0[..list.len()].copy_from_slice(list);
+                }
+            }
+        }
+
+        added.into_inner()
+    }
+}
+
+impl<P, V, S> HnswMap<P, V, S>
+where
+    P: Point,
+    V: ?Sized,
+    S: ValueStore<V>,
+{
+    /// Link nodes with clustered neighbors to up to `max_per_node` of their two-hop neighbors
+    ///
+    /// See [`Hnsw::add_shortcuts()`] for details.
+    pub fn add_shortcuts(&mut self, max_per_node: usize) -> usize {
+        self.hnsw.add_shortcuts(max_per_node)
+    }
+}
+
+/// Compute the extended neighbor list of each of the `len` nodes on `layer` into `lists`
+///
+/// Each node has room for `slots` neighbors. Nodes that don't get any shortcuts are `None`.
+fn extend<L: Layer + Copy + Sync, P: Point>(
+    layer: L,
+    len: usize,
+    slots: usize,
+    points: &[P],
+    max_per_node: usize,
+    added: &AtomicUsize,
+    lists: &mut Vec<Option<Vec<PointId>>>,
+) {
+    (0..len)
+        .into_par_iter()
+        .map(|i| {
+            let pid = PointId(i as u32);
+            let point = &points[pid];
+            let neighbors = layer.nearest_iter(pid).collect::<Vec<_>>();
+            let free = (slots - neighbors.len()).min(max_per_node);
+            if free == 0 || !clustered(point, &neighbors, points) {
+                return None;
+            }
+
+            let mut candidates = neighbors
+                .iter()
+                .flat_map(|&neighbor| layer.nearest_iter(neighbor))
+                .filter(|&candidate| candidate != pid && !neighbors.contains(&candidate))
+                .collect::<Vec<_>>();
+            candidates.sort_unstable();
+            candidates.dedup();
+
+            // Sort the unoccluded candidates first, then by distance
+            let mut candidates = candidates
+                .into_iter()
+                .map(|candidate| {
+                    let candidate_point = &points[candidate];
+                    let distance = point.distance(candidate_point);
+                    let occluded = neighbors
+                        .iter()
+                        .any(|&neighbor| candidate_point.distance(&points[neighbor]) < distance);
+                    (occluded, OrderedFloat::from(distance), candidate)
+                })
+                .collect::<Vec<_>>();
+            candidates.sort_unstable();
+            candidates.truncate(free);
+            if candidates.is_empty() {
+                return None;
+            }
+
+            added.fetch_add(candidates.len(), Ordering::Relaxed);
+            let mut list = neighbors
+                .iter()
+                .map(|&neighbor| Candidate {
+                    distance: OrderedFloat::from(point.distance(&points[neighbor])),
+                    pid: neighbor,
+                })
+                .chain(
+                    candidates
+                        .into_iter()
+                        .map(|(_, distance, pid)| Candidate { distance, pid }),
+                )
+                .collect::<Vec<_>>();
+            list.sort_unstable();
+            Some(list.into_iter().map(|candidate| candidate.pid).collect())
+        })
+        .collect_into_vec(lists);
+}
+
+/// Whether all of a node's `neighbors` but one are closer to that one than the node is
+fn clustered<P: Point>(point: &P, neighbors: &[PointId], points: &[P]) -> bool {
+    neighbors.iter().any(|&center| {
+        let center_point = &points[center];
+        let radius = point.distance(center_point);
+        neighbors
+            .iter()
+            .filter(|&&neighbor| neighbor != center)
+            .all(|&neighbor| center_point.distance(&points[neighbor]) < radius)
+    })
+}
@@ -0,0 +1,93 @@
+use std::fmt::Write;
+
+use crate::PointId;
+
+/// A record of the nodes visited during a search
+///
+/// Enable tracing with [`Search::record_trace()`](crate::Search::record_trace); after each
+/// search, [`Search::trace()`](crate::Search::trace) returns the trace for that search. This is
+/// meant for debugging, for example to find out why a particular point wasn't returned.
+#[derive(Clone, Debug, Default)]
+pub struct Trace {
+    /// The layers searched, from the top down
+    pub layers: Vec<LayerTrace>,
+}
+
+impl Trace {
+    /// Serialize the trace as JSON
+    ///
+    /// The output is an array with an object for each layer, containing the layer number and an
+    /// array of visits (`{"pid": .., "distance": .., "accepted": ..}`) in the order they happened.
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[");
+        for (i, layer) in self.layers.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            write!(out, "{{\"layer\":{},\"visits\":[", layer.layer).unwrap();
+            for (j, visit) in layer.visits.iter().enumerate() {
+                if j > 0 {
+                    out.push(',');
+                }
+
+                // JSON has no representation for non-finite numbers
+                let distance = match visit.distance.is_finite() {
+                    true => visit.distance.to_string(),
+                    false => "null".to_owned(),
+                };
+                write!(
+                    out,
+                    "{{\"pid\":{},\"distance\":{},\"accepted\":{}}}",
+                    visit.pid.into_inner(),
+                    distance,
+                    visit.accepted
+                )
+                .unwrap();
+            }
+            out.push_str("]}");
+        }
+        out.push(']');
+        out
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.layers.clear();
+    }
+
+    pub(crate) fn start_layer(&mut self, layer: usize) {
+        self.layers.push(LayerTrace {
+            layer,
+            visits: Vec::new(),
+        });
+    }
+
+    pub(crate) fn visit(&mut self, pid: PointId, distance: f32, accepted: bool) {
+        if let Some(layer) = self.layers.last_mut() {
+            layer.visits.push(Visit {
+                pid,
+                distance,
+                accepted,
+            });
+        }
+    }
+}
+
+/// The nodes visited on a single layer
+#[derive(Clone, Debug)]
+pub struct LayerTrace {
+    /// The layer number, where `0` is the bottom layer
+    pub layer: usize,
+    /// The visited nodes, in the order their distance was computed
+    pub visits: Vec<Visit>,
+}
+
+/// A single node visited during a search
+#[derive(Clone, Copy, Debug)]
+pub struct Visit {
+    pub pid: PointId,
+    /// The distance from the query point
+    pub distance: f32,
+    /// Whether the node was close enough to be added to the set of nearest neighbors
+    pub accepted: bool,
+}
@@ -0,0 +1,854 @@
+//! k-means clustering
+//!
+//! [`KMeans`] trains the centroids used by the codebook quantizers and by
+//! [`IvfIndex`](crate::ivf::IvfIndex). It can also be used to train other coarse quantizers.
+//!
+//! By default, each iteration assigns all vectors to their nearest centroid and moves each
+//! centroid to the mean of its vectors (Lloyd's algorithm). For large samples,
+//! [`KMeans::mini_batch()`] updates the centroids from a random batch of vectors per iteration
+//! instead, which converges to slightly worse centroids in a fraction of the time. Assignments are
+//! computed in parallel in both cases.
+//!
+//! For inner-product search, [`KMeans::anisotropic()`] trains with the score-aware
+//! [`AnisotropicLoss`] instead, which penalizes the quantization error along each vector more than
+//! the error orthogonal to it.
+//!
+//! Training rarely needs all the data. [`Reservoir`] and [`Stratified`] draw a fixed-size random
+//! sample from a stream of points in a single pass, keeping only the sample in memory, so a
+//! training set can be selected while the points are read from disk.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use ordered_float::OrderedFloat;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::par::*;
+use crate::points::PointDataSource;
+use crate::Error;
+
+/// Parameters for training k-means centroids
+#[derive(Clone, Debug)]
+pub struct KMeans {
+    clusters: usize,
+    iterations: usize,
+    batch: Option<usize>,
+    balanced: bool,
+    loss: Option<AnisotropicLoss>,
+    seed: u64,
+}
+
+impl KMeans {
+    /// Train `clusters` centroids
+    pub fn new(clusters: usize) -> Self {
+        Self {
+            clusters,
+            iterations: 25,
+            batch: None,
+            balanced: false,
+            loss: None,
+            seed: 0,
+        }
+    }
+
+    /// Set the maximum number of iterations
+    ///
+    /// Full-batch training stops early once no vector changes clusters. Defaults to 25.
+    pub fn iterations(mut self, iterations: usize) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Update the centroids from a random batch of `size` vectors per iteration
+    ///
+    /// Each centroid moves towards the vectors of the batch assigned to it, with a step size
+    /// that decreases with the number of vectors it has seen so far. With `None` (the default),
+    /// every iteration uses all vectors.
+    pub fn mini_batch(mut self, size: Option<usize>) -> Self {
+        self.batch = size;
+        self
+    }
+
+    /// Limit the number of vectors assigned to each cluster during training
+    ///
+    /// With balanced assignment, no cluster receives more than its share (rounded up) of the
+    /// vectors of an iteration, so the clusters end up with similar sizes. This suits inverted
+    /// lists and partitions that are processed in parallel, where a single large cluster would
+    /// dominate the cost. Defaults to `false`.
+    pub fn balanced(mut self, balanced: bool) -> Self {
+        self.balanced = balanced;
+        self
+    }
+
+    /// Measure the error of assigning a vector to a centroid with an [`AnisotropicLoss`]
+    ///
+    /// Vectors are assigned to the centroid with the smallest loss, and each centroid is moved to
+    /// the point that minimizes the total loss of its vectors. With mini-batches, each vector
+    /// moves its centroid along the gradient of its loss instead. With `None` (the default), the
+    /// error is the squared Euclidean distance. Use [`Centroids::nearest_anisotropic()`] to
+    /// encode vectors with the same loss.
+    pub fn anisotropic(mut self, loss: Option<AnisotropicLoss>) -> Self {
+        self.loss = loss;
+        self
+    }
+
+    /// Set the seed for the initial centroids and the batch selection
+    ///
+    /// Training is deterministic for a given seed. Defaults to 0.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Train the centroids on `sample`
+    ///
+    /// If the sample has fewer vectors than the requested number of clusters, one centroid is
+    /// created per vector. Clusters that end up empty are reseeded by splitting the largest
+    /// cluster, so every centroid represents some of the sample as long as it has enough
+    /// distinct vectors.
+    pub fn train<S: PointDataSource + Sync>(&self, sample: &[S]) -> Result<Centroids, Error> {
+        let rows = self.rows(sample)?;
+        Ok(self.train_rows(&rows, &rows))
+    }
+
+    /// Train the centroids on the residuals in `sample`, left by quantizing `directions`
+    ///
+    /// The anisotropic loss of each residual is measured along the vector it belongs to, which
+    /// is what [`Residual`](crate::quantize::Residual) needs for all but its first stage.
+    pub(crate) fn train_residuals<S: PointDataSource + Sync>(
+        &self,
+        sample: &[S],
+        directions: &[&[f32]],
+    ) -> Result<Centroids, Error> {
+        let rows = self.rows(sample)?;
+        Ok(self.train_rows(&rows, directions))
+    }
+
+    /// Check the parameters and the dimensions of `sample`
+    fn rows<'a, S: PointDataSource>(&self, sample: &'a [S]) -> Result<Vec<&'a [f32]>, Error> {
+        if self.clusters == 0 {
+            return Err(Error::InvalidParameter {
+                name: "clusters",
+                reason: "must be at least 1",
+            });
+        }
+
+        if self.batch == Some(0) {
+            return Err(Error::InvalidParameter {
+                name: "mini_batch",
+                reason: "must be at least 1",
+            });
+        }
+
+        let dims = match sample.first() {
+            Some(point) => point.data().len(),
+            None => {
+                return Err(Error::InvalidParameter {
+                    name: "sample",
+                    reason: "must not be empty",
+                })
+            }
+        };
+
+        for (index, point) in sample.iter().enumerate() {
+            if point.data().len() != dims {
+                return Err(Error::DimensionMismatch {
+                    index,
+                    expected: dims,
+                    found: point.data().len(),
+                });
+            }
+        }
+
+        Ok(sample.iter().map(|point| point.data()).collect())
+    }
+
+    fn train_rows(&self, rows: &[&[f32]], directions: &[&[f32]]) -> Centroids {
+        let dims = rows[0].len();
+        let k = self.clusters.min(rows.len());
+        let mut rng = StdRng::seed_from_u64(self.seed);
+        let mut centroids = Centroids {
+            dims,
+            data: rand::seq::index::sample(&mut rng, rows.len(), k)
+                .into_iter()
+                .flat_map(|i| rows[i].iter().copied())
+                .collect(),
+        };
+
+        // The anisotropic loss only depends on the direction of each vector
+        let units = match self.loss {
+            Some(_) => directions.iter().map(|d| unit(d)).collect(),
+            None => Vec::new(),
+        };
+        let objective = Objective {
+            loss: self.loss,
+            units: units.iter().map(|unit| &unit[..]).collect(),
+        };
+
+        match self.batch {
+            Some(size) => self.mini_batch_steps(&mut centroids, rows, &objective, size, &mut rng),
+            None => self.full_steps(&mut centroids, rows, &objective),
+        }
+
+        centroids
+    }
+
+    fn full_steps(&self, centroids: &mut Centroids, rows: &[&[f32]], objective: &Objective<'_>) {
+        let mut assignments = Vec::new();
+        for _ in 0..self.iterations {
+            let next = centroids.assign_rows(rows, objective, self.balanced);
+            if next == assignments {
+                break;
+            }
+            assignments = next;
+
+            if let Some(loss) = objective.loss {
+                let mut counts = vec![0usize; centroids.len()];
+                assignments.iter().for_each(|&cluster| counts[cluster] += 1);
+                loss.update(centroids, rows, &objective.units, &assignments);
+                centroids.split_largest(&mut counts);
+                continue;
+            }
+
+            let (dims, k) = (centroids.dims, centroids.len());
+            let mut sums = vec![0.0f64; k * dims];
+            let mut counts = vec![0usize; k];
+            for (row, &cluster) in rows.iter().zip(&assignments) {
+                counts[cluster] += 1;
+                let sum = &mut sums[cluster * dims..(cluster + 1) * dims];
+                for (sum, &value) in sum.iter_mut().zip(*row) {
+                    *sum += value as f64;
+                }
+            }
+
+            for (cluster, &count) in counts.iter().enumerate().filter(|(_, &n)| n > 0) {
+                let sum = &sums[cluster * dims..(cluster + 1) * dims];
+                for (c, &s) in centroids.get_mut(cluster).iter_mut().zip(sum) {
+                    *c = (s / count as f64) as f32;
+                }
+            }
+
+            centroids.split_largest(&mut counts);
+        }
+    }
+
+    fn mini_batch_steps(
+        &self,
+        centroids: &mut Centroids,
+        rows: &[&[f32]],
+        objective: &Objective<'_>,
+        size: usize,
+        rng: &mut StdRng,
+    ) {
+        let mut seen = vec![0usize; centroids.len()];
+        let mut batch = Vec::with_capacity(size.min(rows.len()));
+        for _ in 0..self.iterations {
+            let indices = rand::seq::index::sample(rng, rows.len(), size.min(rows.len()));
+            batch.clear();
+            batch.extend(indices.iter().map(|i| rows[i]));
+            let batch_objective = objective.select(indices.iter());
+
+            let assignments = centroids.assign_rows(&batch, &batch_objective, self.balanced);
+            for (i, (row, &cluster)) in batch.iter().zip(&assignments).enumerate() {
+                seen[cluster] += 1;
+                let rate = 1.0 / seen[cluster] as f32;
+                let centroid = centroids.get_mut(cluster);
+                match batch_objective.loss {
+                    Some(loss) => loss.step(centroid, row, batch_objective.units[i], rate),
+                    None => {
+                        for (c, &value) in centroid.iter_mut().zip(*row) {
+                            *c += rate * (value - *c);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Batches may have missed some clusters entirely
+        let mut counts = vec![0; centroids.len()];
+        for cluster in centroids.assign_rows(rows, objective, self.balanced) {
+            counts[cluster] += 1;
+        }
+        centroids.split_largest(&mut counts);
+    }
+}
+
+/// Centroids trained by [`KMeans`]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Centroids {
+    dims: usize,
+    /// Row-major centroids
+    data: Vec<f32>,
+}
+
+impl Centroids {
+    /// The index of the centroid nearest to `vector` and its squared Euclidean distance
+    ///
+    /// Returns `(0, f32::INFINITY)` if there are no centroids.
+    pub fn nearest(&self, vector: &[f32]) -> (usize, f32) {
+        self.nearest_by(|centroid| squared_distance(centroid, vector))
+    }
+
+    /// The index of the centroid with the smallest anisotropic loss for `vector`, and the loss
+    ///
+    /// The loss is measured along `direction`: `vector` itself when quantizing a vector, or the
+    /// original vector when quantizing the residual left by an earlier quantizer. Returns
+    /// `(0, f32::INFINITY)` if there are no centroids.
+    pub fn nearest_anisotropic(
+        &self,
+        vector: &[f32],
+        direction: &[f32],
+        loss: &AnisotropicLoss,
+    ) -> (usize, f32) {
+        let unit = unit(direction);
+        self.nearest_by(|centroid| loss.loss_along(vector, centroid, &unit))
+    }
+
+    /// The index of the centroid with the smallest `cost`, and that cost
+    fn nearest_by(&self, cost: impl Fn(&[f32]) -> f32) -> (usize, f32) {
+        self.iter()
+            .map(cost)
+            .enumerate()
+            .fold((0, f32::INFINITY), |best, (i, cost)| match cost < best.1 {
+                true => (i, cost),
+                false => best,
+            })
+    }
+
+    /// Assign each vector to its nearest centroid
+    pub fn assign<S: PointDataSource + Sync>(&self, vectors: &[S]) -> Vec<usize> {
+        let rows = vectors.iter().map(|point| point.data()).collect::<Vec<_>>();
+        self.assign_rows(&rows, &Objective::EUCLIDEAN, false)
+    }
+
+    /// Assign each vector to a nearby centroid, such that the clusters have similar sizes
+    ///
+    /// No cluster receives more than `vectors.len() / self.len()` vectors, rounded up. Vectors
+    /// that are much closer to their nearest centroid than to the next one are assigned first,
+    /// so vectors that are about equally close to several centroids are the ones moved to a
+    /// cluster that still has room.
+    pub fn assign_balanced<S: PointDataSource + Sync>(&self, vectors: &[S]) -> Vec<usize> {
+        let rows = vectors.iter().map(|point| point.data()).collect::<Vec<_>>();
+        self.assign_rows(&rows, &Objective::EUCLIDEAN, true)
+    }
+
+    fn assign_rows(
+        &self,
+        rows: &[&[f32]],
+        objective: &Objective<'_>,
+        balanced: bool,
+    ) -> Vec<usize> {
+        if !balanced || self.is_empty() {
+            let mut assignments = Vec::with_capacity(rows.len());
+            (0..rows.len())
+                .into_par_iter()
+                .map(|i| {
+                    self.nearest_by(|centroid| objective.cost(rows[i], centroid, i))
+                        .0
+                })
+                .collect_into_vec(&mut assignments);
+            return assignments;
+        }
+
+        // The centroids of each vector, nearest first
+        let mut ranked = Vec::with_capacity(rows.len());
+        (0..rows.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut order = self
+                    .iter()
+                    .map(|centroid| OrderedFloat(objective.cost(rows[i], centroid, i)))
+                    .enumerate()
+                    .collect::<Vec<_>>();
+                order.sort_unstable_by_key(|&(i, distance)| (distance, i));
+                order
+            })
+            .collect_into_vec(&mut ranked);
+
+        let margin = |order: &[(usize, OrderedFloat<f32>)]| match order {
+            [first, second, ..] => second.1 - first.1,
+            _ => OrderedFloat(0.0),
+        };
+        let mut order = (0..rows.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse(margin(&ranked[i])));
+
+        let capacity = (rows.len() + self.len() - 1) / self.len();
+        let mut sizes = vec![0; self.len()];
+        let mut assignments = vec![0; rows.len()];
+        for i in order {
+            let (cluster, _) = ranked[i]
+                .iter()
+                .find(|(cluster, _)| sizes[*cluster] < capacity)
+                .unwrap();
+            sizes[*cluster] += 1;
+            assignments[i] = *cluster;
+        }
+
+        assignments
+    }
+
+    /// Move each empty cluster next to the largest cluster, taking over half of its vectors
+    ///
+    /// `counts` holds the size of each cluster and is updated to the expected new sizes.
+    fn split_largest(&mut self, counts: &mut [usize]) {
+        for empty in 0..counts.len() {
+            if counts[empty] > 0 {
+                continue;
+            }
+
+            let (largest, _) = counts
+                .iter()
+                .enumerate()
+                .max_by_key(|&(i, &count)| (count, std::cmp::Reverse(i)))
+                .unwrap();
+            if counts[largest] < 2 {
+                return;
+            }
+
+            let dims = self.dims;
+            for j in 0..dims {
+                let value = self.data[largest * dims + j];
+                let sign = if j % 2 == 0 { 1.0 } else { -1.0 };
+                let offset = sign * SPLIT_EPSILON * value.abs().max(SPLIT_EPSILON);
+                self.data[empty * dims + j] = value + offset;
+                self.data[largest * dims + j] = value - offset;
+            }
+
+            counts[empty] = counts[largest] / 2;
+            counts[largest] -= counts[empty];
+        }
+    }
+
+    /// The centroid of cluster `cluster`
+    pub fn get(&self, cluster: usize) -> &[f32] {
+        &self.data[cluster * self.dims..(cluster + 1) * self.dims]
+    }
+
+    fn get_mut(&mut self, cluster: usize) -> &mut [f32] {
+        &mut self.data[cluster * self.dims..(cluster + 1) * self.dims]
+    }
+
+    /// Iterate over the centroids
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = &[f32]> {
+        self.data.chunks_exact(self.dims.max(1))
+    }
+
+    /// The dimensionality of the centroids
+    pub fn dims(&self) -> usize {
+        self.dims
+    }
+
+    /// The number of centroids
+    pub fn len(&self) -> usize {
+        self.data.len() / self.dims.max(1)
+    }
+
+    /// Whether there are no centroids
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// The score-aware quantization loss of ScaNN, for maximum inner product search
+///
+/// The quantization error of a vector matters most along the vector itself: a residual parallel
+/// to the vector changes its inner product with the queries it scores highest for, while an
+/// orthogonal residual mostly changes the scores of queries it doesn't match well anyway. This
+/// loss weights the parallel part of the residual `r` `eta` times as heavily as the orthogonal
+/// part, as `‖r⊥‖² + eta · ‖r∥‖²`. With `eta` 1, it is the squared Euclidean distance.
+///
+/// See "Accelerating Large-Scale Inference with Anisotropic Vector Quantization" by Guo et al.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnisotropicLoss {
+    eta: f32,
+}
+
+impl AnisotropicLoss {
+    /// Weight the parallel residual `eta` times as heavily as the orthogonal residual
+    ///
+    /// Returns [`Error::InvalidParameter`] unless `eta` is positive and finite.
+    pub fn new(eta: f32) -> Result<Self, Error> {
+        match eta > 0.0 && eta.is_finite() {
+            true => Ok(Self { eta }),
+            false => Err(Error::InvalidParameter {
+                name: "eta",
+                reason: "must be positive and finite",
+            }),
+        }
+    }
+
+    /// The weight ScaNN derives from a score `threshold`, for vectors with `dims` dimensions
+    ///
+    /// Only inner products of at least `threshold` times the norm of the vector (for queries of
+    /// unit norm) count towards the loss, which gives an `eta` of
+    /// `(dims - 1) · threshold² / (1 - threshold²)`. ScaNN suggests 0.2 for normalized vectors.
+    /// Returns [`Error::InvalidParameter`] unless `threshold` is between 0 and 1 and `dims` is at
+    /// least 2.
+    pub fn from_threshold(threshold: f32, dims: usize) -> Result<Self, Error> {
+        if !(threshold > 0.0 && threshold < 1.0) {
+            return Err(Error::InvalidParameter {
+                name: "threshold",
+                reason: "must be between 0 and 1",
+            });
+        } else if dims < 2 {
+            return Err(Error::InvalidParameter {
+                name: "dims",
+                reason: "must be at least 2",
+            });
+        }
+
+        let squared = threshold * threshold;
+        Self::new((dims as f32 - 1.0) * squared / (1.0 - squared))
+    }
+
+    /// The weight of the parallel residual relative to the orthogonal residual
+    pub fn eta(&self) -> f32 {
+        self.eta
+    }
+
+    /// The loss of quantizing `vector` as `quantized`
+    pub fn loss(&self, vector: &[f32], quantized: &[f32]) -> f32 {
+        self.loss_along(vector, quantized, &unit(vector))
+    }
+
+    /// The loss of quantizing `vector` as `quantized`, measured along the unit vector `unit`
+    fn loss_along(&self, vector: &[f32], quantized: &[f32], unit: &[f32]) -> f32 {
+        let (mut squared, mut parallel) = (0.0, 0.0);
+        for ((&v, &q), &u) in vector.iter().zip(quantized).zip(unit) {
+            squared += (v - q) * (v - q);
+            parallel += (v - q) * u;
+        }
+        squared + (self.eta - 1.0) * parallel * parallel
+    }
+
+    /// Move `centroid` along the gradient of the loss of `row`, with step size `rate`
+    fn step(&self, centroid: &mut [f32], row: &[f32], unit: &[f32], rate: f32) {
+        let parallel = row
+            .iter()
+            .zip(&*centroid)
+            .zip(unit)
+            .map(|((&r, &c), &u)| (r - c) * u)
+            .sum::<f32>();
+
+        // Scaled down so a step never overshoots the minimum along the steepest direction
+        let rate = rate / self.eta.max(1.0);
+        for ((c, &r), &u) in centroid.iter_mut().zip(row).zip(unit) {
+            *c += rate * ((r - *c) + (self.eta - 1.0) * parallel * u);
+        }
+    }
+
+    /// Move each centroid to the point minimizing the total loss of the rows assigned to it
+    ///
+    /// For the rows `x` with unit directions `u` assigned to a cluster, the minimum `c` solves
+    /// `(n·I + (eta - 1)·Σ u·uᵀ)·c = Σ x + (eta - 1)·Σ u·(u·x)`. The matrix is positive definite,
+    /// so the system is solved with conjugate gradients, without forming the matrix.
+    fn update(
+        &self,
+        centroids: &mut Centroids,
+        rows: &[&[f32]],
+        units: &[&[f32]],
+        assignments: &[usize],
+    ) {
+        let mut members = vec![Vec::new(); centroids.len()];
+        for (i, &cluster) in assignments.iter().enumerate() {
+            members[cluster].push(i);
+        }
+
+        let dims = centroids.dims;
+        let weight = (self.eta - 1.0) as f64;
+        let mut updated = Vec::with_capacity(members.len());
+        (0..members.len())
+            .into_par_iter()
+            .map(|cluster| {
+                let members = &members[cluster];
+                if members.is_empty() {
+                    return None;
+                }
+
+                // The product of the matrix with `v`
+                let product = |v: &[f64]| {
+                    let mut out = v
+                        .iter()
+                        .map(|&v| v * members.len() as f64)
+                        .collect::<Vec<_>>();
+                    for &i in members {
+                        let dot = dot(units[i], v);
+                        for (o, &u) in out.iter_mut().zip(units[i]) {
+                            *o += weight * dot * u as f64;
+                        }
+                    }
+                    out
+                };
+
+                let mut target = vec![0.0f64; dims];
+                for &i in members {
+                    let projection = units[i]
+                        .iter()
+                        .zip(rows[i])
+                        .map(|(&u, &x)| u as f64 * x as f64)
+                        .sum::<f64>();
+                    for ((t, &x), &u) in target.iter_mut().zip(rows[i]).zip(units[i]) {
+                        *t += x as f64 + weight * projection * u as f64;
+                    }
+                }
+
+                let start = centroids.get(cluster).iter().map(|&c| c as f64).collect();
+                let centroid = conjugate_gradient(product, &target, start);
+                Some(centroid.into_iter().map(|c| c as f32).collect::<Vec<_>>())
+            })
+            .collect_into_vec(&mut updated);
+
+        for (cluster, centroid) in updated.into_iter().enumerate() {
+            if let Some(centroid) = centroid {
+                centroids.get_mut(cluster).copy_from_slice(&centroid);
+            }
+        }
+    }
+}
+
+/// How the cost of assigning a training vector to a centroid is measured
+struct Objective<'a> {
+    loss: Option<AnisotropicLoss>,
+    /// The unit direction of each training vector, only used with an anisotropic loss
+    units: Vec<&'a [f32]>,
+}
+
+impl Objective<'_> {
+    const EUCLIDEAN: Objective<'static> = Objective {
+        loss: None,
+        units: Vec::new(),
+    };
+
+    /// The objective for the training vectors at the given positions
+    fn select(&self, indices: impl Iterator<Item = usize>) -> Self {
+        Objective {
+            loss: self.loss,
+            units: match self.loss {
+                Some(_) => indices.map(|i| self.units[i]).collect(),
+                None => Vec::new(),
+            },
+        }
+    }
+
+    /// The cost of assigning the training vector `row` at position `i` to `centroid`
+    fn cost(&self, row: &[f32], centroid: &[f32], i: usize) -> f32 {
+        match self.loss {
+            Some(loss) => loss.loss_along(row, centroid, self.units[i]),
+            None => squared_distance(centroid, row),
+        }
+    }
+}
+
+/// A uniform random sample of at most `size` items from a stream
+///
+/// Every item pushed so far is equally likely to be in the sample. The sample is maintained
+/// with Li's "Algorithm L", which computes how many items to skip before the next replacement,
+/// so the cost per item is small even for very long streams. Sampling is deterministic for a
+/// given seed and order of items.
+pub struct Reservoir<T> {
+    items: Vec<T>,
+    size: usize,
+    /// The number of items pushed so far
+    seen: u64,
+    /// The index of the next item to put in the sample, once the sample is full
+    next: u64,
+    weight: f64,
+    rng: StdRng,
+}
+
+impl<T> Reservoir<T> {
+    /// Create an empty reservoir holding at most `size` items
+    pub fn new(size: usize, seed: u64) -> Self {
+        Self {
+            items: Vec::with_capacity(size),
+            size,
+            seen: 0,
+            next: 0,
+            weight: 1.0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    /// Offer `item` to the sample
+    pub fn push(&mut self, item: T) {
+        self.seen += 1;
+        if self.items.len() < self.size {
+            self.items.push(item);
+            if self.items.len() == self.size {
+                self.weight = self.draw_weight();
+                self.skip();
+            }
+        } else if self.size > 0 && self.seen == self.next {
+            let index = self.rng.gen_range(0..self.size);
+            self.items[index] = item;
+            self.weight *= self.draw_weight();
+            self.skip();
+        }
+    }
+
+    /// The number of items pushed so far, including those that weren't sampled
+    pub fn seen(&self) -> u64 {
+        self.seen
+    }
+
+    /// The current sample
+    pub fn items(&self) -> &[T] {
+        &self.items
+    }
+
+    /// Take the sample, in no particular order
+    pub fn into_vec(self) -> Vec<T> {
+        self.items
+    }
+
+    fn draw_weight(&mut self) -> f64 {
+        (self.uniform().ln() / self.size as f64).exp()
+    }
+
+    fn skip(&mut self) {
+        let skipped = (self.uniform().ln() / (1.0 - self.weight).ln()).floor();
+        self.next = self.seen.saturating_add(skipped as u64).saturating_add(1);
+    }
+
+    /// A random number in `(0, 1]`
+    fn uniform(&mut self) -> f64 {
+        1.0 - self.rng.gen::<f64>()
+    }
+}
+
+impl<T> Extend<T> for Reservoir<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        iter.into_iter().for_each(|item| self.push(item));
+    }
+}
+
+/// A random sample of at most `size` items per stratum from a stream
+///
+/// Each item is pushed with the key of its stratum, such as a label, a language or the source
+/// of the point, and every stratum is sampled by its own [`Reservoir`]. Rare strata are then
+/// represented as well as common ones, which a uniform sample doesn't guarantee.
+pub struct Stratified<K, T> {
+    strata: HashMap<K, usize>,
+    reservoirs: Vec<(K, Reservoir<T>)>,
+    size: usize,
+    seed: u64,
+}
+
+impl<K: Clone + Eq + Hash, T> Stratified<K, T> {
+    /// Create an empty sample holding at most `size` items per stratum
+    pub fn new(size: usize, seed: u64) -> Self {
+        Self {
+            strata: HashMap::new(),
+            reservoirs: Vec::new(),
+            size,
+            seed,
+        }
+    }
+
+    /// Offer `item`, which belongs to stratum `key`, to the sample
+    pub fn push(&mut self, key: K, item: T) {
+        let index = match self.strata.get(&key) {
+            Some(&index) => index,
+            None => {
+                let index = self.reservoirs.len();
+                let seed = self.seed ^ (index as u64).wrapping_mul(SPREAD);
+                self.reservoirs
+                    .push((key.clone(), Reservoir::new(self.size, seed)));
+                self.strata.insert(key, index);
+                index
+            }
+        };
+
+        self.reservoirs[index].1.push(item);
+    }
+
+    /// The reservoir of stratum `key`, if any items of it were pushed
+    pub fn get(&self, key: &K) -> Option<&Reservoir<T>> {
+        self.strata.get(key).map(|&index| &self.reservoirs[index].1)
+    }
+
+    /// Take the sample of each stratum, in the order the strata first appeared
+    pub fn into_vec(self) -> Vec<(K, Vec<T>)> {
+        self.reservoirs
+            .into_iter()
+            .map(|(key, reservoir)| (key, reservoir.into_vec()))
+            .collect()
+    }
+}
+
+pub(crate) fn squared_distance(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Solve `A·x = target` with the conjugate gradient method, starting from `x`
+///
+/// `A` must be symmetric and positive definite, and is given by its `product` with a vector.
+fn conjugate_gradient(
+    product: impl Fn(&[f64]) -> Vec<f64>,
+    target: &[f64],
+    mut x: Vec<f64>,
+) -> Vec<f64> {
+    let squared_norm = |v: &[f64]| v.iter().map(|v| v * v).sum::<f64>();
+    let mut residual = product(&x);
+    residual
+        .iter_mut()
+        .zip(target)
+        .for_each(|(r, &t)| *r = t - *r);
+    let mut search = residual.clone();
+    let mut norm = squared_norm(&residual);
+    let tolerance = CG_TOLERANCE * squared_norm(target);
+
+    // In exact arithmetic, the solution is found after at most one step per dimension
+    for _ in 0..x.len().min(CG_STEPS) {
+        if norm <= tolerance {
+            break;
+        }
+
+        let product = product(&search);
+        let step = norm / search.iter().zip(&product).map(|(s, p)| s * p).sum::<f64>();
+        x.iter_mut().zip(&search).for_each(|(x, &s)| *x += step * s);
+        residual
+            .iter_mut()
+            .zip(&product)
+            .for_each(|(r, &p)| *r -= step * p);
+
+        let next = squared_norm(&residual);
+        let scale = next / norm;
+        search
+            .iter_mut()
+            .zip(&residual)
+            .for_each(|(s, &r)| *s = r + scale * *s);
+        norm = next;
+    }
+
+    x
+}
+
+/// `vector` scaled to unit length, or all zeros if it has none
+fn unit(vector: &[f32]) -> Vec<f32> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    match norm > 0.0 {
+        true => vector.iter().map(|v| v / norm).collect(),
+        false => vec![0.0; vector.len()],
+    }
+}
+
+fn dot(a: &[f32], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(&a, &b)| a as f64 * b).sum()
+}
+
+/// The maximum number of conjugate gradient steps per anisotropic centroid update
+const CG_STEPS: usize = 32;
+/// The squared residual norm, relative to that of the target, at which the solution is final
+const CG_TOLERANCE: f64 = 1e-12;
+
+/// Relative offset between the two halves of a split cluster
+const SPLIT_EPSILON: f32 = 1.0 / 1024.0;
+
+/// Spreads the per-stratum seeds over the seed space
+const SPREAD: u64 = 0x9e37_79b9_7f4a_7c15;
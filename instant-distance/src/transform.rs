@@ -0,0 +1,649 @@
+//! Transforms applied to vectors before they are indexed
+//!
+//! A [`TransformedMap`] stores a [`Transform`] together with the index. The transform is
+//! applied to every point when the index is built and to every query when it is searched, so
+//! the two can't get out of sync. Many metrics can be expressed as Euclidean distance after a
+//! linear transform; [`Weighted`] implements a per-dimension weighted Euclidean metric this way,
+//! and [`Mahalanobis`] implements the Mahalanobis distance for a given covariance matrix.
+//!
+//! [`Pca`] reduces the number of dimensions of the index by projecting onto the principal
+//! components of a sample of the data. [`RandomProjection`] is a cheaper alternative that
+//! doesn't need a sample.
+
+use std::cmp::Reverse;
+
+use ordered_float::OrderedFloat;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::points::{PointDataSource, Vector};
+use crate::{Builder, Error, HnswMap, MapItem, Search};
+
+/// A mapping from input vectors to the vectors that are indexed
+pub trait Transform {
+    /// The number of components of the vectors this transform accepts
+    fn input_dims(&self) -> usize;
+
+    /// Transform `vector`, which has `input_dims()` components
+    fn apply(&self, vector: &[f32]) -> Vec<f32>;
+}
+
+/// An `HnswMap` that transforms its points and queries
+///
+/// Distances reported by `search()` are Euclidean distances between the transformed vectors.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct TransformedMap<T, V> {
+    transform: T,
+    map: HnswMap<Vector, V>,
+}
+
+impl<T: Transform, V: Clone> TransformedMap<T, V> {
+    /// Transform `points` with `transform` and build an index for them
+    ///
+    /// Performs the same checks as [`Builder::try_build()`]; additionally, all points must have
+    /// the number of dimensions expected by the transform.
+    pub fn build<S: PointDataSource>(
+        builder: Builder,
+        transform: T,
+        points: &[S],
+        values: Vec<V>,
+    ) -> Result<Self, Error> {
+        let expected = transform.input_dims();
+        let transformed = points
+            .iter()
+            .enumerate()
+            .map(|(index, point)| match point.data().len() {
+                found if found == expected => Ok(Vector(transform.apply(point.data()))),
+                found => Err(Error::DimensionMismatch {
+                    index,
+                    expected,
+                    found,
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let map = builder.try_build(transformed, values)?;
+        Ok(Self { transform, map })
+    }
+
+    /// Transform `query` and search the index for the points nearest to it
+    ///
+    /// Panics if `query` doesn't have the number of dimensions expected by the transform.
+    pub fn search<'a>(
+        &'a self,
+        query: &[f32],
+        search: &'a mut Search,
+    ) -> impl ExactSizeIterator<Item = MapItem<'a, Vector, V>> + 'a {
+        assert_eq!(
+            query.len(),
+            self.transform.input_dims(),
+            "query has the wrong number of dimensions"
+        );
+        self.map
+            .search(&Vector(self.transform.apply(query)), search)
+    }
+
+    /// The transform applied to points and queries
+    pub fn transform(&self) -> &T {
+        &self.transform
+    }
+
+    /// The index of transformed points
+    pub fn map(&self) -> &HnswMap<Vector, V> {
+        &self.map
+    }
+
+    /// The number of points in this index
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Whether this index contains no points
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+/// Weighted Euclidean distance, with a non-negative weight for each dimension
+///
+/// The distance between `a` and `b` is `sqrt(sum(w[i] * (a[i] - b[i])^2))`. This is computed
+/// exactly by scaling each component by the square root of its weight, so search performance is
+/// the same as for unweighted vectors. A weight of zero ignores a dimension entirely.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Weighted {
+    weights: Vec<f32>,
+}
+
+impl Weighted {
+    /// Create a metric with one weight per dimension
+    pub fn new(weights: Vec<f32>) -> Result<Self, Error> {
+        if weights.is_empty() {
+            return Err(Error::InvalidParameter {
+                name: "weights",
+                reason: "must not be empty",
+            });
+        }
+
+        if !weights.iter().all(|w| w.is_finite() && *w >= 0.0) {
+            return Err(Error::InvalidParameter {
+                name: "weights",
+                reason: "must be finite and non-negative",
+            });
+        }
+
+        Ok(Self { weights })
+    }
+
+    /// The weight for each dimension
+    pub fn weights(&self) -> &[f32] {
+        &self.weights
+    }
+}
+
+impl Transform for Weighted {
+    fn input_dims(&self) -> usize {
+        self.weights.len()
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        vector
+            .iter()
+            .zip(&self.weights)
+            .map(|(value, weight)| value * weight.sqrt())
+            .collect()
+    }
+}
+
+/// Mahalanobis distance, defined by a covariance matrix or a whitening matrix
+///
+/// The distance between `a` and `b` is `sqrt((a - b)^T * S^-1 * (a - b))` for a covariance
+/// matrix `S`. This equals the Euclidean distance between `W * a` and `W * b` for any whitening
+/// matrix `W` with `W^T * W = S^-1`, which is how it is computed. Learned metrics are often
+/// given as such a matrix directly; a `W` with fewer rows than columns (a low-rank metric) also
+/// reduces the number of dimensions of the index.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mahalanobis {
+    input_dims: usize,
+    whitening: Whitening,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+enum Whitening {
+    /// Scale factors for each dimension
+    Diagonal(Vec<f32>),
+    /// A row-major matrix with `input_dims` columns
+    Matrix(Vec<f32>),
+}
+
+impl Mahalanobis {
+    /// Create a metric for independent dimensions, given the variance of each dimension
+    pub fn diagonal(variances: Vec<f32>) -> Result<Self, Error> {
+        if variances.is_empty() {
+            return Err(Error::InvalidParameter {
+                name: "variances",
+                reason: "must not be empty",
+            });
+        }
+
+        if !variances.iter().all(|v| v.is_finite() && *v > 0.0) {
+            return Err(Error::InvalidParameter {
+                name: "variances",
+                reason: "must be finite and positive",
+            });
+        }
+
+        Ok(Self {
+            input_dims: variances.len(),
+            whitening: Whitening::Diagonal(variances.iter().map(|v| v.sqrt().recip()).collect()),
+        })
+    }
+
+    /// Create a metric from a symmetric, positive definite covariance matrix
+    ///
+    /// `covariance` holds the rows of the matrix. The whitening matrix is derived from its
+    /// Cholesky decomposition.
+    pub fn covariance(covariance: &[Vec<f32>]) -> Result<Self, Error> {
+        let n = covariance.len();
+        if n == 0 {
+            return Err(Error::InvalidParameter {
+                name: "covariance",
+                reason: "must not be empty",
+            });
+        }
+
+        check_rows(covariance, n, "covariance")?;
+
+        // Decompose into `L * L^T`; then `S^-1 = L^-T * L^-1`, so `W = L^-1`
+        let mut lower = vec![0.0f64; n * n];
+        for i in 0..n {
+            for j in 0..=i {
+                let dot = (0..j)
+                    .map(|k| lower[i * n + k] * lower[j * n + k])
+                    .sum::<f64>();
+                let value = covariance[i][j] as f64 - dot;
+                if i == j {
+                    if value <= 0.0 || !value.is_finite() {
+                        return Err(Error::InvalidParameter {
+                            name: "covariance",
+                            reason: "must be positive definite",
+                        });
+                    }
+                    lower[i * n + i] = value.sqrt();
+                } else {
+                    lower[i * n + j] = value / lower[j * n + j];
+                }
+            }
+        }
+
+        // Invert the lower triangular matrix by forward substitution
+        let mut inverse = vec![0.0f64; n * n];
+        for col in 0..n {
+            for i in col..n {
+                let identity = if i == col { 1.0 } else { 0.0 };
+                let dot = (col..i)
+                    .map(|k| lower[i * n + k] * inverse[k * n + col])
+                    .sum::<f64>();
+                inverse[i * n + col] = (identity - dot) / lower[i * n + i];
+            }
+        }
+
+        Ok(Self {
+            input_dims: n,
+            whitening: Whitening::Matrix(inverse.into_iter().map(|v| v as f32).collect()),
+        })
+    }
+
+    /// Create a metric from a whitening matrix `W`, given as its rows
+    ///
+    /// All rows must have the same length, which is the number of input dimensions. The index
+    /// has one dimension for each row.
+    pub fn whitening(rows: &[Vec<f32>]) -> Result<Self, Error> {
+        let input_dims = match rows.first() {
+            Some(row) if !row.is_empty() => row.len(),
+            _ => {
+                return Err(Error::InvalidParameter {
+                    name: "whitening",
+                    reason: "must not be empty",
+                })
+            }
+        };
+
+        check_rows(rows, input_dims, "whitening")?;
+        Ok(Self {
+            input_dims,
+            whitening: Whitening::Matrix(rows.concat()),
+        })
+    }
+}
+
+impl Transform for Mahalanobis {
+    fn input_dims(&self) -> usize {
+        self.input_dims
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        match &self.whitening {
+            Whitening::Diagonal(scales) => vector.iter().zip(scales).map(|(v, s)| v * s).collect(),
+            Whitening::Matrix(matrix) => matrix
+                .chunks_exact(self.input_dims)
+                .map(|row| row.iter().zip(vector).map(|(w, v)| w * v).sum())
+                .collect(),
+        }
+    }
+}
+
+/// Projection onto the principal components of a sample
+///
+/// Keeping only the components with the most variance reduces the size of the index and the
+/// cost of distance calculations, while approximately preserving Euclidean distances. The
+/// projected vectors are centered on the mean of the sample.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pca {
+    mean: Vec<f32>,
+    /// Row-major matrix with a row for each component
+    components: Vec<f32>,
+    variances: Vec<f32>,
+}
+
+impl Pca {
+    /// Fit the `components` principal components of `sample`, ordered by decreasing variance
+    pub fn fit<S: PointDataSource>(sample: &[S], components: usize) -> Result<Self, Error> {
+        let (mean, mut eigen) = principal_components(sample, components)?;
+        eigen.truncate(components);
+        Ok(Self::new(mean, eigen))
+    }
+
+    /// Fit principal components, ordered for product quantization with `subspaces` subspaces
+    ///
+    /// This implements the parametric form of optimized product quantization (OPQ): assuming
+    /// the data is roughly Gaussian, quantization error is minimized by dividing the principal
+    /// components over the subspaces so that the product of their variances is balanced. The
+    /// first `components / subspaces` components of the output form the first subspace, and so
+    /// on. `components` must be a multiple of `subspaces`.
+    pub fn fit_opq<S: PointDataSource>(
+        sample: &[S],
+        components: usize,
+        subspaces: usize,
+    ) -> Result<Self, Error> {
+        if subspaces == 0 || components % subspaces != 0 {
+            return Err(Error::InvalidParameter {
+                name: "subspaces",
+                reason: "must divide the number of components",
+            });
+        }
+
+        let (mean, mut eigen) = principal_components(sample, components)?;
+        eigen.truncate(components);
+
+        // Assign each component, in order of decreasing variance, to the subspace with the
+        // smallest product of variances that still has room
+        let size = components / subspaces;
+        let mut buckets = vec![(0.0f64, Vec::with_capacity(size)); subspaces];
+        for component in eigen {
+            let (product, members) = buckets
+                .iter_mut()
+                .filter(|(_, members)| members.len() < size)
+                .min_by_key(|(product, _)| OrderedFloat(*product))
+                .unwrap();
+            *product += component.0.max(f64::MIN_POSITIVE).ln();
+            members.push(component);
+        }
+
+        let eigen = buckets
+            .into_iter()
+            .flat_map(|(_, members)| members)
+            .collect();
+        Ok(Self::new(mean, eigen))
+    }
+
+    fn new(mean: Vec<f64>, eigen: Vec<(f64, Vec<f64>)>) -> Self {
+        Self {
+            mean: mean.into_iter().map(|v| v as f32).collect(),
+            components: eigen
+                .iter()
+                .flat_map(|(_, vector)| vector.iter().map(|&v| v as f32))
+                .collect(),
+            variances: eigen.iter().map(|&(value, _)| value as f32).collect(),
+        }
+    }
+
+    /// The variance of the sample along each component, in output order
+    pub fn variances(&self) -> &[f32] {
+        &self.variances
+    }
+
+    /// The mean of the sample
+    pub fn mean(&self) -> &[f32] {
+        &self.mean
+    }
+}
+
+impl Transform for Pca {
+    fn input_dims(&self) -> usize {
+        self.mean.len()
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        let centered = vector
+            .iter()
+            .zip(&self.mean)
+            .map(|(v, m)| v - m)
+            .collect::<Vec<_>>();
+        self.components
+            .chunks_exact(self.mean.len())
+            .map(|row| row.iter().zip(&centered).map(|(w, v)| w * v).sum())
+            .collect()
+    }
+}
+
+/// Projection onto a random subspace
+///
+/// By the Johnson-Lindenstrauss lemma, a random linear projection to `k` dimensions
+/// approximately preserves Euclidean distances, with a relative error that shrinks with `k`.
+/// Unlike [`Pca`], this doesn't need to be fitted, and the sparse variant is cheap to apply.
+/// The projection matrix is derived from a seed, so it can be reproduced.
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct RandomProjection {
+    input_dims: usize,
+    matrix: Projection,
+}
+
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[derive(Clone, Debug, PartialEq)]
+enum Projection {
+    /// A row-major matrix with `input_dims` columns
+    Dense(Vec<f32>),
+    /// For each row, the non-zero entries and their columns
+    Sparse(Vec<Vec<(u32, f32)>>),
+}
+
+impl RandomProjection {
+    /// A projection with independent standard normal entries, scaled by `1 / sqrt(output_dims)`
+    pub fn gaussian(input_dims: usize, output_dims: usize, seed: u64) -> Result<Self, Error> {
+        check_projection(input_dims, output_dims)?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let scale = (output_dims as f32).sqrt().recip();
+        let matrix = (0..input_dims * output_dims)
+            .map(|_| {
+                // Box-Muller transform
+                let (u, v) = (1.0 - rng.gen::<f32>(), rng.gen::<f32>());
+                (-2.0 * u.ln()).sqrt() * (2.0 * std::f32::consts::PI * v).cos() * scale
+            })
+            .collect();
+
+        Ok(Self {
+            input_dims,
+            matrix: Projection::Dense(matrix),
+        })
+    }
+
+    /// A sparse projection as described by Achlioptas
+    ///
+    /// Each entry is `+sqrt(3 / output_dims)` or `-sqrt(3 / output_dims)` with probability 1/6
+    /// each, and zero otherwise, so applying it takes about a third of the work of a dense
+    /// projection.
+    pub fn sparse(input_dims: usize, output_dims: usize, seed: u64) -> Result<Self, Error> {
+        check_projection(input_dims, output_dims)?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        let scale = (3.0 / output_dims as f32).sqrt();
+        let rows = (0..output_dims)
+            .map(|_| {
+                (0..input_dims as u32)
+                    .filter_map(|column| match rng.gen_range(0..6) {
+                        0 => Some((column, scale)),
+                        1 => Some((column, -scale)),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(Self {
+            input_dims,
+            matrix: Projection::Sparse(rows),
+        })
+    }
+}
+
+impl Transform for RandomProjection {
+    fn input_dims(&self) -> usize {
+        self.input_dims
+    }
+
+    fn apply(&self, vector: &[f32]) -> Vec<f32> {
+        match &self.matrix {
+            Projection::Dense(matrix) => matrix
+                .chunks_exact(self.input_dims)
+                .map(|row| row.iter().zip(vector).map(|(w, v)| w * v).sum())
+                .collect(),
+            Projection::Sparse(rows) => rows
+                .iter()
+                .map(|row| row.iter().map(|&(i, w)| w * vector[i as usize]).sum())
+                .collect(),
+        }
+    }
+}
+
+fn check_projection(input_dims: usize, output_dims: usize) -> Result<(), Error> {
+    match (input_dims, output_dims) {
+        (0, _) => Err(Error::InvalidParameter {
+            name: "input_dims",
+            reason: "must be at least 1",
+        }),
+        (_, 0) => Err(Error::InvalidParameter {
+            name: "output_dims",
+            reason: "must be at least 1",
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Compute the mean and the eigenvalues and eigenvectors of the covariance matrix of `sample`
+///
+/// The eigenvectors are sorted by decreasing eigenvalue.
+#[allow(clippy::type_complexity)]
+fn principal_components<S: PointDataSource>(
+    sample: &[S],
+    components: usize,
+) -> Result<(Vec<f64>, Vec<(f64, Vec<f64>)>), Error> {
+    if sample.len() < 2 {
+        return Err(Error::InvalidParameter {
+            name: "sample",
+            reason: "must contain at least two points",
+        });
+    }
+
+    let n = sample[0].data().len();
+    for (index, point) in sample.iter().enumerate() {
+        if point.data().len() != n {
+            return Err(Error::DimensionMismatch {
+                index,
+                expected: n,
+                found: point.data().len(),
+            });
+        }
+    }
+
+    if components == 0 || components > n {
+        return Err(Error::InvalidParameter {
+            name: "components",
+            reason: "must be between 1 and the number of dimensions",
+        });
+    }
+
+    let mut mean = vec![0.0f64; n];
+    for point in sample {
+        for (sum, &value) in mean.iter_mut().zip(point.data()) {
+            *sum += value as f64;
+        }
+    }
+    mean.iter_mut().for_each(|sum| *sum /= sample.len() as f64);
+
+    let mut covariance = vec![0.0f64; n * n];
+    let mut centered = vec![0.0f64; n];
+    for point in sample {
+        for ((c, &value), m) in centered.iter_mut().zip(point.data()).zip(&mean) {
+            *c = value as f64 - m;
+        }
+        for i in 0..n {
+            for j in i..n {
+                covariance[i * n + j] += centered[i] * centered[j];
+            }
+        }
+    }
+    for i in 0..n {
+        for j in i..n {
+            let value = covariance[i * n + j] / (sample.len() - 1) as f64;
+            covariance[i * n + j] = value;
+            covariance[j * n + i] = value;
+        }
+    }
+
+    let (values, vectors) = jacobi(covariance, n);
+    let mut eigen = (0..n)
+        .map(|i| (values[i], (0..n).map(|k| vectors[k * n + i]).collect()))
+        .collect::<Vec<_>>();
+    eigen.sort_by_key(|&(value, _)| Reverse(OrderedFloat(value)));
+    Ok((mean, eigen))
+}
+
+/// Diagonalize the symmetric `n` x `n` matrix `a` with the cyclic Jacobi eigenvalue algorithm
+///
+/// Returns the eigenvalues and a row-major matrix with the corresponding eigenvectors as its
+/// columns.
+fn jacobi(mut a: Vec<f64>, n: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut v = vec![0.0f64; n * n];
+    (0..n).for_each(|i| v[i * n + i] = 1.0);
+
+    let total = a.iter().map(|x| x * x).sum::<f64>();
+    for _ in 0..MAX_SWEEPS {
+        let off = (0..n)
+            .flat_map(|i| (0..n).filter(move |&j| j != i).map(move |j| (i, j)))
+            .map(|(i, j)| a[i * n + j] * a[i * n + j])
+            .sum::<f64>();
+        if off <= total * f64::EPSILON * f64::EPSILON {
+            break;
+        }
+
+        for p in 0..n {
+            for q in p + 1..n {
+                let apq = a[p * n + q];
+                if apq == 0.0 {
+                    continue;
+                }
+
+                // Choose the rotation that zeroes `a[p][q]`
+                let theta = (a[q * n + q] - a[p * n + p]) / (2.0 * apq);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+
+                for k in 0..n {
+                    let (akp, akq) = (a[k * n + p], a[k * n + q]);
+                    a[k * n + p] = c * akp - s * akq;
+                    a[k * n + q] = s * akp + c * akq;
+                }
+                for k in 0..n {
+                    let (apk, aqk) = (a[p * n + k], a[q * n + k]);
+                    a[p * n + k] = c * apk - s * aqk;
+                    a[q * n + k] = s * apk + c * aqk;
+                }
+                for k in 0..n {
+                    let (vkp, vkq) = (v[k * n + p], v[k * n + q]);
+                    v[k * n + p] = c * vkp - s * vkq;
+                    v[k * n + q] = s * vkp + c * vkq;
+                }
+            }
+        }
+    }
+
+    ((0..n).map(|i| a[i * n + i]).collect(), v)
+}
+
+/// Upper bound on the number of Jacobi sweeps; convergence usually takes fewer than ten
+const MAX_SWEEPS: usize = 50;
+
+fn check_rows(rows: &[Vec<f32>], len: usize, name: &'static str) -> Result<(), Error> {
+    if rows.iter().any(|row| row.len() != len) {
+        return Err(Error::InvalidParameter {
+            name,
+            reason: "rows must have the same length",
+        });
+    }
+
+    if !rows.iter().flatten().all(|v| v.is_finite()) {
+        return Err(Error::InvalidParameter {
+            name,
+            reason: "must be finite",
+        });
+    }
+
+    Ok(())
+}
@@ -1,11 +1,11 @@
 use std::hash::Hash;
 use std::ops::{Deref, Index};
+use std::sync::atomic::{self, AtomicU32, Ordering};
 
 use ordered_float::OrderedFloat;
-use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard};
 #[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
-#[cfg(feature = "serde-big-array")]
+#[cfg(feature = "serde")]
 use serde_big_array::BigArray;
 
 use crate::{Hnsw, Point, M};
@@ -60,20 +60,26 @@ impl Visited {
 
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 #[derive(Clone, Copy, Debug, Default)]
-pub(crate) struct UpperNode([PointId; M]);
+pub(crate) struct UpperNode(pub(crate) [PointId; M]);
 
 impl UpperNode {
-    pub(crate) fn from_zero(node: &ZeroNode) -> Self {
+    /// Copy the first `len` (at most `M`) neighbors of `node`
+    pub(crate) fn from_zero(node: &ZeroNode, len: usize) -> Self {
         let mut nearest = [INVALID; M];
-        nearest.copy_from_slice(&node.0[..M]);
+        nearest[..len].copy_from_slice(&node.0[..len]);
         Self(nearest)
     }
+
+    /// The number of neighbor slots in use
+    pub(crate) fn fill(&self) -> usize {
+        self.0.iter().take_while(|pid| pid.is_valid()).count()
+    }
 }
 
 impl<'a> Layer for &'a [UpperNode] {
-    type Slice = &'a [PointId];
+    type Iter = NearestIter<&'a [PointId]>;
 
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice> {
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
         NearestIter::new(&self[pid.0 as usize].0)
     }
 }
@@ -97,16 +103,17 @@ impl ZeroNode {
         }
     }
 
-    pub(crate) fn insert(&mut self, idx: usize, pid: PointId) {
+    /// Insert `pid` at `idx`, shifting later neighbors back and dropping any beyond `len`
+    pub(crate) fn insert(&mut self, idx: usize, pid: PointId, len: usize) {
         // It might be possible for all the neighbor's current neighbors to be closer to our
         // neighbor than to the new node, in which case we skip insertion of our new node's ID.
-        if idx >= self.0.len() {
+        let len = len.min(M * 2);
+        if idx >= len {
             return;
         }
 
         if self.0[idx].is_valid() {
-            let end = (M * 2) - 1;
-            self.0.copy_within(idx..end, idx + 1);
+            self.0.copy_within(idx..len - 1, idx + 1);
         }
 
         self.0[idx] = pid;
@@ -115,6 +122,87 @@ impl ZeroNode {
     pub(crate) fn set(&mut self, idx: usize, pid: PointId) {
         self.0[idx] = pid;
     }
+
+    /// The number of neighbor slots in use
+    pub(crate) fn fill(&self) -> usize {
+        self.0.iter().take_while(|pid| pid.is_valid()).count()
+    }
+}
+
+/// A read-only view of one layer of an `Hnsw`, returned by [`Hnsw::layers()`]
+#[derive(Clone, Copy)]
+pub struct LayerView<'a> {
+    layer: usize,
+    nodes: LayerNodes<'a>,
+}
+
+impl<'a> LayerView<'a> {
+    pub(crate) fn zero(nodes: &'a [ZeroNode]) -> Self {
+        Self {
+            layer: 0,
+            nodes: LayerNodes::Zero(nodes),
+        }
+    }
+
+    pub(crate) fn upper(layer: usize, nodes: &'a [UpperNode]) -> Self {
+        Self {
+            layer,
+            nodes: LayerNodes::Upper(nodes),
+        }
+    }
+
+    /// The layer number, where `0` is the bottom layer
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+
+    /// The number of points on this layer
+    ///
+    /// Points are sorted by layer, so the points on this layer are the points with the IDs
+    /// `0..len()`.
+    pub fn len(&self) -> usize {
+        match self.nodes {
+            LayerNodes::Zero(nodes) => nodes.len(),
+            LayerNodes::Upper(nodes) => nodes.len(),
+        }
+    }
+
+    /// Whether this layer contains no points
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The maximum number of neighbors of each point on this layer
+    pub fn max_neighbors(&self) -> usize {
+        match self.nodes {
+            LayerNodes::Zero(_) => M * 2,
+            LayerNodes::Upper(_) => M,
+        }
+    }
+
+    /// The neighbors of `pid` on this layer, nearest first
+    ///
+    /// Panics if `pid` is not on this layer.
+    pub fn neighbors(&self, pid: PointId) -> impl Iterator<Item = PointId> + 'a {
+        let slots = match self.nodes {
+            LayerNodes::Zero(nodes) => &nodes[pid.0 as usize].0[..],
+            LayerNodes::Upper(nodes) => &nodes[pid.0 as usize].0[..],
+        };
+        slots.iter().copied().take_while(|pid| pid.is_valid())
+    }
+
+    /// The total number of links from the points on this layer to their neighbors
+    pub fn links(&self) -> usize {
+        (0..self.len())
+            .map(|i| self.neighbors(PointId(i as u32)).count())
+            .sum()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum LayerNodes<'a> {
+    Zero(&'a [ZeroNode]),
+    Upper(&'a [UpperNode]),
 }
 
 impl Default for ZeroNode {
@@ -132,27 +220,163 @@ impl Deref for ZeroNode {
 }
 
 impl<'a> Layer for &'a [ZeroNode] {
-    type Slice = &'a [PointId];
+    type Iter = NearestIter<&'a [PointId]>;
 
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice> {
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
         NearestIter::new(&self[pid.0 as usize])
     }
 }
 
-impl<'a> Layer for &'a [RwLock<ZeroNode>] {
-    type Slice = MappedRwLockReadGuard<'a, [PointId]>;
+/// A zero layer node that can be updated concurrently during construction
+///
+/// This is a sequence lock: `version` is odd while a writer is updating the slots. Writers
+/// serialize on the version with a compare-and-swap, while readers never write to the node at
+/// all. They copy the slots and retry if a writer was active or finished in the meantime, so
+/// searching through a node doesn't bounce its cache line between threads the way taking a
+/// shared lock does.
+///
+/// Updates that need more than the node itself to compute its new neighbors (like the selection
+/// heuristic, which looks at other nodes) use `load_versioned()` and `replace()` instead of
+/// `update()`: the new neighbors are computed without holding the node, and only stored if no
+/// other writer changed the node in the meantime, so concurrent updates are retried rather than
+/// lost.
+pub(crate) struct AtomicZeroNode {
+    version: AtomicU32,
+    slots: [AtomicU32; M * 2],
+}
+
+impl AtomicZeroNode {
+    pub(crate) fn new(node: ZeroNode) -> Self {
+        Self {
+            version: AtomicU32::new(0),
+            slots: node.0.map(|pid| AtomicU32::new(pid.0)),
+        }
+    }
+
+    /// A consistent copy of the node's neighbors
+    pub(crate) fn load(&self) -> ZeroNode {
+        self.load_versioned().0
+    }
+
+    /// A consistent copy of the node's neighbors, and the version to pass to `replace()`
+    pub(crate) fn load_versioned(&self) -> (ZeroNode, u32) {
+        let mut spins = 0;
+        loop {
+            let version = self.version.load(Ordering::Acquire);
+            if version % 2 == 0 {
+                let node = self.copy();
+                atomic::fence(Ordering::Acquire);
+                if self.version.load(Ordering::Relaxed) == version {
+                    return (node, version);
+                }
+            }
+            backoff(&mut spins);
+        }
+    }
+
+    /// Replace the node's neighbors with `node`, unless it changed since `version` was loaded
+    ///
+    /// Returns `false` without changing the node if another writer got in first; the caller
+    /// should then load the node again and recompute its neighbors.
+    pub(crate) fn replace(&self, version: u32, node: &ZeroNode) -> bool {
+        if self
+            .version
+            .compare_exchange(
+                version,
+                version.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return false;
+        }
+
+        atomic::fence(Ordering::Release);
+        self.store(node);
+        self.version
+            .store(version.wrapping_add(2), Ordering::Release);
+        true
+    }
+
+    /// Update the node's neighbors, excluding other writers while `f` runs
+    pub(crate) fn update<T>(&self, f: impl FnOnce(&mut ZeroNode) -> T) -> T {
+        let (mut version, mut spins) = (self.version.load(Ordering::Relaxed), 0);
+        loop {
+            if version % 2 == 1 {
+                backoff(&mut spins);
+                version = self.version.load(Ordering::Relaxed);
+                continue;
+            }
+
+            match self.version.compare_exchange_weak(
+                version,
+                version.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => version = current,
+            }
+        }
+
+        // Make sure readers see the odd version before any of the new slot values
+        atomic::fence(Ordering::Release);
+        let mut node = self.copy();
+        let result = f(&mut node);
+        self.store(&node);
+
+        self.version
+            .store(version.wrapping_add(2), Ordering::Release);
+        result
+    }
+
+    pub(crate) fn into_inner(self) -> ZeroNode {
+        ZeroNode(self.slots.map(|slot| PointId(slot.into_inner())))
+    }
+
+    fn store(&self, node: &ZeroNode) {
+        for (slot, pid) in self.slots.iter().zip(node.0) {
+            slot.store(pid.0, Ordering::Relaxed);
+        }
+    }
+
+    fn copy(&self) -> ZeroNode {
+        let mut node = ZeroNode::default();
+        for (pid, slot) in node.0.iter_mut().zip(&self.slots) {
+            *pid = PointId(slot.load(Ordering::Relaxed));
+        }
+        node
+    }
+}
+
+/// Wait for another thread to finish writing a node
+///
+/// Writers only hold a node for a few stores, so spin at first, but yield to the scheduler once
+/// that takes long: the writer may have been preempted, for example if there are more build
+/// threads than cores.
+fn backoff(spins: &mut u32) {
+    *spins += 1;
+    match *spins < SPINS_BEFORE_YIELD {
+        true => std::hint::spin_loop(),
+        false => std::thread::yield_now(),
+    }
+}
+
+/// The number of spins before `backoff()` starts yielding
+const SPINS_BEFORE_YIELD: u32 = 64;
+
+impl Layer for &[AtomicZeroNode] {
+    type Iter = NearestIter<ZeroNode>;
 
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice> {
-        NearestIter::new(RwLockReadGuard::map(
-            self[pid.0 as usize].read(),
-            Deref::deref,
-        ))
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter {
+        NearestIter::new(self[pid.0 as usize].load())
     }
 }
 
 pub(crate) trait Layer {
-    type Slice: Deref<Target = [PointId]>;
-    fn nearest_iter(&self, pid: PointId) -> NearestIter<Self::Slice>;
+    type Iter: Iterator<Item = PointId>;
+    fn nearest_iter(&self, pid: PointId) -> Self::Iter;
 }
 
 pub(crate) struct NearestIter<T> {
@@ -282,8 +506,8 @@ impl<P: Point> Index<PointId> for [P] {
     }
 }
 
-impl Index<PointId> for [RwLock<ZeroNode>] {
-    type Output = RwLock<ZeroNode>;
+impl Index<PointId> for [AtomicZeroNode] {
+    type Output = AtomicZeroNode;
 
     fn index(&self, index: PointId) -> &Self::Output {
         &self[index.0 as usize]
@@ -0,0 +1,559 @@
+//! Storage for the values of an `HnswMap`
+//!
+//! By default, an `HnswMap` keeps its values in a `Vec`. The [`ValueStore`] trait lets the
+//! values live elsewhere instead, such as in a columnar store or a memory-mapped file, so they
+//! don't all have to be cloned into memory. Build the map as usual (for example with the row
+//! number of each value as its value), then attach the values with [`HnswMap::from_parts()`].
+//!
+//! With the `mmap` feature, [`MappedValues`] keeps the values in a memory-mapped file, so only
+//! the pages holding the values of search results have to be resident. Like vector files, value
+//! files are mapped for [`Access::Random`](crate::hybrid::Access::Random) by default.
+//!
+//! With the `arrow` feature, Arrow string and binary arrays implement `ValueStore<str>` and
+//! `ValueStore<[u8]>`, and [`RecordBatchValues`] makes each row of a `RecordBatch` the value of
+//! a point, so search results can refer to full rows without copying them.
+//!
+//! With the `object_store` feature, [`RemoteValues`] reads the values from a value file in an
+//! object store with ranged reads, instead of mapping a local copy.
+//!
+//! [`HnswMap::from_parts()`]: crate::HnswMap::from_parts
+
+#[cfg(feature = "mmap")]
+use std::fs::File;
+#[cfg(feature = "mmap")]
+use std::io::{self, BufWriter, Write};
+#[cfg(feature = "mmap")]
+use std::path::Path;
+
+#[cfg(any(feature = "arrow", feature = "object_store"))]
+use std::sync::Arc;
+
+#[cfg(feature = "arrow")]
+use arrow_array::RecordBatch;
+#[cfg(feature = "arrow")]
+use arrow_array::{Array, ArrayRef, GenericBinaryArray, GenericStringArray, OffsetSizeTrait};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+#[cfg(feature = "object_store")]
+use object_store::{path::Path as ObjectPath, ObjectStore};
+
+#[cfg(feature = "mmap")]
+use crate::files::write_atomic;
+#[cfg(feature = "object_store")]
+use crate::hybrid::read_ranges;
+#[cfg(feature = "mmap")]
+use crate::hybrid::{advise, map, read_u64, warm_up, Access};
+#[cfg(any(feature = "mmap", feature = "arrow"))]
+use crate::Error;
+#[cfg(feature = "arrow")]
+use crate::{Hnsw, Point, PointId};
+
+/// Values indexed by the position of their point in an `HnswMap`
+///
+/// The value at `index` belongs to the point with `PointId` `index`, so the values have to be
+/// stored in the order of [`Hnsw::iter()`](crate::Hnsw::iter), not in the order the points were
+/// passed to the `Builder`.
+pub trait ValueStore<V: ?Sized> {
+    /// The value at `index`
+    ///
+    /// `index` is always less than `len()`.
+    fn value(&self, index: usize) -> &V;
+
+    /// The number of values in the store
+    fn len(&self) -> usize;
+
+    /// Whether the store contains no values
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<V> ValueStore<V> for Vec<V> {
+    fn value(&self, index: usize) -> &V {
+        &self[index]
+    }
+
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+}
+
+/// The strings of an Arrow `StringArray` or `LargeStringArray`
+///
+/// Nulls aren't distinguished from other entries; they usually read as empty strings.
+#[cfg(feature = "arrow")]
+impl<O: OffsetSizeTrait> ValueStore<str> for GenericStringArray<O> {
+    fn value(&self, index: usize) -> &str {
+        GenericStringArray::value(self, index)
+    }
+
+    fn len(&self) -> usize {
+        Array::len(self)
+    }
+}
+
+/// The byte strings of an Arrow `BinaryArray` or `LargeBinaryArray`
+///
+/// Nulls aren't distinguished from other entries; they usually read as empty byte strings.
+#[cfg(feature = "arrow")]
+impl<O: OffsetSizeTrait> ValueStore<[u8]> for GenericBinaryArray<O> {
+    fn value(&self, index: usize) -> &[u8] {
+        GenericBinaryArray::value(self, index)
+    }
+
+    fn len(&self) -> usize {
+        Array::len(self)
+    }
+}
+
+/// The rows of an Arrow `RecordBatch`, as the values of the points an index was built from
+///
+/// The batch holds a row for each point, in the order the points were passed to the `Builder`,
+/// and is shared by all [`Row`]s rather than copied. Implements `ValueStore<Row>`.
+#[cfg(feature = "arrow")]
+pub struct RecordBatchValues {
+    batch: Arc<RecordBatch>,
+    rows: Vec<Row>,
+}
+
+#[cfg(feature = "arrow")]
+impl RecordBatchValues {
+    /// Use the rows of `batch` as the values of the points of `hnsw`
+    ///
+    /// Row `i` of the batch belongs to the point that was at position `i` in the slice `hnsw`
+    /// was built from. Returns [`Error::LengthMismatch`] if the batch doesn't have a row for each
+    /// point, and [`Error::InvalidParameter`] if `hnsw` doesn't know the order of its input
+    /// points (see [`Item::index`](crate::Item::index)).
+    pub fn new<P: Point>(batch: RecordBatch, hnsw: &Hnsw<P>) -> Result<Self, Error> {
+        if batch.num_rows() != hnsw.len() {
+            return Err(Error::LengthMismatch {
+                points: hnsw.len(),
+                values: batch.num_rows(),
+            });
+        }
+
+        let batch = Arc::new(batch);
+        let rows = (0..hnsw.len())
+            .map(|i| match hnsw.input_index(PointId::from(i as u32)) {
+                Some(index) => Ok(Row {
+                    batch: batch.clone(),
+                    index,
+                }),
+                None => Err(Error::InvalidParameter {
+                    name: "hnsw",
+                    reason: "the index doesn't know the input order of its points",
+                }),
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { batch, rows })
+    }
+
+    /// The batch holding the rows
+    pub fn batch(&self) -> &RecordBatch {
+        &self.batch
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl ValueStore<Row> for RecordBatchValues {
+    fn value(&self, index: usize) -> &Row {
+        &self.rows[index]
+    }
+
+    fn len(&self) -> usize {
+        self.rows.len()
+    }
+}
+
+/// A row of a `RecordBatch`, the value type of [`RecordBatchValues`]
+#[cfg(feature = "arrow")]
+#[derive(Clone, Debug)]
+pub struct Row {
+    batch: Arc<RecordBatch>,
+    index: usize,
+}
+
+#[cfg(feature = "arrow")]
+impl Row {
+    /// The batch this row is part of
+    pub fn batch(&self) -> &RecordBatch {
+        &self.batch
+    }
+
+    /// The position of this row in the batch
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The column called `name`, whose element at [`Row::index()`] belongs to this row
+    pub fn column(&self, name: &str) -> Option<&ArrayRef> {
+        self.batch.column_by_name(name)
+    }
+
+    /// A batch holding only this row, sharing the buffers of the original batch
+    pub fn to_batch(&self) -> RecordBatch {
+        self.batch.slice(self.index, 1)
+    }
+}
+
+/// A memory-mapped file of byte string values
+///
+/// The values are either all the same size, or indexed by an offset table. The file starts with
+/// an 8-byte magic value, followed by the number of values, the size of each value (`u64::MAX`
+/// if the sizes vary) and the total size of the values as little-endian `u64`s. The values
+/// follow back to back. If the sizes vary, the values are followed by the offsets of the start
+/// of each value and the end of the last value, as little-endian `u64`s.
+///
+/// Implements `ValueStore<[u8]>` and `ValueStore<str>`. Values are only checked to be valid
+/// UTF-8 as they are accessed as `str`; use [`MappedValues::get_str()`] to handle invalid values
+/// instead of panicking.
+#[cfg(feature = "mmap")]
+pub struct MappedValues {
+    mmap: Mmap,
+    len: usize,
+    width: Option<usize>,
+    /// The position of the offset table in the file
+    offsets: usize,
+}
+
+#[cfg(feature = "mmap")]
+impl MappedValues {
+    /// Write `values` to a new file at `path` and map it
+    ///
+    /// The file is written to a temporary file and renamed into place, so an existing file at
+    /// `path` is only replaced once all values have been written.
+    pub fn create<T: AsRef<[u8]>>(
+        path: impl AsRef<Path>,
+        values: impl IntoIterator<Item = T>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        write_atomic(path, |writer| {
+            writer.write_all(&[0; HEADER_LEN])?;
+
+            let mut offsets = vec![0u64];
+            let mut size = 0u64;
+            for value in values {
+                let value = value.as_ref();
+                writer.write_all(value)?;
+                size += value.len() as u64;
+                offsets.push(size);
+            }
+
+            for offset in &offsets {
+                writer.write_all(&offset.to_le_bytes())?;
+            }
+
+            write_header(writer, [offsets.len() as u64 - 1, VARIABLE, size])
+        })?;
+        Self::open(path)
+    }
+
+    /// Write `values`, which must all be `width` bytes long, to a new file at `path` and map it
+    ///
+    /// Since the position of each value follows from its index, no offset table is stored. Like
+    /// [`MappedValues::create()`], this leaves an existing file at `path` untouched if it fails.
+    pub fn create_fixed<T: AsRef<[u8]>>(
+        path: impl AsRef<Path>,
+        width: usize,
+        values: impl IntoIterator<Item = T>,
+    ) -> Result<Self, Error> {
+        let path = path.as_ref();
+        write_atomic(path, |writer| {
+            writer.write_all(&[0; HEADER_LEN])?;
+
+            let mut len = 0u64;
+            for value in values {
+                let value = value.as_ref();
+                if value.len() != width {
+                    return Err(Error::InvalidParameter {
+                        name: "values",
+                        reason: "all values must be `width` bytes long",
+                    });
+                }
+
+                writer.write_all(value)?;
+                len += 1;
+            }
+
+            write_header(writer, [len, width as u64, len * width as u64])
+        })?;
+        Self::open(path)
+    }
+
+    /// Map an existing value file
+    ///
+    /// The file is mapped for [`Access::Random`]; see [`MappedValues::set_access()`]. If the
+    /// values vary in size, the whole offset table is read to check that each value lies within
+    /// the file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let mmap = map(&File::open(path)?, Access::Random)?;
+        let header = parse_header(&mmap[..HEADER_LEN.min(mmap.len())], mmap.len() as u64)?;
+        if header.width.is_none() {
+            let table = &mmap[header.offsets..header.offsets + (header.len + 1) * 8];
+            let mut last = 0;
+            for offset in table.chunks_exact(8).map(read_u64) {
+                if offset < last || offset > (header.offsets - HEADER_LEN) as u64 {
+                    return Err(Error::Serialization(
+                        "value file has invalid offsets".to_owned(),
+                    ));
+                }
+                last = offset;
+            }
+        }
+
+        Ok(Self {
+            mmap,
+            len: header.len,
+            width: header.width,
+            offsets: header.offsets,
+        })
+    }
+
+    /// The value at position `index`
+    pub fn get(&self, index: usize) -> &[u8] {
+        assert!(index < self.len, "value index out of bounds");
+        let (start, end) = match self.width {
+            Some(width) => (index * width, (index + 1) * width),
+            None => {
+                let offset = |i: usize| {
+                    let start = self.offsets + i * 8;
+                    read_u64(&self.mmap[start..start + 8]) as usize
+                };
+                (offset(index), offset(index + 1))
+            }
+        };
+
+        &self.mmap[HEADER_LEN..self.offsets][start..end]
+    }
+
+    /// The value at position `index` as a string
+    ///
+    /// Returns [`Error::Serialization`] if the value is not valid UTF-8.
+    pub fn get_str(&self, index: usize) -> Result<&str, Error> {
+        std::str::from_utf8(self.get(index))
+            .map_err(|error| Error::Serialization(format!("value {index}: {error}")))
+    }
+
+    /// Tell the kernel how the values will be accessed, so it can adjust its readahead
+    ///
+    /// Only supported on Unix; on other platforms, this does nothing.
+    pub fn set_access(&mut self, access: Access) -> Result<(), Error> {
+        advise(&self.mmap, access)
+    }
+
+    /// Start reading the whole file into memory in the background
+    ///
+    /// See [`VectorFile::warm_up()`](crate::hybrid::VectorFile::warm_up) for details.
+    pub fn warm_up(&self) -> Result<(), Error> {
+        warm_up(&self.mmap)
+    }
+
+    /// The size of each value, or `None` if the values are indexed by an offset table
+    pub fn width(&self) -> Option<usize> {
+        self.width
+    }
+
+    /// The number of values in the file
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the file contains no values
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ValueStore<[u8]> for MappedValues {
+    fn value(&self, index: usize) -> &[u8] {
+        self.get(index)
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl ValueStore<str> for MappedValues {
+    /// The value at `index` as a string
+    ///
+    /// Panics if the value is not valid UTF-8; see [`MappedValues::get_str()`].
+    fn value(&self, index: usize) -> &str {
+        std::str::from_utf8(self.get(index)).expect("value is not valid UTF-8")
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+/// Ranged reads of a [`MappedValues`] file stored in an object store
+///
+/// Like [`RemoteVectorFile`](crate::hybrid::RemoteVectorFile), opening the file only reads its
+/// header, and the values are fetched on demand. Reading values of varying sizes takes two
+/// round trips, the first one for their offsets.
+#[cfg(feature = "object_store")]
+pub struct RemoteValues {
+    store: Arc<dyn ObjectStore>,
+    location: ObjectPath,
+    len: usize,
+    width: Option<usize>,
+    /// The position of the offset table in the file
+    offsets: usize,
+}
+
+#[cfg(feature = "object_store")]
+impl RemoteValues {
+    /// Read the header of the value file at `location` in `store`
+    pub async fn open(store: Arc<dyn ObjectStore>, location: ObjectPath) -> Result<Self, Error> {
+        let size = store.head(&location).await?.size;
+        let header = store
+            .get_range(&location, 0..(HEADER_LEN as u64).min(size))
+            .await?;
+        let header = parse_header(&header, size)?;
+        Ok(Self {
+            store,
+            location,
+            len: header.len,
+            width: header.width,
+            offsets: header.offsets,
+        })
+    }
+
+    /// Read the value at position `index`
+    ///
+    /// Panics if `index` is out of bounds.
+    pub async fn get(&self, index: usize) -> Result<Vec<u8>, Error> {
+        let mut values = self.read([index]).await?;
+        Ok(values.swap_remove(0))
+    }
+
+    /// Read the values at the given positions, in the same order
+    ///
+    /// Panics if a position is out of bounds.
+    pub async fn read(
+        &self,
+        indices: impl IntoIterator<Item = usize>,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let indices = indices.into_iter().collect::<Vec<_>>();
+        assert!(
+            indices.iter().all(|&index| index < self.len),
+            "value index out of bounds"
+        );
+
+        let ranges = match self.width {
+            Some(width) => indices
+                .iter()
+                .map(|&index| (index * width) as u64..((index + 1) * width) as u64)
+                .collect::<Vec<_>>(),
+            None => {
+                let offsets = indices
+                    .iter()
+                    .map(|&index| {
+                        let start = (self.offsets + index * 8) as u64;
+                        start..start + 16
+                    })
+                    .collect::<Vec<_>>();
+                let offsets = read_ranges(&*self.store, &self.location, offsets).await?;
+                let size = (self.offsets - HEADER_LEN) as u64;
+                offsets
+                    .iter()
+                    .map(|offsets| {
+                        let (start, end) = (read_u64(&offsets[..8]), read_u64(&offsets[8..]));
+                        match start <= end && end <= size {
+                            true => Ok(start..end),
+                            false => Err(Error::Serialization(
+                                "value file has invalid offsets".to_owned(),
+                            )),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        let ranges = ranges
+            .into_iter()
+            .map(|range| range.start + HEADER_LEN as u64..range.end + HEADER_LEN as u64)
+            .collect::<Vec<_>>();
+        read_ranges(&*self.store, &self.location, ranges).await
+    }
+
+    /// The size of each value, or `None` if the values are indexed by an offset table
+    pub fn width(&self) -> Option<usize> {
+        self.width
+    }
+
+    /// The number of values in the file
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the file contains no values
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// The layout of a value file, from its header
+#[cfg(feature = "mmap")]
+struct Header {
+    len: usize,
+    width: Option<usize>,
+    /// The position of the offset table in the file
+    offsets: usize,
+}
+
+/// Parse the `header` of a value file, checking that a file of `size` bytes holds all values
+#[cfg(feature = "mmap")]
+fn parse_header(header: &[u8], size: u64) -> Result<Header, Error> {
+    if header.len() < HEADER_LEN || header[..8] != MAGIC {
+        return Err(Error::Serialization("not a value file".to_owned()));
+    }
+
+    let len = read_u64(&header[8..16]);
+    let width = match read_u64(&header[16..24]) {
+        VARIABLE => None,
+        width => Some(width),
+    };
+    let values = read_u64(&header[24..32]);
+
+    // The offset table has an entry for each value, plus the end of the last value
+    let table = match width {
+        Some(width) => len.checked_mul(width).filter(|&n| n == values).map(|_| 0),
+        None => len.checked_add(1).and_then(|n| n.checked_mul(8)),
+    };
+    let used = table
+        .and_then(|table| table.checked_add(values))
+        .and_then(|n| n.checked_add(HEADER_LEN as u64));
+    if !matches!(used, Some(used) if used <= size) {
+        return Err(Error::Serialization("value file is truncated".to_owned()));
+    }
+
+    Ok(Header {
+        len: len as usize,
+        width: width.map(|width| width as usize),
+        offsets: HEADER_LEN + values as usize,
+    })
+}
+
+/// Write the header last, so a partially written file is never valid
+#[cfg(feature = "mmap")]
+fn write_header(writer: &mut BufWriter<File>, header: [u64; 3]) -> Result<(), Error> {
+    io::Seek::rewind(writer)?;
+    writer.write_all(&MAGIC)?;
+    for value in header {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "mmap")]
+const MAGIC: [u8; 8] = *b"idvals\0\0";
+#[cfg(feature = "mmap")]
+const HEADER_LEN: usize = 32;
+/// The width of values that are indexed by an offset table
+#[cfg(feature = "mmap")]
+const VARIABLE: u64 = u64::MAX;
@@ -0,0 +1,276 @@
+//! Consistency checks for built or loaded indexes
+
+use std::fmt;
+
+use crate::types::{PointId, INVALID};
+use crate::{Hnsw, HnswMap, Point, ValueStore};
+
+/// The problems found by [`Hnsw::verify()`] or [`HnswMap::verify()`]
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// All problems found, in the order they were found
+    pub problems: Vec<Problem>,
+}
+
+impl VerifyReport {
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.problems.len() {
+            0 => write!(f, "no problems found"),
+            len => {
+                write!(f, "{len} problem(s) found")?;
+                for problem in &self.problems {
+                    write!(f, "; {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// An inconsistency in an index
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Problem {
+    /// The index holds more points than a `PointId` can address
+    TooManyPoints(usize),
+    /// The zero layer doesn't have a node for each point
+    ZeroLayerLength { nodes: usize, points: usize },
+    /// An upper layer has more nodes than the layer below it
+    ///
+    /// Each layer must contain a prefix of the nodes of the layer below it.
+    LayerLength {
+        layer: usize,
+        nodes: usize,
+        below: usize,
+    },
+    /// A node links to a point that isn't on its layer
+    LinkOutOfRange {
+        layer: usize,
+        node: PointId,
+        link: PointId,
+    },
+    /// A node links to itself
+    SelfLink { layer: usize, node: PointId },
+    /// A node links to the same point more than once
+    DuplicateLink {
+        layer: usize,
+        node: PointId,
+        link: PointId,
+    },
+    /// A node has a link after an unused slot, which searches never follow
+    LinkAfterEnd { layer: usize, node: PointId },
+    /// A point does not have the same dimensionality as the first point
+    DimensionMismatch {
+        pid: PointId,
+        expected: usize,
+        found: usize,
+    },
+    /// A point has NaN or infinite components
+    NonFinite { pid: PointId },
+    /// The number of values does not match the number of points
+    ValueCount { values: usize, points: usize },
+    /// The number of input positions does not match the number of points
+    InputCount { inputs: usize, points: usize },
+    /// A point's input position is out of range or shared with an earlier point
+    InputPosition { pid: PointId, index: usize },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::TooManyPoints(len) => write!(f, "too many points ({len})"),
+            Problem::ZeroLayerLength { nodes, points } => {
+                write!(f, "{nodes} zero layer nodes for {points} points")
+            }
+            Problem::LayerLength {
+                layer,
+                nodes,
+                below,
+            } => write!(
+                f,
+                "layer {layer} has {nodes} nodes, more than the {below} of the layer below it"
+            ),
+            Problem::LinkOutOfRange { layer, node, link } => write!(
+                f,
+                "node {} on layer {layer} links to point {} outside the layer",
+                node.0, link.0
+            ),
+            Problem::SelfLink { layer, node } => {
+                write!(f, "node {} on layer {layer} links to itself", node.0)
+            }
+            Problem::DuplicateLink { layer, node, link } => write!(
+                f,
+                "node {} on layer {layer} links to point {} more than once",
+                node.0, link.0
+            ),
+            Problem::LinkAfterEnd { layer, node } => write!(
+                f,
+                "node {} on layer {layer} has links after an unused slot",
+                node.0
+            ),
+            Problem::DimensionMismatch {
+                pid,
+                expected,
+                found,
+            } => write!(
+                f,
+                "point {} has {found} dimensions, expected {expected}",
+                pid.0
+            ),
+            Problem::NonFinite { pid } => write!(f, "point {} has non-finite components", pid.0),
+            Problem::ValueCount { values, points } => {
+                write!(f, "{values} values for {points} points")
+            }
+            Problem::InputCount { inputs, points } => {
+                write!(f, "{inputs} input positions for {points} points")
+            }
+            Problem::InputPosition { pid, index } => write!(
+                f,
+                "point {} has input position {index}, which is out of range or taken",
+                pid.0
+            ),
+        }
+    }
+}
+
+impl<P: Point> Hnsw<P> {
+    /// Check the whole index for inconsistencies
+    ///
+    /// This checks the points, the size of each layer and every link on every layer, so it takes
+    /// time proportional to the size of the index. Unlike the checks done by [`Hnsw::load()`],
+    /// all problems are reported rather than only the first one.
+    pub fn verify(&self) -> VerifyReport {
+        let mut problems = Vec::new();
+        let len = self.points.len();
+        if len >= u32::MAX as usize {
+            problems.push(Problem::TooManyPoints(len));
+        }
+
+        let mut expected = None;
+        for (i, point) in self.points.iter().enumerate() {
+            let pid = PointId(i as u32);
+            if !point.is_finite() {
+                problems.push(Problem::NonFinite { pid });
+            }
+
+            if let Some(found) = point.dims() {
+                match *expected.get_or_insert(found) {
+                    expected if expected != found => problems.push(Problem::DimensionMismatch {
+                        pid,
+                        expected,
+                        found,
+                    }),
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(inputs) = &self.inputs {
+            if inputs.len() != len {
+                problems.push(Problem::InputCount {
+                    inputs: inputs.len(),
+                    points: len,
+                });
+            }
+
+            let mut seen = vec![false; len];
+            for (i, &index) in inputs.iter().enumerate() {
+                let index = index as usize;
+                match seen.get_mut(index) {
+                    Some(seen) if !*seen => *seen = true,
+                    _ => problems.push(Problem::InputPosition {
+                        pid: PointId(i as u32),
+                        index,
+                    }),
+                }
+            }
+        }
+
+        // Flat indexes have no graph at all
+        if self.is_flat() {
+            return VerifyReport { problems };
+        }
+
+        if self.zero.len() != len {
+            problems.push(Problem::ZeroLayerLength {
+                nodes: self.zero.len(),
+                points: len,
+            });
+        }
+
+        let zero = self.zero.iter().map(|node| &node.0[..]);
+        links(zero, 0, self.zero.len(), &mut problems);
+
+        let mut below = self.zero.len();
+        for (i, layer) in self.layers.iter().enumerate() {
+            if layer.len() > below {
+                problems.push(Problem::LayerLength {
+                    layer: i + 1,
+                    nodes: layer.len(),
+                    below,
+                });
+            }
+
+            let nodes = layer.iter().map(|node| &node.0[..]);
+            links(nodes, i + 1, layer.len(), &mut problems);
+            below = layer.len();
+        }
+
+        VerifyReport { problems }
+    }
+}
+
+impl<P, V, S> HnswMap<P, V, S>
+where
+    P: Point,
+    V: ?Sized,
+    S: ValueStore<V>,
+{
+    /// Check the whole index and the number of values for inconsistencies
+    ///
+    /// See [`Hnsw::verify()`] for details.
+    pub fn verify(&self) -> VerifyReport {
+        let mut report = self.hnsw.verify();
+        if self.values.len() != self.hnsw.len() {
+            report.problems.push(Problem::ValueCount {
+                values: self.values.len(),
+                points: self.hnsw.len(),
+            });
+        }
+        report
+    }
+}
+
+/// Check the links of the `len` nodes on `layer`
+fn links<'a>(
+    nodes: impl Iterator<Item = &'a [PointId]>,
+    layer: usize,
+    len: usize,
+    problems: &mut Vec<Problem>,
+) {
+    for (i, links) in nodes.enumerate() {
+        let node = PointId(i as u32);
+        let end = links.iter().position(|&pid| pid == INVALID);
+        let (used, unused) = links.split_at(end.unwrap_or(links.len()));
+        if unused.iter().any(|&pid| pid != INVALID) {
+            problems.push(Problem::LinkAfterEnd { layer, node });
+        }
+
+        for (j, &link) in used.iter().enumerate() {
+            if link.0 as usize >= len {
+                problems.push(Problem::LinkOutOfRange { layer, node, link });
+            } else if link == node {
+                problems.push(Problem::SelfLink { layer, node });
+            } else if used[..j].contains(&link) {
+                problems.push(Problem::DuplicateLink { layer, node, link });
+            }
+        }
+    }
+}
@@ -4,7 +4,9 @@ use ordered_float::OrderedFloat;
 use rand::rngs::{StdRng, ThreadRng};
 use rand::{Rng, SeedableRng};
 
-use instant_distance::{Builder, Point as _, Search};
+use instant_distance::{
+    Builder, ContentHash, Error, Explicit, Heuristic, LayerAssignment, Point as _, Search,
+};
 
 #[test]
 #[allow(clippy::float_cmp, clippy::approx_constant)]
@@ -38,6 +40,1245 @@ fn map() {
     }
 }
 
+#[test]
+fn value_store() {
+    use instant_distance::{HnswMap, ValueStore};
+
+    /// Strings stored back to back in a single buffer
+    struct Strings {
+        data: String,
+        ends: Vec<usize>,
+    }
+
+    impl ValueStore<str> for Strings {
+        fn value(&self, index: usize) -> &str {
+            let start = index.checked_sub(1).map_or(0, |i| self.ends[i]);
+            &self.data[start..self.ends[index]]
+        }
+
+        fn len(&self) -> usize {
+            self.ends.len()
+        }
+    }
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let (hnsw, values) = Builder::default()
+        .build(points, (0..64).collect::<Vec<usize>>())
+        .into_parts();
+
+    // Store the values in `PointId` order
+    let mut strings = Strings {
+        data: String::new(),
+        ends: Vec::new(),
+    };
+    for i in values {
+        strings.data.push_str(&format!("point {i}"));
+        strings.ends.push(strings.data.len());
+    }
+
+    let map = HnswMap::from_parts(hnsw, strings).unwrap();
+    let mut search = Search::default();
+    let item = map.search(&Point(12.2, 0.0), &mut search).next().unwrap();
+    assert_eq!(item.value, "point 12");
+
+    let (hnsw, _) = map.into_parts();
+    assert!(matches!(
+        HnswMap::<_, usize>::from_parts(hnsw, vec![0; 3]),
+        Err(Error::LengthMismatch {
+            points: 64,
+            values: 3
+        })
+    ));
+}
+
+#[test]
+fn input_index() {
+    use instant_distance::packed::PackedHnsw;
+
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, pids) = Builder::default().seed(1).build_hnsw(points.clone());
+    for (i, &pid) in pids.iter().enumerate() {
+        assert_eq!(hnsw.input_index(pid), Some(i));
+    }
+
+    let mut search = Search::default();
+    for item in hnsw.search(&Point(3.0, 5.0), &mut search) {
+        assert_eq!(pids[item.index.unwrap()], item.pid);
+    }
+
+    let item = hnsw.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(item.index, Some(5 * 16 + 3));
+    let packed = PackedHnsw::new(hnsw);
+    let item = packed.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(item.index, Some(5 * 16 + 3));
+
+    let values = (0..256).collect::<Vec<u32>>();
+    let map = Builder::default().seed(1).build(points.clone(), values);
+    let item = map.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(item.index, Some(*item.value as usize));
+
+    // Flat indexes keep the input order
+    let (flat, _) = Builder::default().flat_threshold(1024).build_hnsw(points);
+    let item = flat.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(item.index, Some(item.pid.into_inner() as usize));
+}
+
+#[test]
+fn id_map() {
+    use instant_distance::IdMap;
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let keys = (0..64).map(|i| format!("doc-{i}")).collect::<Vec<_>>();
+    let (hnsw, ids) = Builder::default().try_build_keyed(points, keys).unwrap();
+    assert_eq!(ids.len(), 64);
+
+    let mut search = Search::default();
+    let item = hnsw.search(&Point(12.2, 0.0), &mut search).next().unwrap();
+    assert_eq!(ids.key(item.pid), "doc-12");
+    assert_eq!(ids.pid("doc-12"), Some(item.pid));
+    assert_eq!(ids.pid("doc-64"), None);
+    for (pid, key) in ids.iter() {
+        assert_eq!(ids.pid(key.as_str()), Some(pid));
+    }
+
+    let (_, pids) = Builder::default().build_hnsw(vec![Point(0.0, 0.0), Point(1.0, 0.0)]);
+    assert!(matches!(
+        IdMap::new(vec![7u64, 7], &pids),
+        Err(Error::InvalidParameter { name: "keys", .. })
+    ));
+    assert!(matches!(
+        IdMap::new(vec![7u64], &pids),
+        Err(Error::LengthMismatch {
+            points: 2,
+            values: 1
+        })
+    ));
+}
+
+#[test]
+fn kmeans() {
+    use instant_distance::train::KMeans;
+
+    // Three well-separated clusters of different sizes
+    let mut rng = StdRng::seed_from_u64(11);
+    let centers = [(0.0, 0.0), (10.0, 0.0), (0.0, 10.0)];
+    let sample = [300, 200, 100]
+        .iter()
+        .zip(centers)
+        .flat_map(|(&n, (x, y))| {
+            (0..n)
+                .map(|_| vec![x + rng.gen_range(-1.0..1.0), y + rng.gen_range(-1.0..1.0)])
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<Vec<f32>>>();
+
+    for kmeans in [
+        KMeans::new(3).seed(2),
+        KMeans::new(3).seed(2).mini_batch(Some(64)).iterations(50),
+    ] {
+        let centroids = kmeans.train(&sample).unwrap();
+        assert_eq!(centroids.len(), 3);
+        assert_eq!(centroids.dims(), 2);
+        for (x, y) in centers {
+            let (_, distance) = centroids.nearest(&[x, y]);
+            assert!(distance < 0.25, "no centroid near ({x}, {y})");
+        }
+
+        let mut sizes = [0; 3];
+        centroids
+            .assign(&sample)
+            .into_iter()
+            .for_each(|c| sizes[c] += 1);
+        sizes.sort_unstable();
+        assert_eq!(sizes, [100, 200, 300]);
+
+        let mut sizes = [0; 3];
+        centroids
+            .assign_balanced(&sample)
+            .into_iter()
+            .for_each(|c| sizes[c] += 1);
+        assert_eq!(sizes, [200, 200, 200]);
+    }
+
+    // Every cluster gets vectors, even with more clusters than natural groups
+    let centroids = KMeans::new(12).seed(5).train(&sample).unwrap();
+    let mut sizes = vec![0; 12];
+    centroids
+        .assign(&sample)
+        .into_iter()
+        .for_each(|c| sizes[c] += 1);
+    assert!(sizes.iter().all(|&n| n > 0), "{sizes:?}");
+
+    // Fewer vectors than clusters
+    assert_eq!(KMeans::new(8).train(&sample[..3]).unwrap().len(), 3);
+    assert!(matches!(
+        KMeans::new(0).train(&sample),
+        Err(Error::InvalidParameter {
+            name: "clusters",
+            ..
+        })
+    ));
+    assert!(matches!(
+        KMeans::new(2).train(&Vec::<Vec<f32>>::new()),
+        Err(Error::InvalidParameter { name: "sample", .. })
+    ));
+}
+
+#[test]
+fn sampling() {
+    use instant_distance::train::{Reservoir, Stratified};
+
+    let mut reservoir = Reservoir::new(1000, 9);
+    reservoir.extend(0..100_000u32);
+    assert_eq!(reservoir.seen(), 100_000);
+    let sample = reservoir.into_vec();
+    assert_eq!(sample.len(), 1000);
+    assert_eq!(sample.iter().collect::<HashSet<_>>().len(), 1000);
+
+    // Uniform samples cover the whole stream
+    let mean = sample.iter().map(|&i| i as f64).sum::<f64>() / 1000.0;
+    assert!((mean - 50_000.0).abs() < 3000.0, "mean = {mean}");
+    let late = sample.iter().filter(|&&i| i >= 90_000).count();
+    assert!((50..=150).contains(&late), "late = {late}");
+
+    let mut again = Reservoir::new(1000, 9);
+    again.extend(0..100_000u32);
+    assert_eq!(again.into_vec(), sample);
+
+    let mut short = Reservoir::new(10, 9);
+    short.extend(0..4);
+    assert_eq!(short.into_vec(), vec![0, 1, 2, 3]);
+
+    let mut stratified = Stratified::new(50, 9);
+    for i in 0..10_000u32 {
+        let key = match i % 100 {
+            0 => "rare",
+            1..=9 => "uncommon",
+            _ => "common",
+        };
+        stratified.push(key, i);
+    }
+    assert_eq!(stratified.get(&"rare").unwrap().seen(), 100);
+    let strata = stratified.into_vec();
+    assert_eq!(
+        strata.iter().map(|(key, _)| *key).collect::<Vec<_>>(),
+        ["rare", "uncommon", "common"]
+    );
+    for (key, items) in strata {
+        assert_eq!(items.len(), 50);
+        assert!(items.iter().all(|i| match key {
+            "rare" => i % 100 == 0,
+            "uncommon" => (1..=9).contains(&(i % 100)),
+            _ => i % 100 >= 10,
+        }));
+    }
+}
+
+#[test]
+fn ivf() {
+    use instant_distance::ivf::IvfIndex;
+    use instant_distance::points::Vector;
+    use instant_distance::Score;
+
+    let mut rng = StdRng::seed_from_u64(3);
+    let points = (0..1024)
+        .map(|_| Vector(vec![rng.gen_range(0.0..100.0), rng.gen_range(0.0..100.0)]))
+        .collect::<Vec<_>>();
+    let values = (0..1024).collect::<Vec<usize>>();
+    let index = IvfIndex::build(Builder::default(), points.clone(), values, 8, 3).unwrap();
+    assert_eq!(index.num_lists(), 8);
+    assert_eq!(index.len(), 1024);
+
+    let query = Vector(vec![31.0, 62.0]);
+    let nearest = (0..1024)
+        .min_by_key(|&i| OrderedFloat(query.distance(&points[i])))
+        .unwrap();
+
+    // Probing all lists finds the exact nearest neighbor
+    let mut search = Search::default();
+    let results = index.search(&query, 8, &mut search);
+    assert_eq!(*results[0].1.value, nearest);
+    assert!(results
+        .windows(2)
+        .all(|pair| pair[0].1.distance <= pair[1].1.distance));
+
+    // Similarities are merged highest first, so truncation drops the furthest results
+    let mut similar = Search::default();
+    similar.score(Score::Inverse);
+    let scored = index.search(&query, 8, &mut similar);
+    assert_eq!(scored.len(), results.len());
+    assert_eq!(*scored[0].1.value, nearest);
+    for ((_, item), (_, expected)) in scored.iter().zip(&results) {
+        assert_eq!(item.value, expected.value);
+        assert_eq!(item.distance, 1.0 / (1.0 + expected.distance));
+    }
+
+    // Probing a single list only returns points from the nearest list
+    let lists = index.nearest_lists(&query, 1);
+    let results = index.search(&query, 1, &mut search);
+    assert!(!results.is_empty());
+    assert!(results.iter().all(|(list, _)| *list == lists[0]));
+
+    assert!(matches!(
+        IvfIndex::build(Builder::default(), points, vec![0; 3], 8, 3),
+        Err(Error::LengthMismatch { .. })
+    ));
+}
+
+#[test]
+fn accessors() {
+    let (hnsw, _) = Builder::default().build_hnsw(Vec::<Point>::new());
+    assert!(hnsw.is_empty());
+    assert_eq!(hnsw.len(), 0);
+    assert_eq!(hnsw.dims(), None);
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+    assert!(!hnsw.is_empty());
+    assert_eq!(hnsw.len(), 64);
+    assert_eq!(hnsw.dims(), Some(2));
+}
+
+#[test]
+fn build_report() {
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, _, report) = Builder::default().build_hnsw_with_report(points);
+
+    let zero = report.layers.last().unwrap();
+    assert_eq!(zero.layer, 0);
+    assert_eq!(zero.points, hnsw.len());
+    assert!(zero.neighbor_fill > 0.0 && zero.neighbor_fill <= 1.0);
+    assert!(
+        report
+            .layers
+            .iter()
+            .map(|layer| layer.pruned)
+            .sum::<usize>()
+            > 0
+    );
+    assert!(report.duration >= report.layers.iter().map(|layer| layer.duration).sum());
+}
+
+#[cfg(feature = "affinity")]
+#[test]
+fn pin_threads() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+
+    // Building in a dedicated pool leaves the global pool used by the other tests unpinned
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let (hnsw, pids) = pool.install(|| Builder::default().pin_threads([0]).build_hnsw(points));
+
+    let mut search = Search::default();
+    let nearest = hnsw.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(nearest.pid, pids[5 * 16 + 3]);
+}
+
+#[test]
+fn concurrent_links() {
+    use instant_distance::points::Vector;
+
+    // A hub that is the nearest neighbor of every other point: random directions in a high
+    // dimension are much further apart from each other than from the origin
+    let mut rng = StdRng::seed_from_u64(4);
+    let mut points = vec![Vector(vec![0.0; 64])];
+    for i in 0..200 {
+        let direction = (0..64)
+            .map(|_| rng.gen_range(-1.0f32..1.0))
+            .collect::<Vec<_>>();
+        let norm = direction.iter().map(|v| v * v).sum::<f32>().sqrt();
+        let scale = (1.0 + i as f32 * 1e-4) / norm;
+        points.push(Vector(direction.iter().map(|v| v * scale).collect()));
+    }
+
+    // The hub is the only point on the upper layer, so every insertion starts from it and links
+    // back to it. Its final neighbors must be the points nearest to it, which a lost update
+    // would break.
+    let mut layers = vec![0; points.len()];
+    layers[0] = 1;
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(16)
+        .build()
+        .unwrap();
+    for heuristic in [Some(Heuristic::default()), None] {
+        let (hnsw, pids) = pool.install(|| {
+            Builder::default()
+                .select_heuristic(heuristic)
+                .layer_assignment(Explicit(layers.clone()))
+                .build_hnsw(points.clone())
+        });
+        assert_eq!(pids[0].into_inner(), 0);
+
+        let zero = hnsw.layers().next().unwrap();
+        let hub = zero.neighbors(pids[0]).collect::<Vec<_>>();
+        let expected = pids[1..=zero.max_neighbors()].to_vec();
+        assert_eq!(hub, expected, "{heuristic:?}");
+    }
+}
+
+#[cfg(feature = "huge-pages")]
+#[test]
+fn huge_pages() {
+    // Large enough for the zero layer to span several huge pages
+    let points = (0..16_384)
+        .map(|i| Point((i % 128) as f32, (i / 128) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, pids) = Builder::default()
+        .ef_construction(20)
+        .huge_pages(true)
+        .build_hnsw(points);
+
+    let mut search = Search::default();
+    let nearest = hnsw.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(nearest.pid, pids[5 * 128 + 3]);
+}
+
+#[cfg(feature = "replay")]
+#[test]
+fn record_replay() {
+    let mut rng = StdRng::seed_from_u64(9);
+    let points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("replay-{}.rec", std::process::id()));
+
+    let graph = |builder: Builder| {
+        let (hnsw, _) = builder.try_build_hnsw(points.clone())?;
+        let zero = hnsw.layers().next().unwrap();
+        let graph = hnsw
+            .iter()
+            .map(|(pid, _)| zero.neighbors(pid).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        Ok::<_, Error>(graph)
+    };
+
+    // The recording replaces the seed, and replays build the same graph every time
+    graph(Builder::default().seed(1).record(&path)).unwrap();
+    let replayed = graph(Builder::default().seed(2).replay(&path)).unwrap();
+    assert_eq!(graph(Builder::default().replay(&path)).unwrap(), replayed);
+
+    let err = Builder::default()
+        .replay(&path)
+        .try_build_hnsw(points[..512].to_vec())
+        .err();
+    assert!(matches!(
+        err,
+        Some(Error::InvalidParameter { name: "replay", .. })
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn build_background() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let values = (0..256).collect::<Vec<u32>>();
+
+    let handle = Builder::default().build_background(points.clone(), values.clone());
+    assert_eq!(handle.progress().total, 256);
+    let map = handle.join();
+    assert_eq!(map.len(), 256);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    Builder::default()
+        .build_background(points, values)
+        .on_complete(move |map| tx.send(map.len()).unwrap());
+    assert_eq!(rx.recv().unwrap(), 256);
+}
+
+#[test]
+fn search_trace() {
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, pids) = Builder::default().seed(3).build_hnsw(points);
+
+    let mut search = Search::default();
+    let _ = hnsw.search(&Point(3.0, 5.0), &mut search);
+    assert!(search.trace().is_none());
+
+    search.record_trace(true);
+    let _ = hnsw.search(&Point(3.0, 5.0), &mut search);
+    let trace = search.trace().unwrap();
+    assert_eq!(trace.layers.last().unwrap().layer, 0);
+    assert_eq!(trace.layers[0].visits[0].pid.into_inner(), 0);
+
+    let target = pids[5 * 32 + 3];
+    let zero = &trace.layers.last().unwrap().visits;
+    assert!(zero
+        .iter()
+        .any(|visit| visit.pid == target && visit.accepted));
+
+    let json = trace.to_json();
+    assert!(json.starts_with(&format!("[{{\"layer\":{},", trace.layers[0].layer)));
+    assert!(json.ends_with("]}]"));
+}
+
+#[test]
+fn exclude() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, pids) = Builder::default().build_hnsw(points);
+    let target = pids[5 * 16 + 3];
+
+    let mut search = Search::default();
+    search.exclude(target);
+    assert!(hnsw
+        .search(&Point(3.0, 5.0), &mut search)
+        .all(|item| item.pid != target));
+    let nearest = hnsw.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(nearest.distance, 1.0);
+
+    search.clear_excluded();
+    let nearest = hnsw.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(nearest.pid, target);
+}
+
+#[test]
+fn max_distance() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+
+    let mut search = Search::default();
+    search.max_distance(Some(1.0));
+    let distances = hnsw
+        .search(&Point(3.0, 5.0), &mut search)
+        .map(|item| item.distance)
+        .collect::<Vec<_>>();
+    assert_eq!(distances, [0.0, 1.0, 1.0, 1.0, 1.0]);
+
+    search.max_distance(None);
+    assert_eq!(hnsw.search(&Point(3.0, 5.0), &mut search).len(), 100);
+}
+
+#[test]
+fn dedup() {
+    // Four copies of each grid point, slightly offset
+    let points = (0..1024)
+        .map(|i| {
+            let offset = (i / 256) as f32 * 0.01;
+            Point((i % 16) as f32 + offset, ((i % 256) / 16) as f32)
+        })
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+
+    let mut search = Search::default();
+    search.dedup(Some(0.1));
+    let results = hnsw
+        .search(&Point(3.0, 5.0), &mut search)
+        .map(|item| *item.point)
+        .collect::<Vec<_>>();
+    assert_eq!(results[0].0, 3.0);
+    assert!(results.len() <= 25);
+    for (i, a) in results.iter().enumerate() {
+        assert!(results[i + 1..].iter().all(|b| a.distance(b) >= 0.1));
+    }
+}
+
+#[test]
+fn search_more() {
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().ef_search(10).build_hnsw(points);
+
+    let query = Point(15.5, 15.5);
+    let mut search = Search::default();
+    let mut found = hnsw
+        .search(&query, &mut search)
+        .map(|item| item.pid)
+        .collect::<Vec<_>>();
+    assert_eq!(found.len(), 10);
+
+    let more = hnsw
+        .search_more(&query, &mut search, 20)
+        .map(|item| (item.pid, item.distance))
+        .collect::<Vec<_>>();
+    assert!(more.len() >= 15);
+    assert!(more.windows(2).all(|w| w[0].1 <= w[1].1));
+    assert!(more.iter().all(|(pid, _)| !found.contains(pid)));
+
+    found.extend(more.iter().map(|(pid, _)| *pid));
+    let again = hnsw
+        .search_more(&query, &mut search, 10)
+        .map(|item| item.pid)
+        .collect::<Vec<_>>();
+    assert!(!again.is_empty());
+    assert!(again.iter().all(|pid| !found.contains(pid)));
+
+    // Flat indexes widen the beam even if the search overrides `ef_search`
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().flat_threshold(100).build_hnsw(points);
+    let mut search = Search::default();
+    search.ef_search(Some(4));
+    assert_eq!(hnsw.search(&Point(0.0, 0.0), &mut search).len(), 4);
+    let more = hnsw
+        .search_more(&Point(0.0, 0.0), &mut search, 3)
+        .map(|item| item.point.0)
+        .collect::<Vec<_>>();
+    assert_eq!(more, [4.0, 5.0, 6.0]);
+}
+
+#[test]
+fn probes() {
+    use instant_distance::points::Vector;
+
+    // Clusters of points in 16 dimensions, with queries in between the clusters
+    let mut rng = StdRng::seed_from_u64(1);
+    let centers = (0..32)
+        .map(|_| (0..16).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect::<Vec<Vec<f32>>>();
+    let points = (0..1024)
+        .map(|i| {
+            let center = &centers[i % centers.len()];
+            Vector(
+                center
+                    .iter()
+                    .map(|c| c + rng.gen_range(-0.1..0.1))
+                    .collect(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let queries = (0..256)
+        .map(|_| Vector((0..16).map(|_| rng.gen_range(-1.0..1.0)).collect()))
+        .collect::<Vec<_>>();
+
+    let (hnsw, pids) = Builder::default()
+        .seed(1)
+        .ef_search(1)
+        .build_hnsw(points.clone());
+    let mut found = [0, 0];
+    for (probes, found) in [1, 8].into_iter().zip(&mut found) {
+        let mut search = Search::default();
+        search.probes(probes);
+        for query in &queries {
+            let nearest = (0..points.len())
+                .min_by_key(|&i| OrderedFloat(query.distance(&points[i])))
+                .unwrap();
+            let item = hnsw.search(query, &mut search).next().unwrap();
+            if item.pid == pids[nearest] {
+                *found += 1;
+            }
+        }
+    }
+
+    println!(
+        "probes: recall with 1 probe {}, with 8 probes {}",
+        found[0], found[1]
+    );
+    assert!(found[1] > found[0]);
+}
+
+#[test]
+fn k() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let map = Builder::default()
+        .ef_search(20)
+        .build(points, (0..256).collect());
+    let mut search = Search::default();
+    let query = Point(7.2, 8.1);
+    let full = map
+        .search(&query, &mut search)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(full.len(), 20);
+
+    // Fewer results from the same beam
+    search.k(Some(3));
+    let top = map
+        .search(&query, &mut search)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(top, full[..3]);
+
+    // A wider beam to fit more results than `ef_search`
+    search.k(Some(50));
+    assert_eq!(map.search(&query, &mut search).len(), 50);
+
+    search.k(None);
+    assert_eq!(map.search(&query, &mut search).len(), 20);
+}
+
+#[test]
+fn timeout() {
+    use std::time::Duration;
+
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().seed(1).build_hnsw(points);
+
+    let mut search = Search::default();
+    search.timeout(Some(Duration::from_secs(60)));
+    assert_eq!(hnsw.search(&Point(3.0, 5.0), &mut search).len(), 100);
+    assert!(!search.truncated());
+
+    // An expired deadline stops the search right after visiting the entry point
+    search.timeout(Some(Duration::ZERO));
+    assert_eq!(hnsw.search(&Point(3.0, 5.0), &mut search).len(), 1);
+    assert!(search.truncated());
+
+    search.timeout(None);
+    assert_eq!(hnsw.search(&Point(3.0, 5.0), &mut search).len(), 100);
+    assert!(!search.truncated());
+}
+
+#[test]
+fn namespaces() {
+    use instant_distance::namespace::NamespacedMap;
+
+    // Four large namespaces interleaved on a grid, plus a small one in a corner
+    let mut points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let mut namespaces = (0..1024).map(|i| i % 4).collect::<Vec<_>>();
+    points.extend([Point(0.5, 0.5), Point(1.5, 0.5), Point(0.5, 1.5)]);
+    namespaces.extend([7, 7, 7]);
+
+    let values = (0..points.len()).collect();
+    let map = NamespacedMap::build(Builder::default(), points, namespaces, values).unwrap();
+    let mut search = Search::default();
+
+    let results = map
+        .search(&Point(18.0, 16.0), 2, &mut search)
+        .map(|item| (item.pid, *item.value))
+        .collect::<Vec<_>>();
+    assert_eq!(results.len(), 100);
+    assert_eq!(results[0].1, 16 * 32 + 18);
+    assert!(results
+        .iter()
+        .all(|&(pid, value)| { map.namespace(pid) == 2 && value % 4 == 2 && value < 1024 }));
+
+    // The small namespace is found from the other end of the index
+    let results = map
+        .search(&Point(31.0, 31.0), 7, &mut search)
+        .map(|item| *item.value)
+        .collect::<HashSet<_>>();
+    assert_eq!(results, HashSet::from([1024, 1025, 1026]));
+
+    assert_eq!(map.search(&Point(0.0, 0.0), 5, &mut search).len(), 0);
+}
+
+#[test]
+fn search_filtered() {
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let values = (0..points.len()).collect();
+    let map = Builder::default().build(points, values);
+    let mut search = Search::default();
+
+    // Only odd rows, so the point nearest to the query doesn't pass the filter
+    let results = map
+        .search_filtered(&Point(18.0, 16.0), &mut search, |_, &value| {
+            (value / 32) % 2 == 1
+        })
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(results.len(), 100);
+    assert!([15 * 32 + 18, 17 * 32 + 18].contains(&results[0]));
+    assert!(results.iter().all(|value| (value / 32) % 2 == 1));
+
+    let results = map.search_filtered(&Point(0.0, 0.0), &mut search, |_, &value| value == 1000);
+    assert_eq!(results.map(|item| *item.value).collect::<Vec<_>>(), [1000]);
+
+    // The entry point on the top layer doesn't pass the filter, but is still traversed
+    let (first, entry) = map.iter().next().unwrap();
+    let results = map
+        .search_filtered(entry, &mut search, |pid, _| pid != first)
+        .collect::<Vec<_>>();
+    assert_eq!(results.len(), 100);
+    assert_eq!(results[0].distance, 1.0);
+    assert!(results.iter().all(|item| item.pid != first));
+    assert_eq!(
+        map.search_filtered(&Point(0.0, 0.0), &mut search, |_, _| false)
+            .len(),
+        0
+    );
+}
+
+#[test]
+fn word_vectors() {
+    use instant_distance::loaders::word_vectors;
+    use instant_distance::points::Vector;
+
+    let file = "3 2\nthe 0.5 1\n\nof -1 2.5e-1\nand 0 0\nto 1 1\n";
+    let loaded = word_vectors::read::<Vector>(file.as_bytes(), None).unwrap();
+    assert_eq!(loaded.words, ["the", "of", "and", "to"]);
+    assert_eq!(loaded.points[1], Vector(vec![-1.0, 0.25]));
+
+    // GloVe files have no header
+    let loaded = word_vectors::read::<Vec<f32>>(&b"a 1 2 3\nb 4 5 6\n"[..], Some(1)).unwrap();
+    assert_eq!(loaded.words, ["a"]);
+    assert_eq!(loaded.points, [vec![1.0, 2.0, 3.0]]);
+
+    let result = word_vectors::read::<Vector>(&b"a 1 2\n\nb 3 x\n"[..], None);
+    assert!(matches!(result, Err(Error::Parse { line: 3, .. })));
+    let result = word_vectors::read::<Vector>(&b"3 2\na 1 2 3\n"[..], None);
+    assert!(matches!(
+        result,
+        Err(Error::DimensionMismatch { index: 0, .. })
+    ));
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn parquet_vectors() {
+    use std::fs::File;
+    use std::sync::Arc;
+
+    use ::parquet::arrow::ArrowWriter;
+    use arrow_array::types::{Float32Type, Float64Type};
+    use arrow_array::{ArrayRef, FixedSizeListArray, Int64Array, ListArray, RecordBatch};
+    use instant_distance::loaders::parquet;
+    use instant_distance::points::Vector;
+
+    let path = std::env::temp_dir().join(format!("vectors-{}.parquet", std::process::id()));
+    let write = |columns: Vec<(&str, ArrayRef)>| {
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let mut writer = ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None);
+        let writer = writer.as_mut().unwrap();
+        writer.write(&batch).unwrap();
+        writer.write(&batch).unwrap();
+        writer.finish().unwrap();
+    };
+
+    let rows = (0..3).map(|i| Some(vec![Some(i as f32), Some(0.5)]));
+    write(vec![
+        ("id", Arc::new(Int64Array::from(vec![1, 2, 3])) as ArrayRef),
+        (
+            "embedding",
+            Arc::new(ListArray::from_iter_primitive::<Float32Type, _, _>(rows)),
+        ),
+    ]);
+
+    let points = parquet::open::<Vector>(&path, "embedding", None).unwrap();
+    assert_eq!(points.len(), 6);
+    assert_eq!(points[4], Vector(vec![1.0, 0.5]));
+    let points = parquet::open::<Vec<f32>>(&path, "embedding", Some(2)).unwrap();
+    assert_eq!(points, [vec![0.0, 0.5], vec![1.0, 0.5]]);
+
+    let result = parquet::open::<Vector>(&path, "missing", None);
+    assert!(matches!(result, Err(Error::InvalidParameter { .. })));
+    let result = parquet::open::<Vector>(&path, "id", None);
+    assert!(matches!(result, Err(Error::InvalidParameter { .. })));
+
+    // Fixed size lists of doubles, with a null row in the second copy of the batch
+    let rows = [
+        Some(vec![Some(1.0), Some(2.0)]),
+        Some(vec![Some(3.0), Some(4.0)]),
+        None,
+    ];
+    write(vec![(
+        "embedding",
+        Arc::new(FixedSizeListArray::from_iter_primitive::<Float64Type, _, _>(rows, 2)),
+    )]);
+
+    let file = File::open(&path).unwrap();
+    let mut batches = parquet::batches::<Vector>(file, "embedding", Some(2)).unwrap();
+    let batch = batches.next().unwrap().unwrap();
+    assert_eq!(batch, [Vector(vec![1.0, 2.0]), Vector(vec![3.0, 4.0])]);
+    assert!(batches.next().is_none());
+
+    let result = parquet::open::<Vector>(&path, "embedding", None);
+    assert!(matches!(result, Err(Error::EmptyPoint { index: 2 })));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn contrastive() {
+    // Two points at the same distance from the query, one of them near a point to avoid
+    let points = vec![Point(-1.0, 0.0), Point(1.0, 0.0), Point(0.0, 5.0)];
+    let map = Builder::default().build(points, vec!["left", "right", "far"]);
+    let mut search = Search::default();
+
+    let avoid = [Point(-1.0, 0.5)];
+    let results = map
+        .search_contrastive(&Point(0.0, 0.0), &avoid, 1.0, &mut search)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(results, ["right", "left", "far"]);
+
+    let results = map
+        .search_contrastive(&Point(0.0, 0.0), &avoid, 10.0, &mut search)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(results, ["right", "far", "left"]);
+
+    // With `k`, the penalty picks from the whole beam: `x + 10 / (1 + x)` is smallest at 2
+    let points = (0..20).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+    search.k(Some(1));
+    let results = hnsw
+        .search_contrastive(&Point(0.0, 0.0), &[Point(0.0, 0.0)], 10.0, &mut search)
+        .map(|item| item.point.0)
+        .collect::<Vec<_>>();
+    assert_eq!(results, [2.0]);
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn search_multi() {
+    use instant_distance::{Fusion, Score};
+
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let map = Builder::default().build(points, (0..256).collect());
+    let mut search = Search::default();
+    let queries = [Point(2.0, 2.0), Point(12.0, 12.0)];
+
+    // Both query points find themselves at distance zero
+    let results = map
+        .search_multi(&queries, Fusion::Min, &mut search)
+        .map(|item| (*item.value, item.distance))
+        .collect::<Vec<_>>();
+    assert_eq!(results.len(), 100);
+    let mut first = vec![results[0].0, results[1].0];
+    first.sort_unstable();
+    assert_eq!(first, [2 * 16 + 2, 12 * 16 + 12]);
+    assert_eq!((results[0].1, results[1].1), (0.0, 0.0));
+
+    // The mean distance is smallest on the line between the query points
+    let item = map
+        .search_multi(&queries, Fusion::Mean, &mut search)
+        .next()
+        .unwrap();
+    assert_eq!(item.value % 17, 0);
+    assert!((item.distance - 10.0 * 2f32.sqrt() / 2.0).abs() < 1e-5);
+
+    let results = map
+        .search_multi(&queries, Fusion::ReciprocalRank { k: 60.0 }, &mut search)
+        .collect::<Vec<_>>();
+    assert!((results[0].distance + 1.0 / 61.0).abs() < 1e-6);
+    assert!(results.windows(2).all(|w| w[0].distance <= w[1].distance));
+
+    // The fused ranking isn't limited to the similarity score either
+    search.score(Score::Inverse);
+    let item = map
+        .search_multi(&queries, Fusion::ReciprocalRank { k: 60.0 }, &mut search)
+        .next()
+        .unwrap();
+    assert!((item.distance + 1.0 / 61.0).abs() < 1e-6);
+
+    // The point in the middle is only third for each query point, but nearest on average
+    let points = vec![
+        Point(-1.0, 0.3),
+        Point(-1.0, -0.3),
+        Point(1.0, 0.3),
+        Point(1.0, -0.3),
+        Point(0.0, 0.0),
+    ];
+    let map = Builder::default().build(points, (0..5).collect());
+    let queries = [Point(-1.0, 0.0), Point(1.0, 0.0)];
+    let mut search = Search::default();
+    search.k(Some(2));
+    let results = map
+        .search_multi(&queries, Fusion::Mean, &mut search)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(results.len(), 2);
+    assert_eq!(results[0], 4);
+
+    // The distance threshold applies to the fused distances
+    search.k(None);
+    search.max_distance(Some(1.05));
+    let results = map
+        .search_multi(&queries, Fusion::Mean, &mut search)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(results, [4]);
+}
+
+#[test]
+fn search_batch() {
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let map = Builder::default().build(points, (0..1024).collect());
+    let mut search = Search::default();
+
+    // A query stream drifting across the grid, with repeated queries
+    let queries = (0..64)
+        .map(|i| Point(5.0 + (i / 2) as f32 * 0.3, 7.0 + (i / 2) as f32 * 0.2))
+        .collect::<Vec<_>>();
+    let batch = map
+        .search_batch(&queries, &mut search)
+        .map(|items| items.map(|item| item.distance).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(batch.len(), queries.len());
+    for (query, found) in queries.iter().zip(batch) {
+        let expected = map
+            .search(query, &mut search)
+            .take(10)
+            .map(|item| item.distance)
+            .collect::<Vec<_>>();
+        assert_eq!(found[..10], expected);
+    }
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn score() {
+    use instant_distance::Score;
+
+    let points = vec![Point(0.0, 0.0), Point(1.0, 0.0), Point(3.0, 0.0)];
+    let map = Builder::default().build(points, vec!['a', 'b', 'c']);
+    let mut search = Search::default();
+    search.score(Score::Inverse);
+    let results = map
+        .search(&Point(0.0, 0.0), &mut search)
+        .map(|item| (*item.value, item.distance))
+        .collect::<Vec<_>>();
+    assert_eq!(results, [('a', 1.0), ('b', 0.5), ('c', 0.25)]);
+
+    search.score(Score::Distance);
+    let item = map.search(&Point(0.0, 0.0), &mut search).nth(2).unwrap();
+    assert_eq!(item.distance, 3.0);
+}
+
+#[test]
+fn try_build() {
+    let points = (0..16).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let values = (0..16).collect::<Vec<_>>();
+
+    let err = Builder::default()
+        .ml(0.0)
+        .try_build_hnsw(points.clone())
+        .err();
+    assert!(matches!(
+        err,
+        Some(Error::InvalidParameter { name: "ml", .. })
+    ));
+
+    let err = Builder::default()
+        .try_build(points.clone(), values[..8].to_vec())
+        .err();
+    assert!(matches!(
+        err,
+        Some(Error::LengthMismatch {
+            points: 16,
+            values: 8
+        })
+    ));
+
+    let map = Builder::default()
+        .seed(42)
+        .ef_construction(50)
+        .try_build(points, values)
+        .unwrap();
+    assert_eq!(map.len(), 16);
+
+    let config = map.config();
+    assert_eq!(config.seed, 42);
+    assert_eq!(config.ef_construction, 50);
+    assert_eq!(config.dims, Some(2));
+    assert!(config.metric.ends_with("Point"));
+}
+
+#[test]
+fn presets() {
+    use instant_distance::Preset;
+
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+
+    let (hnsw, _) = Builder::default()
+        .preset(Preset::Fast)
+        .build_hnsw(points.clone());
+    assert_eq!(hnsw.config().ef_search, 32);
+    assert_eq!(hnsw.config().heuristic, None);
+
+    let (hnsw, _) = Builder::default()
+        .preset(Preset::Balanced)
+        .build_hnsw(points.clone());
+    let (default, _) = Builder::default().build_hnsw(points.clone());
+    assert_eq!(hnsw.config().ef_search, default.config().ef_search);
+    assert_eq!(hnsw.config().heuristic, default.config().heuristic);
+
+    // Setters after the preset override it
+    let (hnsw, _) = Builder::default()
+        .preset(Preset::Accurate)
+        .ef_search(300)
+        .build_hnsw(points);
+    assert_eq!(hnsw.config().ef_construction, 400);
+    assert_eq!(hnsw.config().ef_search, 300);
+}
+
+#[test]
+fn non_finite() {
+    use instant_distance::points::Vector;
+
+    let mut points = vec![Point(0.0, 0.0), Point(1.0, 1.0), Point(f32::NAN, 1.0)];
+    let error = Builder::default().try_build_hnsw(points.clone());
+    assert!(matches!(error, Err(Error::NonFinite { index: 2 })));
+
+    points[2] = Point(f32::INFINITY, 1.0);
+    let error = Builder::default().try_build_hnsw(points.clone());
+    assert!(matches!(error, Err(Error::NonFinite { index: 2 })));
+
+    points.pop();
+    let (hnsw, _) = Builder::default().try_build_hnsw(points).unwrap();
+    let mut search = Search::default();
+    assert_eq!(
+        hnsw.try_search(&Point(0.5, 0.5), &mut search)
+            .unwrap()
+            .len(),
+        2
+    );
+    let error = hnsw.try_search(&Point(0.5, f32::NAN), &mut search).err();
+    assert!(matches!(error, Some(Error::NonFiniteQuery)));
+
+    let vectors = vec![Vector(vec![1.0, 2.0]), Vector(vec![f32::NEG_INFINITY, 0.0])];
+    let error = Builder::default().try_build(vectors, vec![(), ()]).err();
+    assert!(matches!(error, Some(Error::NonFinite { index: 1 })));
+}
+
+#[test]
+fn query_dimensions() {
+    use instant_distance::points::Vector;
+
+    let vectors = vec![Vector(vec![1.0, 2.0]), Vector(vec![3.0, 4.0])];
+    let map = Builder::default().try_build(vectors, vec![1, 2]).unwrap();
+    let mut search = Search::default();
+    let item = map
+        .try_search(&Vector(vec![3.0, 4.0]), &mut search)
+        .unwrap()
+        .next();
+    assert_eq!(item.map(|item| *item.value), Some(2));
+
+    let error = map.try_search(&Vector(vec![3.0]), &mut search).err();
+    assert!(matches!(
+        error,
+        Some(Error::QueryDimensionMismatch {
+            expected: 2,
+            found: 1
+        })
+    ));
+}
+
+#[test]
+fn packed() {
+    use instant_distance::packed::{Encoding, PackedHnsw};
+
+    let mut rng = StdRng::seed_from_u64(3);
+    let points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().seed(3).build_hnsw(points.clone());
+
+    let mut search = Search::default();
+    let expected = points
+        .iter()
+        .map(|point| {
+            let results = hnsw.search(point, &mut search);
+            results
+                .map(|item| (item.pid, item.distance))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let (copy, _) = Builder::default().seed(3).build_hnsw(points.clone());
+    let packed = PackedHnsw::with_encoding(copy, Encoding::Plain);
+    let plain = packed.graph_bytes();
+    assert_eq!(packed.encoding(), Encoding::Plain);
+    for (point, expected) in points.iter().zip(&expected) {
+        let found = packed.search(point, &mut search).collect::<Vec<_>>();
+        assert_eq!(found.len(), expected.len());
+        assert!(found.iter().zip(expected).all(|(a, b)| a.pid == b.0));
+    }
+
+    let packed = PackedHnsw::new(hnsw);
+    assert_eq!(packed.len(), 1024);
+    for (point, expected) in points.iter().zip(expected) {
+        let found = packed
+            .search(point, &mut search)
+            .map(|item| item.distance)
+            .collect::<Vec<_>>();
+        assert!(found.into_iter().eq(expected.into_iter().map(|(_, d)| d)));
+    }
+
+    assert!(packed.graph_bytes() < plain / 2);
+}
+
+#[test]
+fn flat() {
+    use instant_distance::packed::PackedHnsw;
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let values = (0..64).collect::<Vec<_>>();
+    let map = Builder::default()
+        .flat_threshold(100)
+        .build(points.clone(), values);
+    assert!(map.is_flat());
+
+    #[cfg(feature = "with-serde")]
+    {
+        let mut buf = Vec::new();
+        map.save(&mut buf).unwrap();
+        let loaded = instant_distance::HnswMap::<Point, i32>::load(&buf[..]).unwrap();
+        assert!(loaded.is_flat());
+    }
+
+    let mut search = Search::default();
+    let results = map
+        .search(&Point(10.2, 0.0), &mut search)
+        .map(|item| *item.value)
+        .take(3)
+        .collect::<Vec<_>>();
+    assert_eq!(results, [10, 11, 9]);
+
+    search.exclude(instant_distance::PointId::from(10));
+    let item = map.search(&Point(10.2, 0.0), &mut search).next().unwrap();
+    assert_eq!(*item.value, 11);
+    search.clear_excluded();
+
+    let mut search = Search::default();
+    let map = Builder::default()
+        .ef_search(4)
+        .flat_threshold(100)
+        .build(points.clone(), (0..64).collect());
+    assert_eq!(map.search(&Point(0.0, 0.0), &mut search).len(), 4);
+    let more = map
+        .search_more(&Point(0.0, 0.0), &mut search, 2)
+        .map(|item| *item.value)
+        .collect::<Vec<_>>();
+    assert_eq!(more, [4, 5]);
+
+    let (hnsw, pids) = Builder::default()
+        .flat_threshold(100)
+        .build_hnsw(points.clone());
+    assert!(pids
+        .iter()
+        .enumerate()
+        .all(|(i, pid)| pid.into_inner() == i as u32));
+    let packed = PackedHnsw::new(hnsw);
+    let item = packed
+        .search(&Point(30.4, 0.0), &mut search)
+        .next()
+        .unwrap();
+    assert_eq!(item.pid, pids[30]);
+
+    let (hnsw, _) = Builder::default().flat_threshold(64).build_hnsw(points);
+    assert!(!hnsw.is_flat());
+}
+
 #[test]
 fn random_heuristic() {
     let (seed, recall) = randomized(Builder::default());
@@ -46,47 +1287,1735 @@ fn random_heuristic() {
 }
 
 #[test]
-fn random_simple() {
-    let (seed, recall) = randomized(Builder::default().select_heuristic(None));
-    println!("simple (seed = {seed}) recall = {recall}");
-    assert!(recall > 90, "expected at least 90, got {recall}");
+fn random_bulk() {
+    let (seed, recall) = randomized(Builder::default().bulk(true));
+    println!("bulk (seed = {seed}) recall = {recall}");
+    assert!(recall > 97, "expected at least 98, got {recall}");
+
+    let (seed, recall) = randomized(Builder::default().bulk(true).select_heuristic(None));
+    println!("bulk simple (seed = {seed}) recall = {recall}");
+    assert!(recall > 90, "expected at least 90, got {recall}");
+}
+
+#[test]
+fn random_alpha() {
+    let heuristic = Heuristic {
+        alpha: 1.2,
+        ..Heuristic::default()
+    };
+    let (seed, recall) = randomized(Builder::default().select_heuristic(Some(heuristic)));
+    println!("alpha (seed = {seed}) recall = {recall}");
+    assert!(recall > 97, "expected at least 98, got {recall}");
+}
+
+#[test]
+fn retain() {
+    let mut rng = StdRng::seed_from_u64(4);
+    let points = (0..2048)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    for builder in [
+        Builder::default(),
+        Builder::default().select_heuristic(None),
+        Builder::default().bulk(true),
+    ] {
+        let (hnsw, _) = builder.retain(8).build_hnsw(points.clone());
+        let zero = hnsw.layers().next().unwrap();
+        let max = hnsw
+            .iter()
+            .map(|(pid, _)| zero.neighbors(pid).count())
+            .max()
+            .unwrap();
+        assert!(max <= 8, "max = {max}");
+    }
+
+    let (seed, recall) = randomized(Builder::default().retain(16));
+    println!("retain (seed = {seed}) recall = {recall}");
+    assert!(recall > 90, "expected at least 90, got {recall}");
+
+    for retain in [0, 65] {
+        assert!(matches!(
+            Builder::default()
+                .retain(retain)
+                .try_build_hnsw(points.clone()),
+            Err(Error::InvalidParameter { name: "retain", .. })
+        ));
+    }
+}
+
+#[test]
+fn upper_m() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let points = (0..4096)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    for builder in [
+        Builder::default(),
+        Builder::default().select_heuristic(None),
+        Builder::default().bulk(true),
+    ] {
+        let (hnsw, _) = builder.upper_m(vec![4, 8]).build_hnsw(points.clone());
+        assert_eq!(hnsw.config().upper_m, [4, 8]);
+
+        let max = hnsw
+            .layers()
+            .map(|layer| {
+                (0..layer.len())
+                    .map(|i| {
+                        layer
+                            .neighbors(instant_distance::PointId::from(i as u32))
+                            .count()
+                    })
+                    .max()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert!(max.len() > 3, "{max:?}");
+        assert!(max[1] <= 4 && max[2] <= 8, "{max:?}");
+    }
+
+    // Layers beyond the schedule keep up to `M` neighbors
+    let (hnsw, _) = Builder::default()
+        .select_heuristic(None)
+        .upper_m(vec![4, 8])
+        .build_hnsw(points.clone());
+    let layer = hnsw.layers().nth(3).unwrap();
+    assert_eq!(
+        layer.neighbors(instant_distance::PointId::from(0)).count(),
+        32
+    );
+
+    for upper_m in [vec![0], vec![8, 33]] {
+        assert!(matches!(
+            Builder::default()
+                .upper_m(upper_m)
+                .try_build_hnsw(points.clone()),
+            Err(Error::InvalidParameter {
+                name: "upper_m",
+                ..
+            })
+        ));
+    }
+}
+
+#[test]
+fn optimize() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    let (mut hnsw, _) = Builder::default()
+        .seed(10)
+        .select_heuristic(None)
+        .build_hnsw(points);
+
+    let links = |hnsw: &instant_distance::Hnsw<Point>| {
+        hnsw.layers().map(|layer| layer.links()).sum::<usize>()
+    };
+
+    let before = links(&hnsw);
+    let removed = hnsw.optimize(1.0);
+    assert!(removed > 0);
+    assert_eq!(links(&hnsw), before - removed);
+    let report = hnsw.verify();
+    assert!(report.is_ok(), "{report}");
+
+    // Every point is still reachable
+    let mut search = Search::default();
+    for (pid, point) in hnsw.iter() {
+        let nearest = hnsw.search(point, &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+    }
+
+    // A second pass finds nothing left to prune
+    assert_eq!(hnsw.optimize(1.0), 0);
+}
+
+#[test]
+fn shortcuts() {
+    let mut rng = StdRng::seed_from_u64(11);
+    let points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+    let (mut hnsw, _) = Builder::default()
+        .seed(11)
+        .select_heuristic(Some(Heuristic {
+            keep_pruned: false,
+            alpha: 1.2,
+            ..Heuristic::default()
+        }))
+        .build_hnsw(points);
+
+    let degrees = |hnsw: &instant_distance::Hnsw<Point>| {
+        let zero = hnsw.layers().next().unwrap();
+        (0..zero.len())
+            .map(|i| {
+                zero.neighbors(instant_distance::PointId::from(i as u32))
+                    .count()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let before = degrees(&hnsw);
+    let added = hnsw.add_shortcuts(2);
+    assert!(added > 0);
+    let after = degrees(&hnsw);
+    assert!(before
+        .iter()
+        .zip(&after)
+        .all(|(b, a)| *a >= *b && *a <= *b + 2));
+    let report = hnsw.verify();
+    assert!(report.is_ok(), "{report}");
+
+    let mut search = Search::default();
+    for (pid, point) in hnsw.iter() {
+        let nearest = hnsw.search(point, &mut search).next().unwrap();
+        assert_eq!(nearest.pid, pid);
+    }
+}
+
+#[test]
+fn extension() {
+    use instant_distance::Extension;
+
+    let mut rng = StdRng::seed_from_u64(8);
+    let points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    let build = |extend_candidates, extension| {
+        let heuristic = Heuristic {
+            extend_candidates,
+            ..Heuristic::default()
+        };
+        let (hnsw, _) = Builder::default()
+            .seed(8)
+            .bulk(true)
+            .select_heuristic(Some(heuristic))
+            .extension(extension)
+            .build_hnsw(points.clone());
+        let zero = hnsw.layers().next().unwrap();
+        hnsw.iter()
+            .map(|(pid, _)| zero.neighbors(pid).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+
+    // Without any extension budget, the graph is the same as without extension
+    let none = Extension {
+        max_candidates: Some(0),
+        ..Extension::default()
+    };
+    assert_eq!(build(true, none), build(false, Extension::default()));
+
+    let bounded = Extension {
+        max_candidates: Some(32),
+        within_furthest: true,
+    };
+    let heuristic = Heuristic {
+        extend_candidates: true,
+        ..Heuristic::default()
+    };
+    let builder = Builder::default()
+        .select_heuristic(Some(heuristic))
+        .extension(bounded);
+    let (seed, recall) = randomized(builder);
+    println!("extension (seed = {seed}) recall = {recall}");
+    assert!(recall > 90, "expected at least 90, got {recall}");
+}
+
+#[test]
+fn explicit_layers() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let layers = (0..256).map(|i| if i % 64 == 0 { 2 } else { 0 }).collect();
+
+    let (hnsw, pids) = Builder::default()
+        .layer_assignment(Explicit(layers))
+        .build_hnsw(points);
+    let mut search = Search::default();
+    let nearest = hnsw.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(nearest.pid, pids[5 * 16 + 3]);
+    assert_eq!(nearest.distance, 0.0);
+
+    let err = Builder::default()
+        .layer_assignment(Explicit(vec![0; 16]))
+        .try_build_hnsw(vec![Point(0.0, 0.0); 8])
+        .err();
+    assert!(matches!(
+        err,
+        Some(Error::LayerMismatch {
+            points: 8,
+            layers: 16
+        })
+    ));
+
+    // A stray layer number is rejected instead of allocating that many layers
+    let mut layers = vec![0; 8];
+    layers[3] = usize::MAX;
+    let err = Builder::default()
+        .layer_assignment(Explicit(layers))
+        .try_build_hnsw(vec![Point(0.0, 0.0); 8])
+        .err();
+    assert!(matches!(
+        err,
+        Some(Error::InvalidParameter {
+            name: "layer_assignment",
+            ..
+        })
+    ));
+}
+
+#[test]
+fn custom_rng() {
+    use rand::rngs::{SmallRng, StdRng};
+    use rand::SeedableRng;
+
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+
+    // The default generator is a `SmallRng` seeded from `Builder::seed()`
+    let (_, seeded) = Builder::default().seed(7).build_hnsw(points.clone());
+    let (_, custom) = Builder::default()
+        .rng(SmallRng::seed_from_u64(7))
+        .build_hnsw(points.clone());
+    assert_eq!(seeded, custom);
+
+    let (_, first) = Builder::default()
+        .rng(StdRng::seed_from_u64(7))
+        .build_hnsw(points.clone());
+    let (_, second) = Builder::default()
+        .rng(StdRng::seed_from_u64(7))
+        .build_hnsw(points);
+    assert_eq!(first, second);
+    assert_ne!(first, seeded);
+}
+
+#[test]
+fn content_hash_layers() {
+    let mut rng = StdRng::seed_from_u64(0);
+    let vectors = (0..1024)
+        .map(|_| [rng.gen::<f32>(), rng.gen::<f32>()])
+        .collect::<Vec<_>>();
+    let ml = 1.0 / 32f32.ln();
+
+    let forward = ContentHash::from_vectors(vectors.iter().map(|v| &v[..])).assign(
+        vectors.len(),
+        ml,
+        &mut rng,
+    );
+    let backward = ContentHash::from_vectors(vectors.iter().rev().map(|v| &v[..])).assign(
+        vectors.len(),
+        ml,
+        &mut rng,
+    );
+
+    assert!(forward.iter().any(|&layer| layer > 0));
+    assert_eq!(forward, backward.into_iter().rev().collect::<Vec<_>>());
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn recall() {
+    use instant_distance::eval::{mean_recall_at_k, recall_at_k};
+
+    assert_eq!(recall_at_k(&[1, 2, 3, 4], &[1, 3, 5, 7], 4), 0.5);
+    assert_eq!(recall_at_k(&[1, 2, 3, 4], &[1, 3, 5, 7], 2), 0.5);
+    assert_eq!(recall_at_k(&[1], &[1, 3], 2), 0.5);
+
+    let results = vec![vec![1, 2], vec![3, 4]];
+    let truth = vec![vec![1, 2], vec![5, 6]];
+    assert_eq!(mean_recall_at_k(&results, &truth, 2), Some(0.5));
+    assert_eq!(mean_recall_at_k::<u32>(&[], &[], 2), None);
+}
+
+#[test]
+fn sweep_ef() {
+    use std::time::Duration;
+
+    use instant_distance::eval::{exact_neighbors, sweep_ef};
+
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut point = || Point(rng.gen(), rng.gen());
+    let points = (0..2048).map(|_| point()).collect::<Vec<_>>();
+    let queries = (0..64).map(|_| point()).collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().seed(5).build_hnsw(points);
+
+    let mut search = Search::default();
+    search.ef_search(Some(5));
+    assert_eq!(hnsw.search(&queries[0], &mut search).len(), 5);
+
+    let truth = exact_neighbors(&hnsw, &queries, 10);
+    assert!(truth.iter().all(|neighbors| neighbors.len() == 10));
+    let sweep = sweep_ef(&hnsw, &queries, &truth, &[10, 40, 160], 10);
+    assert_eq!(
+        sweep.iter().map(|&(ef, _, _)| ef).collect::<Vec<_>>(),
+        [10, 40, 160]
+    );
+    assert!(sweep[2].1 >= sweep[0].1);
+    assert!(sweep[2].1 > 0.95, "recall = {}", sweep[2].1);
+    assert!(sweep
+        .iter()
+        .all(|&(_, _, latency)| latency > Duration::ZERO));
+}
+
+#[test]
+fn build_tradeoff() {
+    use instant_distance::eval::build_tradeoff;
+
+    let mut rng = StdRng::seed_from_u64(6);
+    let mut point = || Point(rng.gen(), rng.gen());
+    let sample = (0..1024).map(|_| point()).collect::<Vec<_>>();
+    let queries = (0..32).map(|_| point()).collect::<Vec<_>>();
+
+    let settings = [(8, None), (100, Some(Heuristic::default()))];
+    let builder = Builder::default().seed(6);
+    let tradeoffs = build_tradeoff(&builder, &sample, &queries, 10, &settings).unwrap();
+    assert_eq!(tradeoffs.len(), 2);
+    assert_eq!(tradeoffs[0].ef_construction, 8);
+    assert_eq!(tradeoffs[1].heuristic, Some(Heuristic::default()));
+    assert!(
+        tradeoffs[1].recall > 0.95,
+        "recall = {}",
+        tradeoffs[1].recall
+    );
+
+    assert!(matches!(
+        build_tradeoff(&builder, &sample, &queries, 10, &[(0, None)]),
+        Err(Error::InvalidParameter { .. })
+    ));
+}
+
+#[test]
+fn random_simple() {
+    let (seed, recall) = randomized(Builder::default().select_heuristic(None));
+    println!("simple (seed = {seed}) recall = {recall}");
+    assert!(recall > 90, "expected at least 90, got {recall}");
+}
+
+fn randomized(builder: Builder) -> (u64, usize) {
+    let seed = ThreadRng::default().gen::<u64>();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let points = (0..1024)
+        .map(|_| Point(rng.gen(), rng.gen()))
+        .collect::<Vec<_>>();
+
+    let query = Point(rng.gen(), rng.gen());
+    let mut nearest = Vec::with_capacity(256);
+    for (i, p) in points.iter().enumerate() {
+        nearest.push((OrderedFloat::from(query.distance(p)), i));
+        if nearest.len() >= 200 {
+            nearest.sort_unstable();
+            nearest.truncate(100);
+        }
+    }
+
+    let (hnsw, pids) = builder.seed(seed).build_hnsw(points);
+    let report = hnsw.verify();
+    assert!(report.is_ok(), "{report}");
+
+    let mut search = Search::default();
+    let results = hnsw.search(&query, &mut search);
+    assert!(results.len() >= 100);
+
+    nearest.sort_unstable();
+    nearest.truncate(100);
+    let forced = nearest
+        .iter()
+        .map(|(_, i)| pids[*i])
+        .collect::<HashSet<_>>();
+    let found = results
+        .take(100)
+        .map(|item| item.pid)
+        .collect::<HashSet<_>>();
+    (seed, forced.intersection(&found).count())
+}
+
+#[cfg(feature = "with-serde")]
+#[test]
+fn save_load() {
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let values = (0..256).collect::<Vec<u32>>();
+    let map = Builder::default().seed(1).build(points, values);
+
+    let mut buf = Vec::new();
+    map.save(&mut buf).unwrap();
+    let loaded = instant_distance::HnswMap::<Point, u32>::load(&buf[..]).unwrap();
+    assert_eq!(loaded.config(), map.config());
+
+    let mut search = Search::default();
+    let item = loaded.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(*item.value, 5 * 16 + 3);
+    assert_eq!(item.index, Some(5 * 16 + 3));
+
+    // The format is little-endian with fixed-width integers: `Config::m` follows the header,
+    // followed by the length of `Config::upper_m`
+    assert_eq!(&buf[..12], b"idhnsw\0\0\x04\0\0\0");
+    assert_eq!(buf[12..20], 32u64.to_le_bytes());
+    assert_eq!(buf[20..28], 0u64.to_le_bytes());
+
+    // Corruption in the body is caught by the checksum
+    let mut corrupted = buf.clone();
+    let last = corrupted.len() - 5;
+    corrupted[last] ^= 0x01;
+    let err = instant_distance::HnswMap::<Point, u32>::load(&corrupted[..]).err();
+    assert!(matches!(err, Some(Error::ChecksumMismatch { .. })));
+
+    // Format version 3 had no `Config::upper_m`
+    let mut v3 = [&buf[..20], &buf[28..buf.len() - 4]].concat();
+    v3[8..12].copy_from_slice(&3u32.to_le_bytes());
+    let checksum = crc32fast::hash(&v3[12..]);
+    v3.extend(checksum.to_le_bytes());
+    let loaded = instant_distance::HnswMap::<Point, u32>::load(&v3[..]).unwrap();
+    assert_eq!(loaded.config(), map.config());
+    assert_eq!(
+        loaded
+            .search(&Point(3.0, 5.0), &mut search)
+            .next()
+            .unwrap()
+            .index,
+        Some(83)
+    );
+
+    // Format version 1 had no checksum and no input positions, which precede the values
+    let values = buf.len() - 4 - (8 + 256 * 4);
+    let inputs = values - (1 + 8 + 256 * 4);
+    let mut unchecked = [&buf[..20], &buf[28..inputs], &buf[values..buf.len() - 4]].concat();
+    unchecked[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let loaded = instant_distance::HnswMap::<Point, u32>::load(&unchecked[..]).unwrap();
+    let item = loaded.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(*item.value, 5 * 16 + 3);
+    assert_eq!(item.index, None);
+
+    let path = std::env::temp_dir().join(format!("save-load-{}.idx", std::process::id()));
+    map.save_file(&path).unwrap();
+    let loaded = instant_distance::HnswMap::<Point, u32>::load_file(&path).unwrap();
+    assert_eq!(loaded.len(), map.len());
+    std::fs::remove_file(&path).unwrap();
+
+    // Bump the format version past the current one
+    buf[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    let err = instant_distance::HnswMap::<Point, u32>::load(&buf[..]).err();
+    assert!(matches!(err, Some(Error::UnsupportedVersion(u32::MAX))));
+
+    // Format version 0 had no header and only recorded `ef_search`
+    let mut legacy = Vec::new();
+    legacy.extend(100u64.to_le_bytes()); // ef_search
+    legacy.extend(1u64.to_le_bytes()); // points
+    legacy.extend(1.0f32.to_le_bytes());
+    legacy.extend(2.0f32.to_le_bytes());
+    legacy.extend(1u64.to_le_bytes()); // zero
+    legacy.extend([0xff; 64 * 4]);
+    legacy.extend(0u64.to_le_bytes()); // layers
+    let hnsw = instant_distance::Hnsw::<Point>::load(&legacy[..]).unwrap();
+    assert_eq!(hnsw.len(), 1);
+    assert_eq!(hnsw.config().ef_search, 100);
+
+    // Links to points that don't exist are rejected instead of panicking during search
+    let start = legacy.len() - 8 - 64 * 4;
+    legacy[start..start + 4].copy_from_slice(&5u32.to_le_bytes());
+    let err = instant_distance::Hnsw::<Point>::load(&legacy[..]).err();
+    assert!(matches!(err, Some(Error::InvalidIndex(_))));
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn save_load_encrypted() {
+    use instant_distance::{Hnsw, HnswMap};
+
+    // Large enough to span several chunks
+    let points = (0..1024)
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
+        .collect::<Vec<_>>();
+    let values = (0..1024).collect::<Vec<u32>>();
+    let map = Builder::default().seed(1).build(points, values);
+    let key = [7; 32];
+
+    let mut buf = Vec::new();
+    map.save_encrypted(&mut buf, &key).unwrap();
+    assert!(buf.len() > 2 * 64 * 1024);
+    assert_eq!(&buf[..8], b"idcrypt\0");
+    let loaded = HnswMap::<Point, u32>::load_encrypted(&buf[..], &key).unwrap();
+    let mut search = Search::default();
+    let item = loaded.search(&Point(3.0, 5.0), &mut search).next().unwrap();
+    assert_eq!(*item.value, 5 * 32 + 3);
+
+    // Every save uses a new nonce
+    let mut again = Vec::new();
+    map.save_encrypted(&mut again, &key).unwrap();
+    assert_ne!(again, buf);
+
+    let invalid_data = |result: Result<HnswMap<Point, u32>, Error>| matches!(result, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::InvalidData);
+    assert!(invalid_data(HnswMap::load_encrypted(&buf[..], &[8; 32])));
+    let mut modified = buf.clone();
+    modified[100] ^= 0x01;
+    assert!(invalid_data(HnswMap::load_encrypted(&modified[..], &key)));
+    // Cut after the first chunk, which then fails to authenticate as the last one
+    let truncated = &buf[..8 + 19 + 64 * 1024 + 16];
+    assert!(invalid_data(HnswMap::load_encrypted(truncated, &key)));
+
+    let mut plain = Vec::new();
+    map.save(&mut plain).unwrap();
+    let result = HnswMap::<Point, u32>::load_encrypted(&plain[..], &key);
+    assert!(matches!(result, Err(Error::Serialization(_))));
+
+    let (hnsw, _) = map.into_parts();
+    let mut buf = Vec::new();
+    hnsw.save_encrypted(&mut buf, &key).unwrap();
+    let loaded = Hnsw::<Point>::load_encrypted(&buf[..], &key).unwrap();
+    assert_eq!(loaded.len(), 1024);
+}
+
+#[cfg(feature = "with-serde")]
+#[test]
+fn upstream() {
+    use bincode::Options;
+
+    /// The layout of `Hnsw` in the upstream crate, with the neighbor arrays split up so serde
+    /// can derive their implementations
+    #[derive(serde::Serialize)]
+    struct UpstreamHnsw {
+        ef_search: usize,
+        points: Vec<Point>,
+        zero: Vec<([u32; 32], [u32; 32])>,
+        layers: Vec<Vec<[u32; 32]>>,
+    }
+
+    #[derive(serde::Serialize)]
+    struct UpstreamHnswMap {
+        hnsw: UpstreamHnsw,
+        values: Vec<char>,
+    }
+
+    let mut zero = vec![([u32::MAX; 32], [u32::MAX; 32]); 2];
+    zero[0].0[0] = 1;
+    zero[1].0[0] = 0;
+    let hnsw = UpstreamHnsw {
+        ef_search: 50,
+        points: vec![Point(0.0, 0.0), Point(1.0, 1.0)],
+        zero,
+        layers: vec![],
+    };
+    let upstream = UpstreamHnswMap {
+        hnsw,
+        values: vec!['a', 'b'],
+    };
+
+    // Upstream users could pick any serde format; use one that `load()` doesn't detect
+    let options = bincode::DefaultOptions::new().with_big_endian();
+    let buf = options.serialize(&upstream).unwrap();
+    let mut deserializer = bincode::Deserializer::from_slice(&buf, options);
+    let map =
+        instant_distance::HnswMap::<Point, char>::deserialize_upstream(&mut deserializer).unwrap();
+    assert_eq!(map.config().ef_search, 50);
+
+    let mut search = Search::default();
+    let item = map.search(&Point(0.9, 0.9), &mut search).next().unwrap();
+    assert_eq!(*item.value, 'b');
+}
+
+#[cfg(feature = "with-serde")]
+#[test]
+fn verify() {
+    use instant_distance::{Hnsw, Problem};
+
+    // A format version 0 index with three points on the zero layer
+    let mut legacy = Vec::new();
+    legacy.extend(100u64.to_le_bytes()); // ef_search
+    legacy.extend(3u64.to_le_bytes()); // points
+    for value in [0.0f32, 0.0, 1.0, 0.0, f32::NAN, 0.0] {
+        legacy.extend(value.to_le_bytes());
+    }
+    legacy.extend(3u64.to_le_bytes()); // zero
+    for links in [&[1, 2][..], &[0, 0], &[1, u32::MAX, 2]] {
+        for slot in 0..64 {
+            legacy.extend(links.get(slot).unwrap_or(&u32::MAX).to_le_bytes());
+        }
+    }
+    legacy.extend(0u64.to_le_bytes()); // layers
+
+    // These problems don't stop the index from loading, but are found by `verify()`
+    let hnsw = Hnsw::<Point>::load(&legacy[..]).unwrap();
+    let report = hnsw.verify();
+    let pids = hnsw.iter().map(|(pid, _)| pid).collect::<Vec<_>>();
+    assert_eq!(
+        report.problems,
+        [
+            Problem::NonFinite { pid: pids[2] },
+            Problem::DuplicateLink {
+                layer: 0,
+                node: pids[1],
+                link: pids[0]
+            },
+            Problem::LinkAfterEnd {
+                layer: 0,
+                node: pids[2]
+            },
+        ]
+    );
+
+    let err = Hnsw::<Point>::load_verified(&legacy[..]).err();
+    assert!(matches!(err, Some(Error::InvalidIndex(reason)) if reason.starts_with("3 problem")));
+}
+
+#[test]
+fn raw_parts() {
+    use instant_distance::{Hnsw, RawParts};
+
+    let points = (0..256)
+        .map(|i| Point((i % 16) as f32, (i / 16) as f32))
+        .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().seed(1).build_hnsw(points);
+    let mut search = Search::default();
+    let expected = hnsw
+        .search(&Point(3.0, 5.0), &mut search)
+        .map(|item| (item.pid, item.index))
+        .collect::<Vec<_>>();
+
+    let parts = hnsw.into_raw_parts();
+    assert_eq!(
+        parts.neighbors[0].len(),
+        256 * RawParts::<Point>::ZERO_SLOTS
+    );
+    let hnsw = Hnsw::from_raw_parts(parts.clone()).unwrap();
+    let found = hnsw
+        .search(&Point(3.0, 5.0), &mut search)
+        .map(|item| (item.pid, item.index))
+        .collect::<Vec<_>>();
+    assert_eq!(found, expected);
+
+    let mut truncated = parts.clone();
+    truncated.neighbors[0].pop();
+    let err = Hnsw::from_raw_parts(truncated).err();
+    assert!(matches!(err, Some(Error::InvalidIndex(_))));
+
+    let mut dangling = parts.clone();
+    dangling.neighbors[0][0] = 256;
+    let err = Hnsw::from_raw_parts(dangling).err();
+    assert!(matches!(err, Some(Error::InvalidIndex(_))));
+
+    let mut order = parts;
+    order.order.as_mut().unwrap()[1] = order.order.as_ref().unwrap()[0];
+    let err = Hnsw::from_raw_parts(order).err();
+    assert!(matches!(err, Some(Error::InvalidIndex(reason)) if reason.contains("input position")));
+}
+
+#[cfg(feature = "with-serde")]
+#[test]
+fn checkpoint_resume() {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    // Panics after a number of distance calculations while `FAIL` is set, to interrupt a build
+    static FAIL: AtomicBool = AtomicBool::new(true);
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+    struct Flaky(Point);
+
+    impl instant_distance::Point for Flaky {
+        fn distance(&self, other: &Self) -> f32 {
+            if FAIL.load(Ordering::Relaxed) && CALLS.fetch_add(1, Ordering::Relaxed) > 500_000 {
+                panic!("interrupted");
+            }
+            self.0.distance(&other.0)
+        }
+    }
+
+    let points = (0..1024)
+        .map(|i| Flaky(Point((i % 32) as f32, (i / 32) as f32)))
+        .collect::<Vec<_>>();
+    let values = (0..1024).collect::<Vec<u32>>();
+    let path = std::env::temp_dir().join(format!("checkpoint-{}.ckpt", std::process::id()));
+
+    let builder = Builder::default().seed(7);
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        builder.build_checkpointed(points, values, &path, 32).ok();
+    }));
+    assert!(result.is_err());
+
+    FAIL.store(false, Ordering::Relaxed);
+    assert!(path.exists());
+    let map = Builder::default().resume::<Flaky, u32>(&path).unwrap();
+    assert!(!path.exists());
+    assert_eq!(map.len(), 1024);
+
+    let mut search = Search::default();
+    let item = map
+        .search(&Flaky(Point(3.0, 5.0)), &mut search)
+        .next()
+        .unwrap();
+    assert_eq!(*item.value, 5 * 32 + 3);
 }
 
-fn randomized(builder: Builder) -> (u64, usize) {
-    let seed = ThreadRng::default().gen::<u64>();
-    let mut rng = StdRng::seed_from_u64(seed);
+#[test]
+#[allow(clippy::float_cmp)]
+fn fixed_point() {
+    use instant_distance::points::{FixedPoint, PointDataSource};
+
+    // Use a dimensionality that isn't a multiple of the number of lanes
+    let mut rng = StdRng::seed_from_u64(0);
+    let points = (0..256)
+        .map(|_| FixedPoint::<13>(rng.gen()))
+        .collect::<Vec<_>>();
+    for pair in points.windows(2) {
+        let expected = pair[0]
+            .data()
+            .iter()
+            .zip(pair[1].data())
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>()
+            .sqrt();
+        assert!((pair[0].distance(&pair[1]) - expected).abs() < 1e-5);
+    }
+
+    let (hnsw, pids) = Builder::default().build_hnsw(points.clone());
+    assert_eq!(hnsw.dims(), Some(13));
+    let mut search = Search::default();
+    let item = hnsw.search(&points[42], &mut search).next().unwrap();
+    assert_eq!(item.pid, pids[42]);
+    assert_eq!(item.distance, 0.0);
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn distance_bounded() {
+    use instant_distance::points::{FixedPoint, Vector};
+
+    // More components than fit in one block between bound checks
+    let mut rng = StdRng::seed_from_u64(0);
+    let (mut a, mut b) = ([0.0; 200], [0.0; 200]);
+    rng.fill(&mut a[..]);
+    rng.fill(&mut b[..]);
+    let (fixed, other) = (FixedPoint(a), FixedPoint(b));
+    let distance = fixed.distance(&other);
+    assert_eq!(fixed.distance_bounded(&other, distance), distance);
+    assert_eq!(
+        fixed.distance_bounded(&other, distance * 0.9),
+        f32::INFINITY
+    );
+
+    let (vector, other) = (Vector(a.to_vec()), Vector(b.to_vec()));
+    assert_eq!(vector.distance(&other), distance);
+    assert_eq!(vector.distance_bounded(&other, f32::INFINITY), distance);
+    assert_eq!(vector.distance_bounded(&other, 0.1), f32::INFINITY);
+
+    // Bounded distances don't change the results
+    let points = (0..128)
+        .map(|_| Vector((0..80).map(|_| rng.gen()).collect()))
+        .collect::<Vec<_>>();
+    let map = Builder::default()
+        .seed(0)
+        .build(points.clone(), (0..128).collect());
+    let mut search = Search::default();
+    for query in &points[..16] {
+        let mut expected = points
+            .iter()
+            .map(|p| OrderedFloat(query.distance(p)))
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        let found = map
+            .search(query, &mut search)
+            .take(10)
+            .map(|item| OrderedFloat(item.distance))
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected[..10]);
+    }
+}
+
+#[test]
+fn weighted() {
+    use instant_distance::transform::{TransformedMap, Weighted};
+
+    assert!(Weighted::new(vec![1.0, -1.0]).is_err());
+
+    // With the second dimension ignored, only the first one determines the nearest point
+    let points = (0..64)
+        .map(|i| vec![(i % 8) as f32, (i / 8) as f32 * 100.0])
+        .collect::<Vec<_>>();
+    let weights = Weighted::new(vec![4.0, 0.0]).unwrap();
+    let values = (0..64).collect();
+    let map = TransformedMap::build(Builder::default(), weights, &points, values).unwrap();
+
+    let mut search = Search::default();
+    for item in map.search(&[3.0, 0.0], &mut search).take(8) {
+        assert_eq!(item.value % 8, 3);
+        assert_eq!(item.distance, 0.0);
+    }
+
+    let item = map.search(&[5.0, 0.0], &mut search).nth(8).unwrap();
+    assert!([4, 6].contains(&(item.value % 8)));
+    assert_eq!(item.distance, 2.0);
+
+    let error = TransformedMap::build(
+        Builder::default(),
+        Weighted::new(vec![1.0; 3]).unwrap(),
+        &points,
+        (0..64).collect(),
+    );
+    assert!(matches!(
+        error,
+        Err(Error::DimensionMismatch { index: 0, .. })
+    ));
+}
+
+#[test]
+fn mahalanobis() {
+    use instant_distance::transform::{Mahalanobis, Transform};
+
+    let distance = |metric: &Mahalanobis, a: &[f32], b: &[f32]| {
+        let (a, b) = (metric.apply(a), metric.apply(b));
+        a.iter()
+            .zip(&b)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>()
+            .sqrt()
+    };
+
+    // For a diagonal covariance, all three constructors describe the same metric
+    let diagonal = Mahalanobis::diagonal(vec![4.0, 0.25]).unwrap();
+    let covariance = Mahalanobis::covariance(&[vec![4.0, 0.0], vec![0.0, 0.25]]).unwrap();
+    let whitening = Mahalanobis::whitening(&[vec![0.5, 0.0], vec![0.0, 2.0]]).unwrap();
+    for metric in [&diagonal, &covariance, &whitening] {
+        assert!((distance(metric, &[0.0, 0.0], &[2.0, 0.5]) - 2f32.sqrt()).abs() < 1e-6);
+    }
+
+    // Correlated dimensions: moving along the correlation is cheaper than moving against it
+    let correlated = Mahalanobis::covariance(&[vec![1.0, 0.9], vec![0.9, 1.0]]).unwrap();
+    let along = distance(&correlated, &[0.0, 0.0], &[1.0, 1.0]);
+    let against = distance(&correlated, &[0.0, 0.0], &[1.0, -1.0]);
+    assert!((along - (2.0f32 / 1.9).sqrt()).abs() < 1e-5);
+    assert!((against - (2.0f32 / 0.1).sqrt()).abs() < 1e-4);
+
+    assert!(Mahalanobis::covariance(&[vec![1.0, 2.0], vec![2.0, 1.0]]).is_err());
+    assert!(Mahalanobis::whitening(&[vec![1.0, 2.0], vec![1.0]]).is_err());
+    assert_eq!(
+        Mahalanobis::whitening(&[vec![1.0, 0.0, 1.0]])
+            .unwrap()
+            .apply(&[1.0, 2.0, 3.0]),
+        vec![4.0]
+    );
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn cosine() {
+    use instant_distance::points::Cosine;
+    use instant_distance::Score;
+
+    let a = Cosine::new(vec![3.0, 4.0]);
+    assert_eq!(a.norm(), 5.0);
+    assert_eq!(a.distance(&Cosine::new(vec![6.0, 8.0])), 0.0);
+    assert!((a.distance(&Cosine::new(vec![-4.0, 3.0])) - 1.0).abs() < 1e-6);
+    assert_eq!(a.distance(&Cosine::new(vec![-3.0, -4.0])), 2.0);
+    assert_eq!(a.distance(&Cosine::new(vec![0.0, 0.0])), 1.0);
+    assert!(!Cosine::new(vec![f32::NAN, 1.0]).is_finite());
+
+    // Points on a circle with varying lengths; only the angle matters
+    let points = (0..64)
+        .map(|i| {
+            let (angle, length) = (i as f32 * std::f32::consts::PI / 32.0, 1.0 + (i % 5) as f32);
+            Cosine::from(vec![angle.cos() * length, angle.sin() * length])
+        })
+        .collect::<Vec<_>>();
+    let map = Builder::default().build(points, (0..64).collect());
+    let mut search = Search::default();
+    search.score(Score::Cosine);
+    let query = Cosine::new(vec![0.0, 0.1]);
+    let item = map.search(&query, &mut search).next().unwrap();
+    assert_eq!(*item.value, 16);
+    assert!((item.distance - 1.0).abs() < 1e-6);
+
+    // Opposite first blocks and short tails: after the first block, the remaining components
+    // can't bring the points within the bound, so the rest of the dot product is skipped
+    let (mut a, mut b) = (vec![0.01; 256], vec![0.01; 256]);
+    a[..64].fill(1.0);
+    b[..64].fill(-1.0);
+    let (a, b) = (Cosine::new(a), Cosine::new(b));
+    let distance = a.distance(&b);
+    assert!(distance > 1.9);
+    assert_eq!(a.distance_bounded(&b, 1.5), f32::INFINITY);
+    assert_eq!(a.distance_bounded(&b, distance), distance);
+    assert_eq!(a.distance_bounded(&b, f32::INFINITY), distance);
+
+    // Bounded distances don't change the results
+    let mut rng = StdRng::seed_from_u64(0);
+    let points = (0..128)
+        .map(|_| Cosine::new((0..200).map(|_| rng.gen::<f32>() - 0.5).collect()))
+        .collect::<Vec<_>>();
+    let map = Builder::default()
+        .seed(0)
+        .build(points.clone(), (0..128).collect());
+    let mut search = Search::default();
+    for query in &points[..16] {
+        let mut expected = points
+            .iter()
+            .map(|p| OrderedFloat(query.distance(p)))
+            .collect::<Vec<_>>();
+        expected.sort_unstable();
+        let found = map
+            .search(query, &mut search)
+            .take(10)
+            .map(|item| OrderedFloat(item.distance))
+            .collect::<Vec<_>>();
+        assert_eq!(found, expected[..10]);
+    }
+}
+
+#[test]
+fn iter_layer() {
     let points = (0..1024)
-        .map(|_| Point(rng.gen(), rng.gen()))
+        .map(|i| Point((i % 32) as f32, (i / 32) as f32))
         .collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+    assert_eq!(hnsw.iter_layer(0).len(), 1024);
 
-    let query = Point(rng.gen(), rng.gen());
-    let mut nearest = Vec::with_capacity(256);
-    for (i, p) in points.iter().enumerate() {
-        nearest.push((OrderedFloat::from(query.distance(p)), i));
-        if nearest.len() >= 200 {
-            nearest.sort_unstable();
-            nearest.truncate(100);
-        }
+    // Each layer holds a prefix of the points of the layer below it
+    let sizes = (0..)
+        .map(|layer| hnsw.iter_layer(layer).len())
+        .take_while(|&len| len > 0)
+        .collect::<Vec<_>>();
+    assert!(sizes.len() > 1);
+    assert!(sizes.windows(2).all(|pair| pair[0] >= pair[1]));
+
+    let (entry, _) = hnsw.iter_layer(sizes.len() - 1).next().unwrap();
+    assert_eq!(Some(entry), hnsw.iter().map(|(pid, _)| pid).next());
+
+    let layers = hnsw.layers().collect::<Vec<_>>();
+    assert_eq!(layers.iter().map(|l| l.len()).collect::<Vec<_>>(), sizes);
+    assert_eq!((layers[0].layer(), layers[0].max_neighbors()), (0, 64));
+    assert_eq!((layers[1].layer(), layers[1].max_neighbors()), (1, 32));
+    for layer in &layers {
+        let links = hnsw
+            .iter_layer(layer.layer())
+            .flat_map(|(pid, _)| layer.neighbors(pid))
+            .inspect(|pid| assert!((pid.into_inner() as usize) < layer.len()))
+            .count();
+        assert_eq!(layer.links(), links);
+        assert!(links > 0 || layer.len() == 1);
     }
+}
 
-    let (hnsw, pids) = builder.seed(seed).build_hnsw(points);
+#[test]
+fn reconstruct() {
+    use instant_distance::points::{Cosine, FixedPoint, Vector};
+
+    let points = (0..16)
+        .map(|i| Vector(vec![i as f32, 1.0, 2.0]))
+        .collect::<Vec<_>>();
+    let map = Builder::default().build(points, (0..16).collect::<Vec<u32>>());
+    let (pid, point) = map.iter().find(|(_, p)| p.0[0] == 5.0).unwrap();
+
+    assert_eq!(map.reconstruct::<Vec<f32>>(pid), Some(point.0.clone()));
+    assert_eq!(map.reconstruct::<[f32; 3]>(pid), Some([5.0, 1.0, 2.0]));
+    assert_eq!(
+        map.reconstruct::<FixedPoint<3>>(pid),
+        Some(FixedPoint([5.0, 1.0, 2.0]))
+    );
+    assert_eq!(map.reconstruct::<FixedPoint<4>>(pid), None);
+
+    let cosine = map.reconstruct::<Cosine>(pid).unwrap();
+    assert_eq!(cosine.norm(), 30f32.sqrt());
+}
+
+#[test]
+#[allow(clippy::float_cmp)]
+fn divergences() {
+    use instant_distance::points::{kl_divergence, JensenShannon, KullbackLeibler};
+
+    let (p, q) = (vec![0.5, 0.5, 0.0], vec![0.25, 0.25, 0.5]);
+    assert_eq!(kl_divergence(&p, &q), 1.0);
+    assert_eq!(kl_divergence(&q, &p), f32::INFINITY);
+    assert_eq!(
+        KullbackLeibler(p.clone()).distance(&KullbackLeibler(p.clone())),
+        0.0
+    );
+
+    let (a, b) = (JensenShannon(p.clone()), JensenShannon(q.clone()));
+    assert_eq!(a.distance(&b), b.distance(&a));
+    assert_eq!(a.distance(&a), 0.0);
+    let disjoint = JensenShannon(vec![0.0, 0.0, 1.0]);
+    assert!((a.distance(&disjoint) - 1.0).abs() < 1e-6);
+
+    // Histograms peaking at different positions
+    let histograms = (0..32)
+        .map(|peak| {
+            let weights = (0..8)
+                .map(|i| 1.0 / (1.0 + (i as f32 - peak as f32 / 4.0).powi(2)))
+                .collect::<Vec<_>>();
+            let sum = weights.iter().sum::<f32>();
+            weights.into_iter().map(|w| w / sum).collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let points = histograms.iter().cloned().map(JensenShannon).collect();
+    let map = Builder::default().build(points, (0..32).collect());
     let mut search = Search::default();
-    let results = hnsw.search(&query, &mut search);
-    assert!(results.len() >= 100);
+    let query = JensenShannon(histograms[13].clone());
+    let nearest = map
+        .search(&query, &mut search)
+        .take(3)
+        .map(|item| *item.value)
+        .collect::<HashSet<_>>();
+    assert_eq!(nearest, HashSet::from([12, 13, 14]));
+}
 
-    nearest.sort_unstable();
-    nearest.truncate(100);
-    let forced = nearest
+#[test]
+#[allow(clippy::float_cmp)]
+fn jaccard() {
+    use instant_distance::points::{BitSet, Jaccard};
+
+    let a = Jaccard([1, 2, 3, 100].into_iter().collect());
+    let b = Jaccard([2, 3, 4].into_iter().collect());
+    assert_eq!(a.0.len(), 4);
+    assert!(a.0.contains(100) && !a.0.contains(4));
+    assert_eq!(a.distance(&b), 1.0 - 2.0 / 5.0);
+    assert_eq!(b.distance(&a), a.distance(&b));
+    assert_eq!(
+        Jaccard(BitSet::default()).distance(&Jaccard(BitSet::with_capacity(8))),
+        0.0
+    );
+
+    // Sets of four consecutive tags
+    let points = (0..64)
+        .map(|i| Jaccard((i..i + 4).collect()))
+        .collect::<Vec<_>>();
+    let map = Builder::default().build(points, (0..64).collect());
+    let mut search = Search::default();
+    let query = Jaccard([20, 21, 22].into_iter().collect());
+    let item = map.search(&query, &mut search).next().unwrap();
+    assert!([19, 20].contains(item.value));
+    assert_eq!(item.distance, 0.25);
+}
+
+#[test]
+fn prefix() {
+    use instant_distance::prefix::PrefixIndex;
+
+    // The prefix only tells the rows apart; the full vectors are needed to find the column
+    let points = (0..256)
+        .map(|i| [(i / 16) as f32, 0.0, (i % 16) as f32])
+        .collect::<Vec<_>>();
+    let index = PrefixIndex::build(Builder::default(), 2, &points, (0..256).collect()).unwrap();
+    assert_eq!((index.prefix(), index.dims(), index.len()), (2, 3, 256));
+
+    let mut search = Search::default();
+    let results = index.search(&[5.0, 0.0, 3.0], 3, &mut search);
+    assert_eq!(results[0], (&(5 * 16 + 3), 0.0));
+    assert_eq!(results[1].1, 1.0);
+    assert_eq!(results.len(), 3);
+
+    let error = PrefixIndex::build(Builder::default(), 4, &points, (0..256).collect());
+    assert!(matches!(
+        error,
+        Err(Error::InvalidParameter { name: "prefix", .. })
+    ));
+}
+
+#[test]
+fn pca() {
+    use instant_distance::transform::{Pca, Transform, TransformedMap};
+
+    // Points on a tilted plane in 3D, with most variance along (1, 1, 0)
+    let mut rng = StdRng::seed_from_u64(0);
+    let points = (0..512)
+        .map(|_| {
+            let (a, b) = (rng.gen_range(-10.0..10.0), rng.gen_range(-1.0..1.0));
+            vec![a + b + 5.0, a - b, 2.0 * b]
+        })
+        .collect::<Vec<_>>();
+
+    let pca = Pca::fit(&points, 2).unwrap();
+    let variances = pca.variances();
+    assert!(variances[0] > variances[1] && variances[1] > 0.0);
+
+    // The plane is spanned by the two components, so distances are preserved exactly
+    let (a, b) = (pca.apply(&points[0]), pca.apply(&points[1]));
+    let projected = a.iter().zip(&b).map(|(a, b)| (a - b).powi(2)).sum::<f32>();
+    let original = points[0]
         .iter()
-        .map(|(_, i)| pids[*i])
-        .collect::<HashSet<_>>();
-    let found = results
-        .take(100)
-        .map(|item| item.pid)
-        .collect::<HashSet<_>>();
-    (seed, forced.intersection(&found).count())
+        .zip(&points[1])
+        .map(|(a, b)| (a - b).powi(2))
+        .sum::<f32>();
+    assert!((projected - original).abs() / original < 1e-3);
+
+    let map = TransformedMap::build(Builder::default(), pca, &points, (0..512).collect()).unwrap();
+    let mut search = Search::default();
+    let item = map.search(&points[42], &mut search).next().unwrap();
+    assert_eq!(*item.value, 42);
+
+    // OPQ ordering balances the variance of the two subspaces
+    let points = (0..512)
+        .map(|_| {
+            (0..4)
+                .map(|i| rng.gen_range(-1.0..1.0) * (1 << i) as f32)
+                .collect()
+        })
+        .collect::<Vec<Vec<f32>>>();
+    let opq = Pca::fit_opq(&points, 4, 2).unwrap();
+    let variances = opq.variances();
+    assert!(variances[0] > variances[2] && variances[3] > variances[1]);
+    assert!(Pca::fit_opq(&points, 4, 3).is_err());
+    assert!(Pca::fit(&points, 5).is_err());
+}
+
+#[test]
+fn random_projection() {
+    use instant_distance::transform::{RandomProjection, Transform};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let points = (0..32)
+        .map(|_| (0..512).map(|_| rng.gen_range(-1.0..1.0)).collect())
+        .collect::<Vec<Vec<f32>>>();
+    let distance = |a: &[f32], b: &[f32]| {
+        a.iter()
+            .zip(b)
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>()
+            .sqrt()
+    };
+
+    for projection in [
+        RandomProjection::gaussian(512, 256, 7).unwrap(),
+        RandomProjection::sparse(512, 256, 7).unwrap(),
+    ] {
+        let projected = points
+            .iter()
+            .map(|point| projection.apply(point))
+            .collect::<Vec<_>>();
+        assert_eq!(projected[0].len(), 256);
+        for (a, b) in (0..32).zip(1..32) {
+            let ratio = distance(&projected[a], &projected[b]) / distance(&points[a], &points[b]);
+            assert!((0.75..1.25).contains(&ratio), "ratio {ratio}");
+        }
+    }
+
+    assert_eq!(
+        RandomProjection::sparse(16, 4, 1).unwrap(),
+        RandomProjection::sparse(16, 4, 1).unwrap()
+    );
+    assert!(RandomProjection::gaussian(16, 0, 1).is_err());
+}
+
+#[test]
+fn sq8() {
+    use instant_distance::quantize::Sq8;
+
+    let vectors = [[0.0, 1.0], [2.0, -0.55]];
+    let quantizer = Sq8::train(vectors);
+    let code = quantizer.encode(&vectors[1]);
+    for (decoded, original) in quantizer.decode(&code).iter().zip(vectors[1]) {
+        assert!((decoded - original).abs() <= quantizer.scale() / 2.0);
+    }
+}
+
+#[test]
+fn sq4() {
+    use instant_distance::quantize::Sq4;
+
+    let vectors = [vec![0.0, 1.0, 3.0], vec![2.0, -0.55, 0.4]];
+    let quantizer = Sq4::train(&vectors);
+    let codes = vectors
+        .iter()
+        .map(|v| quantizer.encode(v))
+        .collect::<Vec<_>>();
+    assert_eq!(codes[0].as_bytes().len(), 2);
+    assert_eq!(codes[0].dims(), Some(3));
+
+    let decoded = vectors
+        .iter()
+        .zip(&codes)
+        .map(|(vector, code)| {
+            let decoded = quantizer.decode(code);
+            for (decoded, original) in decoded.iter().zip(vector) {
+                assert!((decoded - original).abs() <= quantizer.scale() / 2.0);
+            }
+            decoded
+        })
+        .collect::<Vec<_>>();
+
+    // Code distances match the distances between the decoded vectors
+    let exact = decoded[0]
+        .iter()
+        .zip(&decoded[1])
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f32>()
+        .sqrt();
+    let approx = codes[0].distance(&codes[1]) * quantizer.scale();
+    assert!((exact - approx).abs() < 1e-4);
+
+    let long = (0..37).map(|i| (i % 7) as f32).collect::<Vec<_>>();
+    let reversed = long.iter().rev().copied().collect::<Vec<_>>();
+    let quantizer = Sq4::train([&long]);
+    let (a, b) = (quantizer.encode(&long), quantizer.encode(&reversed));
+    let expected = (0..37)
+        .map(|i| {
+            let diff = a.get(i) as f32 - b.get(i) as f32;
+            diff * diff
+        })
+        .sum::<f32>()
+        .sqrt();
+    assert_eq!(a.distance(&b), expected);
+}
+
+#[test]
+fn residual() {
+    use instant_distance::quantize::Residual;
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let sample = (0..2048)
+        .map(|_| {
+            (0..8)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<_>>();
+
+    // Each stage reduces the quantization error
+    let error = |quantizer: &Residual| {
+        sample
+            .iter()
+            .map(|vector| quantizer.distance(vector, &quantizer.encode(vector)))
+            .sum::<f32>()
+    };
+    let one = Residual::train(&sample, 1, 1).unwrap();
+    let three = Residual::train(&sample, 3, 1).unwrap();
+    assert_eq!(three.encode(&sample[0]).0.len(), 3);
+    assert!(error(&three) < error(&one) * 0.75);
+
+    let decoded = three.decode(&three.encode(&sample[5]));
+    assert_eq!(decoded.len(), 8);
+    assert_eq!(Residual::train(&sample, 3, 1).unwrap(), three);
+
+    assert!(matches!(
+        Residual::train(&sample, 0, 1),
+        Err(Error::InvalidParameter { name: "stages", .. })
+    ));
+    assert!(matches!(
+        Residual::train(&[vec![0.0; 2], vec![0.0; 3]], 1, 1),
+        Err(Error::DimensionMismatch {
+            index: 1,
+            expected: 2,
+            found: 3
+        })
+    ));
+}
+
+#[test]
+fn anisotropic() {
+    use instant_distance::quantize::Residual;
+    use instant_distance::train::{AnisotropicLoss, KMeans};
+
+    let mut rng = StdRng::seed_from_u64(9);
+    let sample = (0..1024)
+        .map(|_| {
+            (0..16)
+                .map(|_| rng.gen_range(-1.0..1.0))
+                .collect::<Vec<f32>>()
+        })
+        .collect::<Vec<_>>();
+
+    // The loss weights the error parallel to the vector by eta
+    let loss = AnisotropicLoss::from_threshold(0.5, 16).unwrap();
+    assert_eq!(loss.eta(), 5.0);
+    assert_eq!(loss.loss(&[2.0, 0.0], &[1.0, 1.0]), 6.0);
+
+    // Parallel error, and squared error of the inner products with the vectors themselves
+    let errors = |quantizer: &Residual| {
+        sample
+            .iter()
+            .fold((0.0, 0.0), |(parallel, product), vector| {
+                let code = quantizer.encode(vector);
+                let decoded = quantizer.decode(&code);
+                let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+                let along = vector
+                    .iter()
+                    .zip(&decoded)
+                    .map(|(v, d)| (v - d) * v)
+                    .sum::<f32>();
+                let exact = norm * norm;
+                let estimate = quantizer.inner_product(vector, &code);
+                (
+                    parallel + (along / norm).powi(2),
+                    product + (exact - estimate).powi(2),
+                )
+            })
+    };
+    let plain = Residual::train(&sample, 2, 1).unwrap();
+    let anisotropic = Residual::train_anisotropic(&sample, 2, loss, 1).unwrap();
+    assert_eq!(anisotropic.loss(), Some(&loss));
+    assert_eq!(plain.loss(), None);
+    let (plain_parallel, plain_product) = errors(&plain);
+    let (parallel, product) = errors(&anisotropic);
+    assert!(
+        parallel < plain_parallel * 0.75,
+        "{parallel} {plain_parallel}"
+    );
+    assert!(product < plain_product, "{product} {plain_product}");
+
+    // Mini-batch training also runs with the loss
+    let centroids = KMeans::new(16)
+        .seed(3)
+        .mini_batch(Some(128))
+        .iterations(20)
+        .anisotropic(Some(loss))
+        .train(&sample)
+        .unwrap();
+    assert_eq!(centroids.len(), 16);
+    let (i, cost) = centroids.nearest_anisotropic(&sample[0], &sample[0], &loss);
+    assert_eq!(cost, loss.loss(&sample[0], centroids.get(i)));
+
+    assert!(matches!(
+        AnisotropicLoss::new(0.0),
+        Err(Error::InvalidParameter { name: "eta", .. })
+    ));
+    assert!(matches!(
+        AnisotropicLoss::from_threshold(1.0, 16),
+        Err(Error::InvalidParameter {
+            name: "threshold",
+            ..
+        })
+    ));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn hybrid() {
+    use instant_distance::hybrid::{Access, HybridIndex, VectorFile};
+
+    let vectors = (0..256)
+        .map(|i| [(i % 16) as f32, (i / 16) as f32, 0.5])
+        .collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("hybrid-{}.vecs", std::process::id()));
+    let file = VectorFile::create(&path, 3, vectors.iter().map(|v| &v[..])).unwrap();
+    assert_eq!(file.len(), 256);
+    assert_eq!(file.get(17).to_vec(), vectors[17]);
+
+    let index = HybridIndex::build(Builder::default(), VectorFile::open(&path).unwrap()).unwrap();
+    let mut search = Search::default();
+    let results = index.search(&[3.0, 5.0, 0.5], 3, &mut search);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0], (5 * 16 + 3, 0.0));
+    assert_eq!(results[1].1, 1.0);
+
+    // Access hints don't change the results
+    index.warm_up().unwrap();
+    assert_eq!(index.search(&[3.0, 5.0, 0.5], 3, &mut search), results);
+    let mut file = file;
+    file.set_access(Access::Sequential).unwrap();
+    assert_eq!(file.iter().nth(17).unwrap(), vectors[17]);
+
+    // Windows doesn't delete files that are still mapped
+    drop((file, index));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+#[test]
+fn hybrid_uring() {
+    use instant_distance::hybrid::{HybridIndex, UringReader, VectorFile};
+
+    let vectors = (0..256)
+        .map(|i| [(i % 16) as f32, (i / 16) as f32, 0.5])
+        .collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("uring-{}.vecs", std::process::id()));
+    let file = VectorFile::create(&path, 3, vectors.iter().map(|v| &v[..])).unwrap();
+    let index = HybridIndex::build(Builder::default(), file).unwrap();
+
+    // io_uring may be disabled in the kernel or blocked in containers
+    let mut reader = match UringReader::new(index.vectors(), 8) {
+        Ok(reader) => reader,
+        Err(Error::Io(_)) => {
+            std::fs::remove_file(&path).unwrap();
+            return;
+        }
+        Err(error) => panic!("{error}"),
+    };
+
+    assert_eq!(reader.read([17, 3]).unwrap(), [vectors[17], vectors[3]]);
+    let mut search = Search::default();
+    let expected = index.search(&[3.0, 5.0, 0.5], 3, &mut search);
+    let results = index
+        .search_uring(&[3.0, 5.0, 0.5], 3, &mut search, &mut reader)
+        .unwrap();
+    assert_eq!(results, expected);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(all(feature = "uring", target_os = "linux"))]
+#[test]
+fn uring_reader_errors() {
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    use instant_distance::hybrid::{UringReader, VectorFile};
+
+    let vectors = (0..16).map(|i| [i as f32, 1.0]).collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("uring-errors-{}.vecs", std::process::id()));
+    let file = VectorFile::create(&path, 2, vectors.iter().map(|v| &v[..])).unwrap();
+
+    // io_uring may be disabled in the kernel or blocked in containers
+    let mut reader = match UringReader::new(&file, 4) {
+        Ok(reader) => reader,
+        Err(Error::Io(_)) => {
+            std::fs::remove_file(&path).unwrap();
+            return;
+        }
+        Err(error) => panic!("{error}"),
+    };
+    drop(file);
+
+    // An out-of-range position panics before any read is submitted
+    let result = catch_unwind(AssertUnwindSafe(|| reader.read([3, 16, 5])));
+    assert!(result.is_err());
+    assert_eq!(reader.read([3, 5]).unwrap(), [vectors[3], vectors[5]]);
+
+    // Cut the last vector in half: its read comes up short, but the rest of the batches are
+    // still completed, so the next read doesn't see their completions
+    let size = std::fs::metadata(&path).unwrap().len();
+    let truncated = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+    truncated.set_len(size - 4).unwrap();
+    let result = reader.read([0, 15, 1, 2, 3, 4, 5, 6, 7]);
+    assert!(matches!(result, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof));
+    for _ in 0..4 {
+        assert_eq!(
+            reader.read([9, 8, 7, 6, 5]).unwrap(),
+            [vectors[9], vectors[8], vectors[7], vectors[6], vectors[5]]
+        );
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped_values() {
+    use instant_distance::values::MappedValues;
+    use instant_distance::HnswMap;
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let (hnsw, rows) = Builder::default()
+        .build(points, (0..64).collect::<Vec<usize>>())
+        .into_parts();
+
+    let path = std::env::temp_dir().join(format!("values-{}.vals", std::process::id()));
+    let strings = rows.iter().map(|i| format!("point {i}"));
+    let values = MappedValues::create(&path, strings).unwrap();
+    assert_eq!(values.width(), None);
+    assert_eq!(values.get(3), format!("point {}", rows[3]).as_bytes());
+
+    let map = HnswMap::<_, str, _>::from_parts(hnsw, values).unwrap();
+    let mut search = Search::default();
+    let item = map.search(&Point(12.2, 0.0), &mut search).next().unwrap();
+    assert_eq!(item.value, "point 12");
+
+    let (hnsw, _) = map.into_parts();
+    let fixed = rows.iter().map(|&i| (i as u32).to_le_bytes());
+    let values = MappedValues::create_fixed(&path, 4, fixed).unwrap();
+    assert_eq!(values.width(), Some(4));
+
+    let map = HnswMap::<_, [u8], _>::from_parts(hnsw, MappedValues::open(&path).unwrap()).unwrap();
+    let item = map.search(&Point(40.1, 0.0), &mut search).next().unwrap();
+    assert_eq!(item.value, 40u32.to_le_bytes());
+
+    // A failed write leaves the existing file, and the mappings of it, intact
+    let result = MappedValues::create_fixed(&path, 4, [&b"abc"[..]]);
+    assert!(matches!(result, Err(Error::InvalidParameter { .. })));
+    assert_eq!(values.get(3), (rows[3] as u32).to_le_bytes());
+    let item = map.search(&Point(40.1, 0.0), &mut search).next().unwrap();
+    assert_eq!(item.value, 40u32.to_le_bytes());
+
+    // Windows doesn't replace files that are still mapped
+    drop((values, map));
+    let values = MappedValues::create(&path, [&b"ab"[..], &[0xff], b"c"]).unwrap();
+    assert_eq!(values.get_str(0).unwrap(), "ab");
+    assert!(matches!(values.get_str(1), Err(Error::Serialization(_))));
+    drop(values);
+
+    // The offset table follows the 32-byte header and the 4 bytes of values
+    let file = std::fs::read(&path).unwrap();
+    for (entry, offset) in [(1, 100u64), (2, 1)] {
+        let mut corrupt = file.clone();
+        let start = 36 + entry * 8;
+        corrupt[start..start + 8].copy_from_slice(&offset.to_le_bytes());
+        std::fs::write(&path, corrupt).unwrap();
+        let result = MappedValues::open(&path);
+        assert!(matches!(result, Err(Error::Serialization(_))));
+    }
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "object_store")]
+#[test]
+fn object_store() {
+    use std::sync::Arc;
+
+    use futures::executor::block_on;
+    use instant_distance::hybrid::{HybridIndex, RemoteHybridIndex, RemoteVectorFile, VectorFile};
+    use instant_distance::values::{MappedValues, RemoteValues};
+    use instant_distance::{Hnsw, HnswMap};
+    use object_store::memory::InMemory;
+    use object_store::path::Path as ObjectPath;
+    use object_store::ObjectStore;
+
+    let store = Arc::new(InMemory::new());
+    let upload = |path: &std::path::Path, location: &str| {
+        let bytes = std::fs::read(path).unwrap();
+        block_on(store.put(&location.into(), bytes.into())).unwrap();
+        std::fs::remove_file(path).unwrap();
+    };
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+    block_on(hnsw.save_object(&*store, &"points.idx".into())).unwrap();
+    let loaded = block_on(Hnsw::<Point>::load_object(&*store, &"points.idx".into())).unwrap();
+    let mut search = Search::default();
+    let item = loaded
+        .search(&Point(12.2, 0.0), &mut search)
+        .next()
+        .unwrap();
+    assert_eq!(item.point.0, 12.0);
+
+    let result = block_on(Hnsw::<Point>::load_object(&*store, &"missing".into()));
+    assert!(matches!(result, Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::NotFound));
+
+    // The graph and the vector file of a hybrid index, both read from the store
+    let vectors = (0..256)
+        .map(|i| [(i % 16) as f32, (i / 16) as f32, 0.5])
+        .collect::<Vec<_>>();
+    let path = std::env::temp_dir().join(format!("remote-{}.vecs", std::process::id()));
+    let file = VectorFile::create(&path, 3, vectors.iter().map(|v| &v[..])).unwrap();
+    let index = HybridIndex::build(Builder::default(), file).unwrap();
+    let expected = index.search(&[3.0, 5.0, 0.5], 3, &mut search);
+    let (map, quantizer) = index.parts();
+    block_on(map.save_object(&*store, &"hybrid/map.idx".into())).unwrap();
+    let quantizer = *quantizer;
+    drop(index);
+    upload(&path, "hybrid/vectors");
+
+    let map = block_on(HnswMap::load_object(&*store, &"hybrid/map.idx".into())).unwrap();
+    let location = ObjectPath::from("hybrid/vectors");
+    let vectors_file = block_on(RemoteVectorFile::open(store.clone(), location)).unwrap();
+    assert_eq!((vectors_file.len(), vectors_file.dims()), (256, Some(3)));
+    let read = block_on(vectors_file.read([17, 3])).unwrap();
+    assert_eq!(read, [vectors[17], vectors[3]]);
+
+    let index = RemoteHybridIndex::from_parts(map, quantizer, vectors_file).unwrap();
+    let results = block_on(index.search(&[3.0, 5.0, 0.5], 3, &mut search)).unwrap();
+    assert_eq!(results, expected);
+
+    // Value files with and without an offset table
+    let path = std::env::temp_dir().join(format!("remote-{}.vals", std::process::id()));
+    MappedValues::create(&path, ["a", "bcd", "", "ef"]).unwrap();
+    upload(&path, "values/strings");
+    let values = block_on(RemoteValues::open(store.clone(), "values/strings".into())).unwrap();
+    assert_eq!((values.len(), values.width()), (4, None));
+    assert_eq!(block_on(values.get(1)).unwrap(), b"bcd");
+    let read = block_on(values.read([3, 2, 0])).unwrap();
+    assert_eq!(read, [&b"ef"[..], b"", b"a"]);
+
+    MappedValues::create_fixed(&path, 2, ["ab", "cd", "ef"]).unwrap();
+    upload(&path, "values/fixed");
+    let values = block_on(RemoteValues::open(store.clone(), "values/fixed".into())).unwrap();
+    assert_eq!(values.width(), Some(2));
+    assert_eq!(block_on(values.read([2, 0])).unwrap(), [b"ef", b"ab"]);
+
+    let result = block_on(RemoteValues::open(store, "hybrid/vectors".into()));
+    assert!(matches!(result, Err(Error::Serialization(_))));
+}
+
+#[cfg(feature = "arrow")]
+#[test]
+fn arrow_values() {
+    use std::sync::Arc;
+
+    use arrow_array::{Array, ArrayRef, Int64Array, RecordBatch, StringArray};
+    use instant_distance::values::{RecordBatchValues, Row};
+    use instant_distance::HnswMap;
+
+    let points = (0..64).map(|i| Point(i as f32, 0.0)).collect::<Vec<_>>();
+    let names = (0..64).map(|i| format!("point {i}")).collect::<Vec<_>>();
+    let (hnsw, _) = Builder::default().build_hnsw(points);
+
+    // Each row holds the name and the square of the input position of its point
+    let batch = RecordBatch::try_from_iter([
+        (
+            "name",
+            Arc::new(StringArray::from(names.clone())) as ArrayRef,
+        ),
+        (
+            "square",
+            Arc::new(Int64Array::from_iter_values((0..64).map(|i| i * i))) as ArrayRef,
+        ),
+    ])
+    .unwrap();
+
+    let values = RecordBatchValues::new(batch.clone(), &hnsw).unwrap();
+    let map = HnswMap::<_, Row, _>::from_parts(hnsw, values).unwrap();
+    let mut search = Search::default();
+    let item = map.search(&Point(12.2, 0.0), &mut search).next().unwrap();
+    assert_eq!(item.value.index(), 12);
+    let squares = item.value.column("square").unwrap();
+    let squares = squares.as_any().downcast_ref::<Int64Array>().unwrap();
+    assert_eq!(squares.value(item.value.index()), 144);
+    assert_eq!(item.value.to_batch().num_rows(), 1);
+
+    // A single column, in `PointId` order
+    let (hnsw, _) = map.into_parts();
+    let strings = (0..hnsw.len() as u32)
+        .map(|pid| Some(&names[hnsw.input_index(pid.into()).unwrap()]))
+        .collect::<StringArray>();
+    let map = HnswMap::<_, str, _>::from_parts(hnsw, strings).unwrap();
+    let item = map.search(&Point(40.1, 0.0), &mut search).next().unwrap();
+    assert_eq!(item.value, "point 40");
+
+    let (hnsw, _) = map.into_parts();
+    let short = batch.slice(0, 10);
+    let result = RecordBatchValues::new(short, &hnsw);
+    assert!(matches!(result, Err(Error::LengthMismatch { .. })));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn vector_file_append() {
+    use instant_distance::hybrid::VectorFile;
+
+    let path = std::env::temp_dir().join(format!("append-{}.vecs", std::process::id()));
+    let mut file = VectorFile::create(&path, 2, [&[0.0, 1.0][..]]).unwrap();
+    assert_eq!(file.capacity(), 1);
+
+    file.reserve(10).unwrap();
+    assert!(file.capacity() >= 11);
+    assert_eq!(file.len(), 1);
+
+    let vectors = (1..8).map(|i| [i as f32, 1.0]).collect::<Vec<_>>();
+    file.append(vectors.iter().map(|v| &v[..])).unwrap();
+    assert_eq!(file.len(), 8);
+    assert_eq!(file.get(3).to_vec(), [3.0, 1.0]);
+
+    let err = file.append([&[1.0][..]]).err();
+    assert!(matches!(
+        err,
+        Some(Error::DimensionMismatch { index: 8, .. })
+    ));
+
+    // Appending past the reserved space grows the file
+    let vectors = (8..32).map(|i| [i as f32, 1.0]).collect::<Vec<_>>();
+    file.append(vectors.iter().map(|v| &v[..])).unwrap();
+    assert_eq!(file.get(31).to_vec(), [31.0, 1.0]);
+
+    let reopened = VectorFile::open(&path).unwrap();
+    assert_eq!(reopened.len(), 32);
+    assert_eq!(reopened.get(20).to_vec(), [20.0, 1.0]);
+
+    // Windows doesn't delete files that are still mapped
+    drop((file, reopened));
+    std::fs::remove_file(&path).unwrap();
 }
 
+#[cfg_attr(feature = "with-serde", derive(serde::Deserialize, serde::Serialize))]
 #[derive(Clone, Copy, Debug)]
 struct Point(f32, f32);
 
@@ -95,4 +3024,8 @@ impl instant_distance::Point for Point {
         // Euclidean distance metric
         ((self.0 - other.0).powi(2) + (self.1 - other.1).powi(2)).sqrt()
     }
+
+    fn dims(&self) -> Option<usize> {
+        Some(2)
+    }
 }